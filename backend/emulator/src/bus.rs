@@ -0,0 +1,79 @@
+/*
+ * Everything the CPU is connected to. The ports get the whole 16 bits
+ * put on the address bus: B or A in the high byte.
+ */
+pub trait Bus {
+	fn read(&mut self, address: u16) -> u8;
+	fn write(&mut self, address: u16, value: u8);
+	fn input(&mut self, port: u16) -> u8;
+	fn output(&mut self, port: u16, value: u8);
+}
+
+/*
+ * 64 KiB of RAM, with the ports handled by the host. Without any
+ * handler, the inputs read 0xFF and the outputs are lost.
+ */
+pub struct Memory<'a> {
+	bytes: Vec<u8>,
+	input: Box<dyn FnMut(u16) -> u8 + 'a>,
+	output: Box<dyn FnMut(u16, u8) + 'a>,
+}
+
+impl Default for Memory<'_> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<'a> Memory<'a> {
+	pub fn new() -> Self {
+		Self {
+			bytes: vec![0; 0x10000],
+			input: Box::new(|_| 0xFF),
+			output: Box::new(|_, _| {}),
+		}
+	}
+
+	pub fn set_input_handler(&mut self, handler: impl FnMut(u16) -> u8 + 'a) {
+		self.input = Box::new(handler);
+	}
+
+	pub fn set_output_handler(&mut self, handler: impl FnMut(u16, u8) + 'a) {
+		self.output = Box::new(handler);
+	}
+
+	pub fn load(&mut self, address: u16, data: &[u8]) {
+		for (i, byte) in data.iter().enumerate() {
+			self.bytes[address.wrapping_add(i as u16) as usize] = *byte;
+		}
+	}
+
+	pub fn read_byte(&self, address: u16) -> u8 {
+		self.bytes[address as usize]
+	}
+
+	pub fn read_word(&self, address: u16) -> u16 {
+		u16::from_le_bytes([
+			self.read_byte(address),
+			self.read_byte(address.wrapping_add(1)),
+		])
+	}
+}
+
+impl Bus for Memory<'_> {
+	fn read(&mut self, address: u16) -> u8 {
+		self.bytes[address as usize]
+	}
+
+	fn write(&mut self, address: u16, value: u8) {
+		self.bytes[address as usize] = value;
+	}
+
+	fn input(&mut self, port: u16) -> u8 {
+		(self.input)(port)
+	}
+
+	fn output(&mut self, port: u16, value: u8) {
+		(self.output)(port, value)
+	}
+}
@@ -0,0 +1,523 @@
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::bus::{Bus, Memory};
+use crate::cpu;
+use crate::cpu::Cpu;
+
+/*
+ * Where everything is, with a TPA going up to BDOS_BASE. CALL 5 is
+ * trapped: nothing of CP/M itself is in memory.
+ */
+pub const BDOS: u16 = 0x0005;
+pub const BDOS_BASE: u16 = 0xFE00;
+pub const BIOS_BASE: u16 = 0xFF00;
+pub const FCB1: u16 = 0x005C;
+pub const FCB2: u16 = 0x006C;
+pub const DEFAULT_DMA: u16 = 0x0080;
+pub const TPA: u16 = 0x0100;
+
+const RECORD_SIZE: usize = 128;
+const END_OF_FILE: u8 = 0x1A;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	Cpu(cpu::Error),
+	UnsupportedFunction(u8),
+	Io(io::ErrorKind),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| Error::Cpu(cpu::Error::UndocumentedInstruction(address)) => {
+				write!(f, "Undocumented instruction at {:04X}H", address)
+			}
+			| Error::Cpu(cpu::Error::CycleLimitReached) => {
+				write!(f, "The program doesn't terminate")
+			}
+			| Error::UnsupportedFunction(c) => write!(f, "Unsupported BDOS function {}", c),
+			| Error::Io(kind) => write!(f, "Console error: {}", kind),
+		}
+	}
+}
+
+impl From<cpu::Error> for Error {
+	fn from(e: cpu::Error) -> Self {
+		Error::Cpu(e)
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Self {
+		Error::Io(e.kind())
+	}
+}
+
+/*
+ * A CP/M 2.2 machine running a .COM file: the BDOS calls are done by the
+ * host, the console is a reader and a writer, and drive A: is a directory.
+ */
+pub struct Cpm<'a> {
+	pub cpu: Cpu,
+	pub memory: Memory<'a>,
+	directory: PathBuf,
+	input: Box<dyn BufRead + 'a>,
+	output: Box<dyn Write + 'a>,
+	dma: u16,
+	// The entries left to return to SEARCH NEXT
+	search: Vec<String>,
+	cycle_limit: Option<u64>,
+}
+
+impl<'a> Cpm<'a> {
+	pub fn new(directory: &Path, input: impl BufRead + 'a, output: impl Write + 'a) -> Self {
+		let mut memory = Memory::new();
+		memory.load(0x0000, &[0xC3, 0x03, (BIOS_BASE >> 8) as u8]);
+		memory.load(BDOS, &[0xC3, BDOS_BASE as u8, (BDOS_BASE >> 8) as u8]);
+
+		// Returning from the program goes to the warm boot
+		let mut cpu = Cpu::new(false);
+		cpu.registers.sp = BDOS_BASE - 2;
+		cpu.registers.pc = TPA;
+		Self {
+			cpu,
+			memory,
+			directory: directory.to_path_buf(),
+			input: Box::new(input),
+			output: Box::new(output),
+			dma: DEFAULT_DMA,
+			search: vec![],
+			cycle_limit: None,
+		}
+	}
+
+	pub fn set_cycle_limit(&mut self, cycles: u64) {
+		self.cycle_limit = Some(cycles);
+	}
+
+	pub fn load(&mut self, program: &[u8]) {
+		self.memory.load(TPA, program);
+	}
+
+	/*
+	 * What the CCP does with the arguments: they go to the command tail,
+	 * and the first two are parsed into the default FCBs.
+	 */
+	pub fn set_arguments(&mut self, arguments: &[String]) {
+		let mut tail: String = arguments
+			.iter()
+			.map(|arg| format!(" {}", arg.to_ascii_uppercase()))
+			.collect();
+		tail.truncate(RECORD_SIZE - 2);
+		let mut bytes = vec![tail.len() as u8];
+		bytes.extend(tail.bytes());
+		bytes.push(0);
+		self.memory.load(DEFAULT_DMA, &bytes);
+
+		for (i, fcb) in [FCB1, FCB2].into_iter().enumerate() {
+			let name = arguments
+				.get(i)
+				.map_or([b' '; 11], |argument| file_name_to_fcb(argument));
+			self.memory.load(fcb, &[0; 16]);
+			self.memory.load(fcb + 1, &name);
+		}
+	}
+
+	// Until the program returns, does a warm boot or a system reset
+	pub fn run(&mut self) -> Result<(), Error> {
+		let start = self.cpu.cycles;
+		loop {
+			match self.cpu.registers.pc {
+				| 0 => return Ok(()),
+				| BDOS => {
+					if !self.bdos()? {
+						return Ok(());
+					}
+				}
+				| _ => {
+					self.cpu.step(&mut self.memory)?;
+				}
+			}
+
+			// Nothing can end a HALT with the interrupts disabled
+			if self.cpu.halted && !self.cpu.iff1 {
+				return Ok(());
+			}
+			if let Some(limit) = self.cycle_limit {
+				if self.cpu.cycles - start >= limit {
+					return Err(Error::Cpu(cpu::Error::CycleLimitReached));
+				}
+			}
+		}
+	}
+
+	// Executes the function in C, and returns false on a system reset
+	fn bdos(&mut self) -> Result<bool, Error> {
+		let regs = self.cpu.registers;
+		let (function, de) = (regs.c, regs.de());
+		let result: u16 = match function {
+			| 0 => return Ok(false),
+			| 1 => {
+				let c = self.read_console()?.unwrap_or(END_OF_FILE);
+				let c = if c == b'\n' { b'\r' } else { c };
+				if c == b'\r' {
+					self.write_console(b"\r\n")?;
+				} else {
+					self.write_console(&[c])?;
+				}
+				c as u16
+			}
+			| 2 => {
+				self.write_console(&[regs.e])?;
+				0
+			}
+			| 6 => match regs.e {
+				| 0xFF => self.read_console()?.unwrap_or(0) as u16,
+				| 0xFE => self.console_status()?,
+				| c => {
+					self.write_console(&[c])?;
+					0
+				}
+			},
+			| 9 => {
+				let mut text = vec![];
+				let mut address = de;
+				while self.memory.read_byte(address) != b'$' {
+					text.push(self.memory.read_byte(address));
+					address = address.wrapping_add(1);
+				}
+				self.write_console(&text)?;
+				0
+			}
+			| 10 => {
+				let mut line = vec![];
+				self.input.read_until(b'\n', &mut line)?;
+				while let Some(b'\n' | b'\r') = line.last() {
+					line.pop();
+				}
+				line.truncate(self.memory.read_byte(de) as usize);
+				self.write_console(&line)?;
+				self.write_console(b"\r\n")?;
+				self.memory.write(de.wrapping_add(1), line.len() as u8);
+				self.memory.load(de.wrapping_add(2), &line);
+				0
+			}
+			| 11 => self.console_status()?,
+			| 12 => 0x0022,
+			| 13 => {
+				self.dma = DEFAULT_DMA;
+				0
+			}
+			| 14 | 32 => 0,
+			| 15 => self.open_file(de),
+			| 16 => self.find(de).map_or(0xFF, |_| 0),
+			| 17 => {
+				self.search = self.matching_files(de);
+				self.search.reverse();
+				self.search_next()
+			}
+			| 18 => self.search_next(),
+			| 19 => {
+				let files = self.matching_files(de);
+				let deleted = files
+					.iter()
+					.filter(|name| fs::remove_file(self.directory.join(name)).is_ok())
+					.count();
+				if deleted == 0 {
+					0xFF
+				} else {
+					0
+				}
+			}
+			| 20 => self.read_sequential(de),
+			| 21 => self.write_sequential(de),
+			| 22 => self.make_file(de),
+			| 23 => self.rename_file(de),
+			| 24 => 0x0001,
+			| 25 => 0,
+			| 26 => {
+				self.dma = de;
+				0
+			}
+			| 33 => {
+				let record = self.random_record(de);
+				self.set_current_record(de, record);
+				self.read_record(de, record)
+			}
+			| 34 => {
+				let record = self.random_record(de);
+				self.set_current_record(de, record);
+				self.write_record(de, record)
+			}
+			| 35 => match self.find(de) {
+				| Some(path) => {
+					let size = fs::metadata(path).map_or(0, |m| m.len() as usize);
+					self.set_random_record(de, size.div_ceil(RECORD_SIZE));
+					0
+				}
+				| None => 0xFF,
+			},
+			| 36 => {
+				let record = self.current_record(de);
+				self.set_random_record(de, record);
+				0
+			}
+			| _ => return Err(Error::UnsupportedFunction(function)),
+		};
+
+		// A is L and B is H, as on a real BDOS
+		let [low, high] = result.to_le_bytes();
+		let regs = &mut self.cpu.registers;
+		(regs.l, regs.a, regs.h, regs.b) = (low, low, high, high);
+		let sp = regs.sp;
+		regs.pc = self.memory.read_word(sp);
+		regs.sp = sp.wrapping_add(2);
+		Ok(true)
+	}
+
+	fn read_console(&mut self) -> Result<Option<u8>, Error> {
+		let mut byte = [0];
+		match self.input.read(&mut byte)? {
+			| 0 => Ok(None),
+			| _ => Ok(Some(byte[0])),
+		}
+	}
+
+	fn console_status(&mut self) -> Result<u16, Error> {
+		Ok(if self.input.fill_buf()?.is_empty() {
+			0
+		} else {
+			0xFF
+		})
+	}
+
+	fn write_console(&mut self, bytes: &[u8]) -> Result<(), Error> {
+		self.output.write_all(bytes)?;
+		self.output.flush()?;
+		Ok(())
+	}
+
+	fn fcb_name(&self, fcb: u16) -> [u8; 11] {
+		let mut name = [0; 11];
+		for (i, c) in name.iter_mut().enumerate() {
+			// The high bits are the file attributes
+			*c = self.memory.read_byte(fcb + 1 + i as u16) & 0x7F;
+		}
+		name
+	}
+
+	// The host files with the name of the FCB, where '?' matches anything
+	fn matching_files(&self, fcb: u16) -> Vec<String> {
+		let pattern = self.fcb_name(fcb);
+		let Ok(entries) = fs::read_dir(&self.directory) else {
+			return vec![];
+		};
+		let mut files: Vec<String> = entries
+			.filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+			.filter(|name| {
+				let name = file_name_to_fcb(name);
+				is_cpm_name(&name)
+					&& name
+						.iter()
+						.zip(pattern.iter())
+						.all(|(c, p)| *p == b'?' || c == p)
+			})
+			.collect();
+		files.sort();
+		files
+	}
+
+	fn find(&self, fcb: u16) -> Option<PathBuf> {
+		let name = self.fcb_name(fcb);
+		if name.contains(&b'?') {
+			return None;
+		}
+		self.matching_files(fcb)
+			.first()
+			.map(|file| self.directory.join(file))
+	}
+
+	// Where the host file is created: the name in upper case
+	fn new_file_path(&self, name: &[u8; 11]) -> PathBuf {
+		let (base, extension) = name.split_at(8);
+		let base = String::from_utf8_lossy(base).trim_end().to_string();
+		let extension = String::from_utf8_lossy(extension).trim_end().to_string();
+		if extension.is_empty() {
+			self.directory.join(base)
+		} else {
+			self.directory.join(format!("{}.{}", base, extension))
+		}
+	}
+
+	fn search_next(&mut self) -> u16 {
+		match self.search.pop() {
+			| Some(name) => {
+				let mut entry = [0; 32];
+				entry[1..12].copy_from_slice(&file_name_to_fcb(&name));
+				let size = fs::metadata(self.directory.join(&name)).map_or(0, |m| m.len() as usize);
+				entry[15] = size.div_ceil(RECORD_SIZE).min(RECORD_SIZE) as u8;
+				self.memory.load(self.dma, &entry);
+				0
+			}
+			| None => 0xFF,
+		}
+	}
+
+	fn open_file(&mut self, fcb: u16) -> u16 {
+		let Some(path) = self.find(fcb) else {
+			return 0xFF;
+		};
+		let size = fs::metadata(path).map_or(0, |m| m.len() as usize);
+		let extent = self.current_record(fcb) / RECORD_SIZE;
+		let records = size
+			.div_ceil(RECORD_SIZE)
+			.saturating_sub(extent * RECORD_SIZE);
+		self.memory.write(fcb + 15, records.min(RECORD_SIZE) as u8);
+		0
+	}
+
+	fn make_file(&mut self, fcb: u16) -> u16 {
+		let path = self.new_file_path(&self.fcb_name(fcb));
+		match File::create(path) {
+			| Ok(_) => {
+				self.memory.write(fcb + 15, 0);
+				0
+			}
+			| Err(_) => 0xFF,
+		}
+	}
+
+	fn rename_file(&mut self, fcb: u16) -> u16 {
+		let Some(path) = self.find(fcb) else {
+			return 0xFF;
+		};
+		let new_path = self.new_file_path(&self.fcb_name(fcb + 16));
+		if fs::rename(path, new_path).is_ok() {
+			0
+		} else {
+			0xFF
+		}
+	}
+
+	/*
+	 * The sequential position: CR records in the extent EX, with S2
+	 * counting the groups of 32 extents.
+	 */
+	fn current_record(&self, fcb: u16) -> usize {
+		let extent = self.memory.read_byte(fcb + 12) as usize & 0x1F;
+		let module = self.memory.read_byte(fcb + 14) as usize & 0x3F;
+		let record = self.memory.read_byte(fcb + 32) as usize & 0x7F;
+		(module * 32 + extent) * RECORD_SIZE + record
+	}
+
+	fn set_current_record(&mut self, fcb: u16, record: usize) {
+		self.memory.write(fcb + 32, (record % RECORD_SIZE) as u8);
+		self.memory
+			.write(fcb + 12, (record / RECORD_SIZE % 32) as u8);
+		self.memory
+			.write(fcb + 14, (record / RECORD_SIZE / 32) as u8);
+	}
+
+	fn random_record(&self, fcb: u16) -> usize {
+		self.memory.read_word(fcb + 33) as usize
+	}
+
+	fn set_random_record(&mut self, fcb: u16, record: usize) {
+		let [low, high] = (record as u16).to_le_bytes();
+		self.memory
+			.load(fcb + 33, &[low, high, (record >> 16) as u8]);
+	}
+
+	fn read_sequential(&mut self, fcb: u16) -> u16 {
+		let record = self.current_record(fcb);
+		let result = self.read_record(fcb, record);
+		if result == 0 {
+			self.set_current_record(fcb, record + 1);
+		}
+		result
+	}
+
+	fn write_sequential(&mut self, fcb: u16) -> u16 {
+		let record = self.current_record(fcb);
+		let result = self.write_record(fcb, record);
+		if result == 0 {
+			self.set_current_record(fcb, record + 1);
+		}
+		result
+	}
+
+	// The record goes to the DMA buffer, padded with ^Z. 1 is the end of file.
+	fn read_record(&mut self, fcb: u16, record: usize) -> u16 {
+		let Some(path) = self.find(fcb) else {
+			return 0xFF;
+		};
+		let mut buffer = [END_OF_FILE; RECORD_SIZE];
+		let read = File::open(path).and_then(|mut file| {
+			file.seek(SeekFrom::Start((record * RECORD_SIZE) as u64))?;
+			let mut total = 0;
+			while total < RECORD_SIZE {
+				match file.read(&mut buffer[total..])? {
+					| 0 => break,
+					| n => total += n,
+				}
+			}
+			Ok(total)
+		});
+		match read {
+			| Ok(0) | Err(_) => 1,
+			| Ok(_) => {
+				self.memory.load(self.dma, &buffer);
+				0
+			}
+		}
+	}
+
+	// 2 is the disk full, for any error of the host
+	fn write_record(&mut self, fcb: u16, record: usize) -> u16 {
+		let Some(path) = self.find(fcb) else {
+			return 0xFF;
+		};
+		let buffer: Vec<u8> = (0..RECORD_SIZE as u16)
+			.map(|i| self.memory.read_byte(self.dma.wrapping_add(i)))
+			.collect();
+		let written = OpenOptions::new()
+			.write(true)
+			.open(path)
+			.and_then(|mut file| {
+				file.seek(SeekFrom::Start((record * RECORD_SIZE) as u64))?;
+				file.write_all(&buffer)
+			});
+		match written {
+			| Ok(()) => 0,
+			| Err(_) => 2,
+		}
+	}
+}
+
+fn is_cpm_name(name: &[u8; 11]) -> bool {
+	name.iter().all(|c| c.is_ascii_graphic() || *c == b' ') && name[0] != b' '
+}
+
+// NAME.TYP as in a FCB: upper case and padded with spaces, '*' filling the field with '?'
+pub fn file_name_to_fcb(name: &str) -> [u8; 11] {
+	let mut fcb = [b' '; 11];
+	let name = name.to_ascii_uppercase();
+	let (base, extension) = name.split_once('.').unwrap_or((&name, ""));
+	let (base_field, extension_field) = fcb.split_at_mut(8);
+	for (field, text) in [(base_field, base), (extension_field, extension)] {
+		let length = field.len();
+		for (i, c) in text.bytes().take(length).enumerate() {
+			if c == b'*' {
+				field[i..].fill(b'?');
+				break;
+			}
+			field[i] = c;
+		}
+	}
+	fcb
+}
@@ -0,0 +1,1117 @@
+use z80::timing::{ed_cycles, CYCLES};
+
+use crate::bus::Bus;
+
+pub const FLAG_C: u8 = 0x01;
+pub const FLAG_N: u8 = 0x02;
+pub const FLAG_PV: u8 = 0x04;
+// The undocumented bits 3 and 5, copies of the result most of the time
+pub const FLAG_X: u8 = 0x08;
+pub const FLAG_H: u8 = 0x10;
+pub const FLAG_Y: u8 = 0x20;
+pub const FLAG_Z: u8 = 0x40;
+pub const FLAG_S: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+	pub a: u8,
+	pub f: u8,
+	pub b: u8,
+	pub c: u8,
+	pub d: u8,
+	pub e: u8,
+	pub h: u8,
+	pub l: u8,
+
+	// Swapped by EX AF,AF' and EXX
+	pub af_: u16,
+	pub bc_: u16,
+	pub de_: u16,
+	pub hl_: u16,
+
+	pub ix: u16,
+	pub iy: u16,
+	pub sp: u16,
+	pub pc: u16,
+	pub i: u8,
+	pub r: u8,
+
+	// The internal MEMPTR, only visible through the flags of BIT n,(HL)
+	pub wz: u16,
+}
+
+macro_rules! pair {
+	($get: ident, $set: ident, $high: ident, $low: ident) => {
+		pub fn $get(&self) -> u16 {
+			u16::from_le_bytes([self.$low, self.$high])
+		}
+
+		pub fn $set(&mut self, value: u16) {
+			[self.$low, self.$high] = value.to_le_bytes();
+		}
+	};
+}
+
+impl Registers {
+	pair!(af, set_af, a, f);
+	pair!(bc, set_bc, b, c);
+	pair!(de, set_de, d, e);
+	pair!(hl, set_hl, h, l);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	// Only raised when they are disabled, nothing is executed
+	UndocumentedInstruction(u16),
+	CycleLimitReached,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Index {
+	HL,
+	IX,
+	IY,
+}
+
+fn sz53(value: u8) -> u8 {
+	(value & (FLAG_S | FLAG_Y | FLAG_X)) | if value == 0 { FLAG_Z } else { 0 }
+}
+
+fn parity(value: u8) -> u8 {
+	if value.count_ones().is_multiple_of(2) {
+		FLAG_PV
+	} else {
+		0
+	}
+}
+
+fn sz53p(value: u8) -> u8 {
+	sz53(value) | parity(value)
+}
+
+// The instructions which DD and FD turn into documented IX and IY ones
+fn is_documented_indexed(op: u8) -> bool {
+	let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+	match op {
+		| 0x09 | 0x19 | 0x29 | 0x39 | 0x21 | 0x22 | 0x2A | 0x23 | 0x2B | 0x34 | 0x35 | 0x36 => true,
+		| 0xE1 | 0xE3 | 0xE5 | 0xE9 | 0xF9 => true,
+		| _ if x == 1 => (y == 6) != (z == 6),
+		| _ if x == 2 => z == 6,
+		| _ => false,
+	}
+}
+
+fn is_documented_ed(op: u8) -> bool {
+	let (y, z, p) = ((op >> 3) & 7, op & 7, (op >> 4) & 3);
+	match op {
+		| 0x40..=0x7F => match z {
+			| 0 | 1 => y != 6,
+			| 2 => true,
+			| 3 => p != 2,
+			| 4 => y == 0,
+			| 5 => y < 2,
+			| 6 => y == 0 || y == 2 || y == 3,
+			| _ => y < 6,
+		},
+		| 0xA0..=0xBF => z < 4 && y >= 4,
+		| _ => false,
+	}
+}
+
+/*
+ * A Z80, flags included: the undocumented X and Y flags follow
+ * "The Undocumented Z80 Documented" (docs/z80). Each step executes one
+ * instruction and returns the T-states it took.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cpu {
+	pub registers: Registers,
+	pub iff1: bool,
+	pub iff2: bool,
+	pub interrupt_mode: u8,
+	pub halted: bool,
+	pub cycles: u64,
+	enable_undocumented_instructions: bool,
+	// The interrupts are only accepted after the instruction following EI
+	ei_delay: bool,
+}
+
+impl Cpu {
+	pub fn new(enable_undocumented_instructions: bool) -> Self {
+		Self {
+			registers: Registers {
+				a: 0xFF,
+				f: 0xFF,
+				sp: 0xFFFF,
+				..Default::default()
+			},
+			iff1: false,
+			iff2: false,
+			interrupt_mode: 0,
+			halted: false,
+			cycles: 0,
+			enable_undocumented_instructions,
+			ei_delay: false,
+		}
+	}
+
+	pub fn step(&mut self, bus: &mut impl Bus) -> Result<u32, Error> {
+		self.ei_delay = false;
+		let cycles = if self.halted {
+			// HALT executes NOP until an interrupt
+			self.increment_r();
+			4
+		} else {
+			let saved = self.registers;
+			match self.execute(bus) {
+				| Ok(cycles) => cycles,
+				| Err(e) => {
+					self.registers = saved;
+					return Err(e);
+				}
+			}
+		};
+		self.cycles += cycles as u64;
+		Ok(cycles)
+	}
+
+	/*
+	 * Runs until PC reaches `address`, and returns the T-states spent.
+	 * Used to call a routine with a return address pushed beforehand.
+	 */
+	pub fn run_until(
+		&mut self,
+		bus: &mut impl Bus,
+		address: u16,
+		max_cycles: u64,
+	) -> Result<u64, Error> {
+		let start = self.cycles;
+		while self.registers.pc != address {
+			if self.cycles - start >= max_cycles {
+				return Err(Error::CycleLimitReached);
+			}
+			self.step(bus)?;
+		}
+		Ok(self.cycles - start)
+	}
+
+	/*
+	 * A maskable interrupt, with the byte put on the bus by the device.
+	 * In mode 0 only a RST can be given. Returns the T-states spent, or
+	 * 0 if the interrupt isn't accepted.
+	 */
+	pub fn interrupt(&mut self, bus: &mut impl Bus, data: u8) -> u32 {
+		if !self.iff1 || self.ei_delay {
+			return 0;
+		}
+
+		self.halted = false;
+		self.iff1 = false;
+		self.iff2 = false;
+		self.increment_r();
+		let pc = self.registers.pc;
+		let cycles = match self.interrupt_mode {
+			| 2 => {
+				self.push(bus, pc);
+				let vector = u16::from_le_bytes([data, self.registers.i]);
+				self.registers.pc = self.read_word(bus, vector);
+				19
+			}
+			| 1 => {
+				self.push(bus, pc);
+				self.registers.pc = 0x38;
+				13
+			}
+			| _ if data & 0xC7 == 0xC7 => {
+				self.push(bus, pc);
+				self.registers.pc = (data & 0x38) as u16;
+				13
+			}
+			| _ => 4,
+		};
+		self.registers.wz = self.registers.pc;
+		self.cycles += cycles as u64;
+		cycles
+	}
+
+	pub fn non_maskable_interrupt(&mut self, bus: &mut impl Bus) -> u32 {
+		self.halted = false;
+		self.iff1 = false;
+		self.increment_r();
+		let pc = self.registers.pc;
+		self.push(bus, pc);
+		self.registers.pc = 0x66;
+		self.registers.wz = 0x66;
+		self.cycles += 11;
+		11
+	}
+
+	fn increment_r(&mut self) {
+		let r = self.registers.r;
+		self.registers.r = (r & 0x80) | (r.wrapping_add(1) & 0x7F);
+	}
+
+	fn fetch_opcode(&mut self, bus: &mut impl Bus) -> u8 {
+		self.increment_r();
+		self.fetch_byte(bus)
+	}
+
+	fn fetch_byte(&mut self, bus: &mut impl Bus) -> u8 {
+		let value = bus.read(self.registers.pc);
+		self.registers.pc = self.registers.pc.wrapping_add(1);
+		value
+	}
+
+	fn fetch_word(&mut self, bus: &mut impl Bus) -> u16 {
+		let low = self.fetch_byte(bus);
+		let high = self.fetch_byte(bus);
+		u16::from_le_bytes([low, high])
+	}
+
+	fn read_word(&mut self, bus: &mut impl Bus, address: u16) -> u16 {
+		u16::from_le_bytes([bus.read(address), bus.read(address.wrapping_add(1))])
+	}
+
+	fn write_word(&mut self, bus: &mut impl Bus, address: u16, value: u16) {
+		let [low, high] = value.to_le_bytes();
+		bus.write(address, low);
+		bus.write(address.wrapping_add(1), high);
+	}
+
+	fn push(&mut self, bus: &mut impl Bus, value: u16) {
+		self.registers.sp = self.registers.sp.wrapping_sub(2);
+		let sp = self.registers.sp;
+		self.write_word(bus, sp, value);
+	}
+
+	fn pop(&mut self, bus: &mut impl Bus) -> u16 {
+		let sp = self.registers.sp;
+		self.registers.sp = sp.wrapping_add(2);
+		self.read_word(bus, sp)
+	}
+
+	fn execute(&mut self, bus: &mut impl Bus) -> Result<u32, Error> {
+		let start = self.registers.pc;
+		let op = self.fetch_opcode(bus);
+		let index = match op {
+			| 0xDD => Index::IX,
+			| 0xFD => Index::IY,
+			| 0xCB => {
+				let op = self.fetch_opcode(bus);
+				if (0x30..0x38).contains(&op) {
+					self.check_undocumented(start)?;
+				}
+				return Ok(self.execute_cb(bus, op));
+			}
+			| 0xED => {
+				let op = self.fetch_opcode(bus);
+				if !is_documented_ed(op) {
+					self.check_undocumented(start)?;
+				}
+				return Ok(self.execute_ed(bus, op));
+			}
+			| _ => return Ok(self.execute_main(bus, op, Index::HL)),
+		};
+
+		// Followed by another prefix, DD and FD act as a NOP
+		let next = bus.read(self.registers.pc);
+		if next == 0xDD || next == 0xFD || next == 0xED {
+			self.check_undocumented(start)?;
+			return Ok(4);
+		}
+
+		let op = self.fetch_opcode(bus);
+		if op == 0xCB {
+			let d = self.fetch_byte(bus) as i8;
+			let op = self.fetch_byte(bus);
+			if op & 7 != 6 {
+				self.check_undocumented(start)?;
+			}
+			let address = self.index(index).wrapping_add(d as u16);
+			return Ok(self.execute_indexed_cb(bus, op, address));
+		}
+
+		if !is_documented_indexed(op) {
+			self.check_undocumented(start)?;
+		}
+		Ok(4 + self.execute_main(bus, op, index))
+	}
+
+	// Checked before the instruction has any effect on the bus
+	fn check_undocumented(&self, start: u16) -> Result<(), Error> {
+		if self.enable_undocumented_instructions {
+			Ok(())
+		} else {
+			Err(Error::UndocumentedInstruction(start))
+		}
+	}
+
+	fn index(&self, index: Index) -> u16 {
+		match index {
+			| Index::HL => self.registers.hl(),
+			| Index::IX => self.registers.ix,
+			| Index::IY => self.registers.iy,
+		}
+	}
+
+	fn set_index(&mut self, index: Index, value: u16) {
+		match index {
+			| Index::HL => self.registers.set_hl(value),
+			| Index::IX => self.registers.ix = value,
+			| Index::IY => self.registers.iy = value,
+		}
+	}
+
+	// B, C, D, E, H, L, -, A with H and L replaced by the index halves
+	fn register(&self, r: u8, index: Index) -> u8 {
+		let regs = &self.registers;
+		match (r, index) {
+			| (0, _) => regs.b,
+			| (1, _) => regs.c,
+			| (2, _) => regs.d,
+			| (3, _) => regs.e,
+			| (4, Index::HL) => regs.h,
+			| (5, Index::HL) => regs.l,
+			| (4, _) => (self.index(index) >> 8) as u8,
+			| (5, _) => self.index(index) as u8,
+			| _ => regs.a,
+		}
+	}
+
+	fn set_register(&mut self, r: u8, index: Index, value: u8) {
+		match (r, index) {
+			| (0, _) => self.registers.b = value,
+			| (1, _) => self.registers.c = value,
+			| (2, _) => self.registers.d = value,
+			| (3, _) => self.registers.e = value,
+			| (4, Index::HL) => self.registers.h = value,
+			| (5, Index::HL) => self.registers.l = value,
+			| (4, _) => {
+				let low = self.index(index) & 0xFF;
+				self.set_index(index, ((value as u16) << 8) | low);
+			}
+			| (5, _) => {
+				let high = self.index(index) & 0xFF00;
+				self.set_index(index, high | value as u16);
+			}
+			| _ => self.registers.a = value,
+		}
+	}
+
+	// BC, DE, HL, SP
+	fn pair(&self, p: u8, index: Index) -> u16 {
+		match p {
+			| 0 => self.registers.bc(),
+			| 1 => self.registers.de(),
+			| 2 => self.index(index),
+			| _ => self.registers.sp,
+		}
+	}
+
+	fn set_pair(&mut self, p: u8, index: Index, value: u16) {
+		match p {
+			| 0 => self.registers.set_bc(value),
+			| 1 => self.registers.set_de(value),
+			| 2 => self.set_index(index, value),
+			| _ => self.registers.sp = value,
+		}
+	}
+
+	// NZ, Z, NC, C, PO, PE, P, M
+	fn condition(&self, y: u8) -> bool {
+		let f = self.registers.f;
+		let flag = [FLAG_Z, FLAG_C, FLAG_PV, FLAG_S][(y >> 1) as usize];
+		(f & flag != 0) == (y & 1 != 0)
+	}
+
+	// (HL), or (IX+d) with the displacement read from the instruction
+	fn memory_operand(&mut self, bus: &mut impl Bus, index: Index) -> u16 {
+		match index {
+			| Index::HL => self.registers.hl(),
+			| _ => {
+				let d = self.fetch_byte(bus) as i8;
+				let address = self.index(index).wrapping_add(d as u16);
+				self.registers.wz = address;
+				address
+			}
+		}
+	}
+
+	fn add8(&mut self, value: u8, carry: bool) {
+		let a = self.registers.a;
+		let result = a as u16 + value as u16 + carry as u16;
+		let r = result as u8;
+		self.registers.a = r;
+		self.registers.f = sz53(r)
+			| ((a ^ value ^ r) & FLAG_H)
+			| ((((a ^ !value) & (a ^ r)) >> 5) & FLAG_PV)
+			| (result > 0xFF) as u8;
+	}
+
+	fn sub8(&mut self, value: u8, carry: bool) -> u8 {
+		let a = self.registers.a;
+		let result = a as i16 - value as i16 - carry as i16;
+		let r = result as u8;
+		self.registers.f = sz53(r)
+			| FLAG_N | ((a ^ value ^ r) & FLAG_H)
+			| ((((a ^ value) & (a ^ r)) >> 5) & FLAG_PV)
+			| (result < 0) as u8;
+		r
+	}
+
+	// ADD, ADC, SUB, SBC, AND, XOR, OR, CP
+	fn alu(&mut self, y: u8, value: u8) {
+		let carry = self.registers.f & FLAG_C != 0;
+		match y {
+			| 0 => self.add8(value, false),
+			| 1 => self.add8(value, carry),
+			| 2 => self.registers.a = self.sub8(value, false),
+			| 3 => self.registers.a = self.sub8(value, carry),
+			| 4 => {
+				self.registers.a &= value;
+				self.registers.f = sz53p(self.registers.a) | FLAG_H;
+			}
+			| 5 => {
+				self.registers.a ^= value;
+				self.registers.f = sz53p(self.registers.a);
+			}
+			| 6 => {
+				self.registers.a |= value;
+				self.registers.f = sz53p(self.registers.a);
+			}
+			| _ => {
+				// X and Y come from the operand
+				self.sub8(value, false);
+				self.registers.f =
+					(self.registers.f & !(FLAG_X | FLAG_Y)) | (value & (FLAG_X | FLAG_Y));
+			}
+		}
+	}
+
+	fn inc8(&mut self, value: u8) -> u8 {
+		let r = value.wrapping_add(1);
+		self.registers.f = (self.registers.f & FLAG_C)
+			| sz53(r) | if value & 0x0F == 0x0F { FLAG_H } else { 0 }
+			| if value == 0x7F { FLAG_PV } else { 0 };
+		r
+	}
+
+	fn dec8(&mut self, value: u8) -> u8 {
+		let r = value.wrapping_sub(1);
+		self.registers.f = (self.registers.f & FLAG_C)
+			| FLAG_N | sz53(r)
+			| if value & 0x0F == 0 { FLAG_H } else { 0 }
+			| if value == 0x80 { FLAG_PV } else { 0 };
+		r
+	}
+
+	fn add16(&mut self, lhs: u16, rhs: u16) -> u16 {
+		let result = lhs as u32 + rhs as u32;
+		let r = result as u16;
+		self.registers.wz = lhs.wrapping_add(1);
+		self.registers.f = (self.registers.f & (FLAG_S | FLAG_Z | FLAG_PV))
+			| ((r >> 8) as u8 & (FLAG_X | FLAG_Y))
+			| (((lhs ^ rhs ^ r) >> 8) as u8 & FLAG_H)
+			| (result > 0xFFFF) as u8;
+		r
+	}
+
+	fn adc16(&mut self, value: u16) {
+		let hl = self.registers.hl();
+		let result = hl as u32 + value as u32 + (self.registers.f & FLAG_C) as u32;
+		let r = result as u16;
+		self.registers.wz = hl.wrapping_add(1);
+		self.registers.set_hl(r);
+		self.registers.f = ((r >> 8) as u8 & (FLAG_S | FLAG_X | FLAG_Y))
+			| if r == 0 { FLAG_Z } else { 0 }
+			| (((hl ^ value ^ r) >> 8) as u8 & FLAG_H)
+			| ((((hl ^ !value) & (hl ^ r)) >> 13) as u8 & FLAG_PV)
+			| (result > 0xFFFF) as u8;
+	}
+
+	fn sbc16(&mut self, value: u16) {
+		let hl = self.registers.hl();
+		let result = hl as i32 - value as i32 - (self.registers.f & FLAG_C) as i32;
+		let r = result as u16;
+		self.registers.wz = hl.wrapping_add(1);
+		self.registers.set_hl(r);
+		self.registers.f = ((r >> 8) as u8 & (FLAG_S | FLAG_X | FLAG_Y))
+			| FLAG_N | if r == 0 { FLAG_Z } else { 0 }
+			| (((hl ^ value ^ r) >> 8) as u8 & FLAG_H)
+			| ((((hl ^ value) & (hl ^ r)) >> 13) as u8 & FLAG_PV)
+			| (result < 0) as u8;
+	}
+
+	// RLC, RRC, RL, RR, SLA, SRA, SLL, SRL, with the flags of CB xx
+	fn rotate(&mut self, y: u8, value: u8) -> u8 {
+		let carry = self.registers.f & FLAG_C;
+		let (r, out) = match y {
+			| 0 => (value.rotate_left(1), value >> 7),
+			| 1 => (value.rotate_right(1), value & 1),
+			| 2 => ((value << 1) | carry, value >> 7),
+			| 3 => ((value >> 1) | (carry << 7), value & 1),
+			| 4 => (value << 1, value >> 7),
+			| 5 => ((value >> 1) | (value & 0x80), value & 1),
+			| 6 => ((value << 1) | 1, value >> 7),
+			| _ => (value >> 1, value & 1),
+		};
+		self.registers.f = sz53p(r) | out;
+		r
+	}
+
+	fn bit(&mut self, n: u8, value: u8, xy: u8) {
+		let tested = value & (1 << n);
+		self.registers.f = (self.registers.f & FLAG_C)
+			| FLAG_H | (xy & (FLAG_X | FLAG_Y))
+			| if tested == 0 { FLAG_Z | FLAG_PV } else { 0 }
+			| (tested & FLAG_S);
+	}
+
+	fn daa(&mut self) {
+		let (a, f) = (self.registers.a, self.registers.f);
+		let low = a & 0x0F;
+		let mut correction = 0;
+		let carry = f & FLAG_C != 0 || a > 0x99;
+		if carry {
+			correction |= 0x60;
+		}
+		if f & FLAG_H != 0 || low > 9 {
+			correction |= 0x06;
+		}
+		let (r, half) = if f & FLAG_N != 0 {
+			(a.wrapping_sub(correction), f & FLAG_H != 0 && low < 6)
+		} else {
+			(a.wrapping_add(correction), low > 9)
+		};
+		self.registers.a = r;
+		self.registers.f = sz53p(r) | (f & FLAG_N) | if half { FLAG_H } else { 0 } | carry as u8;
+	}
+
+	// The flags of INI, IND, OUTI and OUTD
+	fn block_io_flags(&mut self, value: u8, k: u16) {
+		let b = self.registers.b;
+		self.registers.f = sz53(b)
+			| ((value >> 6) & FLAG_N)
+			| if k > 0xFF { FLAG_H | FLAG_C } else { 0 }
+			| parity((k as u8 & 7) ^ b);
+	}
+
+	fn execute_main(&mut self, bus: &mut impl Bus, op: u8, index: Index) -> u32 {
+		let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+		let (p, q) = (y >> 1, y & 1);
+		let mut cycles = CYCLES[op as usize] as u32;
+		// (IX+d) takes the time to compute the address
+		let indexed = if index == Index::HL { 0 } else { 8 };
+
+		match x {
+			| 0 => match z {
+				| 0 => match y {
+					| 0 => {}
+					| 1 => {
+						let af = self.registers.af();
+						self.registers.set_af(self.registers.af_);
+						self.registers.af_ = af;
+					}
+					| _ => {
+						let d = self.fetch_byte(bus) as i8;
+						let taken = match y {
+							| 2 => {
+								self.registers.b = self.registers.b.wrapping_sub(1);
+								self.registers.b != 0
+							}
+							| 3 => true,
+							| _ => self.condition(y - 4),
+						};
+						if taken {
+							self.registers.pc = self.registers.pc.wrapping_add(d as u16);
+							self.registers.wz = self.registers.pc;
+							if y != 3 {
+								cycles += 5;
+							}
+						}
+					}
+				},
+				| 1 if q == 0 => {
+					let value = self.fetch_word(bus);
+					self.set_pair(p, index, value);
+				}
+				| 1 => {
+					let (lhs, rhs) = (self.index(index), self.pair(p, index));
+					let r = self.add16(lhs, rhs);
+					self.set_index(index, r);
+				}
+				| 2 => match (p, q) {
+					| (0, 0) | (1, 0) => {
+						let address = self.pair(p, index);
+						bus.write(address, self.registers.a);
+						self.registers.wz =
+							u16::from_le_bytes([address.wrapping_add(1) as u8, self.registers.a]);
+					}
+					| (0, 1) | (1, 1) => {
+						let address = self.pair(p, index);
+						self.registers.a = bus.read(address);
+						self.registers.wz = address.wrapping_add(1);
+					}
+					| (2, 0) => {
+						let address = self.fetch_word(bus);
+						let value = self.index(index);
+						self.write_word(bus, address, value);
+						self.registers.wz = address.wrapping_add(1);
+					}
+					| (2, _) => {
+						let address = self.fetch_word(bus);
+						let value = self.read_word(bus, address);
+						self.set_index(index, value);
+						self.registers.wz = address.wrapping_add(1);
+					}
+					| (_, 0) => {
+						let address = self.fetch_word(bus);
+						bus.write(address, self.registers.a);
+						self.registers.wz =
+							u16::from_le_bytes([address.wrapping_add(1) as u8, self.registers.a]);
+					}
+					| _ => {
+						let address = self.fetch_word(bus);
+						self.registers.a = bus.read(address);
+						self.registers.wz = address.wrapping_add(1);
+					}
+				},
+				| 3 => {
+					let value = self.pair(p, index);
+					let value = if q == 0 {
+						value.wrapping_add(1)
+					} else {
+						value.wrapping_sub(1)
+					};
+					self.set_pair(p, index, value);
+				}
+				| 4 | 5 => {
+					if y == 6 {
+						let address = self.memory_operand(bus, index);
+						let value = bus.read(address);
+						let r = if z == 4 {
+							self.inc8(value)
+						} else {
+							self.dec8(value)
+						};
+						bus.write(address, r);
+						cycles += indexed;
+					} else {
+						let value = self.register(y, index);
+						let r = if z == 4 {
+							self.inc8(value)
+						} else {
+							self.dec8(value)
+						};
+						self.set_register(y, index, r);
+					}
+				}
+				| 6 => {
+					if y == 6 {
+						// The displacement comes before the value
+						let address = self.memory_operand(bus, index);
+						let value = self.fetch_byte(bus);
+						bus.write(address, value);
+						if index != Index::HL {
+							cycles += 5;
+						}
+					} else {
+						let value = self.fetch_byte(bus);
+						self.set_register(y, index, value);
+					}
+				}
+				| _ => {
+					let (a, f) = (self.registers.a, self.registers.f);
+					let kept = f & (FLAG_S | FLAG_Z | FLAG_PV);
+					match y {
+						| 0..=3 => {
+							let (r, carry) = match y {
+								| 0 => (a.rotate_left(1), a >> 7),
+								| 1 => (a.rotate_right(1), a & 1),
+								| 2 => ((a << 1) | (f & FLAG_C), a >> 7),
+								| _ => ((a >> 1) | ((f & FLAG_C) << 7), a & 1),
+							};
+							self.registers.a = r;
+							self.registers.f = kept | (r & (FLAG_X | FLAG_Y)) | carry;
+						}
+						| 4 => self.daa(),
+						| 5 => {
+							self.registers.a = !a;
+							self.registers.f = (f & (FLAG_S | FLAG_Z | FLAG_PV | FLAG_C))
+								| FLAG_H | FLAG_N | (!a & (FLAG_X | FLAG_Y));
+						}
+						| 6 => self.registers.f = kept | (a & (FLAG_X | FLAG_Y)) | FLAG_C,
+						| _ => {
+							self.registers.f = kept
+								| (a & (FLAG_X | FLAG_Y))
+								| if f & FLAG_C != 0 { FLAG_H } else { FLAG_C };
+						}
+					}
+				}
+			},
+			| 1 => {
+				if y == 6 && z == 6 {
+					self.halted = true;
+				} else if z == 6 {
+					// With (IX+d), H and L are the real ones
+					let address = self.memory_operand(bus, index);
+					let value = bus.read(address);
+					self.set_register(y, Index::HL, value);
+					cycles += indexed;
+				} else if y == 6 {
+					let address = self.memory_operand(bus, index);
+					bus.write(address, self.register(z, Index::HL));
+					cycles += indexed;
+				} else {
+					let value = self.register(z, index);
+					self.set_register(y, index, value);
+				}
+			}
+			| 2 => {
+				let value = if z == 6 {
+					let address = self.memory_operand(bus, index);
+					cycles += indexed;
+					bus.read(address)
+				} else {
+					self.register(z, index)
+				};
+				self.alu(y, value);
+			}
+			| _ => match z {
+				| 0 => {
+					if self.condition(y) {
+						self.registers.pc = self.pop(bus);
+						self.registers.wz = self.registers.pc;
+						cycles += 6;
+					}
+				}
+				| 1 if q == 0 => {
+					let value = self.pop(bus);
+					match p {
+						| 3 => self.registers.set_af(value),
+						| _ => self.set_pair(p, index, value),
+					}
+				}
+				| 1 => match p {
+					| 0 => {
+						self.registers.pc = self.pop(bus);
+						self.registers.wz = self.registers.pc;
+					}
+					| 1 => {
+						let regs = &mut self.registers;
+						let (bc, de, hl) = (regs.bc(), regs.de(), regs.hl());
+						regs.set_bc(regs.bc_);
+						regs.set_de(regs.de_);
+						regs.set_hl(regs.hl_);
+						(regs.bc_, regs.de_, regs.hl_) = (bc, de, hl);
+					}
+					| 2 => self.registers.pc = self.index(index),
+					| _ => self.registers.sp = self.index(index),
+				},
+				| 2 => {
+					let address = self.fetch_word(bus);
+					self.registers.wz = address;
+					if self.condition(y) {
+						self.registers.pc = address;
+					}
+				}
+				| 3 => match y {
+					| 0 => {
+						let address = self.fetch_word(bus);
+						self.registers.pc = address;
+						self.registers.wz = address;
+					}
+					| 2 => {
+						let n = self.fetch_byte(bus);
+						let a = self.registers.a;
+						bus.output(u16::from_le_bytes([n, a]), a);
+						self.registers.wz = u16::from_le_bytes([n.wrapping_add(1), a]);
+					}
+					| 3 => {
+						let n = self.fetch_byte(bus);
+						let port = u16::from_le_bytes([n, self.registers.a]);
+						self.registers.a = bus.input(port);
+						self.registers.wz = port.wrapping_add(1);
+					}
+					| 4 => {
+						let sp = self.registers.sp;
+						let value = self.read_word(bus, sp);
+						let current = self.index(index);
+						self.write_word(bus, sp, current);
+						self.set_index(index, value);
+						self.registers.wz = value;
+					}
+					// Never with IX or IY
+					| 5 => {
+						let regs = &mut self.registers;
+						let (de, hl) = (regs.de(), regs.hl());
+						regs.set_de(hl);
+						regs.set_hl(de);
+					}
+					| 6 => {
+						self.iff1 = false;
+						self.iff2 = false;
+					}
+					| _ => {
+						self.iff1 = true;
+						self.iff2 = true;
+						self.ei_delay = true;
+					}
+				},
+				| 4 => {
+					let address = self.fetch_word(bus);
+					self.registers.wz = address;
+					if self.condition(y) {
+						let pc = self.registers.pc;
+						self.push(bus, pc);
+						self.registers.pc = address;
+						cycles += 7;
+					}
+				}
+				| 5 if q == 0 => {
+					let value = match p {
+						| 3 => self.registers.af(),
+						| _ => self.pair(p, index),
+					};
+					self.push(bus, value);
+				}
+				| 5 => {
+					// CALL nn, the prefixes are handled before
+					let address = self.fetch_word(bus);
+					let pc = self.registers.pc;
+					self.push(bus, pc);
+					self.registers.pc = address;
+					self.registers.wz = address;
+				}
+				| 6 => {
+					let value = self.fetch_byte(bus);
+					self.alu(y, value);
+				}
+				| _ => {
+					let pc = self.registers.pc;
+					self.push(bus, pc);
+					self.registers.pc = (y * 8) as u16;
+					self.registers.wz = self.registers.pc;
+				}
+			},
+		}
+		cycles
+	}
+
+	fn execute_cb(&mut self, bus: &mut impl Bus, op: u8) -> u32 {
+		let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+		if z != 6 {
+			let value = self.register(z, Index::HL);
+			match x {
+				| 0 => {
+					let r = self.rotate(y, value);
+					self.set_register(z, Index::HL, r);
+				}
+				| 1 => self.bit(y, value, value),
+				| 2 => self.set_register(z, Index::HL, value & !(1 << y)),
+				| _ => self.set_register(z, Index::HL, value | (1 << y)),
+			}
+			return 8;
+		}
+
+		let address = self.registers.hl();
+		let value = bus.read(address);
+		match x {
+			| 0 => {
+				let r = self.rotate(y, value);
+				bus.write(address, r);
+			}
+			| 1 => {
+				let xy = (self.registers.wz >> 8) as u8;
+				self.bit(y, value, xy);
+				return 12;
+			}
+			| 2 => bus.write(address, value & !(1 << y)),
+			| _ => bus.write(address, value | (1 << y)),
+		}
+		15
+	}
+
+	// DD CB d xx: the result also goes to the register, if there is one
+	fn execute_indexed_cb(&mut self, bus: &mut impl Bus, op: u8, address: u16) -> u32 {
+		let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+		let value = bus.read(address);
+		self.registers.wz = address;
+		let r = match x {
+			| 0 => self.rotate(y, value),
+			| 1 => {
+				self.bit(y, value, (address >> 8) as u8);
+				return 20;
+			}
+			| 2 => value & !(1 << y),
+			| _ => value | (1 << y),
+		};
+		bus.write(address, r);
+		if z != 6 {
+			self.set_register(z, Index::HL, r);
+		}
+		23
+	}
+
+	fn execute_ed(&mut self, bus: &mut impl Bus, op: u8) -> u32 {
+		let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+		let (p, q) = (y >> 1, y & 1);
+		let mut cycles = ed_cycles(op);
+
+		match (x, z) {
+			| (1, 0) => {
+				// IN F,(C) only sets the flags
+				let bc = self.registers.bc();
+				let value = bus.input(bc);
+				self.registers.wz = bc.wrapping_add(1);
+				if y != 6 {
+					self.set_register(y, Index::HL, value);
+				}
+				self.registers.f = (self.registers.f & FLAG_C) | sz53p(value);
+			}
+			| (1, 1) => {
+				// OUT (C),0 on a NMOS Z80
+				let value = if y == 6 {
+					0
+				} else {
+					self.register(y, Index::HL)
+				};
+				let bc = self.registers.bc();
+				bus.output(bc, value);
+				self.registers.wz = bc.wrapping_add(1);
+			}
+			| (1, 2) => {
+				let value = self.pair(p, Index::HL);
+				if q == 0 {
+					self.sbc16(value);
+				} else {
+					self.adc16(value);
+				}
+			}
+			| (1, 3) => {
+				let address = self.fetch_word(bus);
+				if q == 0 {
+					let value = self.pair(p, Index::HL);
+					self.write_word(bus, address, value);
+				} else {
+					let value = self.read_word(bus, address);
+					self.set_pair(p, Index::HL, value);
+				}
+				self.registers.wz = address.wrapping_add(1);
+			}
+			| (1, 4) => {
+				let a = self.registers.a;
+				self.registers.a = 0;
+				self.registers.a = self.sub8(a, false);
+			}
+			| (1, 5) => {
+				// RETI also copies IFF2, as RETN does
+				self.iff1 = self.iff2;
+				self.registers.pc = self.pop(bus);
+				self.registers.wz = self.registers.pc;
+			}
+			| (1, 6) => self.interrupt_mode = [0, 0, 1, 2][(y & 3) as usize],
+			| (1, 7) => match y {
+				| 0 => self.registers.i = self.registers.a,
+				| 1 => self.registers.r = self.registers.a,
+				| 2 | 3 => {
+					let value = if y == 2 {
+						self.registers.i
+					} else {
+						self.registers.r
+					};
+					self.registers.a = value;
+					self.registers.f = (self.registers.f & FLAG_C)
+						| sz53(value) | if self.iff2 { FLAG_PV } else { 0 };
+				}
+				| 4 | 5 => {
+					let hl = self.registers.hl();
+					let (a, value) = (self.registers.a, bus.read(hl));
+					let (a, value) = if y == 4 {
+						// RRD
+						((a & 0xF0) | (value & 0x0F), (a << 4) | (value >> 4))
+					} else {
+						// RLD
+						((a & 0xF0) | (value >> 4), (value << 4) | (a & 0x0F))
+					};
+					bus.write(hl, value);
+					self.registers.a = a;
+					self.registers.wz = hl.wrapping_add(1);
+					self.registers.f = (self.registers.f & FLAG_C) | sz53p(a);
+				}
+				| _ => {}
+			},
+			| (2, 0..=3) if y >= 4 => {
+				// The block instructions: y is 4 for xxI, 5 for xxD, 6 for xxIR, 7 for xxDR
+				let step: u16 = if y & 1 == 0 { 1 } else { 0xFFFF };
+				let repeat = y >= 6;
+				let hl = self.registers.hl();
+				let again = match z {
+					| 0 => {
+						let value = bus.read(hl);
+						let de = self.registers.de();
+						bus.write(de, value);
+						self.registers.set_hl(hl.wrapping_add(step));
+						self.registers.set_de(de.wrapping_add(step));
+						let bc = self.registers.bc().wrapping_sub(1);
+						self.registers.set_bc(bc);
+						let n = value.wrapping_add(self.registers.a);
+						self.registers.f = (self.registers.f & (FLAG_S | FLAG_Z | FLAG_C))
+							| (n & FLAG_X) | ((n << 4) & FLAG_Y)
+							| if bc != 0 { FLAG_PV } else { 0 };
+						bc != 0
+					}
+					| 1 => {
+						let value = bus.read(hl);
+						let a = self.registers.a;
+						let r = a.wrapping_sub(value);
+						let half = (a ^ value ^ r) & FLAG_H;
+						let n = r.wrapping_sub((half != 0) as u8);
+						self.registers.set_hl(hl.wrapping_add(step));
+						let bc = self.registers.bc().wrapping_sub(1);
+						self.registers.set_bc(bc);
+						self.registers.wz = self.registers.wz.wrapping_add(step);
+						self.registers.f = (self.registers.f & FLAG_C)
+							| FLAG_N | (r & FLAG_S)
+							| if r == 0 { FLAG_Z } else { 0 }
+							| half | (n & FLAG_X) | ((n << 4) & FLAG_Y)
+							| if bc != 0 { FLAG_PV } else { 0 };
+						bc != 0 && r != 0
+					}
+					| 2 => {
+						let bc = self.registers.bc();
+						let value = bus.input(bc);
+						bus.write(hl, value);
+						self.registers.wz = bc.wrapping_add(step);
+						self.registers.b = self.registers.b.wrapping_sub(1);
+						self.registers.set_hl(hl.wrapping_add(step));
+						let c = self.registers.c.wrapping_add(step as u8);
+						self.block_io_flags(value, value as u16 + c as u16);
+						self.registers.b != 0
+					}
+					| _ => {
+						let value = bus.read(hl);
+						self.registers.b = self.registers.b.wrapping_sub(1);
+						let bc = self.registers.bc();
+						bus.output(bc, value);
+						self.registers.wz = bc.wrapping_add(step);
+						self.registers.set_hl(hl.wrapping_add(step));
+						let l = self.registers.l;
+						self.block_io_flags(value, value as u16 + l as u16);
+						self.registers.b != 0
+					}
+				};
+				if repeat && again {
+					self.registers.pc = self.registers.pc.wrapping_sub(2);
+					if z < 2 {
+						self.registers.wz = self.registers.pc.wrapping_add(1);
+					}
+					cycles += 5;
+				}
+			}
+			// The other ones do nothing
+			| _ => {}
+		}
+		cycles
+	}
+}
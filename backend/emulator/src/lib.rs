@@ -0,0 +1,3 @@
+pub mod bus;
+pub mod cpm;
+pub mod cpu;
@@ -0,0 +1,267 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use emulator::bus::Bus;
+use emulator::cpm::*;
+use emulator::cpu;
+use z80::assembler::Assembler;
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+
+type Inst = Instruction<u8, u16, i32, i8>;
+
+const DATA: u16 = 0x0800;
+
+fn assemble(program: Vec<Inst>) -> Vec<u8> {
+	let mut assembler = Assembler::new(program.into_iter(), false, false);
+	let bytes: Vec<u8> = assembler.by_ref().collect();
+	assert!(!assembler.has_error_occured());
+	bytes
+}
+
+// LD C,function; LD DE,parameter; CALL 5
+fn bdos(function: i32, de: i32) -> Vec<Inst> {
+	vec![
+		LD(ByteRegister(C), Constant(function)),
+		LD(WordRegister(DE), Constant(de)),
+		CALL(None, Constant(BDOS as i32)),
+	]
+}
+
+// Where the result of each BDOS call is stored, one byte each
+fn bdos_results(calls: &[(i32, i32)]) -> Vec<Inst> {
+	let mut program = vec![];
+	for (i, (function, de)) in calls.iter().enumerate() {
+		program.extend(bdos(*function, *de));
+		program.push(LD(Address(0x0900 + i as u16), ByteRegister(A)));
+	}
+	program.push(RET(None));
+	program
+}
+
+fn directory(name: &str) -> PathBuf {
+	let path = std::env::temp_dir().join(format!("plm-cpm-{}-{}", name, process::id()));
+	let _ = fs::remove_dir_all(&path);
+	fs::create_dir_all(&path).unwrap();
+	path
+}
+
+#[test]
+fn test_page_zero() {
+	let path = directory("page-zero");
+	let mut cpm = Cpm::new(&path, &b""[..], Vec::new());
+	cpm.set_arguments(&["input.txt".to_string(), "*.com".to_string()]);
+
+	assert_eq!(cpm.memory.read_word(0x0006), BDOS_BASE);
+	assert_eq!(cpm.memory.read_byte(0x0080), 16);
+	assert_eq!(
+		&(0..16)
+			.map(|i| cpm.memory.read_byte(0x81 + i))
+			.collect::<Vec<_>>(),
+		b" INPUT.TXT *.COM"
+	);
+	assert_eq!(
+		&(0..11)
+			.map(|i| cpm.memory.read_byte(FCB1 + 1 + i))
+			.collect::<Vec<_>>(),
+		b"INPUT   TXT"
+	);
+	assert_eq!(
+		&(0..11)
+			.map(|i| cpm.memory.read_byte(FCB2 + 1 + i))
+			.collect::<Vec<_>>(),
+		b"????????COM"
+	);
+	assert_eq!(cpm.cpu.registers.pc, TPA);
+	fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_console_output() {
+	let path = directory("console-output");
+	let mut output = Vec::new();
+	let mut cpm = Cpm::new(&path, &b""[..], &mut output);
+	let mut program = bdos(9, DATA as i32);
+	program.extend([
+		LD(ByteRegister(E), Constant(b'!' as i32)),
+		LD(ByteRegister(C), Constant(2)),
+	]);
+	program.extend([CALL(None, Constant(BDOS as i32)), JP(None, Constant(0))]);
+	cpm.load(&assemble(program));
+	cpm.memory.load(DATA, b"Hello, world$");
+	assert_eq!(cpm.run(), Ok(()));
+	drop(cpm);
+	assert_eq!(output, b"Hello, world!");
+	fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_console_input() {
+	let path = directory("console-input");
+	let mut output = Vec::new();
+	let mut cpm = Cpm::new(&path, &b"a\nhello\nx"[..], &mut output);
+	let mut program = bdos(1, 0);
+	program.extend([INC(ByteRegister(A)), LD(ByteRegister(E), ByteRegister(A))]);
+	program.extend([
+		LD(ByteRegister(C), Constant(2)),
+		CALL(None, Constant(BDOS as i32)),
+	]);
+	program.extend(bdos(1, 0));
+	program.extend(bdos(10, DATA as i32));
+	program.extend(bdos(11, 0));
+	program.push(LD(Address(0x0900), ByteRegister(A)));
+	program.extend(bdos(6, 0xFF));
+	program.push(LD(Address(0x0901), ByteRegister(A)));
+	program.extend(bdos(11, 0));
+	program.push(LD(Address(0x0902), ByteRegister(A)));
+	program.extend(bdos(0, 0));
+	cpm.load(&assemble(program));
+	cpm.memory.load(DATA, &[3]);
+	assert_eq!(cpm.run(), Ok(()));
+
+	assert_eq!(
+		(0..5)
+			.map(|i| cpm.memory.read_byte(DATA + i))
+			.collect::<Vec<_>>(),
+		vec![3, 3, b'h', b'e', b'l']
+	);
+	assert_eq!(
+		(0..3)
+			.map(|i| cpm.memory.read_byte(0x0900 + i))
+			.collect::<Vec<_>>(),
+		vec![0xFF, b'x', 0]
+	);
+	drop(cpm);
+	assert_eq!(output, b"ab\r\nhel\r\n");
+	fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_files() {
+	let path = directory("files");
+	let mut cpm = Cpm::new(&path, &b""[..], Vec::new());
+	cpm.set_arguments(&["result.txt".to_string()]);
+	cpm.load(&assemble(bdos_results(&[
+		(15, FCB1 as i32),
+		(22, FCB1 as i32),
+		(26, DATA as i32),
+		(21, FCB1 as i32),
+		(21, FCB1 as i32),
+		(16, FCB1 as i32),
+	])));
+	cpm.memory.load(DATA, &[b'A'; 128]);
+	assert_eq!(cpm.run(), Ok(()));
+	assert_eq!(
+		(0..6)
+			.map(|i| cpm.memory.read_byte(0x0900 + i))
+			.collect::<Vec<_>>(),
+		vec![0xFF, 0, 0, 0, 0, 0]
+	);
+	assert_eq!(fs::read(path.join("RESULT.TXT")).unwrap(), vec![b'A'; 256]);
+
+	// The host names are in any case, and the last record is padded with ^Z
+	fs::write(path.join("input.txt"), b"Hello").unwrap();
+	let mut cpm = Cpm::new(&path, &b""[..], Vec::new());
+	cpm.set_arguments(&["INPUT.TXT".to_string()]);
+	cpm.load(&assemble(bdos_results(&[
+		(15, FCB1 as i32),
+		(20, FCB1 as i32),
+		(20, FCB1 as i32),
+		(35, FCB1 as i32),
+	])));
+	assert_eq!(cpm.run(), Ok(()));
+	assert_eq!(
+		(0..4)
+			.map(|i| cpm.memory.read_byte(0x0900 + i))
+			.collect::<Vec<_>>(),
+		vec![0, 0, 1, 0]
+	);
+	assert_eq!(cpm.memory.read_byte(FCB1 + 15), 1);
+	assert_eq!(cpm.memory.read_byte(FCB1 + 32), 1);
+	assert_eq!(cpm.memory.read_word(FCB1 + 33), 1);
+	assert_eq!(
+		&(0..6)
+			.map(|i| cpm.memory.read_byte(DEFAULT_DMA + i))
+			.collect::<Vec<_>>(),
+		b"Hello\x1A"
+	);
+	fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_random_access_and_directory() {
+	let path = directory("random");
+	let mut data = vec![b'0'; 128];
+	data.extend([b'1'; 128]);
+	data.extend([b'2'; 128]);
+	fs::write(path.join("DATA.BIN"), &data).unwrap();
+	fs::write(path.join("OTHER.TXT"), b"").unwrap();
+
+	let mut cpm = Cpm::new(&path, &b""[..], Vec::new());
+	cpm.set_arguments(&["data.bin".to_string(), "*.*".to_string()]);
+	cpm.memory.write(FCB1 + 33, 2);
+	let mut program = bdos_results(&[(15, FCB1 as i32), (33, FCB1 as i32)]);
+	program.pop();
+	program.extend(bdos(26, DATA as i32));
+	program.extend(bdos(17, FCB2 as i32));
+	program.push(LD(Address(0x0902), ByteRegister(A)));
+	program.extend(bdos(18, 0));
+	program.push(LD(Address(0x0903), ByteRegister(A)));
+	program.extend(bdos(18, 0));
+	program.push(LD(Address(0x0904), ByteRegister(A)));
+	program.push(RET(None));
+	cpm.load(&assemble(program));
+	assert_eq!(cpm.run(), Ok(()));
+
+	assert_eq!(
+		(0..5)
+			.map(|i| cpm.memory.read_byte(0x0900 + i))
+			.collect::<Vec<_>>(),
+		vec![0, 0, 0, 0, 0xFF]
+	);
+	assert_eq!(cpm.memory.read_byte(DEFAULT_DMA), b'2');
+	// The last entry found is written to the DMA address
+	assert_eq!(
+		&(0..11)
+			.map(|i| cpm.memory.read_byte(DATA + 1 + i))
+			.collect::<Vec<_>>(),
+		b"OTHER   TXT"
+	);
+	fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_errors() {
+	let path = directory("errors");
+	let mut cpm = Cpm::new(&path, &b""[..], Vec::new());
+	cpm.load(&assemble(bdos(99, 0)));
+	assert_eq!(cpm.run(), Err(Error::UnsupportedFunction(99)));
+
+	let mut cpm = Cpm::new(&path, &b""[..], Vec::new());
+	cpm.load(&assemble(vec![JP(None, Constant(TPA as i32))]));
+	cpm.set_cycle_limit(10000);
+	assert_eq!(cpm.run(), Err(Error::Cpu(cpu::Error::CycleLimitReached)));
+
+	let mut cpm = Cpm::new(&path, &b""[..], Vec::new());
+	cpm.load(&[0xED, 0x70]);
+	assert_eq!(
+		cpm.run(),
+		Err(Error::Cpu(cpu::Error::UndocumentedInstruction(TPA)))
+	);
+
+	// The version is in HL, and also in BA
+	let mut cpm = Cpm::new(&path, &b""[..], Vec::new());
+	cpm.load(&assemble(vec![
+		LD(ByteRegister(C), Constant(12)),
+		CALL(None, Constant(BDOS as i32)),
+		HALT,
+	]));
+	assert_eq!(cpm.run(), Ok(()));
+	assert_eq!(cpm.cpu.registers.hl(), 0x0022);
+	assert_eq!((cpm.cpu.registers.b, cpm.cpu.registers.a), (0x00, 0x22));
+	fs::remove_dir_all(path).unwrap();
+}
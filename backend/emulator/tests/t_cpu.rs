@@ -0,0 +1,761 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use emulator::bus::Memory;
+use emulator::cpu::*;
+use z80::assembler::Assembler;
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Condition;
+use z80::instruction::Instruction;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::UndocumentedRegister::*;
+use z80::instruction::WordRegister::*;
+
+type Inst = Instruction<u8, u16, i32, i8>;
+
+const START: u16 = 0x100;
+
+fn assemble(program: Vec<Inst>, enable_undocumented_instructions: bool) -> Vec<u8> {
+	let mut assembler =
+		Assembler::new(program.into_iter(), false, enable_undocumented_instructions);
+	let bytes: Vec<u8> = assembler.by_ref().collect();
+	assert!(!assembler.has_error_occured());
+	bytes
+}
+
+fn machine(code: &[u8], enable_undocumented_instructions: bool) -> (Cpu, Memory<'static>) {
+	let mut memory = Memory::new();
+	memory.load(START, code);
+	let mut cpu = Cpu::new(enable_undocumented_instructions);
+	cpu.registers.pc = START;
+	cpu.registers.sp = 0x8000;
+	(cpu, memory)
+}
+
+/*
+ * The flags of every instruction of docs/z80/z80-op.txt, on a sample
+ * of its forms: '-' is unchanged, '0' reset and '1' set, whatever the
+ * flags were before.
+ */
+fn flag_table() -> HashMap<String, String> {
+	include_str!("../../../docs/z80/z80-op.txt")
+		.lines()
+		.filter_map(|line| {
+			let columns: Vec<&str> = line.split('|').collect();
+			if columns.len() < 4 || columns[2].len() != 6 || columns[2] == "SZHPNC" {
+				return None;
+			}
+			Some((columns[1].trim().to_string(), columns[2].to_string()))
+		})
+		.collect()
+}
+
+fn samples() -> Vec<(&'static str, Vec<Inst>)> {
+	let a = || ByteRegister(A);
+	let b = || ByteRegister(B);
+	let hl = || AddressRegister(HL);
+	let ix = || AddressRegisterWithOffset(IX, 1);
+	vec![
+		(
+			"ADC A,s",
+			vec![
+				ADC(a(), b()),
+				ADC(a(), Constant(0x55)),
+				ADC(a(), hl()),
+				ADC(a(), ix()),
+			],
+		),
+		(
+			"ADC HL,ss",
+			vec![
+				ADC(WordRegister(HL), WordRegister(BC)),
+				ADC(WordRegister(HL), WordRegister(SP)),
+			],
+		),
+		(
+			"ADD A,s",
+			vec![ADD(a(), b()), ADD(a(), Constant(0x7F)), ADD(a(), hl())],
+		),
+		(
+			"ADD HL,ss",
+			vec![
+				ADD(WordRegister(HL), WordRegister(BC)),
+				ADD(WordRegister(HL), WordRegister(HL)),
+			],
+		),
+		("ADD IX,pp", vec![ADD(WordRegister(IX), WordRegister(DE))]),
+		("ADD IY,rr", vec![ADD(WordRegister(IY), WordRegister(IY))]),
+		("AND s", vec![AND(b()), AND(Constant(0x0F)), AND(ix())]),
+		("BIT b,m", vec![BIT(0, b()), BIT(7, hl()), BIT(3, ix())]),
+		(
+			"CALL cc,nn",
+			vec![
+				CALL(Some(Condition::NZ), Constant(0x1000)),
+				CALL(Some(Condition::C), Constant(0x1000)),
+			],
+		),
+		("CALL nn", vec![CALL(None, Constant(0x1000))]),
+		("CCF", vec![CCF]),
+		("CP s", vec![CP(b()), CP(Constant(0x80)), CP(hl())]),
+		("CPD", vec![CPD]),
+		("CPDR", vec![CPDR]),
+		("CPI", vec![CPI]),
+		("CPIR", vec![CPIR]),
+		("CPL", vec![CPL]),
+		("DAA", vec![DAA]),
+		("DEC s", vec![DEC(b()), DEC(hl()), DEC(ix())]),
+		("DEC xx", vec![DEC(WordRegister(IX))]),
+		("DEC ss", vec![DEC(WordRegister(BC))]),
+		("DI", vec![DI]),
+		("DJNZ e", vec![DJNZ(0)]),
+		("EI", vec![EI]),
+		(
+			"EX [SP],HL",
+			vec![EX(AddressRegister(SP), WordRegister(HL))],
+		),
+		(
+			"EX [SP],xx",
+			vec![EX(AddressRegister(SP), WordRegister(IY))],
+		),
+		("EX DE,HL", vec![EX(WordRegister(DE), WordRegister(HL))]),
+		("EXX", vec![EXX]),
+		("HALT", vec![HALT]),
+		("IM n", vec![IM(0), IM(1), IM(2)]),
+		("IN A,[n]", vec![IN(a(), Port(0x10))]),
+		(
+			"IN r,[C]",
+			vec![IN(b(), PortRegister(C)), IN(a(), PortRegister(C))],
+		),
+		("INC r", vec![INC(b()), INC(a())]),
+		("INC [HL]", vec![INC(hl())]),
+		("INC xx", vec![INC(WordRegister(IX))]),
+		("INC [xx+d]", vec![INC(ix())]),
+		("INC ss", vec![INC(WordRegister(SP))]),
+		("IND", vec![IND]),
+		("INDR", vec![INDR]),
+		("INI", vec![INI]),
+		("INIR", vec![INIR]),
+		("JP [HL]", vec![JP(None, WordRegister(HL))]),
+		("JP [xx]", vec![JP(None, WordRegister(IX))]),
+		("JP nn", vec![JP(None, Constant(0x1000))]),
+		(
+			"JP cc,nn",
+			vec![
+				JP(Some(Condition::PE), Constant(0x1000)),
+				JP(Some(Condition::M), Constant(0x1000)),
+			],
+		),
+		("JR e", vec![JR(None, Constant(0))]),
+		(
+			"JR cc,e",
+			vec![
+				JR(Some(Condition::Z), Constant(0)),
+				JR(Some(Condition::NC), Constant(0)),
+			],
+		),
+		(
+			"LD dst,src",
+			vec![
+				LD(a(), b()),
+				LD(b(), Constant(0x12)),
+				LD(WordRegister(HL), Constant(0x1234)),
+				LD(a(), ix()),
+			],
+		),
+		("LD A,i", vec![LD(a(), I), LD(a(), R)]),
+		("LDD", vec![LDD]),
+		("LDDR", vec![LDDR]),
+		("LDI", vec![LDI]),
+		("LDIR", vec![LDIR]),
+		("NEG", vec![NEG]),
+		("NOP", vec![NOP]),
+		("OR s", vec![OR(b()), OR(hl())]),
+		("OTDR", vec![OTDR]),
+		("OTIR", vec![OTIR]),
+		("OUT [C],r", vec![OUT(PortRegister(C), b())]),
+		("OUT [n],A", vec![OUT(Port(0x10), a())]),
+		("OUTD", vec![OUTD]),
+		("OUTI", vec![OUTI]),
+		("POP xx", vec![POP(WordRegister(IX))]),
+		("POP qq", vec![POP(WordRegister(BC))]),
+		("PUSH xx", vec![PUSH(WordRegister(IY))]),
+		("PUSH qq", vec![PUSH(WordRegister(AF))]),
+		("RES b,m", vec![RES(0, b()), RES(5, hl())]),
+		("RET", vec![RET(None)]),
+		(
+			"RET cc",
+			vec![RET(Some(Condition::NZ)), RET(Some(Condition::PO))],
+		),
+		("RETI", vec![RETI]),
+		("RETN", vec![RETN]),
+		("RL m", vec![RL(b()), RL(hl())]),
+		("RLA", vec![RLA]),
+		("RLC m", vec![RLC(b()), RLC(ix())]),
+		("RLCA", vec![RLCA]),
+		("RLD", vec![RLD]),
+		("RR m", vec![RR(b())]),
+		("RRA", vec![RRA]),
+		("RRC m", vec![RRC(b())]),
+		("RRCA", vec![RRCA]),
+		("RRD", vec![RRD]),
+		("RST p", vec![RST(0x38)]),
+		("SBC A,s", vec![SBC(a(), b())]),
+		("SBC HL,ss", vec![SBC(WordRegister(HL), WordRegister(DE))]),
+		("SCF", vec![SCF]),
+		("SET b,m", vec![SET(7, b()), SET(1, hl())]),
+		("SLA m", vec![SLA(b())]),
+		("SRA m", vec![SRA(b())]),
+		("SRL m", vec![SRL(b())]),
+		("SUB s", vec![SUB(b()), SUB(Constant(0xFF))]),
+		("XOR s", vec![XOR(b()), XOR(a())]),
+	]
+}
+
+#[test]
+fn test_flags_follow_the_documentation() {
+	let table = flag_table();
+	let bits = [FLAG_S, FLAG_Z, FLAG_H, FLAG_PV, FLAG_N, FLAG_C];
+	// They also set C as H, see z80-documented-v0.91.pdf
+	let block_io = ["INI", "INIR", "IND", "INDR", "OUTI", "OTIR", "OUTD", "OTDR"];
+	let operands = [
+		(0x00, 0x00),
+		(0x5A, 0xA5),
+		(0x99, 0x01),
+		(0x80, 0x80),
+		(0x7F, 0xFF),
+	];
+
+	for (mnemonic, instructions) in samples() {
+		let flags = table
+			.get(mnemonic)
+			.unwrap_or_else(|| panic!("{} isn't documented", mnemonic));
+		for inst in instructions {
+			let code = assemble(vec![inst.clone()], false);
+			for (a, value) in operands {
+				for before in [0x00, 0xFF] {
+					let (mut cpu, mut memory) = machine(&code, false);
+					memory.load(0x2000, &[value; 16]);
+					if block_io.contains(&mnemonic) {
+						// N is bit 7 of the byte transferred
+						memory.load(0x1E00, &[value | 0x80; 0x400]);
+						memory.set_input_handler(move |_| value | 0x80);
+					}
+					let regs = &mut cpu.registers;
+					(regs.a, regs.f, regs.b, regs.c) = (a, before, value, 3);
+					if mnemonic.starts_with("LD") || mnemonic.starts_with("CP") {
+						// Not to overwrite the program with BC up to 0xFF03
+						regs.b = 0;
+					}
+					regs.set_de(0x3000);
+					regs.set_hl(0x2008);
+					regs.ix = 0x2004;
+					regs.iy = 0x2004;
+
+					cpu.step(&mut memory).unwrap();
+					// The repeated instructions jump back to themselves
+					while cpu.registers.pc == START {
+						cpu.step(&mut memory).unwrap();
+					}
+
+					let after = cpu.registers.f;
+					for (flag, bit) in flags.chars().zip(bits) {
+						if block_io.contains(&mnemonic) && bit == FLAG_C {
+							continue;
+						}
+						let expected = match flag {
+							| '-' => before & bit,
+							| '0' => 0,
+							| '1' => bit,
+							| _ => continue,
+						};
+						assert_eq!(
+							after & bit,
+							expected,
+							"{:?} with A={:02X} and {:02X}: F={:08b}, expected {}",
+							inst,
+							a,
+							value,
+							after,
+							flags
+						);
+					}
+				}
+			}
+		}
+	}
+}
+
+fn run(program: Vec<Inst>, setup: impl FnOnce(&mut Registers)) -> Cpu {
+	let code = assemble(program, true);
+	let end = START + code.len() as u16;
+	let (mut cpu, mut memory) = machine(&code, true);
+	setup(&mut cpu.registers);
+	cpu.run_until(&mut memory, end, 10000).unwrap();
+	cpu
+}
+
+#[test]
+fn test_arithmetic() {
+	let cpu = run(vec![ADD(ByteRegister(A), Constant(0x01))], |r| r.a = 0x7F);
+	assert_eq!(cpu.registers.a, 0x80);
+	assert_eq!(cpu.registers.f, FLAG_S | FLAG_H | FLAG_PV);
+
+	let cpu = run(vec![SUB(ByteRegister(B))], |r| (r.a, r.b) = (0x10, 0x20));
+	assert_eq!(cpu.registers.a, 0xF0);
+	assert_eq!(cpu.registers.f, FLAG_S | FLAG_Y | FLAG_N | FLAG_C);
+
+	// CP takes X and Y from the operand, not from the result
+	let cpu = run(vec![CP(Constant(0x28))], |r| r.a = 0x28);
+	assert_eq!(cpu.registers.a, 0x28);
+	assert_eq!(cpu.registers.f, FLAG_Z | FLAG_Y | FLAG_X | FLAG_N);
+
+	let cpu = run(vec![SBC(WordRegister(HL), WordRegister(DE))], |r| {
+		r.set_hl(0x8000);
+		r.set_de(0x0001);
+		r.f = FLAG_C;
+	});
+	assert_eq!(cpu.registers.hl(), 0x7FFE);
+	assert_eq!(cpu.registers.f, FLAG_Y | FLAG_H | FLAG_X | FLAG_PV | FLAG_N);
+
+	let cpu = run(vec![ADD(WordRegister(HL), WordRegister(BC))], |r| {
+		r.set_hl(0x0FFF);
+		r.set_bc(0x0001);
+		r.f = FLAG_S | FLAG_Z | FLAG_N;
+	});
+	assert_eq!(cpu.registers.hl(), 0x1000);
+	assert_eq!(cpu.registers.f, FLAG_S | FLAG_Z | FLAG_H);
+
+	let cpu = run(vec![NEG], |r| r.a = 0x80);
+	assert_eq!(cpu.registers.a, 0x80);
+	assert_eq!(cpu.registers.f, FLAG_S | FLAG_PV | FLAG_N | FLAG_C);
+}
+
+#[test]
+fn test_decimal_adjust() {
+	let bcd = |n: u32| (((n / 10) << 4) | (n % 10)) as u8;
+	for x in 0..100 {
+		for y in 0..100 {
+			let cpu = run(vec![ADD(ByteRegister(A), ByteRegister(B)), DAA], |r| {
+				(r.a, r.b) = (bcd(x), bcd(y))
+			});
+			assert_eq!(cpu.registers.a, bcd((x + y) % 100), "{} + {}", x, y);
+			assert_eq!(cpu.registers.f & FLAG_C != 0, x + y >= 100, "{} + {}", x, y);
+
+			let cpu = run(vec![SUB(ByteRegister(B)), DAA], |r| {
+				(r.a, r.b) = (bcd(x), bcd(y))
+			});
+			assert_eq!(cpu.registers.a, bcd((100 + x - y) % 100), "{} - {}", x, y);
+			assert_eq!(cpu.registers.f & FLAG_C != 0, x < y, "{} - {}", x, y);
+		}
+	}
+}
+
+#[test]
+fn test_undocumented_flags() {
+	let cpu = run(vec![SCF], |r| (r.a, r.f) = (0x28, 0));
+	assert_eq!(cpu.registers.f, FLAG_Y | FLAG_X | FLAG_C);
+
+	// CCF puts the previous carry into H
+	let cpu = run(vec![CCF], |r| (r.a, r.f) = (0, FLAG_C));
+	assert_eq!(cpu.registers.f, FLAG_H);
+
+	// BIT n,r takes X and Y from r, BIT n,(HL) from MEMPTR
+	let cpu = run(vec![BIT(0, ByteRegister(B))], |r| {
+		(r.b, r.f) = (0x28, FLAG_C)
+	});
+	assert_eq!(
+		cpu.registers.f,
+		FLAG_Y | FLAG_H | FLAG_X | FLAG_Z | FLAG_PV | FLAG_C
+	);
+	let cpu = run(vec![BIT(7, ByteRegister(B))], |r| (r.b, r.f) = (0x80, 0));
+	assert_eq!(cpu.registers.f, FLAG_S | FLAG_H);
+	let cpu = run(
+		vec![
+			LD(ByteRegister(A), Address(0x2800)),
+			BIT(0, AddressRegister(HL)),
+		],
+		|r| (r.f, r.h, r.l) = (0, 0x30, 0x00),
+	);
+	assert_eq!(cpu.registers.wz, 0x2801);
+	assert_eq!(cpu.registers.f, FLAG_Y | FLAG_H | FLAG_X | FLAG_Z | FLAG_PV);
+
+	// LDI: X is bit 3 and Y bit 1 of (HL) + A
+	let cpu = run(vec![LDI], |r| {
+		(r.a, r.f) = (0x0A, 0);
+		r.set_bc(2);
+		r.set_hl(0x2000);
+		r.set_de(0x3000);
+	});
+	assert_eq!(cpu.registers.f, FLAG_Y | FLAG_X | FLAG_PV);
+	assert_eq!(
+		(cpu.registers.bc(), cpu.registers.hl(), cpu.registers.de()),
+		(1, 0x2001, 0x3001)
+	);
+
+	let cpu = run(vec![LD(ByteRegister(A), I)], |r| r.i = 0);
+	assert_eq!(cpu.registers.f & FLAG_PV, 0);
+}
+
+#[test]
+fn test_cycles() {
+	let cases: Vec<(Inst, u32)> = vec![
+		(NOP, 4),
+		(LD(ByteRegister(A), ByteRegister(B)), 4),
+		(LD(ByteRegister(A), AddressRegister(HL)), 7),
+		(LD(WordRegister(HL), Constant(0)), 10),
+		(LD(Address(0x2000), WordRegister(HL)), 16),
+		(LD(Address(0x2000), WordRegister(IX)), 20),
+		(LD(Address(0x2000), WordRegister(DE)), 20),
+		(LD(AddressRegisterWithOffset(IX, 2), Constant(1)), 19),
+		(LD(ByteRegister(B), AddressRegisterWithOffset(IY, 2)), 19),
+		(INC(AddressRegisterWithOffset(IX, 2)), 23),
+		(INC(AddressRegister(HL)), 11),
+		(INC(WordRegister(BC)), 6),
+		(ADD(WordRegister(HL), WordRegister(DE)), 11),
+		(ADD(WordRegister(IX), WordRegister(DE)), 15),
+		(ADC(WordRegister(HL), WordRegister(DE)), 15),
+		(PUSH(WordRegister(BC)), 11),
+		(PUSH(WordRegister(IX)), 15),
+		(POP(WordRegister(BC)), 10),
+		(EX(AddressRegister(SP), WordRegister(HL)), 19),
+		(EX(AddressRegister(SP), WordRegister(IX)), 23),
+		(CALL(None, Constant(0x1000)), 17),
+		(JP(None, Constant(0x1000)), 10),
+		(JR(None, Constant(0)), 12),
+		(RST(0x08), 11),
+		(RET(None), 10),
+		(RLC(ByteRegister(B)), 8),
+		(RLC(AddressRegister(HL)), 15),
+		(BIT(0, AddressRegister(HL)), 12),
+		(RLC(AddressRegisterWithOffset(IX, 0)), 23),
+		(BIT(0, AddressRegisterWithOffset(IX, 0)), 20),
+		(IN(ByteRegister(A), PortRegister(C)), 12),
+		(IN(ByteRegister(A), Port(0)), 11),
+		(NEG, 8),
+		(IM(1), 8),
+		(LD(ByteRegister(A), I), 9),
+		(RLD, 18),
+		(LDI, 16),
+		(EXX, 4),
+		(LD(WordRegister(SP), WordRegister(HL)), 6),
+		(INC(UndocumentedRegister(IXH)), 8),
+		(SLL(ByteRegister(B)), 8),
+	];
+
+	for (inst, cycles) in cases {
+		let code = assemble(vec![inst.clone()], true);
+		let (mut cpu, mut memory) = machine(&code, true);
+		assert_eq!(cpu.step(&mut memory), Ok(cycles), "{:?}", inst);
+		assert_eq!(cpu.cycles, cycles as u64);
+	}
+}
+
+#[test]
+fn test_conditional_cycles() {
+	let cases: Vec<(Inst, u32, u32)> = vec![
+		(JR(Some(Condition::Z), Constant(0)), 7, 12),
+		(JP(Some(Condition::Z), Constant(0x1000)), 10, 10),
+		(CALL(Some(Condition::Z), Constant(0x1000)), 10, 17),
+		(RET(Some(Condition::Z)), 5, 11),
+	];
+
+	for (inst, not_taken, taken) in cases {
+		let code = assemble(vec![inst.clone()], false);
+		for (f, cycles) in [(0, not_taken), (FLAG_Z, taken)] {
+			let (mut cpu, mut memory) = machine(&code, false);
+			cpu.registers.f = f;
+			assert_eq!(cpu.step(&mut memory), Ok(cycles), "{:?}", inst);
+		}
+	}
+
+	let cpu = run(vec![LD(ByteRegister(B), Constant(3)), DJNZ(-2)], |_| {});
+	assert_eq!(cpu.cycles, 7 + 13 + 13 + 8);
+
+	// 21 T-states per byte copied, 16 for the last one
+	let cpu = run(vec![LDIR], |r| r.set_bc(4));
+	assert_eq!(cpu.cycles, 3 * 21 + 16);
+	assert_eq!(cpu.registers.r, 8);
+}
+
+#[test]
+fn test_block_instructions() {
+	let code = assemble(vec![LDIR, CPIR], false);
+	let (mut cpu, mut memory) = machine(&code, false);
+	memory.load(0x2000, b"HELLO, WORLD");
+	let regs = &mut cpu.registers;
+	regs.set_hl(0x2000);
+	regs.set_de(0x3000);
+	regs.set_bc(12);
+	cpu.run_until(&mut memory, START + 2, 1000).unwrap();
+	assert_eq!(
+		(0..12)
+			.map(|i| memory.read_byte(0x3000 + i))
+			.collect::<Vec<_>>(),
+		b"HELLO, WORLD"
+	);
+	assert_eq!(cpu.registers.hl(), 0x200C);
+	assert_eq!(cpu.registers.de(), 0x300C);
+	assert_eq!(cpu.registers.bc(), 0);
+
+	let regs = &mut cpu.registers;
+	regs.a = b',';
+	regs.set_hl(0x3000);
+	regs.set_bc(12);
+	cpu.run_until(&mut memory, START + 4, 1000).unwrap();
+	assert_eq!(cpu.registers.hl(), 0x3006);
+	assert_eq!(cpu.registers.bc(), 6);
+	assert_ne!(cpu.registers.f & FLAG_Z, 0);
+}
+
+#[test]
+fn test_ports() {
+	let outputs = RefCell::new(vec![]);
+	let code = assemble(
+		vec![
+			LD(ByteRegister(A), Constant(0x12)),
+			IN(ByteRegister(A), Port(0x34)),
+			OUT(Port(0x56), ByteRegister(A)),
+			LD(WordRegister(BC), Constant(0x789A)),
+			OUT(PortRegister(C), ByteRegister(B)),
+			LD(ByteRegister(B), Constant(3)),
+			OTIR,
+		],
+		false,
+	);
+	let (mut cpu, mut memory) = machine(&code, false);
+	memory.load(0x2000, &[1, 2, 3]);
+	memory.set_input_handler(|port| (port >> 8) as u8 + 1);
+	memory.set_output_handler(|port, value| outputs.borrow_mut().push((port, value)));
+	cpu.registers.set_hl(0x2000);
+	cpu.run_until(&mut memory, START + code.len() as u16, 1000)
+		.unwrap();
+	drop(memory);
+
+	assert_eq!(
+		outputs.into_inner(),
+		vec![
+			(0x1356, 0x13),
+			(0x789A, 0x78),
+			// OTIR decrements B before the output
+			(0x029A, 1),
+			(0x019A, 2),
+			(0x009A, 3)
+		]
+	);
+}
+
+#[test]
+fn test_undocumented_instructions() {
+	let cpu = run(vec![SLL(ByteRegister(B))], |r| r.b = 0x81);
+	assert_eq!(cpu.registers.b, 0x03);
+	assert_ne!(cpu.registers.f & FLAG_C, 0);
+
+	let cpu = run(
+		vec![
+			LD(WordRegister(IX), Constant(0x1234)),
+			INC(UndocumentedRegister(IXH)),
+			LD(ByteRegister(B), UndocumentedRegister(IXL)),
+			LD(UndocumentedRegister(IXL), Constant(0x55)),
+		],
+		|_| {},
+	);
+	assert_eq!(cpu.registers.ix, 0x1355);
+	assert_eq!(cpu.registers.b, 0x34);
+
+	// RLC (IX+1),B: the result goes to memory and to B
+	let code = [0xDD, 0xCB, 0x01, 0x00];
+	let (mut cpu, mut memory) = machine(&code, true);
+	cpu.registers.ix = 0x2000;
+	memory.load(0x2001, &[0x81]);
+	assert_eq!(cpu.step(&mut memory), Ok(23));
+	assert_eq!(memory.read_byte(0x2001), 0x03);
+	assert_eq!(cpu.registers.b, 0x03);
+
+	// LD H,(IX+d) loads the real H
+	let code = [0xDD, 0x66, 0x01];
+	let (mut cpu, mut memory) = machine(&code, false);
+	cpu.registers.ix = 0x2000;
+	memory.load(0x2001, &[0x42]);
+	cpu.step(&mut memory).unwrap();
+	assert_eq!((cpu.registers.h, cpu.registers.ix), (0x42, 0x2000));
+
+	let outputs = RefCell::new(vec![]);
+	let code = assemble(
+		vec![IN(F, PortRegister(C)), OUT(PortRegister(C), Constant(0))],
+		true,
+	);
+	let (mut cpu, mut memory) = machine(&code, true);
+	memory.set_input_handler(|_| 0x00);
+	memory.set_output_handler(|port, value| outputs.borrow_mut().push((port, value)));
+	cpu.registers.set_bc(0x0110);
+	cpu.registers.a = 0x77;
+	cpu.step(&mut memory).unwrap();
+	assert_eq!(cpu.registers.a, 0x77);
+	assert_eq!(cpu.registers.f & (FLAG_Z | FLAG_PV), FLAG_Z | FLAG_PV);
+	cpu.step(&mut memory).unwrap();
+	drop(memory);
+	assert_eq!(outputs.into_inner(), vec![(0x0110, 0)]);
+}
+
+#[test]
+fn test_undocumented_instructions_can_be_disabled() {
+	for code in [
+		vec![0xCB, 0x30],
+		vec![0xDD, 0x24],
+		vec![0xFD, 0x7D],
+		vec![0xDD, 0xCB, 0x01, 0x00],
+		vec![0xED, 0x70],
+		vec![0xED, 0x71],
+		vec![0xED, 0x4C],
+		vec![0xED, 0x00],
+		vec![0xDD, 0xDD, 0x21, 0, 0],
+	] {
+		let (mut cpu, mut memory) = machine(&code, false);
+		let before = cpu.clone();
+		assert_eq!(
+			cpu.step(&mut memory),
+			Err(Error::UndocumentedInstruction(START)),
+			"{:02X?}",
+			code
+		);
+		assert_eq!(cpu, before);
+		assert_eq!(memory.read_byte(START), code[0]);
+
+		let (mut cpu, mut memory) = machine(&code, true);
+		assert!(cpu.step(&mut memory).is_ok(), "{:02X?}", code);
+	}
+}
+
+#[test]
+fn test_every_opcode_executes() {
+	let mut codes = vec![];
+	for op in 0..=255u8 {
+		codes.push(vec![op, 0x10, 0x20, 0x30]);
+		codes.push(vec![0xCB, op]);
+		codes.push(vec![0xED, op, 0x10, 0x20]);
+		codes.push(vec![0xDD, op, 0x10, 0x20]);
+		codes.push(vec![0xFD, op, 0x10, 0x20]);
+		codes.push(vec![0xDD, 0xCB, 0x10, op]);
+		codes.push(vec![0xFD, 0xCB, 0x10, op]);
+	}
+
+	for code in codes {
+		let (mut cpu, mut memory) = machine(&code, true);
+		let cycles = cpu.step(&mut memory).unwrap();
+		assert!(
+			(4..=23).contains(&cycles),
+			"{:02X?} took {} T-states",
+			code,
+			cycles
+		);
+	}
+}
+
+#[test]
+fn test_interrupts() {
+	// EI takes effect after the next instruction
+	let code = assemble(vec![IM(1), EI, NOP, HALT], false);
+	let (mut cpu, mut memory) = machine(&code, false);
+	cpu.step(&mut memory).unwrap();
+	assert_eq!(cpu.interrupt(&mut memory, 0xFF), 0);
+	cpu.step(&mut memory).unwrap();
+	assert_eq!(cpu.interrupt(&mut memory, 0xFF), 0);
+	cpu.step(&mut memory).unwrap();
+	cpu.step(&mut memory).unwrap();
+	assert!(cpu.halted);
+	assert_eq!(cpu.step(&mut memory), Ok(4));
+	assert_eq!(cpu.registers.pc, START + 5);
+
+	assert_eq!(cpu.interrupt(&mut memory, 0xFF), 13);
+	assert!(!cpu.halted && !cpu.iff1 && !cpu.iff2);
+	assert_eq!(cpu.registers.pc, 0x38);
+	assert_eq!(memory.read_word(cpu.registers.sp), START + 5);
+	assert_eq!(cpu.interrupt(&mut memory, 0xFF), 0);
+
+	// Mode 2 jumps through the table at I * 256 + data
+	let code = assemble(vec![IM(2), EI, NOP], false);
+	let (mut cpu, mut memory) = machine(&code, false);
+	memory.load(0x3010, &[0x34, 0x12]);
+	cpu.registers.i = 0x30;
+	cpu.run_until(&mut memory, START + code.len() as u16, 100)
+		.unwrap();
+	assert_eq!(cpu.interrupt(&mut memory, 0x10), 19);
+	assert_eq!(cpu.registers.pc, 0x1234);
+
+	// Mode 0 executes the RST on the bus
+	let (mut cpu, mut memory) = machine(&[0xFB, 0x00], false);
+	cpu.step(&mut memory).unwrap();
+	cpu.step(&mut memory).unwrap();
+	assert_eq!(cpu.interrupt(&mut memory, 0xD7), 13);
+	assert_eq!(cpu.registers.pc, 0x10);
+
+	// RETN restores IFF1 from IFF2
+	let code = assemble(vec![EI, NOP], false);
+	let (mut cpu, mut memory) = machine(&code, false);
+	memory.load(0x66, &[0xED, 0x45]);
+	cpu.run_until(&mut memory, START + 2, 100).unwrap();
+	assert_eq!(cpu.non_maskable_interrupt(&mut memory), 11);
+	assert_eq!(cpu.registers.pc, 0x66);
+	assert!(!cpu.iff1 && cpu.iff2);
+	assert_eq!(cpu.step(&mut memory), Ok(14));
+	assert_eq!(cpu.registers.pc, START + 2);
+	assert!(cpu.iff1);
+}
+
+#[test]
+fn test_cycle_limit() {
+	let (mut cpu, mut memory) = machine(&[0x18, 0xFE], false);
+	assert_eq!(
+		cpu.run_until(&mut memory, 0, 1000),
+		Err(Error::CycleLimitReached)
+	);
+	assert!(cpu.cycles >= 1000);
+}
+
+/*
+ * The T-states the z80 crate gives every instruction are those it takes,
+ * whether it branches or not, and an instruction which doesn't branch
+ * ends where its size says
+ */
+#[test]
+fn test_cycles_match_the_instructions() {
+	let mut codes = vec![];
+	for op in 0..=255u8 {
+		codes.push(vec![op, 0x10, 0x20, 0x30]);
+		codes.push(vec![0xCB, op]);
+		codes.push(vec![0xED, op, 0x10, 0x20]);
+		codes.push(vec![0xDD, op, 0x10, 0x20]);
+		codes.push(vec![0xFD, op, 0x10, 0x20]);
+		codes.push(vec![0xDD, 0xCB, 0x10, op]);
+		codes.push(vec![0xFD, 0xCB, 0x10, op]);
+	}
+
+	for code in codes {
+		let (inst, mut size) = z80::disassembler::decode(&code, true).unwrap();
+		let mut expected = inst.cycles().unwrap();
+		// A DD or FD which changes nothing is run with the instruction after it
+		if inst == Binary(vec![code[0]]) && ![0xDD, 0xED, 0xFD].contains(&code[1]) {
+			let (inst, next) = z80::disassembler::decode(&code[1..], true).unwrap();
+			expected = expected + inst.cycles().unwrap();
+			size += next;
+		}
+		// Every condition is false then true, and the loops stop then go on
+		for (f, bc) in [(0x00, 0x0101), (0xFF, 0x0202)] {
+			let (mut cpu, mut memory) = machine(&code, true);
+			cpu.registers.f = f;
+			cpu.registers.set_bc(bc);
+			let cycles = cpu.step(&mut memory).unwrap();
+			assert!(
+				cycles == expected.taken || cycles == expected.not_taken,
+				"{:02X?} took {} T-states instead of {}",
+				code,
+				cycles,
+				expected
+			);
+			if cycles == expected.not_taken && expected.taken != expected.not_taken {
+				assert_eq!(cpu.registers.pc, START + size as u16, "{:02X?}", code);
+			}
+		}
+	}
+}
@@ -1,4 +1,5 @@
 use std::cmp::Ordering;
+use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
 pub enum Type {
@@ -148,12 +149,106 @@ pub enum Expression<VariableType> {
 	Constant(Constant),
 }
 
+impl fmt::Display for BinaryOperation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let op = match self {
+			| BinaryOperation::Add => "+",
+			| BinaryOperation::AddWithCarry => "PLUS",
+			| BinaryOperation::Substract => "-",
+			| BinaryOperation::SubstractWithCarry => "MINUS",
+			| BinaryOperation::Multiply => "*",
+			| BinaryOperation::Division => "/",
+			| BinaryOperation::Modulo => "MOD",
+			| BinaryOperation::ShiftLeft => "SHL",
+			| BinaryOperation::ShiftLeftWithCarry => "SCL",
+			| BinaryOperation::ShiftRight => "SHR",
+			| BinaryOperation::ShiftRightWithCarry => "SCR",
+			| BinaryOperation::RotateLeft => "ROL",
+			| BinaryOperation::RotateLeftWithCarry => "RCL",
+			| BinaryOperation::RotateRight => "ROR",
+			| BinaryOperation::RotateRightWithCarry => "RCR",
+			| BinaryOperation::And => "AND",
+			| BinaryOperation::Or => "OR",
+			| BinaryOperation::Xor => "XOR",
+			| BinaryOperation::Greater => ">",
+			| BinaryOperation::Less => "<",
+			| BinaryOperation::GreaterOrEqual => ">=",
+			| BinaryOperation::LessOrEqual => "<=",
+			| BinaryOperation::Equal => "=",
+			| BinaryOperation::NotEqual => "<>",
+		};
+		write!(f, "{}", op)
+	}
+}
+
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter, values: &[T]) -> fmt::Result {
+	for (i, v) in values.iter().enumerate() {
+		if i > 0 {
+			write!(f, ", ")?;
+		}
+		write!(f, "{}", v)?;
+	}
+	Ok(())
+}
+
+/*
+ * Close to the PL/M syntax, the dereference excepted
+ */
+impl<VariableType: fmt::Display> fmt::Display for Expression<VariableType> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| Expression::BinaryOp(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op, rhs),
+			| Expression::UnaryOp(UnaryOperation::Not, e) => write!(f, "NOT {}", e),
+			| Expression::UnaryOp(UnaryOperation::Invert, e) => write!(f, "-{}", e),
+			| Expression::UnaryOp(UnaryOperation::Reference, e) => write!(f, ".{}", e),
+			| Expression::UnaryOp(UnaryOperation::Dereference, e) => write!(f, "[{}]", e),
+			| Expression::FunctionCall(name, args) => {
+				write!(f, "{}(", name)?;
+				write_list(f, args)?;
+				write!(f, ")")
+			}
+			| Expression::Variable(var) => write!(f, "{}", var),
+			| Expression::Phi(lhs, rhs) => {
+				let operand = |v: &Option<VariableType>| match v {
+					| Some(v) => v.to_string(),
+					| None => "?".to_string(),
+				};
+				write!(f, "PHI({}, {})", operand(lhs), operand(rhs))
+			}
+			| Expression::Constant(Constant::Value(value, _)) => write!(f, "{}", value),
+			| Expression::Constant(Constant::Array(values, _)) => {
+				write!(f, "(")?;
+				write_list(f, values)?;
+				write!(f, ")")
+			}
+			| Expression::Constant(Constant::ReadOnlyArray(values, _)) => {
+				write!(f, "DATA(")?;
+				write_list(f, values)?;
+				write!(f, ")")
+			}
+		}
+	}
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Variable<VariableType> {
 	m_name: VariableType,
 	m_type: Type,
 }
 
+impl<VariableType> Variable<VariableType> {
+	pub fn new(name: VariableType, t: Type) -> Self {
+		Self {
+			m_name: name,
+			m_type: t,
+		}
+	}
+
+	pub fn name(&self) -> &VariableType {
+		&self.m_name
+	}
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Statement<VariableType> {
 	IfElse(
@@ -170,15 +265,20 @@ pub enum Statement<VariableType> {
 
 	Return(Option<Expression<VariableType>>),
 	Expression(Expression<VariableType>),
+	// The n of INTERRUPT n comes before the body
 	FunctionDefinition(
 		String,
 		Type,
 		Vec<Variable<VariableType>>,
+		Option<u8>,
 		Vec<Statement<VariableType>>,
 	),
 	Assignment(VariableType, Expression<VariableType>),
 
 	Label(String),
+	// GO TO label
+	Goto(String),
+	// GO TO address, or through a computed address
 	Jump(Expression<VariableType>),
 	DisableInterrupt,
 	EnableInterrupt,
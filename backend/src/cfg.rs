@@ -0,0 +1,554 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::ast::*;
+
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator<VariableType> {
+	Goto(BlockId),
+	// Goes to the first block if the lowest bit of the condition is set
+	Branch(Expression<VariableType>, BlockId, BlockId),
+	// The last target is taken when the value is out of range
+	Switch(Expression<VariableType>, Vec<BlockId>, BlockId),
+	Return(Option<Expression<VariableType>>),
+	// A GOTO to a label outside of the procedure
+	NonLocalGoto(String),
+	// A GOTO to an address
+	IndirectJump(Expression<VariableType>),
+}
+
+impl<VariableType> Terminator<VariableType> {
+	pub fn successors(&self) -> Vec<BlockId> {
+		match self {
+			| Terminator::Goto(b) => vec![*b],
+			| Terminator::Branch(_, b1, b2) => {
+				if b1 == b2 {
+					vec![*b1]
+				} else {
+					vec![*b1, *b2]
+				}
+			}
+			| Terminator::Switch(_, cases, default) => {
+				let mut output: Vec<BlockId> = Vec::with_capacity(cases.len() + 1);
+				for b in cases.iter().chain(std::iter::once(default)) {
+					if !output.contains(b) {
+						output.push(*b);
+					}
+				}
+				output
+			}
+			| Terminator::Return(_) | Terminator::NonLocalGoto(_) | Terminator::IndirectJump(_) => {
+				vec![]
+			}
+		}
+	}
+
+	pub fn map_targets(&mut self, f: impl Fn(BlockId) -> BlockId) {
+		match self {
+			| Terminator::Goto(b) => *b = f(*b),
+			| Terminator::Branch(_, b1, b2) => {
+				*b1 = f(*b1);
+				*b2 = f(*b2);
+			}
+			| Terminator::Switch(_, cases, default) => {
+				cases.iter_mut().for_each(|b| *b = f(*b));
+				*default = f(*default);
+			}
+			| Terminator::Return(_) | Terminator::NonLocalGoto(_) | Terminator::IndirectJump(_) => {}
+		}
+	}
+}
+
+/*
+ * Only Assignment, Expression, DisableInterrupt, EnableInterrupt and
+ * Halt appear in the statements of a block, the control flow being
+ * held by the terminator
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock<VariableType> {
+	pub label: Option<String>,
+	pub statements: Vec<Statement<VariableType>>,
+	pub terminator: Terminator<VariableType>,
+}
+
+/*
+ * The entry is always the first block, and every block can be reached
+ * from it
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFlowGraph<VariableType> {
+	pub blocks: Vec<BasicBlock<VariableType>>,
+	predecessors: Vec<Vec<BlockId>>,
+}
+
+pub const ENTRY: BlockId = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CfgError {
+	// Defined twice in the same procedure
+	DuplicateLabel(String),
+}
+
+struct Builder<VariableType> {
+	labels: Vec<Option<String>>,
+	statements: Vec<Vec<Statement<VariableType>>>,
+	terminators: Vec<Option<Terminator<VariableType>>>,
+	label_blocks: BTreeMap<String, BlockId>,
+	current: BlockId,
+}
+
+fn collect_labels<VariableType>(
+	stmts: &[Statement<VariableType>],
+	output: &mut BTreeSet<String>,
+) -> Result<(), CfgError> {
+	for stmt in stmts {
+		match stmt {
+			| Statement::Label(name) => {
+				if !output.insert(name.clone()) {
+					return Err(CfgError::DuplicateLabel(name.clone()));
+				}
+			}
+			| Statement::IfElse(_, if_blk, else_blk) => {
+				collect_labels(if_blk, output)?;
+				collect_labels(else_blk, output)?;
+			}
+			| Statement::Block(blk) | Statement::Loop(blk) => collect_labels(blk, output)?,
+			| Statement::Switch(_, cases) => {
+				for case in cases.iter().flatten() {
+					collect_labels(std::slice::from_ref(case), output)?;
+				}
+			}
+			| _ => {}
+		}
+	}
+	Ok(())
+}
+
+impl<VariableType> Builder<VariableType> {
+	fn new_block(&mut self) -> BlockId {
+		self.labels.push(None);
+		self.statements.push(Vec::new());
+		self.terminators.push(None);
+		self.labels.len() - 1
+	}
+
+	// Ends the current block, and continues in `next`
+	fn terminate(&mut self, terminator: Terminator<VariableType>, next: BlockId) {
+		self.terminators[self.current] = Some(terminator);
+		self.current = next;
+	}
+
+	fn terminate_unreachable(&mut self, terminator: Terminator<VariableType>) {
+		let next = self.new_block();
+		self.terminate(terminator, next);
+	}
+
+	fn jump_target(&self, label: String) -> Terminator<VariableType> {
+		match self.label_blocks.get(&label) {
+			| Some(target) => Terminator::Goto(*target),
+			| None => Terminator::NonLocalGoto(label),
+		}
+	}
+
+	fn lower(&mut self, stmts: Vec<Statement<VariableType>>) {
+		for stmt in stmts {
+			self.lower_statement(stmt);
+		}
+	}
+
+	fn lower_statement(&mut self, stmt: Statement<VariableType>) {
+		match stmt {
+			| Statement::IfElse(cond, if_blk, else_blk) => {
+				let (then_id, else_id, join_id) = (self.new_block(), self.new_block(), self.new_block());
+				self.terminate(Terminator::Branch(cond, then_id, else_id), then_id);
+				self.lower(if_blk);
+				self.terminate(Terminator::Goto(join_id), else_id);
+				self.lower(else_blk);
+				self.terminate(Terminator::Goto(join_id), join_id);
+			}
+			| Statement::Block(blk) => self.lower(blk),
+			| Statement::Loop(blk) => {
+				let header = self.new_block();
+				self.terminate(Terminator::Goto(header), header);
+				self.lower(blk);
+				self.terminate_unreachable(Terminator::Goto(header));
+			}
+			| Statement::Switch(e, cases) => {
+				let join_id = self.new_block();
+				let mut targets = Vec::with_capacity(cases.len());
+				let mut bodies = Vec::new();
+				for case in cases {
+					match case {
+						| None => targets.push(join_id),
+						| Some(case) => {
+							let id = self.new_block();
+							targets.push(id);
+							bodies.push((id, case));
+						}
+					}
+				}
+				self.terminators[self.current] = Some(Terminator::Switch(e, targets, join_id));
+				for (id, case) in bodies {
+					self.current = id;
+					self.lower_statement(case);
+					self.terminators[self.current] = Some(Terminator::Goto(join_id));
+				}
+				self.current = join_id;
+			}
+			| Statement::Return(e) => self.terminate_unreachable(Terminator::Return(e)),
+			| Statement::Goto(label) => {
+				let terminator = self.jump_target(label);
+				self.terminate_unreachable(terminator)
+			}
+			| Statement::Jump(e) => self.terminate_unreachable(Terminator::IndirectJump(e)),
+			| Statement::Label(name) => {
+				let target = self.label_blocks[&name];
+				self.labels[target] = Some(name);
+				self.terminate(Terminator::Goto(target), target);
+			}
+			// Built separately
+			| Statement::FunctionDefinition(_, _, _, _, _) | Statement::NoOperation => {}
+			| stmt @ (Statement::Assignment(_, _)
+			| Statement::Expression(_)
+			| Statement::DisableInterrupt
+			| Statement::EnableInterrupt
+			| Statement::Halt) => self.statements[self.current].push(stmt),
+		}
+	}
+}
+
+impl<VariableType> ControlFlowGraph<VariableType> {
+	/*
+	 * Builds the graph of a procedure body, or of the main program.
+	 * The nested procedures are ignored, and the blocks which can't be
+	 * reached are dropped. A label can only be defined once.
+	 */
+	pub fn build(stmts: Vec<Statement<VariableType>>) -> Result<Self, CfgError> {
+		let mut builder = Builder {
+			labels: Vec::new(),
+			statements: Vec::new(),
+			terminators: Vec::new(),
+			label_blocks: BTreeMap::new(),
+			current: ENTRY,
+		};
+		builder.new_block();
+
+		let mut labels = BTreeSet::new();
+		collect_labels(&stmts, &mut labels)?;
+		for name in labels {
+			let id = builder.new_block();
+			builder.label_blocks.insert(name, id);
+		}
+
+		builder.lower(stmts);
+		builder.terminate_unreachable(Terminator::Return(None));
+
+		// Only keeps the reachable blocks, numbered in the order they're found
+		let mut new_ids: Vec<Option<BlockId>> = vec![None; builder.labels.len()];
+		let mut order = vec![ENTRY];
+		new_ids[ENTRY] = Some(ENTRY);
+		let mut i = 0;
+		while i < order.len() {
+			let terminator = builder.terminators[order[i]].as_ref().expect("Every block is terminated");
+			for succ in terminator.successors() {
+				if new_ids[succ].is_none() {
+					new_ids[succ] = Some(order.len());
+					order.push(succ);
+				}
+			}
+			i += 1;
+		}
+
+		let mut blocks = Vec::with_capacity(order.len());
+		for old_id in order {
+			let mut terminator = builder.terminators[old_id].take().unwrap();
+			terminator.map_targets(|b| new_ids[b].unwrap());
+			blocks.push(BasicBlock {
+				label: builder.labels[old_id].take(),
+				statements: std::mem::take(&mut builder.statements[old_id]),
+				terminator,
+			});
+		}
+
+		Ok(Self::from_blocks(blocks))
+	}
+
+	// The blocks must only refer to existing blocks
+	pub fn from_blocks(blocks: Vec<BasicBlock<VariableType>>) -> Self {
+		let mut predecessors = vec![Vec::new(); blocks.len()];
+		for (id, block) in blocks.iter().enumerate() {
+			for succ in block.terminator.successors() {
+				predecessors[succ].push(id);
+			}
+		}
+		Self { blocks, predecessors }
+	}
+
+	pub fn len(&self) -> usize {
+		self.blocks.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.blocks.is_empty()
+	}
+
+	pub fn successors(&self, id: BlockId) -> Vec<BlockId> {
+		self.blocks[id].terminator.successors()
+	}
+
+	pub fn predecessors(&self, id: BlockId) -> &[BlockId] {
+		&self.predecessors[id]
+	}
+
+	pub fn block_of_label(&self, name: &str) -> Option<BlockId> {
+		self.blocks.iter().position(|b| b.label.as_deref() == Some(name))
+	}
+
+	pub fn reverse_postorder(&self) -> Vec<BlockId> {
+		let mut visited = vec![false; self.blocks.len()];
+		let mut postorder = Vec::with_capacity(self.blocks.len());
+		// The block, and the index of its next successor to visit
+		let mut stack = vec![(ENTRY, 0)];
+		visited[ENTRY] = true;
+
+		while let Some((id, next)) = stack.pop() {
+			let successors = self.successors(id);
+			if next < successors.len() {
+				stack.push((id, next + 1));
+				let succ = successors[next];
+				if !visited[succ] {
+					visited[succ] = true;
+					stack.push((succ, 0));
+				}
+			} else {
+				postorder.push(id);
+			}
+		}
+
+		postorder.reverse();
+		postorder
+	}
+
+	pub fn dominator_tree(&self) -> DominatorTree {
+		DominatorTree::new(self)
+	}
+}
+
+/*
+ * The graphs of the main program, then of every procedure by order of
+ * definition
+ */
+pub fn build_program<VariableType: Clone>(
+	program: Vec<Statement<VariableType>>,
+) -> Result<Vec<(Option<String>, ControlFlowGraph<VariableType>)>, CfgError> {
+	fn collect_procedures<VariableType: Clone>(
+		stmts: &[Statement<VariableType>],
+		output: &mut Vec<(Option<String>, ControlFlowGraph<VariableType>)>,
+	) -> Result<(), CfgError> {
+		for stmt in stmts {
+			match stmt {
+				| Statement::FunctionDefinition(name, _, _, _, body) => {
+					output.push((Some(name.clone()), ControlFlowGraph::build(body.clone())?));
+					collect_procedures(body, output)?;
+				}
+				| Statement::IfElse(_, if_blk, else_blk) => {
+					collect_procedures(if_blk, output)?;
+					collect_procedures(else_blk, output)?;
+				}
+				| Statement::Block(blk) | Statement::Loop(blk) => collect_procedures(blk, output)?,
+				| _ => {}
+			}
+		}
+		Ok(())
+	}
+
+	let mut output = Vec::new();
+	collect_procedures(&program, &mut output)?;
+	output.insert(0, (None, ControlFlowGraph::build(program)?));
+	Ok(output)
+}
+
+/*
+ * Computed with the algorithm of Cooper, Harvey and Kennedy
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DominatorTree {
+	// The entry is its own immediate dominator
+	idom: Vec<BlockId>,
+}
+
+impl DominatorTree {
+	pub fn new<VariableType>(cfg: &ControlFlowGraph<VariableType>) -> Self {
+		let rpo = cfg.reverse_postorder();
+		let mut order = vec![usize::MAX; cfg.len()];
+		for (i, id) in rpo.iter().enumerate() {
+			order[*id] = i;
+		}
+
+		let mut idom: Vec<Option<BlockId>> = vec![None; cfg.len()];
+		idom[ENTRY] = Some(ENTRY);
+
+		let intersect = |idom: &[Option<BlockId>], mut a: BlockId, mut b: BlockId| {
+			while a != b {
+				while order[a] > order[b] {
+					a = idom[a].unwrap();
+				}
+				while order[b] > order[a] {
+					b = idom[b].unwrap();
+				}
+			}
+			a
+		};
+
+		let mut changed = true;
+		while changed {
+			changed = false;
+			for id in rpo.iter().skip(1) {
+				let mut new_idom = None;
+				for pred in cfg.predecessors(*id) {
+					if idom[*pred].is_none() {
+						continue;
+					}
+					new_idom = Some(match new_idom {
+						| None => *pred,
+						| Some(other) => intersect(&idom, *pred, other),
+					});
+				}
+				if new_idom != idom[*id] {
+					idom[*id] = new_idom;
+					changed = true;
+				}
+			}
+		}
+
+		Self {
+			idom: idom.into_iter().map(|d| d.expect("Every block is reachable")).collect(),
+		}
+	}
+
+	pub fn immediate_dominator(&self, id: BlockId) -> Option<BlockId> {
+		if id == ENTRY {
+			None
+		} else {
+			Some(self.idom[id])
+		}
+	}
+
+	pub fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+		loop {
+			if a == b {
+				return true;
+			}
+			if b == ENTRY {
+				return false;
+			}
+			b = self.idom[b];
+		}
+	}
+
+	pub fn children(&self, id: BlockId) -> Vec<BlockId> {
+		(0..self.idom.len())
+			.filter(|b| *b != ENTRY && self.idom[*b] == id)
+			.collect()
+	}
+
+	/*
+	 * The blocks where the dominance of each block ends
+	 */
+	pub fn dominance_frontiers<VariableType>(
+		&self,
+		cfg: &ControlFlowGraph<VariableType>,
+	) -> Vec<BTreeSet<BlockId>> {
+		let mut frontiers = vec![BTreeSet::new(); cfg.len()];
+		for id in 0..cfg.len() {
+			let preds = cfg.predecessors(id);
+			if preds.len() < 2 {
+				continue;
+			}
+			// The entry has no dominator to stop at
+			let stop = self.immediate_dominator(id);
+			for pred in preds {
+				let mut runner = *pred;
+				while Some(runner) != stop {
+					frontiers[runner].insert(id);
+					if runner == ENTRY {
+						break;
+					}
+					runner = self.idom[runner];
+				}
+			}
+		}
+		frontiers
+	}
+}
+
+impl fmt::Display for DominatorTree {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for id in 0..self.idom.len() {
+			write!(f, "bb{}:", id)?;
+			for child in self.children(id) {
+				write!(f, " bb{}", child)?;
+			}
+			writeln!(f)?;
+		}
+		Ok(())
+	}
+}
+
+fn write_statement<VariableType: fmt::Display>(
+	f: &mut fmt::Formatter,
+	stmt: &Statement<VariableType>,
+) -> fmt::Result {
+	match stmt {
+		| Statement::Assignment(var, e) => writeln!(f, "\t{} := {}", var, e),
+		| Statement::Expression(e) => writeln!(f, "\t{}", e),
+		| Statement::DisableInterrupt => writeln!(f, "\tDISABLE"),
+		| Statement::EnableInterrupt => writeln!(f, "\tENABLE"),
+		| Statement::Halt => writeln!(f, "\tHALT"),
+		| _ => unreachable!("Only simple statements are placed in a basic block"),
+	}
+}
+
+impl<VariableType: fmt::Display> fmt::Display for ControlFlowGraph<VariableType> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (id, block) in self.blocks.iter().enumerate() {
+			write!(f, "bb{}", id)?;
+			if let Some(label) = &block.label {
+				write!(f, " ({})", label)?;
+			}
+			write!(f, ":")?;
+			if !self.predecessors[id].is_empty() {
+				write!(f, " ; preds")?;
+				for pred in self.predecessors[id].iter() {
+					write!(f, " bb{}", pred)?;
+				}
+			}
+			writeln!(f)?;
+
+			for stmt in block.statements.iter() {
+				write_statement(f, stmt)?;
+			}
+
+			match &block.terminator {
+				| Terminator::Goto(b) => writeln!(f, "\tgoto bb{}", b)?,
+				| Terminator::Branch(cond, b1, b2) => {
+					writeln!(f, "\tif {} then bb{} else bb{}", cond, b1, b2)?
+				}
+				| Terminator::Switch(e, cases, default) => {
+					write!(f, "\tcase {} of", e)?;
+					for b in cases {
+						write!(f, " bb{}", b)?;
+					}
+					writeln!(f, " else bb{}", default)?;
+				}
+				| Terminator::Return(None) => writeln!(f, "\treturn")?,
+				| Terminator::Return(Some(e)) => writeln!(f, "\treturn {}", e)?,
+				| Terminator::NonLocalGoto(label) => writeln!(f, "\tgoto {}", label)?,
+				| Terminator::IndirectJump(e) => writeln!(f, "\tgoto [{}]", e)?,
+			}
+		}
+		Ok(())
+	}
+}
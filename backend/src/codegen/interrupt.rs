@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+use z80::instruction::{Instruction, WordRegister};
+
+use crate::codegen::{Item, Label};
+
+/*
+ * PL/M-80 handles INTERRUPT n by placing a jump to the procedure at
+ * the address of RST n. The procedure itself saves every register it
+ * may use, and re-enables the interrupts on return.
+ */
+pub const VECTOR_COUNT: u8 = 8;
+// Size of the room left between two RST addresses
+pub const VECTOR_SIZE: u16 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterSaving {
+	// PUSH/POP of AF, BC, DE and HL
+	Stack,
+	// EX AF,AF' and EXX, only safe if nothing else uses the
+	// alternate registers
+	ShadowRegisters,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterruptOptions {
+	pub saving: RegisterSaving,
+	// IX and IY aren't swapped by EXX, they are always pushed
+	pub save_index_registers: bool,
+	// Return with RETI, so that Z80 peripherals see the end of the
+	// interrupt
+	pub use_reti: bool,
+}
+
+impl Default for InterruptOptions {
+	fn default() -> Self {
+		Self {
+			saving: RegisterSaving::Stack,
+			save_index_registers: false,
+			use_reti: false,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterruptError {
+	VectorOutOfRange(i32),
+	ConflictingVector {
+		vector: u8,
+		first: Label,
+		second: Label,
+	},
+	UndefinedHandler(Label),
+	// The code placed at `address` lies where the jump of `vector`
+	// has to be written
+	OverlappingVector {
+		vector: u8,
+		address: u16,
+	},
+}
+
+// A jump to write at a given address
+pub type VectorJump = (u16, Instruction<u8, u16, i32, i8>);
+
+const SAVED_REGISTERS: [WordRegister; 4] = [AF, BC, DE, HL];
+const INDEX_REGISTERS: [WordRegister; 2] = [IX, IY];
+
+pub fn vector_address(vector: u8) -> u16 {
+	vector as u16 * VECTOR_SIZE
+}
+
+pub fn prologue(options: &InterruptOptions) -> Vec<Instruction<u8, u16, i32, i8>> {
+	let mut output = Vec::new();
+	match options.saving {
+		| RegisterSaving::Stack => {
+			output.extend(SAVED_REGISTERS.iter().map(|r| PUSH(WordRegister(r.clone()))));
+		}
+		| RegisterSaving::ShadowRegisters => {
+			output.push(EX(WordRegister(AF), WordRegister(AF_)));
+			output.push(EXX);
+		}
+	}
+	if options.save_index_registers {
+		output.extend(INDEX_REGISTERS.iter().map(|r| PUSH(WordRegister(r.clone()))));
+	}
+	output
+}
+
+pub fn epilogue(options: &InterruptOptions) -> Vec<Instruction<u8, u16, i32, i8>> {
+	let mut output = Vec::new();
+	if options.save_index_registers {
+		output.extend(INDEX_REGISTERS.iter().rev().map(|r| POP(WordRegister(r.clone()))));
+	}
+	match options.saving {
+		| RegisterSaving::Stack => {
+			output.extend(SAVED_REGISTERS.iter().rev().map(|r| POP(WordRegister(r.clone()))));
+		}
+		| RegisterSaving::ShadowRegisters => {
+			output.push(EXX);
+			output.push(EX(WordRegister(AF), WordRegister(AF_)));
+		}
+	}
+
+	// The Z80 only accepts interrupts after the instruction following
+	// EI, so nothing can interrupt us before the return
+	output.push(EI);
+	output.push(if options.use_reti { RETI } else { RET(None) });
+	output
+}
+
+/*
+ * Wraps the body of an interrupt procedure. Every RET of the body is
+ * turned into a jump to the epilogue.
+ */
+pub fn generate_procedure(name: &str, body: Vec<Item>, options: &InterruptOptions) -> Vec<Item> {
+	let exit_label = format!("{}$EXIT", name);
+
+	let mut output = vec![Item::Label(name.to_string())];
+	output.extend(prologue(options).into_iter().map(Item::Instruction));
+	for item in body {
+		match item {
+			| Item::Instruction(RET(cc)) => output.push(Item::Jump(cc, exit_label.clone())),
+			| item => output.push(item),
+		}
+	}
+	output.push(Item::Label(exit_label));
+	output.extend(epilogue(options).into_iter().map(Item::Instruction));
+	output
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VectorTable {
+	handlers: BTreeMap<u8, Label>,
+}
+
+impl VectorTable {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn assign(&mut self, vector: i32, handler: &str) -> Result<(), InterruptError> {
+		if !(0..VECTOR_COUNT as i32).contains(&vector) {
+			return Err(InterruptError::VectorOutOfRange(vector));
+		}
+
+		let vector = vector as u8;
+		if let Some(first) = self.handlers.get(&vector) {
+			return Err(InterruptError::ConflictingVector {
+				vector,
+				first: first.clone(),
+				second: handler.to_string(),
+			});
+		}
+
+		self.handlers.insert(vector, handler.to_string());
+		Ok(())
+	}
+
+	pub fn handler(&self, vector: u8) -> Option<&Label> {
+		self.handlers.get(&vector)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.handlers.is_empty()
+	}
+
+	/*
+	 * Makes sure that the code placed in [origin, origin + size) doesn't
+	 * lie on a used vector
+	 */
+	pub fn check_overlap(&self, origin: u16, size: u16) -> Result<(), InterruptError> {
+		let end = origin as u32 + size as u32;
+		for vector in self.handlers.keys() {
+			let start = vector_address(*vector) as u32;
+			// Only the 3 bytes of the JP are actually needed
+			if (origin as u32) < start + 3 && start < end {
+				return Err(InterruptError::OverlappingVector {
+					vector: *vector,
+					address: origin.max(start as u16),
+				});
+			}
+		}
+		Ok(())
+	}
+
+	/*
+	 * The jumps to write at each used vector, `labels` being the
+	 * addresses of the linked procedures
+	 */
+	pub fn link(
+		&self,
+		labels: &BTreeMap<Label, u16>,
+	) -> Result<Vec<VectorJump>, InterruptError> {
+		let mut output = Vec::with_capacity(self.handlers.len());
+		for (vector, handler) in self.handlers.iter() {
+			match labels.get(handler) {
+				| None => {
+					return Err(InterruptError::UndefinedHandler(handler.clone()));
+				}
+				| Some(addr) => {
+					output.push((vector_address(*vector), JP(None, Constant(*addr as i32))));
+				}
+			}
+		}
+		Ok(output)
+	}
+}
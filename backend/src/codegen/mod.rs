@@ -0,0 +1,403 @@
+pub mod interrupt;
+pub mod peephole;
+pub mod runtime;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+
+use z80::assembler::{Assembler, Target};
+use z80::instruction::{ByteRegister, Condition, Instruction, Operand, WordRegister};
+use z80::output;
+use z80::resolver::{Block, Linkage, Reference, Relocation, Segment};
+
+use crate::codegen::interrupt::{InterruptError, InterruptOptions, VectorJump, VectorTable};
+
+pub type Label = String;
+
+/*
+ * The output of the code generation: Z80 instructions, along with
+ * the references to labels which can only be resolved once the
+ * code is placed in memory.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+	Label(Label),
+	Instruction(Instruction<u8, u16, i32, i8>),
+	// JP cc,label
+	Jump(Option<Condition>, Label),
+	// JR cc,label
+	JumpRelative(Option<Condition>, Label),
+	// DJNZ label
+	DecrementJumpNonZero(Label),
+	// CALL cc,label
+	Call(Option<Condition>, Label),
+	// LD rr,label
+	LoadAddress(WordRegister, Label),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+	UndefinedLabel(Label),
+	DuplicateLabel(Label),
+	JumpOutOfRange(Label, i32),
+	InvalidInstruction(Instruction<u8, u16, i32, i8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgramError {
+	Link(LinkError),
+	Interrupt(InterruptError),
+}
+
+impl From<LinkError> for ProgramError {
+	fn from(e: LinkError) -> Self {
+		ProgramError::Link(e)
+	}
+}
+
+impl From<InterruptError> for ProgramError {
+	fn from(e: InterruptError) -> Self {
+		ProgramError::Interrupt(e)
+	}
+}
+
+// The code generated for a procedure, the body ending with its RET
+#[derive(Debug, Clone, PartialEq)]
+pub struct Procedure {
+	pub name: Label,
+	// The n of INTERRUPT n
+	pub interrupt: Option<u8>,
+	pub body: Vec<Item>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkedProgram {
+	pub code: LinkedCode,
+	// The jumps to write at the vectors of the interrupt procedures
+	pub vectors: Vec<VectorJump>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkedCode {
+	pub instructions: Vec<Instruction<u8, u16, i32, i8>>,
+	pub labels: BTreeMap<Label, u16>,
+	pub size: u16,
+}
+
+/*
+ * The code of a module for a linker, in the code segment from 0, with
+ * the absolute bytes of its interrupt vectors
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelocatableCode {
+	pub code: LinkedCode,
+	pub blocks: Vec<Block>,
+	// The words holding an address, and the symbols for the linker
+	pub linkage: Linkage,
+}
+
+impl RelocatableCode {
+	// A .REL file, which L80 links with those of the assembler
+	pub fn rel(&self, name: &str) -> Vec<u8> {
+		output::rel(name, &self.blocks, None, &self.linkage)
+	}
+}
+
+fn instruction_size(
+	inst: &Instruction<u8, u16, i32, i8>,
+	target: Target,
+) -> Result<u16, LinkError> {
+	let mut assembler = Assembler::new(iter::once(inst.clone()), false, false).with_target(target);
+	let size = assembler.by_ref().count() as u16;
+	if assembler.has_error_occured() {
+		return Err(LinkError::InvalidInstruction(inst.clone()));
+	}
+	Ok(size)
+}
+
+impl Item {
+	pub fn size(&self) -> Result<u16, LinkError> {
+		match self {
+			| Item::Label(_) => Ok(0),
+			// The Z180 has every instruction the code may use, with the same size
+			| Item::Instruction(inst) => instruction_size(inst, Target::Z180),
+			| Item::Jump(_, _) | Item::Call(_, _) => Ok(3),
+			| Item::JumpRelative(_, _) | Item::DecrementJumpNonZero(_) => Ok(2),
+			| Item::LoadAddress(WordRegister::IX, _) | Item::LoadAddress(WordRegister::IY, _) => Ok(4),
+			| Item::LoadAddress(_, _) => Ok(3),
+		}
+	}
+}
+
+pub fn size_of(items: &[Item]) -> Result<u16, LinkError> {
+	let mut size: u16 = 0;
+	for item in items {
+		size = size.wrapping_add(item.size()?);
+	}
+	Ok(size)
+}
+
+/*
+ * The items with only what the target can run. The 8080 has no
+ * relative jumps: JR becomes JP, and DJNZ becomes DEC B; JP NZ.
+ */
+pub fn lower(items: &[Item], target: Target) -> Vec<Item> {
+	if target != Target::I8080 {
+		return items.to_vec();
+	}
+
+	let mut output = Vec::with_capacity(items.len());
+	for item in items {
+		match item {
+			| Item::JumpRelative(cc, lbl) => output.push(Item::Jump(cc.clone(), lbl.clone())),
+			| Item::DecrementJumpNonZero(lbl) => {
+				let dec_b = Instruction::DEC(Operand::ByteRegister(ByteRegister::B));
+				output.push(Item::Instruction(dec_b));
+				output.push(Item::Jump(Some(Condition::NZ), lbl.clone()));
+			}
+			| item => output.push(item.clone()),
+		}
+	}
+	output
+}
+
+/*
+ * Places the items at `origin` and replaces every reference to a
+ * label by its address.
+ */
+pub fn link(items: &[Item], origin: u16) -> Result<LinkedCode, LinkError> {
+	link_for_target(items, origin, Target::Z80)
+}
+
+/*
+ * Like link, once the items are lowered for the target. What the target
+ * still can't run, like IX or EXX on the 8080, is an InvalidInstruction.
+ */
+pub fn link_for_target(
+	items: &[Item],
+	origin: u16,
+	target: Target,
+) -> Result<LinkedCode, LinkError> {
+	let items = &lower(items, target)[..];
+	let mut labels = BTreeMap::new();
+	let mut position = origin;
+	for item in items {
+		if let Item::Label(lbl) = item {
+			if labels.insert(lbl.clone(), position).is_some() {
+				return Err(LinkError::DuplicateLabel(lbl.clone()));
+			}
+		}
+		position = position.wrapping_add(item.size()?);
+	}
+
+	let address_of = |lbl: &Label| match labels.get(lbl) {
+		| Some(addr) => Ok(*addr),
+		| None => Err(LinkError::UndefinedLabel(lbl.clone())),
+	};
+
+	let mut instructions = Vec::with_capacity(items.len());
+	let mut position = origin;
+	for item in items {
+		let next_position = position.wrapping_add(item.size()?);
+		let offset = |lbl: &Label| -> Result<i32, LinkError> {
+			let offset = address_of(lbl)? as i32 - next_position as i32;
+			if !(-128..128).contains(&offset) {
+				return Err(LinkError::JumpOutOfRange(lbl.clone(), offset));
+			}
+			Ok(offset)
+		};
+
+		match item {
+			| Item::Label(_) => {}
+			| Item::Instruction(inst) => instructions.push(inst.clone()),
+			| Item::Jump(cc, lbl) => instructions.push(Instruction::JP(
+				cc.clone(),
+				Operand::Constant(address_of(lbl)? as i32),
+			)),
+			| Item::JumpRelative(cc, lbl) => {
+				instructions.push(Instruction::JR(cc.clone(), Operand::Constant(offset(lbl)?)))
+			}
+			| Item::DecrementJumpNonZero(lbl) => {
+				instructions.push(Instruction::DJNZ(offset(lbl)? as i8))
+			}
+			| Item::Call(cc, lbl) => instructions.push(Instruction::CALL(
+				cc.clone(),
+				Operand::Constant(address_of(lbl)? as i32),
+			)),
+			| Item::LoadAddress(r, lbl) => instructions.push(Instruction::LD(
+				Operand::WordRegister(r.clone()),
+				Operand::Constant(address_of(lbl)? as i32),
+			)),
+		}
+		position = next_position;
+	}
+
+	if target != Target::Z180 {
+		for inst in instructions.iter() {
+			instruction_size(inst, target)?;
+		}
+	}
+
+	Ok(LinkedCode {
+		instructions,
+		labels,
+		size: position.wrapping_sub(origin),
+	})
+}
+
+fn encode(instructions: &[Instruction<u8, u16, i32, i8>], target: Target) -> Vec<u8> {
+	Assembler::new(instructions.iter().cloned(), false, false)
+		.with_target(target)
+		.collect()
+}
+
+/*
+ * Like link_for_target, but as a module for a linker: the items are in
+ * the code segment from 0, and the words holding the address of a label
+ * are relocated. The labels the items don't define are externals, which
+ * can only be jumped to, called or loaded. Those of `publics` are PUBLIC.
+ */
+pub fn link_relocatable(
+	items: &[Item],
+	publics: &[Label],
+	target: Target,
+) -> Result<RelocatableCode, LinkError> {
+	let items = lower(items, target);
+	let defined: BTreeSet<&Label> = items
+		.iter()
+		.filter_map(|item| match item {
+			| Item::Label(lbl) => Some(lbl),
+			| _ => None,
+		})
+		.collect();
+	let is_external = |lbl: &Label| !defined.contains(lbl);
+
+	// The externals are 0 until they are linked
+	let resolved: Vec<Item> = items
+		.iter()
+		.map(|item| match item {
+			| Item::Jump(cc, lbl) if is_external(lbl) => {
+				Item::Instruction(Instruction::JP(cc.clone(), Operand::Constant(0)))
+			}
+			| Item::Call(cc, lbl) if is_external(lbl) => {
+				Item::Instruction(Instruction::CALL(cc.clone(), Operand::Constant(0)))
+			}
+			| Item::LoadAddress(r, lbl) if is_external(lbl) => Item::Instruction(Instruction::LD(
+				Operand::WordRegister(r.clone()),
+				Operand::Constant(0),
+			)),
+			| item => item.clone(),
+		})
+		.collect();
+	let code = link_for_target(&resolved, 0, target)?;
+
+	// The address is the last word of these items
+	let mut relocations = Vec::new();
+	let mut position: u16 = 0;
+	for item in &items {
+		position = position.wrapping_add(item.size()?);
+		if let Item::Jump(_, lbl) | Item::Call(_, lbl) | Item::LoadAddress(_, lbl) = item {
+			relocations.push(Relocation {
+				segment: Segment::Code,
+				address: position.wrapping_sub(2),
+				reference: match is_external(lbl) {
+					| true => Reference::External(lbl.clone()),
+					| false => Reference::Segment(Segment::Code),
+				},
+			});
+		}
+	}
+
+	let mut linkage = Linkage {
+		relocations,
+		code_size: code.size,
+		..Linkage::default()
+	};
+	for name in publics {
+		match code.labels.get(name) {
+			| Some(address) => linkage.publics.insert(name.clone(), (Segment::Code, *address)),
+			| None => return Err(LinkError::UndefinedLabel(name.clone())),
+		};
+	}
+	let blocks = vec![Block {
+		segment: Segment::Code,
+		address: 0,
+		bytes: encode(&code.instructions, target),
+	}];
+	Ok(RelocatableCode {
+		code,
+		blocks,
+		linkage,
+	})
+}
+
+// The procedures one after the other, with the vectors of the interrupt ones
+fn program_items(
+	procedures: &[Procedure],
+	options: &InterruptOptions,
+) -> Result<(Vec<Item>, VectorTable), InterruptError> {
+	let mut table = VectorTable::new();
+	let mut items = Vec::new();
+	for procedure in procedures {
+		match procedure.interrupt {
+			| Some(vector) => {
+				table.assign(vector as i32, &procedure.name)?;
+				items.extend(interrupt::generate_procedure(
+					&procedure.name,
+					procedure.body.clone(),
+					options,
+				));
+			}
+			| None => {
+				items.push(Item::Label(procedure.name.clone()));
+				items.extend(procedure.body.iter().cloned());
+			}
+		}
+	}
+	Ok((items, table))
+}
+
+/*
+ * Places the procedures one after the other at `origin`. The interrupt
+ * procedures save the registers they use, and get a jump at their
+ * vector, which the code mustn't overwrite.
+ */
+pub fn link_program(
+	procedures: &[Procedure],
+	origin: u16,
+	options: &InterruptOptions,
+) -> Result<LinkedProgram, ProgramError> {
+	let (items, table) = program_items(procedures, options)?;
+	let code = link(&items, origin)?;
+	table.check_overlap(origin, code.size)?;
+	let vectors = table.link(&code.labels)?;
+	Ok(LinkedProgram { code, vectors })
+}
+
+/*
+ * Like link_program, but as a module for a linker, with every procedure
+ * PUBLIC and the items lowered for the target. The jumps at the vectors
+ * are absolute bytes, their target being relocated.
+ */
+pub fn link_module(
+	procedures: &[Procedure],
+	options: &InterruptOptions,
+	target: Target,
+) -> Result<RelocatableCode, ProgramError> {
+	let (items, table) = program_items(procedures, options)?;
+	let names: Vec<Label> = procedures.iter().map(|x| x.name.clone()).collect();
+	let mut module = link_relocatable(&items, &names, target)?;
+	for (vector, jump) in table.link(&module.code.labels)? {
+		module.linkage.relocations.push(Relocation {
+			segment: Segment::Absolute,
+			address: vector.wrapping_add(1),
+			reference: Reference::Segment(Segment::Code),
+		});
+		module.blocks.push(Block {
+			segment: Segment::Absolute,
+			address: vector,
+			bytes: encode(&[jump], target),
+		});
+	}
+	Ok(module)
+}
@@ -0,0 +1,336 @@
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+use z80::instruction::{ByteRegister, Condition, Instruction, Operand, WordRegister};
+
+use crate::codegen::Item;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptimizationLevel {
+	None,
+	// Only removes instructions which have no effect
+	Basic,
+	// Also rewrites instructions, depending on the flags liveness
+	Full,
+}
+
+/*
+ * The bits of the F register, the undocumented ones are ignored
+ */
+pub const FLAG_C: u8 = 0x01;
+pub const FLAG_N: u8 = 0x02;
+pub const FLAG_PV: u8 = 0x04;
+pub const FLAG_H: u8 = 0x10;
+pub const FLAG_Z: u8 = 0x40;
+pub const FLAG_S: u8 = 0x80;
+pub const ALL_FLAGS: u8 = FLAG_C | FLAG_N | FLAG_PV | FLAG_H | FLAG_Z | FLAG_S;
+
+fn condition_flags(cc: &Option<Condition>) -> u8 {
+	match cc {
+		| None => 0,
+		| Some(Condition::Z) | Some(Condition::NZ) => FLAG_Z,
+		| Some(Condition::C) | Some(Condition::NC) => FLAG_C,
+		| Some(Condition::PO) | Some(Condition::PE) => FLAG_PV,
+		| Some(Condition::P) | Some(Condition::M) => FLAG_S,
+	}
+}
+
+fn is_word_register<T, A, C, O>(op: &Operand<T, A, C, O>) -> bool {
+	matches!(op, WordRegister(_))
+}
+
+/*
+ * Returns the flags read and the flags written by an instruction.
+ * Only the documented effects are listed: a flag left undefined is
+ * not considered as written.
+ */
+pub fn flag_effects(inst: &Instruction<u8, u16, i32, i8>) -> (u8, u8) {
+	const SZHPN: u8 = FLAG_S | FLAG_Z | FLAG_H | FLAG_PV | FLAG_N;
+
+	match inst {
+		| LD(ByteRegister(A), I) | LD(ByteRegister(A), R) => (0, SZHPN),
+		| LD(_, _) => (0, 0),
+
+		| PUSH(WordRegister(AF)) => (ALL_FLAGS, 0),
+		| POP(WordRegister(AF)) => (0, ALL_FLAGS),
+		| PUSH(_) | POP(_) => (0, 0),
+		| EX(WordRegister(AF), _) => (ALL_FLAGS, ALL_FLAGS),
+		| EX(_, _) | EXX => (0, 0),
+
+		| LDI | LDIR | LDD | LDDR => (0, FLAG_H | FLAG_PV | FLAG_N),
+		| CPI | CPIR | CPD | CPDR => (0, SZHPN),
+
+		| ADD(WordRegister(_), _) => (0, FLAG_H | FLAG_N | FLAG_C),
+		| ADC(_, _) | SBC(_, _) => (FLAG_C, ALL_FLAGS),
+		| ADD(_, _) | SUB(_) | AND(_) | OR(_) | XOR(_) | CP(_) | NEG => (0, ALL_FLAGS),
+
+		| INC(op) | DEC(op) if is_word_register(op) => (0, 0),
+		| INC(_) | DEC(_) => (0, SZHPN),
+
+		| DAA => (FLAG_C | FLAG_H | FLAG_N, FLAG_S | FLAG_Z | FLAG_H | FLAG_PV | FLAG_C),
+		| CPL => (0, FLAG_H | FLAG_N),
+		| CCF => (FLAG_C, FLAG_H | FLAG_N | FLAG_C),
+		| SCF => (0, FLAG_H | FLAG_N | FLAG_C),
+		| NOP | HALT | DI | EI | IM(_) => (0, 0),
+
+		| RLCA | RRCA => (0, FLAG_H | FLAG_N | FLAG_C),
+		| RLA | RRA => (FLAG_C, FLAG_H | FLAG_N | FLAG_C),
+		| RL(_) | RR(_) => (FLAG_C, ALL_FLAGS),
+		| RLC(_) | RRC(_) | SLA(_) | SLL(_) | SRA(_) | SRL(_) => (0, ALL_FLAGS),
+		| RLD | RRD => (0, SZHPN),
+
+		| BIT(_, _) => (0, SZHPN),
+		| SET(_, _) | RES(_, _) => (0, 0),
+
+		| JP(cc, _) | JR(cc, _) | CALL(cc, _) => (condition_flags(cc), 0),
+		| RET(cc) => (condition_flags(cc), 0),
+		| DJNZ(_) | RETI | RETN | RST(_) => (0, 0),
+
+		| IN(ByteRegister(A), Port(_)) => (0, 0),
+		| IN(_, _) => (0, SZHPN),
+		| INI | INIR | IND | INDR | OUTI | OTIR | OUTD | OTDR => (0, FLAG_Z | FLAG_N),
+		| OUT(_, _) => (0, 0),
+
+		| MLT(_) | OUT0(_, _) | SLP => (0, 0),
+		| TST(_) | TSTIO(_) => (0, ALL_FLAGS),
+		| IN0(_, _) => (0, SZHPN),
+		| OTIM | OTIMR | OTDM | OTDMR => (0, ALL_FLAGS),
+
+		| Binary(_) => (0, 0),
+	}
+}
+
+/*
+ * Checks whether the given flags can be modified without changing the
+ * behaviour of the code that follows. The answer is conservative: any
+ * control transfer keeps the flags alive.
+ */
+pub fn are_flags_dead(following: &[Item], flags: u8) -> bool {
+	let mut pending = flags;
+	for item in following {
+		match item {
+			| Item::Label(_) | Item::LoadAddress(_, _) => {}
+			| Item::Instruction(inst) => {
+				let (read, written) = flag_effects(inst);
+				if read & pending != 0 {
+					return false;
+				}
+				match inst {
+					| JP(_, _) | JR(_, _) | CALL(_, _) | RET(_) | RETI | RETN | RST(_) | DJNZ(_)
+					| HALT | SLP => {
+						return false;
+					}
+					| _ => {}
+				}
+				pending &= !written;
+			}
+			| Item::Jump(_, _)
+			| Item::JumpRelative(_, _)
+			| Item::DecrementJumpNonZero(_)
+			| Item::Call(_, _) => {
+				return false;
+			}
+		}
+
+		if pending == 0 {
+			return true;
+		}
+	}
+	false
+}
+
+// Number of items replaced, and their replacement
+type Rewrite = Option<(usize, Vec<Item>)>;
+
+struct Pattern {
+	level: OptimizationLevel,
+	apply: fn(&[Item]) -> Rewrite,
+}
+
+fn word_register_parts(r: &WordRegister) -> Option<(ByteRegister, ByteRegister)> {
+	match r {
+		| BC => Some((B, C)),
+		| DE => Some((D, E)),
+		| HL => Some((H, L)),
+		| _ => None,
+	}
+}
+
+static PATTERNS: [Pattern; 9] = [
+	// PUSH rr; POP rr
+	Pattern {
+		level: OptimizationLevel::Basic,
+		apply: |items| match items {
+			| [Item::Instruction(PUSH(r)), Item::Instruction(POP(r2)), ..] if r == r2 => {
+				Some((2, vec![]))
+			}
+			| _ => None,
+		},
+	},
+	// LD r,r
+	Pattern {
+		level: OptimizationLevel::Basic,
+		apply: |items| match items {
+			| [Item::Instruction(LD(ByteRegister(r), ByteRegister(r2))), ..] if r == r2 => {
+				Some((1, vec![]))
+			}
+			| _ => None,
+		},
+	},
+	// LD x,y; LD y,x: the second one copies back the same value.
+	// It covers LD A,(x); LD (x),A as well as LD (x),HL; LD HL,(x)
+	Pattern {
+		level: OptimizationLevel::Basic,
+		apply: |items| match items {
+			| [Item::Instruction(LD(dst, src)), Item::Instruction(LD(dst2, src2)), ..]
+				if dst == src2 && src == dst2 && !is_volatile(src) && !changes_address(dst, src) =>
+			{
+				Some((2, vec![items[0].clone()]))
+			}
+			| _ => None,
+		},
+	},
+	// EX DE,HL; EX DE,HL
+	Pattern {
+		level: OptimizationLevel::Basic,
+		apply: |items| match items {
+			| [Item::Instruction(EX(a, b)), Item::Instruction(EX(a2, b2)), ..]
+				if a == a2 && b == b2 && !matches!(a, AddressRegister(_)) =>
+			{
+				Some((2, vec![]))
+			}
+			| _ => None,
+		},
+	},
+	// A jump to the next instruction
+	Pattern {
+		level: OptimizationLevel::Basic,
+		apply: |items| {
+			let target = match items.first() {
+				| Some(Item::Jump(_, lbl)) | Some(Item::JumpRelative(_, lbl)) => lbl,
+				| _ => return None,
+			};
+			for item in items[1..].iter() {
+				match item {
+					| Item::Label(lbl) if lbl == target => return Some((1, vec![])),
+					| Item::Label(_) => {}
+					| _ => return None,
+				}
+			}
+			None
+		},
+	},
+	// LD A,0 -> XOR A
+	Pattern {
+		level: OptimizationLevel::Full,
+		apply: |items| match items {
+			| [Item::Instruction(LD(ByteRegister(A), Constant(0))), following @ ..]
+				if are_flags_dead(following, ALL_FLAGS) =>
+			{
+				Some((1, vec![Item::Instruction(XOR(ByteRegister(A)))]))
+			}
+			| _ => None,
+		},
+	},
+	// ADD A,1 -> INC A, the only difference being the carry
+	Pattern {
+		level: OptimizationLevel::Full,
+		apply: |items| match items {
+			| [Item::Instruction(ADD(ByteRegister(A), Constant(1))), following @ ..]
+				if are_flags_dead(following, FLAG_C) =>
+			{
+				Some((1, vec![Item::Instruction(INC(ByteRegister(A)))]))
+			}
+			| _ => None,
+		},
+	},
+	// SUB 1 -> DEC A
+	Pattern {
+		level: OptimizationLevel::Full,
+		apply: |items| match items {
+			| [Item::Instruction(SUB(Constant(1))), following @ ..] if are_flags_dead(following, FLAG_C) => {
+				Some((1, vec![Item::Instruction(DEC(ByteRegister(A)))]))
+			}
+			| _ => None,
+		},
+	},
+	// PUSH rr; POP rr': same size, but 13 T-states faster as two LD
+	Pattern {
+		level: OptimizationLevel::Full,
+		apply: |items| match items {
+			| [Item::Instruction(PUSH(WordRegister(r))), Item::Instruction(POP(WordRegister(r2))), ..] => {
+				let (high, low) = word_register_parts(r)?;
+				let (high2, low2) = word_register_parts(r2)?;
+				Some((
+					2,
+					vec![
+						Item::Instruction(LD(ByteRegister(high2), ByteRegister(high))),
+						Item::Instruction(LD(ByteRegister(low2), ByteRegister(low))),
+					],
+				))
+			}
+			| _ => None,
+		},
+	},
+];
+
+/*
+ * Whether writing `dst` modifies the address `src` reads from, as in
+ * LD L,(HL)
+ */
+fn changes_address(dst: &Operand<u8, u16, i32, i8>, src: &Operand<u8, u16, i32, i8>) -> bool {
+	match (dst, src) {
+		| (ByteRegister(H), AddressRegister(HL)) | (ByteRegister(L), AddressRegister(HL)) => true,
+		| (WordRegister(r), AddressRegister(r2)) | (WordRegister(r), AddressRegisterWithOffset(r2, _)) => {
+			r == r2
+		}
+		| _ => false,
+	}
+}
+
+/*
+ * Memory mapped I/O could make a read or a write meaningful, but PL/M
+ * only gives access to the ports through INPUT and OUTPUT, so only the
+ * refresh register is volatile.
+ */
+fn is_volatile(op: &Operand<u8, u16, i32, i8>) -> bool {
+	matches!(op, R)
+}
+
+/*
+ * Applies the patterns enabled at `level` until none of them matches.
+ * A pattern only applies to consecutive items, so that a label always
+ * stops it: the code after a label can be reached from elsewhere.
+ */
+pub fn optimize(mut items: Vec<Item>, level: OptimizationLevel) -> Vec<Item> {
+	let patterns: Vec<&Pattern> = PATTERNS.iter().filter(|p| p.level <= level).collect();
+	if patterns.is_empty() {
+		return items;
+	}
+
+	loop {
+		let mut changed = false;
+		let mut output = Vec::with_capacity(items.len());
+		let mut i = 0;
+
+		while i < items.len() {
+			match patterns.iter().find_map(|p| (p.apply)(&items[i..])) {
+				| Some((replaced, replacement)) => {
+					output.extend(replacement);
+					i += replaced;
+					changed = true;
+				}
+				| None => {
+					output.push(items[i].clone());
+					i += 1;
+				}
+			}
+		}
+
+		items = output;
+		if !changed {
+			return items;
+		}
+	}
+}
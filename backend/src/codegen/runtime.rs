@@ -0,0 +1,420 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+use z80::assembler::Target;
+use z80::instruction::{Condition, Instruction};
+
+use crate::ast::{BinaryOperation, Type};
+use crate::codegen::{link_for_target, lower, size_of, Item, Label};
+
+/*
+ * Support routines for the operations the Z80 can't do in a single
+ * instruction. Every routine follows the same convention:
+ *  - 8 bits operands are passed in A and E,
+ *  - 16 bits operands are passed in HL and DE,
+ *  - the result is returned in HL (PL/M's multiplications and
+ *    divisions always produce an ADDRESS),
+ *  - AF, BC and DE are clobbered, unless stated otherwise.
+ * A division by zero yields 0FFH (or 0FFFFH) with the dividend as the
+ * remainder.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Routine {
+	// HL = A * E
+	Multiply8,
+	// HL = HL * DE
+	Multiply16,
+	// C = A / E, A = A MOD E
+	DivideModulo8,
+	// HL = A / E
+	Divide8,
+	// HL = A MOD E
+	Modulo8,
+	// HL = HL / DE, DE = HL MOD DE
+	Divide16,
+	// HL = HL MOD DE
+	Modulo16,
+	// Sets Z if HL = DE and C if HL < DE, only A is clobbered
+	Compare16,
+}
+
+impl Routine {
+	pub const ALL: [Routine; 8] = [
+		Routine::Multiply8,
+		Routine::Multiply16,
+		Routine::DivideModulo8,
+		Routine::Divide8,
+		Routine::Modulo8,
+		Routine::Divide16,
+		Routine::Modulo16,
+		Routine::Compare16,
+	];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			| Routine::Multiply8 => "@MUL8",
+			| Routine::Multiply16 => "@MUL16",
+			| Routine::DivideModulo8 => "@DVM8",
+			| Routine::Divide8 => "@DIV8",
+			| Routine::Modulo8 => "@MOD8",
+			| Routine::Divide16 => "@DIV16",
+			| Routine::Modulo16 => "@MOD16",
+			| Routine::Compare16 => "@CMP16",
+		}
+	}
+
+	/*
+	 * Returns the routine implementing an operation on operands of the
+	 * given type, if the operation can't be done inline.
+	 * 8 bits comparisons are left out, a CP does the job.
+	 */
+	pub fn for_operation(op: &BinaryOperation, operand_type: &Type) -> Option<Routine> {
+		let is_byte = match operand_type {
+			| Type::U8 | Type::I8 => true,
+			| Type::U16 | Type::I16 | Type::Number | Type::Pointer(_) => false,
+			| Type::Reference(t) => return Routine::for_operation(op, t),
+			| Type::Void => return None,
+		};
+
+		match (op, is_byte) {
+			| (BinaryOperation::Multiply, true) => Some(Routine::Multiply8),
+			| (BinaryOperation::Multiply, false) => Some(Routine::Multiply16),
+			| (BinaryOperation::Division, true) => Some(Routine::Divide8),
+			| (BinaryOperation::Division, false) => Some(Routine::Divide16),
+			| (BinaryOperation::Modulo, true) => Some(Routine::Modulo8),
+			| (BinaryOperation::Modulo, false) => Some(Routine::Modulo16),
+
+			| (BinaryOperation::Greater, false)
+			| (BinaryOperation::Less, false)
+			| (BinaryOperation::GreaterOrEqual, false)
+			| (BinaryOperation::LessOrEqual, false)
+			| (BinaryOperation::Equal, false)
+			| (BinaryOperation::NotEqual, false) => Some(Routine::Compare16),
+
+			| _ => None,
+		}
+	}
+
+	pub fn dependencies(&self) -> &'static [Routine] {
+		match self {
+			| Routine::Divide8 | Routine::Modulo8 => &[Routine::DivideModulo8],
+			| Routine::Modulo16 => &[Routine::Divide16],
+			| _ => &[],
+		}
+	}
+
+	pub fn label(&self) -> Label {
+		self.name().to_string()
+	}
+
+	fn local_label(&self, n: usize) -> Label {
+		format!("{}${}", self.name(), n)
+	}
+
+	/*
+	 * The body of the routine, without its entry label.
+	 * Only relative jumps are used within a routine.
+	 */
+	pub fn items(&self) -> Vec<Item> {
+		self.items_for_target(Target::Z80)
+	}
+
+	/*
+	 * The divisions shift with SLA and RL on the Z80, the 8080 uses DAD
+	 * and RAL instead. The relative jumps are lowered when linking.
+	 */
+	pub fn items_for_target(&self, target: Target) -> Vec<Item> {
+		let op = Item::Instruction;
+		let label = |n| Item::Label(self.local_label(n));
+		let jr = |cc, n| Item::JumpRelative(cc, self.local_label(n));
+		let djnz = |n| Item::DecrementJumpNonZero(self.local_label(n));
+		let call = |routine: Routine| Item::Call(None, routine.label());
+
+		match (self, target) {
+			// H is the remainder and L the dividend, both shifted by
+			// ADD HL,HL with the quotient shifted into L. HL is
+			// clobbered, Divide8 and Modulo8 overwrite it anyway.
+			| (Routine::DivideModulo8, Target::I8080) => vec![
+				op(LD(ByteRegister(L), ByteRegister(A))),
+				op(LD(ByteRegister(H), Constant(0))),
+				op(LD(ByteRegister(B), Constant(8))),
+				label(0),
+				op(ADD(WordRegister(HL), WordRegister(HL))),
+				jr(Some(Condition::C), 1),
+				op(LD(ByteRegister(A), ByteRegister(H))),
+				op(CP(ByteRegister(E))),
+				jr(Some(Condition::C), 2),
+				label(1),
+				op(LD(ByteRegister(A), ByteRegister(H))),
+				op(SUB(ByteRegister(E))),
+				op(LD(ByteRegister(H), ByteRegister(A))),
+				op(INC(ByteRegister(L))),
+				label(2),
+				djnz(0),
+				op(LD(ByteRegister(C), ByteRegister(L))),
+				op(LD(ByteRegister(A), ByteRegister(H))),
+				op(RET(None)),
+			],
+
+			// The dividend is shifted out of HL while the quotient is
+			// shifted in, and the remainder is in BC. No register is
+			// left for the count, which stays on the stack.
+			| (Routine::Divide16, Target::I8080) => vec![
+				op(LD(WordRegister(BC), Constant(0))),
+				op(LD(ByteRegister(A), Constant(16))),
+				op(PUSH(WordRegister(AF))),
+				label(0),
+				op(ADD(WordRegister(HL), WordRegister(HL))),
+				op(LD(ByteRegister(A), ByteRegister(C))),
+				op(RLA),
+				op(LD(ByteRegister(C), ByteRegister(A))),
+				op(LD(ByteRegister(A), ByteRegister(B))),
+				op(RLA),
+				op(LD(ByteRegister(B), ByteRegister(A))),
+				jr(Some(Condition::C), 1),
+				op(LD(ByteRegister(A), ByteRegister(C))),
+				op(SUB(ByteRegister(E))),
+				op(LD(ByteRegister(A), ByteRegister(B))),
+				op(SBC(ByteRegister(A), ByteRegister(D))),
+				jr(Some(Condition::C), 2),
+				label(1),
+				op(LD(ByteRegister(A), ByteRegister(C))),
+				op(SUB(ByteRegister(E))),
+				op(LD(ByteRegister(C), ByteRegister(A))),
+				op(LD(ByteRegister(A), ByteRegister(B))),
+				op(SBC(ByteRegister(A), ByteRegister(D))),
+				op(LD(ByteRegister(B), ByteRegister(A))),
+				op(INC(WordRegister(HL))),
+				label(2),
+				op(EX(AddressRegister(SP), WordRegister(HL))),
+				op(DEC(ByteRegister(H))),
+				op(EX(AddressRegister(SP), WordRegister(HL))),
+				jr(Some(Condition::NZ), 0),
+				op(POP(WordRegister(AF))),
+				op(LD(ByteRegister(D), ByteRegister(B))),
+				op(LD(ByteRegister(E), ByteRegister(C))),
+				op(RET(None)),
+			],
+
+			// The Z180 multiplies H by L into HL
+			| (Routine::Multiply8, Target::Z180) => vec![
+				op(LD(ByteRegister(H), ByteRegister(A))),
+				op(LD(ByteRegister(L), ByteRegister(E))),
+				op(MLT(WordRegister(HL))),
+				op(RET(None)),
+			],
+			// Shift and add, one bit of A at a time
+			| (Routine::Multiply8, _) => vec![
+				op(LD(ByteRegister(D), Constant(0))),
+				op(LD(WordRegister(HL), Constant(0))),
+				op(LD(ByteRegister(B), Constant(8))),
+				label(0),
+				op(ADD(WordRegister(HL), WordRegister(HL))),
+				op(RLA),
+				jr(Some(Condition::NC), 1),
+				op(ADD(WordRegister(HL), WordRegister(DE))),
+				label(1),
+				djnz(0),
+				op(RET(None)),
+			],
+			| (Routine::Multiply16, _) => vec![
+				op(LD(ByteRegister(B), ByteRegister(H))),
+				op(LD(ByteRegister(C), ByteRegister(L))),
+				op(LD(WordRegister(HL), Constant(0))),
+				op(LD(ByteRegister(A), Constant(16))),
+				label(0),
+				op(ADD(WordRegister(HL), WordRegister(HL))),
+				op(EX(WordRegister(DE), WordRegister(HL))),
+				op(ADD(WordRegister(HL), WordRegister(HL))),
+				op(EX(WordRegister(DE), WordRegister(HL))),
+				jr(Some(Condition::NC), 1),
+				op(ADD(WordRegister(HL), WordRegister(BC))),
+				label(1),
+				op(DEC(ByteRegister(A))),
+				jr(Some(Condition::NZ), 0),
+				op(RET(None)),
+			],
+
+			// Restoring division, the remainder is kept in A.
+			// The carry out of RLA means the partial remainder
+			// exceeds 8 bits, so it is necessarily greater than E.
+			| (Routine::DivideModulo8, _) => vec![
+				op(LD(ByteRegister(C), ByteRegister(A))),
+				op(XOR(ByteRegister(A))),
+				op(LD(ByteRegister(B), Constant(8))),
+				label(0),
+				op(SLA(ByteRegister(C))),
+				op(RLA),
+				jr(Some(Condition::C), 1),
+				op(CP(ByteRegister(E))),
+				jr(Some(Condition::C), 2),
+				label(1),
+				op(SUB(ByteRegister(E))),
+				op(INC(ByteRegister(C))),
+				label(2),
+				djnz(0),
+				op(RET(None)),
+			],
+			| (Routine::Divide8, _) => vec![
+				call(Routine::DivideModulo8),
+				op(LD(ByteRegister(L), ByteRegister(C))),
+				op(LD(ByteRegister(H), Constant(0))),
+				op(RET(None)),
+			],
+			| (Routine::Modulo8, _) => vec![
+				call(Routine::DivideModulo8),
+				op(LD(ByteRegister(L), ByteRegister(A))),
+				op(LD(ByteRegister(H), Constant(0))),
+				op(RET(None)),
+			],
+
+			// Same algorithm, the dividend is shifted out of BC while
+			// the quotient is shifted in, and the remainder is in HL
+			| (Routine::Divide16, _) => vec![
+				op(LD(ByteRegister(B), ByteRegister(H))),
+				op(LD(ByteRegister(C), ByteRegister(L))),
+				op(LD(WordRegister(HL), Constant(0))),
+				op(LD(ByteRegister(A), Constant(16))),
+				label(0),
+				op(SLA(ByteRegister(C))),
+				op(RL(ByteRegister(B))),
+				op(ADC(WordRegister(HL), WordRegister(HL))),
+				jr(Some(Condition::C), 1),
+				op(OR(ByteRegister(A))),
+				op(SBC(WordRegister(HL), WordRegister(DE))),
+				jr(Some(Condition::NC), 2),
+				op(ADD(WordRegister(HL), WordRegister(DE))),
+				jr(None, 3),
+				label(1),
+				op(OR(ByteRegister(A))),
+				op(SBC(WordRegister(HL), WordRegister(DE))),
+				label(2),
+				op(INC(ByteRegister(C))),
+				label(3),
+				op(DEC(ByteRegister(A))),
+				jr(Some(Condition::NZ), 0),
+				op(EX(WordRegister(DE), WordRegister(HL))),
+				op(LD(ByteRegister(H), ByteRegister(B))),
+				op(LD(ByteRegister(L), ByteRegister(C))),
+				op(RET(None)),
+			],
+			| (Routine::Modulo16, _) => vec![
+				call(Routine::Divide16),
+				op(EX(WordRegister(DE), WordRegister(HL))),
+				op(RET(None)),
+			],
+
+			| (Routine::Compare16, _) => vec![
+				op(LD(ByteRegister(A), ByteRegister(H))),
+				op(CP(ByteRegister(D))),
+				op(RET(Some(Condition::NZ))),
+				op(LD(ByteRegister(A), ByteRegister(L))),
+				op(CP(ByteRegister(E))),
+				op(RET(None)),
+			],
+		}
+	}
+
+	pub fn size(&self) -> u16 {
+		self.size_for_target(Target::Z80)
+	}
+
+	pub fn size_for_target(&self, target: Target) -> u16 {
+		let items = lower(&self.items_for_target(target), target);
+		size_of(&items).expect("The runtime library only contains valid instructions")
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkedRuntime {
+	pub instructions: Vec<Instruction<u8, u16, i32, i8>>,
+	pub addresses: BTreeMap<Routine, u16>,
+	pub size: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeLibrary {
+	referenced: BTreeSet<Routine>,
+	target: Target,
+}
+
+impl RuntimeLibrary {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn for_target(target: Target) -> Self {
+		Self {
+			referenced: BTreeSet::new(),
+			target,
+		}
+	}
+
+	pub fn reference(&mut self, routine: Routine) {
+		if self.referenced.insert(routine) {
+			for dep in routine.dependencies() {
+				self.reference(*dep);
+			}
+		}
+	}
+
+	/*
+	 * References the routine needed by an operation, and returns it
+	 * so that the caller can emit the CALL
+	 */
+	pub fn reference_operation(
+		&mut self,
+		op: &BinaryOperation,
+		operand_type: &Type,
+	) -> Option<Routine> {
+		let routine = Routine::for_operation(op, operand_type);
+		if let Some(routine) = routine {
+			self.reference(routine);
+		}
+		routine
+	}
+
+	pub fn is_referenced(&self, routine: Routine) -> bool {
+		self.referenced.contains(&routine)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.referenced.is_empty()
+	}
+
+	pub fn routines(&self) -> impl Iterator<Item = &Routine> {
+		self.referenced.iter()
+	}
+
+	/*
+	 * Every referenced routine, each one preceded by its entry label.
+	 * Unreferenced routines are not emitted.
+	 */
+	pub fn items(&self) -> Vec<Item> {
+		let mut output = Vec::new();
+		for routine in self.referenced.iter() {
+			output.push(Item::Label(routine.label()));
+			output.extend(routine.items_for_target(self.target));
+		}
+		output
+	}
+
+	pub fn link(&self, origin: u16) -> LinkedRuntime {
+		let code = link_for_target(&self.items(), origin, self.target)
+			.expect("The runtime library is self-contained");
+		let addresses = self
+			.referenced
+			.iter()
+			.map(|routine| (*routine, code.labels[&routine.label()]))
+			.collect();
+
+		LinkedRuntime {
+			instructions: code.instructions,
+			addresses,
+			size: code.size,
+		}
+	}
+}
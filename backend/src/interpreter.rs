@@ -0,0 +1,890 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::optimization::constant_folding::{
+	fold_binary_operation, fold_unary_operation, wrap, FALSE, TRUE,
+};
+use crate::typing::TypeCheckable;
+
+pub const MEMORY_SIZE: usize = 0x10000;
+
+// Where the variables are allocated, unless placed explicitly
+pub const DEFAULT_ORIGIN: u16 = 0x0100;
+
+/*
+ * The 64 KiB of the target, byte-addressed. The words are little-endian
+ * and every access wraps around at the end of the memory.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Memory {
+	bytes: Vec<u8>,
+}
+
+impl Default for Memory {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Memory {
+	pub fn new() -> Self {
+		Self {
+			bytes: vec![0; MEMORY_SIZE],
+		}
+	}
+
+	pub fn read_byte(&self, address: u16) -> u8 {
+		self.bytes[address as usize]
+	}
+
+	pub fn write_byte(&mut self, address: u16, value: u8) {
+		self.bytes[address as usize] = value;
+	}
+
+	pub fn read_word(&self, address: u16) -> u16 {
+		let low = self.read_byte(address) as u16;
+		let high = self.read_byte(address.wrapping_add(1)) as u16;
+		(high << 8) | low
+	}
+
+	pub fn write_word(&mut self, address: u16, value: u16) {
+		self.write_byte(address, value as u8);
+		self.write_byte(address.wrapping_add(1), (value >> 8) as u8);
+	}
+
+	pub fn load(&mut self, address: u16, data: &[u8]) {
+		for (i, byte) in data.iter().enumerate() {
+			self.write_byte(address.wrapping_add(i as u16), *byte);
+		}
+	}
+
+	pub fn read(&self, address: u16, t: &Type) -> i32 {
+		match size_of(t) {
+			| 1 => wrap(self.read_byte(address) as i64, t),
+			| _ => wrap(self.read_word(address) as i64, t),
+		}
+	}
+
+	pub fn write(&mut self, address: u16, value: i32, t: &Type) {
+		match size_of(t) {
+			| 1 => self.write_byte(address, value as u8),
+			| _ => self.write_word(address, value as u16),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterpreterError<VariableType> {
+	UndefinedProcedure(String),
+	UndefinedLabel(String),
+	UntypedVariable(VariableType),
+	WrongArgumentCount(String),
+	// A typed procedure ended without RETURN, or an untyped one was
+	// used in an expression
+	NoReturnValue(String),
+	// A GO TO an address without any routine defined there
+	UnhandledJump(u16),
+	DivisionByZero,
+	// The PHI only exist in the SSA form
+	UnsupportedExpression(String),
+	StepLimitReached,
+}
+
+impl<VariableType: fmt::Debug> fmt::Display for InterpreterError<VariableType> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| InterpreterError::UndefinedProcedure(name) => {
+				write!(f, "The procedure {} is not defined", name)
+			}
+			| InterpreterError::UndefinedLabel(name) => {
+				write!(f, "The label {} is not defined", name)
+			}
+			| InterpreterError::UntypedVariable(var) => {
+				write!(f, "The type of the variable {:?} is unknown", var)
+			}
+			| InterpreterError::WrongArgumentCount(name) => {
+				write!(f, "Wrong number of arguments given to {}", name)
+			}
+			| InterpreterError::NoReturnValue(name) => {
+				write!(f, "The procedure {} didn't return any value", name)
+			}
+			| InterpreterError::UnhandledJump(address) => {
+				write!(f, "Jump to {:04X}H, where no routine is defined", address)
+			}
+			| InterpreterError::DivisionByZero => write!(f, "Division by zero"),
+			| InterpreterError::UnsupportedExpression(e) => {
+				write!(f, "The expression {} can't be interpreted", e)
+			}
+			| InterpreterError::StepLimitReached => write!(f, "The step limit has been reached"),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+	// The end of the main program, or a RETURN from it
+	Finished,
+	Halted,
+}
+
+// The flags of the last arithmetic operation, read by CARRY, ZERO, ...
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Flags {
+	carry: bool,
+	zero: bool,
+	sign: bool,
+	parity: bool,
+}
+
+enum Flow {
+	Next,
+	Return(Option<Constant>),
+	Goto(String),
+}
+
+// A HALT stops the interpreter from anywhere, like an error does
+enum Abort<VariableType> {
+	Halt,
+	Error(InterpreterError<VariableType>),
+}
+
+impl<VariableType> From<InterpreterError<VariableType>> for Abort<VariableType> {
+	fn from(e: InterpreterError<VariableType>) -> Self {
+		Abort::Error(e)
+	}
+}
+
+type Result<T, VariableType> = std::result::Result<T, Abort<VariableType>>;
+
+struct Procedure<VariableType> {
+	return_type: Type,
+	parameters: Vec<VariableType>,
+	body: Vec<Statement<VariableType>>,
+}
+
+type ExternalProcedure<'a> = Box<dyn FnMut(&[i32], &mut Memory) -> Option<i32> + 'a>;
+type Routine<'a> = Box<dyn FnMut(&mut Memory) + 'a>;
+
+fn size_of(t: &Type) -> u16 {
+	match t {
+		| Type::U8 | Type::I8 => 1,
+		| _ => 2,
+	}
+}
+
+/*
+ * The type of the value stored in a variable: the declarations give
+ * the variables a pointer to their storage.
+ */
+fn value_type(t: &Type) -> Option<Type> {
+	match t {
+		| Type::Pointer(inner) | Type::Reference(inner) => match inner.as_ref() {
+			| Type::Pointer(_) | Type::Reference(_) => Some(Type::U16),
+			| Type::Void => None,
+			| Type::Number => Some(Type::U16),
+			| t => Some(t.clone()),
+		},
+		| Type::Void => None,
+		| Type::Number => Some(Type::U16),
+		| t => Some(t.clone()),
+	}
+}
+
+// A constant without an explicit type is a BYTE if it fits in 8 bits
+fn concrete_type(value: i32, t: &Type) -> Type {
+	match t {
+		| Type::Number if (0..=0xFF).contains(&value) => Type::U8,
+		| Type::U8 | Type::I8 | Type::U16 | Type::I16 => t.clone(),
+		| _ => Type::U16,
+	}
+}
+
+fn as_value(c: &Constant) -> (i32, Type) {
+	match c {
+		| Constant::Value(value, t) => (*value, concrete_type(*value, t)),
+		| Constant::Array(_, _) | Constant::ReadOnlyArray(_, _) => (0, Type::U16),
+	}
+}
+
+fn boolean(value: bool) -> Constant {
+	Constant::Value(if value { TRUE } else { FALSE }, Type::U8)
+}
+
+/*
+ * Executes the backend IR on the host, over a model of the memory of
+ * the target. The variables get an address the first time they are
+ * used, and the arithmetic wraps around the way the Z80 code does.
+ *
+ * OUTPUT(port) = value is the call OUTPUT(port, value), and the
+ * procedures which aren't defined in the program can be provided by the
+ * host, like the routines reached by a GO TO to an address.
+ */
+pub struct Interpreter<'a, VariableType, Environment> {
+	env: &'a Environment,
+	memory: Memory,
+	addresses: BTreeMap<VariableType, u16>,
+	array_lengths: BTreeMap<VariableType, usize>,
+	// The arrays written in the expressions, like .(CR, LF)
+	constants: BTreeMap<(Vec<i32>, u16), u16>,
+	next_address: u16,
+	procedures: BTreeMap<String, Rc<Procedure<VariableType>>>,
+	externals: BTreeMap<String, ExternalProcedure<'a>>,
+	routines: BTreeMap<u16, Routine<'a>>,
+	input: Box<dyn FnMut(u8) -> u8 + 'a>,
+	output: Box<dyn FnMut(u8, u8) + 'a>,
+	flags: Flags,
+	interrupts_enabled: bool,
+	steps: usize,
+	step_limit: Option<usize>,
+}
+
+impl<'a, VariableType, Environment> Interpreter<'a, VariableType, Environment>
+where
+	VariableType: Clone + Ord + fmt::Debug + TypeCheckable<Environment>,
+{
+	pub fn new(env: &'a Environment) -> Self {
+		Self {
+			env,
+			memory: Memory::new(),
+			addresses: BTreeMap::new(),
+			array_lengths: BTreeMap::new(),
+			constants: BTreeMap::new(),
+			next_address: DEFAULT_ORIGIN,
+			procedures: BTreeMap::new(),
+			externals: BTreeMap::new(),
+			routines: BTreeMap::new(),
+			// Nothing connected to the ports
+			input: Box::new(|_| 0xFF),
+			output: Box::new(|_, _| {}),
+			flags: Flags::default(),
+			interrupts_enabled: true,
+			steps: 0,
+			step_limit: None,
+		}
+	}
+
+	pub fn memory(&self) -> &Memory {
+		&self.memory
+	}
+
+	pub fn memory_mut(&mut self) -> &mut Memory {
+		&mut self.memory
+	}
+
+	pub fn interrupts_enabled(&self) -> bool {
+		self.interrupts_enabled
+	}
+
+	pub fn steps(&self) -> usize {
+		self.steps
+	}
+
+	// Stops the programs which never end, counted in statements
+	pub fn set_step_limit(&mut self, limit: usize) {
+		self.step_limit = Some(limit);
+	}
+
+	// Where the next variables are allocated
+	pub fn set_origin(&mut self, address: u16) {
+		self.next_address = address;
+	}
+
+	// Reserves the storage of the declared arrays, used by LENGTH and LAST
+	pub fn set_array_length(&mut self, var: VariableType, length: usize) {
+		self.array_lengths.insert(var, length);
+	}
+
+	// Gives a fixed address to a variable, as AT does
+	pub fn place(&mut self, var: VariableType, address: u16) {
+		self.addresses.insert(var, address);
+	}
+
+	pub fn address_of(&self, var: &VariableType) -> Option<u16> {
+		self.addresses.get(var).copied()
+	}
+
+	pub fn set_input_handler(&mut self, handler: impl FnMut(u8) -> u8 + 'a) {
+		self.input = Box::new(handler);
+	}
+
+	pub fn set_output_handler(&mut self, handler: impl FnMut(u8, u8) + 'a) {
+		self.output = Box::new(handler);
+	}
+
+	/*
+	 * A procedure called by the program without being defined in it.
+	 * It gets the value of the arguments and returns the value of the
+	 * call, if any.
+	 */
+	pub fn define_external(
+		&mut self,
+		name: &str,
+		procedure: impl FnMut(&[i32], &mut Memory) -> Option<i32> + 'a,
+	) {
+		self.externals.insert(name.to_string(), Box::new(procedure));
+	}
+
+	/*
+	 * The machine code reached by a GO TO to an address. Once it is
+	 * done, the current procedure returns, as the routine ends with a
+	 * RET.
+	 */
+	pub fn define_routine(&mut self, address: u16, routine: impl FnMut(&mut Memory) + 'a) {
+		self.routines.insert(address, Box::new(routine));
+	}
+
+	// Makes the procedures of the program callable
+	pub fn declare(&mut self, program: &[Statement<VariableType>]) {
+		for stmt in program {
+			match stmt {
+				| Statement::FunctionDefinition(name, t, parameters, _, body) => {
+					self.procedures.insert(
+						name.clone(),
+						Rc::new(Procedure {
+							return_type: t.clone(),
+							parameters: parameters.iter().map(|p| p.name().clone()).collect(),
+							body: body.clone(),
+						}),
+					);
+					self.declare(body);
+				}
+				| Statement::IfElse(_, if_blk, else_blk) => {
+					self.declare(if_blk);
+					self.declare(else_blk);
+				}
+				| Statement::Block(blk) | Statement::Loop(blk) => self.declare(blk),
+				| Statement::Switch(_, cases) => {
+					for case in cases.iter().flatten() {
+						self.declare(std::slice::from_ref(case));
+					}
+				}
+				| _ => {}
+			}
+		}
+	}
+
+	pub fn run(
+		&mut self,
+		program: &[Statement<VariableType>],
+	) -> std::result::Result<Exit, InterpreterError<VariableType>> {
+		self.declare(program);
+		match self.execute_block(program) {
+			| Ok(Flow::Next) | Ok(Flow::Return(_)) => Ok(Exit::Finished),
+			| Ok(Flow::Goto(label)) => Err(InterpreterError::UndefinedLabel(label)),
+			| Err(Abort::Halt) => Ok(Exit::Halted),
+			| Err(Abort::Error(e)) => Err(e),
+		}
+	}
+
+	// Calls a declared procedure, or a built-in
+	pub fn call(
+		&mut self,
+		name: &str,
+		args: &[i32],
+	) -> std::result::Result<Option<i32>, InterpreterError<VariableType>> {
+		let args: Vec<Expression<VariableType>> = args
+			.iter()
+			.map(|value| Expression::Constant(Constant::Value(*value, Type::Number)))
+			.collect();
+		match self.call_procedure(name, &args) {
+			| Ok(value) => Ok(value.map(|c| as_value(&c).0)),
+			| Err(Abort::Halt) => Ok(None),
+			| Err(Abort::Error(e)) => Err(e),
+		}
+	}
+
+	pub fn read_variable(&self, var: &VariableType) -> Option<i32> {
+		let address = self.address_of(var)?;
+		let t = value_type(&var.get_type(self.env)?)?;
+		Some(self.memory.read(address, &t))
+	}
+
+	fn storage_type(&self, var: &VariableType) -> Result<Type, VariableType> {
+		var.get_type(self.env)
+			.and_then(|t| value_type(&t))
+			.ok_or_else(|| InterpreterError::UntypedVariable(var.clone()).into())
+	}
+
+	fn allocate(&mut self, size: u16) -> u16 {
+		let address = self.next_address;
+		self.next_address = self.next_address.wrapping_add(size);
+		address
+	}
+
+	fn address(&mut self, var: &VariableType) -> Result<u16, VariableType> {
+		if let Some(address) = self.addresses.get(var) {
+			return Ok(*address);
+		}
+
+		let size = size_of(&self.storage_type(var)?);
+		let length = self.array_lengths.get(var).copied().unwrap_or(1) as u16;
+		let address = self.allocate(size.wrapping_mul(length));
+		self.addresses.insert(var.clone(), address);
+		Ok(address)
+	}
+
+	fn store(&mut self, var: &VariableType, value: &Constant) -> Result<(), VariableType> {
+		let t = self.storage_type(var)?;
+		let address = self.address(var)?;
+		self.memory.write(address, as_value(value).0, &t);
+		Ok(())
+	}
+
+	fn write_array(&mut self, address: u16, values: &[i32], t: &Type) {
+		let size = size_of(t);
+		for (i, value) in values.iter().enumerate() {
+			let element = address.wrapping_add((i as u16).wrapping_mul(size));
+			self.memory.write(element, *value, t);
+		}
+	}
+
+	fn element_type(t: &Type, values: &[i32]) -> Type {
+		match t {
+			| Type::Number if values.iter().all(|v| (-0x80..=0xFF).contains(v)) => Type::U8,
+			| t => concrete_type(0x100, t),
+		}
+	}
+
+	fn constant_array(&mut self, values: &[i32], t: &Type) -> Constant {
+		let t = Self::element_type(t, values);
+		let key = (values.to_vec(), size_of(&t));
+		let address = match self.constants.get(&key) {
+			| Some(address) => *address,
+			| None => {
+				let address = self.allocate(size_of(&t).wrapping_mul(values.len() as u16));
+				self.write_array(address, values, &t);
+				self.constants.insert(key, address);
+				address
+			}
+		};
+		Constant::Value(address as i32, Type::Pointer(Box::new(t)))
+	}
+
+	fn assign(
+		&mut self,
+		var: &VariableType,
+		e: &Expression<VariableType>,
+	) -> Result<(), VariableType> {
+		match e {
+			// The initial value of an array
+			| Expression::Constant(Constant::Array(values, t))
+			| Expression::Constant(Constant::ReadOnlyArray(values, t)) => {
+				if !self.addresses.contains_key(var) && !self.array_lengths.contains_key(var) {
+					self.array_lengths.insert(var.clone(), values.len());
+				}
+				let t = match t {
+					| Type::Number => self.storage_type(var)?,
+					| t => concrete_type(0x100, t),
+				};
+				let address = self.address(var)?;
+				self.write_array(address, values, &t);
+				Ok(())
+			}
+			| e => {
+				let value = self.evaluate(e)?;
+				self.store(var, &value)
+			}
+		}
+	}
+
+	fn execute_block(&mut self, stmts: &[Statement<VariableType>]) -> Result<Flow, VariableType> {
+		let mut pc = 0;
+		while pc < stmts.len() {
+			match self.execute(&stmts[pc])? {
+				| Flow::Next => pc += 1,
+				| Flow::Goto(label) => {
+					let target = stmts
+						.iter()
+						.position(|stmt| matches!(stmt, Statement::Label(l) if *l == label));
+					match target {
+						| Some(target) => pc = target + 1,
+						// Maybe in an enclosing block
+						| None => return Ok(Flow::Goto(label)),
+					}
+				}
+				| flow => return Ok(flow),
+			}
+		}
+		Ok(Flow::Next)
+	}
+
+	fn step(&mut self) -> Result<(), VariableType> {
+		self.steps += 1;
+		match self.step_limit {
+			| Some(limit) if self.steps > limit => Err(InterpreterError::StepLimitReached.into()),
+			| _ => Ok(()),
+		}
+	}
+
+	fn execute(&mut self, stmt: &Statement<VariableType>) -> Result<Flow, VariableType> {
+		self.step()?;
+		match stmt {
+			| Statement::IfElse(cond, if_blk, else_blk) => {
+				let (cond, _) = as_value(&self.evaluate(cond)?);
+				if cond & 1 != 0 {
+					self.execute_block(if_blk)
+				} else {
+					self.execute_block(else_blk)
+				}
+			}
+			| Statement::Block(blk) => self.execute_block(blk),
+			| Statement::Loop(blk) => loop {
+				self.step()?;
+				match self.execute_block(blk)? {
+					| Flow::Next => {}
+					| flow => return Ok(flow),
+				}
+			},
+			| Statement::Switch(e, cases) => {
+				let (selector, t) = as_value(&self.evaluate(e)?);
+				let selector = wrap(selector as i64, &t) as u16 as usize;
+				match cases.get(selector) {
+					| Some(Some(case)) => self.execute(case),
+					// Out of range, nothing is executed
+					| _ => Ok(Flow::Next),
+				}
+			}
+			| Statement::Return(None) => Ok(Flow::Return(None)),
+			| Statement::Return(Some(e)) => Ok(Flow::Return(Some(self.evaluate(e)?))),
+			| Statement::Expression(Expression::FunctionCall(name, args)) => {
+				self.call_procedure(name, args)?;
+				Ok(Flow::Next)
+			}
+			| Statement::Expression(e) => {
+				self.evaluate(e)?;
+				Ok(Flow::Next)
+			}
+			| Statement::FunctionDefinition(_, _, _, _, _) => Ok(Flow::Next),
+			| Statement::Assignment(var, e) => {
+				self.assign(var, e)?;
+				Ok(Flow::Next)
+			}
+			| Statement::Label(_) => Ok(Flow::Next),
+			| Statement::Goto(label) => Ok(Flow::Goto(label.clone())),
+			| Statement::Jump(e) => {
+				let address = as_value(&self.evaluate(e)?).0 as u16;
+				match self.routines.get_mut(&address) {
+					| Some(routine) => {
+						routine(&mut self.memory);
+						Ok(Flow::Return(None))
+					}
+					| None => Err(InterpreterError::UnhandledJump(address).into()),
+				}
+			}
+			| Statement::DisableInterrupt => {
+				self.interrupts_enabled = false;
+				Ok(Flow::Next)
+			}
+			| Statement::EnableInterrupt => {
+				self.interrupts_enabled = true;
+				Ok(Flow::Next)
+			}
+			| Statement::Halt => Err(Abort::Halt),
+			| Statement::NoOperation => Ok(Flow::Next),
+		}
+	}
+
+	// The type read through a pointer, a BYTE if it isn't known
+	fn pointed_type(&self, e: &Expression<VariableType>) -> Type {
+		match e.get_type(self.env) {
+			| Some(Type::Pointer(t)) | Some(Type::Reference(t)) => match *t {
+				| Type::U16 | Type::I16 | Type::I8 => *t,
+				| Type::Pointer(_) | Type::Reference(_) => Type::U16,
+				| _ => Type::U8,
+			},
+			| _ => Type::U8,
+		}
+	}
+
+	fn evaluate(&mut self, e: &Expression<VariableType>) -> Result<Constant, VariableType> {
+		match e {
+			| Expression::Constant(Constant::Value(value, t)) => {
+				Ok(Constant::Value(*value, t.clone()))
+			}
+			| Expression::Constant(Constant::Array(values, t))
+			| Expression::Constant(Constant::ReadOnlyArray(values, t)) => Ok(self.constant_array(values, t)),
+			| Expression::Variable(var) => {
+				let t = self.storage_type(var)?;
+				let address = self.address(var)?;
+				Ok(Constant::Value(self.memory.read(address, &t), t))
+			}
+			| Expression::UnaryOp(UnaryOperation::Reference, inner) => match inner.as_ref() {
+				| Expression::Variable(var) => {
+					let t = self.storage_type(var)?;
+					let address = self.address(var)?;
+					Ok(Constant::Value(address as i32, Type::Pointer(Box::new(t))))
+				}
+				| Expression::Constant(Constant::Array(values, t))
+				| Expression::Constant(Constant::ReadOnlyArray(values, t)) => {
+					Ok(self.constant_array(values, t))
+				}
+				| Expression::UnaryOp(UnaryOperation::Dereference, address) => {
+					self.evaluate(address)
+				}
+				| e => Err(InterpreterError::UnsupportedExpression(format!("{:?}", e)).into()),
+			},
+			| Expression::UnaryOp(UnaryOperation::Dereference, address) => {
+				let t = self.pointed_type(address);
+				let (address, _) = as_value(&self.evaluate(address)?);
+				Ok(Constant::Value(self.memory.read(address as u16, &t), t))
+			}
+			| Expression::UnaryOp(op, inner) => {
+				let value = self.evaluate(inner)?;
+				match fold_unary_operation(op, &value) {
+					| Some(c) => Ok(c),
+					| None => {
+						Err(InterpreterError::UnsupportedExpression(format!("{:?}", e)).into())
+					}
+				}
+			}
+			| Expression::BinaryOp(op, lhs, rhs) => {
+				let lhs = self.evaluate(lhs)?;
+				let rhs = self.evaluate(rhs)?;
+				self.binary_operation(op, &lhs, &rhs)
+			}
+			| Expression::FunctionCall(name, args) => match self.call_procedure(name, args)? {
+				| Some(value) => Ok(value),
+				| None => Err(InterpreterError::NoReturnValue(name.clone()).into()),
+			},
+			| Expression::Phi(_, _) => {
+				Err(InterpreterError::UnsupportedExpression(format!("{:?}", e)).into())
+			}
+		}
+	}
+
+	fn set_flags(&mut self, result: i64, bits: u32, carry: bool) {
+		let result = result & ((1i64 << bits) - 1);
+		self.flags = Flags {
+			carry,
+			zero: result == 0,
+			sign: result >> (bits - 1) != 0,
+			parity: result.count_ones().is_multiple_of(2),
+		};
+	}
+
+	fn binary_operation(
+		&mut self,
+		op: &BinaryOperation,
+		lhs: &Constant,
+		rhs: &Constant,
+	) -> Result<Constant, VariableType> {
+		let (a, ta) = as_value(lhs);
+		let (b, tb) = as_value(rhs);
+		let is_shift = matches!(
+			op,
+			BinaryOperation::ShiftLeft
+				| BinaryOperation::ShiftLeftWithCarry
+				| BinaryOperation::ShiftRight
+				| BinaryOperation::ShiftRightWithCarry
+				| BinaryOperation::RotateLeft
+				| BinaryOperation::RotateLeftWithCarry
+				| BinaryOperation::RotateRight
+				| BinaryOperation::RotateRightWithCarry
+		);
+		let t = if is_shift {
+			ta.clone()
+		} else {
+			ta.clone().max(tb)
+		};
+		let bits = size_of(&t) as u32 * 8;
+		let mask = (1i64 << bits) - 1;
+		let (ua, ub) = (a as i64 & mask, b as i64 & mask);
+		let carry_in = self.flags.carry as i64;
+
+		// The operations using the carry of the previous one
+		let result = match op {
+			| BinaryOperation::AddWithCarry => Some(ua + ub + carry_in),
+			| BinaryOperation::SubstractWithCarry => Some(ua - ub - carry_in),
+			| BinaryOperation::ShiftLeftWithCarry | BinaryOperation::RotateLeftWithCarry => {
+				let (mut value, mut carry) = (ua, carry_in);
+				for _ in 0..b.rem_euclid(bits as i32 + 1) {
+					let out = value >> (bits - 1);
+					value = ((value << 1) | carry) & mask;
+					carry = out;
+				}
+				self.set_flags(value, bits, carry != 0);
+				return Ok(Constant::Value(wrap(value, &t), t));
+			}
+			| BinaryOperation::ShiftRightWithCarry | BinaryOperation::RotateRightWithCarry => {
+				let (mut value, mut carry) = (ua, carry_in);
+				for _ in 0..b.rem_euclid(bits as i32 + 1) {
+					let out = value & 1;
+					value = (value >> 1) | (carry << (bits - 1));
+					carry = out;
+				}
+				self.set_flags(value, bits, carry != 0);
+				return Ok(Constant::Value(wrap(value, &t), t));
+			}
+			| _ => None,
+		};
+		if let Some(result) = result {
+			self.set_flags(result, bits, result < 0 || result > mask);
+			return Ok(Constant::Value(wrap(result, &t), t));
+		}
+
+		let value = match fold_binary_operation(op, lhs, rhs) {
+			| Some(value) => value,
+			| None => {
+				return Err(match op {
+					| BinaryOperation::Division | BinaryOperation::Modulo => {
+						InterpreterError::DivisionByZero
+					}
+					| op => InterpreterError::UnsupportedExpression(op.to_string()),
+				}
+				.into())
+			}
+		};
+
+		let (result, _) = as_value(&value);
+		let shifted_out = |n: i64| n >= 0 && n < bits as i64 && (ua >> n) & 1 != 0;
+		let (flags_of, carry) = match op {
+			| BinaryOperation::Add => (ua + ub, ua + ub > mask),
+			| BinaryOperation::Substract
+			| BinaryOperation::Greater
+			| BinaryOperation::Less
+			| BinaryOperation::GreaterOrEqual
+			| BinaryOperation::LessOrEqual
+			| BinaryOperation::Equal
+			| BinaryOperation::NotEqual => (ua - ub, ua < ub),
+			| BinaryOperation::ShiftLeft => {
+				(result as i64, ub > 0 && shifted_out(bits as i64 - ub))
+			}
+			| BinaryOperation::ShiftRight => (result as i64, ub > 0 && shifted_out(ub - 1)),
+			| BinaryOperation::RotateLeft => (result as i64, result & 1 != 0),
+			| BinaryOperation::RotateRight => {
+				(result as i64, (result as i64 >> (bits - 1)) & 1 != 0)
+			}
+			| _ => (result as i64, false),
+		};
+		self.set_flags(flags_of, bits, carry);
+		Ok(value)
+	}
+
+	fn argument_values(
+		&mut self,
+		args: &[Expression<VariableType>],
+	) -> Result<Vec<Constant>, VariableType> {
+		args.iter().map(|e| self.evaluate(e)).collect()
+	}
+
+	fn array_length(&self, e: &Expression<VariableType>) -> usize {
+		match e {
+			| Expression::Variable(var) => self.array_lengths.get(var).copied().unwrap_or(1),
+			| Expression::Constant(Constant::Array(values, _))
+			| Expression::Constant(Constant::ReadOnlyArray(values, _)) => values.len(),
+			| _ => 1,
+		}
+	}
+
+	fn call_procedure(
+		&mut self,
+		name: &str,
+		args: &[Expression<VariableType>],
+	) -> Result<Option<Constant>, VariableType> {
+		let wrong_count = || Abort::Error(InterpreterError::WrongArgumentCount(name.to_string()));
+
+		if let Some(procedure) = self.procedures.get(name).cloned() {
+			if args.len() != procedure.parameters.len() {
+				return Err(wrong_count());
+			}
+			// The arguments are all evaluated before being stored
+			let values = self.argument_values(args)?;
+			for (parameter, value) in procedure.parameters.iter().zip(values.iter()) {
+				self.store(parameter, value)?;
+			}
+
+			let value = match self.execute_block(&procedure.body)? {
+				| Flow::Next => None,
+				| Flow::Return(value) => value,
+				| Flow::Goto(label) => return Err(InterpreterError::UndefinedLabel(label).into()),
+			};
+			return Ok(match (value, value_type(&procedure.return_type)) {
+				| (Some(value), Some(t)) => {
+					Some(Constant::Value(wrap(as_value(&value).0 as i64, &t), t))
+				}
+				| _ => None,
+			});
+		}
+
+		let expected = match name {
+			| "LENGTH" | "LAST" | "SIZE" | "LOW" | "HIGH" | "DOUBLE" | "INPUT" | "TIME" => Some(1),
+			| "OUTPUT" => Some(2),
+			| "MOVE" => Some(3),
+			| "CARRY" | "ZERO" | "SIGN" | "PARITY" => Some(0),
+			| _ => None,
+		};
+		if let Some(expected) = expected {
+			if args.len() != expected {
+				return Err(wrong_count());
+			}
+		}
+
+		let length = || args.first().map(|e| self.array_length(e)).unwrap_or(1) as i32;
+		let value = |c: &Constant| as_value(c).0;
+		let result = match name {
+			| "LENGTH" => Constant::Value(length(), Type::U16),
+			| "LAST" => Constant::Value(wrap(length() as i64 - 1, &Type::U16), Type::U16),
+			| "SIZE" => {
+				let size = match &args[0] {
+					| Expression::Variable(var) => size_of(&self.storage_type(var)?),
+					| _ => 1,
+				};
+				Constant::Value(wrap(length() as i64 * size as i64, &Type::U16), Type::U16)
+			}
+			| "LOW" | "HIGH" | "DOUBLE" => {
+				let x = value(&self.evaluate(&args[0])?) as u16;
+				match name {
+					| "LOW" => Constant::Value((x & 0xFF) as i32, Type::U8),
+					| "HIGH" => Constant::Value((x >> 8) as i32, Type::U8),
+					| _ => Constant::Value(x as i32, Type::U16),
+				}
+			}
+			| "INPUT" => {
+				let port = value(&self.evaluate(&args[0])?) as u8;
+				Constant::Value((self.input)(port) as i32, Type::U8)
+			}
+			| "OUTPUT" => {
+				let port = value(&self.evaluate(&args[0])?) as u8;
+				let data = value(&self.evaluate(&args[1])?) as u8;
+				(self.output)(port, data);
+				return Ok(None);
+			}
+			| "MOVE" => {
+				let values = self.argument_values(args)?;
+				let (count, source, destination) = (
+					value(&values[0]) as u16,
+					value(&values[1]) as u16,
+					value(&values[2]) as u16,
+				);
+				for i in 0..count {
+					let byte = self.memory.read_byte(source.wrapping_add(i));
+					self.memory.write_byte(destination.wrapping_add(i), byte);
+				}
+				return Ok(None);
+			}
+			// The time doesn't pass on the host
+			| "TIME" => {
+				self.evaluate(&args[0])?;
+				return Ok(None);
+			}
+			| "CARRY" => boolean(self.flags.carry),
+			| "ZERO" => boolean(self.flags.zero),
+			| "SIGN" => boolean(self.flags.sign),
+			| "PARITY" => boolean(self.flags.parity),
+			| _ => {
+				let values = self.argument_values(args)?;
+				let values: Vec<i32> = values.iter().map(value).collect();
+				return match self.externals.get_mut(name) {
+					| Some(procedure) => {
+						Ok(procedure(&values, &mut self.memory)
+							.map(|v| Constant::Value(v, Type::U16)))
+					}
+					| None => Err(InterpreterError::UndefinedProcedure(name.to_string()).into()),
+				};
+			}
+		};
+		Ok(Some(result))
+	}
+}
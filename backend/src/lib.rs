@@ -1,4 +1,9 @@
 pub mod architecture;
 pub mod ast;
+pub mod cfg;
+pub mod codegen;
 pub mod config;
+pub mod interpreter;
+pub mod optimization;
+pub mod ssa;
 pub mod typing;
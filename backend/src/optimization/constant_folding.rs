@@ -0,0 +1,503 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ast::*;
+use crate::typing::TypeCheckable;
+
+// The values of the comparisons, as in PL/M
+pub const TRUE: i32 = 0xFF;
+pub const FALSE: i32 = 0x00;
+
+/*
+ * A constant without an explicit type is a BYTE if it fits in 8 bits,
+ * an ADDRESS otherwise
+ */
+fn concrete_type(t: &Type, value: i32) -> Option<Type> {
+	match t {
+		| Type::Number => {
+			if (0..=0xFF).contains(&value) {
+				Some(Type::U8)
+			} else {
+				Some(Type::U16)
+			}
+		}
+		| Type::U8 | Type::I8 | Type::U16 | Type::I16 | Type::Pointer(_) => Some(t.clone()),
+		| Type::Reference(t) => concrete_type(t, value),
+		| Type::Void => None,
+	}
+}
+
+fn width(t: &Type) -> u32 {
+	match t {
+		| Type::U8 | Type::I8 => 8,
+		| _ => 16,
+	}
+}
+
+fn is_signed(t: &Type) -> bool {
+	matches!(t, Type::I8 | Type::I16)
+}
+
+/*
+ * Truncates a value to the size of `t`, the way the Z80 would do it
+ */
+pub fn wrap(value: i64, t: &Type) -> i32 {
+	match t {
+		| Type::I8 => value as i8 as i32,
+		| Type::I16 => value as i16 as i32,
+		| Type::U8 => value as u8 as i32,
+		| _ => value as u16 as i32,
+	}
+}
+
+fn unsigned(value: i32, t: &Type) -> i64 {
+	value as i64 & ((1i64 << width(t)) - 1)
+}
+
+/*
+ * Evaluates an operation over two constants. None is returned when the
+ * result can't be known at compile time (the carry of PLUS and MINUS
+ * for instance) or is left to the runtime (a division by zero).
+ */
+pub fn fold_binary_operation(
+	op: &BinaryOperation,
+	lhs: &Constant,
+	rhs: &Constant,
+) -> Option<Constant> {
+	let (a, ta, b, tb) = match (lhs, rhs) {
+		| (Constant::Value(a, ta), Constant::Value(b, tb)) => {
+			(*a, concrete_type(ta, *a)?, *b, concrete_type(tb, *b)?)
+		}
+		| _ => return None,
+	};
+
+	let is_shift = matches!(
+		op,
+		BinaryOperation::ShiftLeft
+			| BinaryOperation::ShiftRight
+			| BinaryOperation::RotateLeft
+			| BinaryOperation::RotateRight
+	);
+	// The count of a shift doesn't change the size of the result
+	let t = if is_shift { ta } else { ta.max(tb) };
+	// In PL/M, the product, quotient and remainder of two BYTEs are
+	// ADDRESS values
+	let widens = matches!(
+		op,
+		BinaryOperation::Multiply | BinaryOperation::Division | BinaryOperation::Modulo
+	);
+	let t = match t {
+		| Type::U8 if widens => Type::U16,
+		| Type::I8 if widens => Type::I16,
+		| t => t,
+	};
+	let bits = width(&t);
+	let (ua, ub) = (unsigned(a, &t), unsigned(b, &t));
+	let (sa, sb) = if is_signed(&t) {
+		(wrap(a as i64, &t) as i64, wrap(b as i64, &t) as i64)
+	} else {
+		(ua, ub)
+	};
+
+	let comparison = |result: bool| {
+		Some(Constant::Value(if result { TRUE } else { FALSE }, Type::U8))
+	};
+
+	let value = match op {
+		| BinaryOperation::Add => sa + sb,
+		| BinaryOperation::Substract => sa - sb,
+		| BinaryOperation::Multiply => sa * sb,
+		| BinaryOperation::Division | BinaryOperation::Modulo if sb == 0 => return None,
+		| BinaryOperation::Division => sa / sb,
+		| BinaryOperation::Modulo => sa % sb,
+
+		| BinaryOperation::And => ua & ub,
+		| BinaryOperation::Or => ua | ub,
+		| BinaryOperation::Xor => ua ^ ub,
+
+		| BinaryOperation::ShiftLeft => {
+			if b as u32 >= bits {
+				0
+			} else {
+				ua << b
+			}
+		}
+		| BinaryOperation::ShiftRight => {
+			if b as u32 >= bits {
+				0
+			} else {
+				ua >> b
+			}
+		}
+		| BinaryOperation::RotateLeft | BinaryOperation::RotateRight => {
+			let n = b.rem_euclid(bits as i32) as u32;
+			let n = if *op == BinaryOperation::RotateLeft { n } else { (bits - n) % bits };
+			if n == 0 {
+				ua
+			} else {
+				(ua << n) | (ua >> (bits - n))
+			}
+		}
+
+		| BinaryOperation::Greater => return comparison(sa > sb),
+		| BinaryOperation::Less => return comparison(sa < sb),
+		| BinaryOperation::GreaterOrEqual => return comparison(sa >= sb),
+		| BinaryOperation::LessOrEqual => return comparison(sa <= sb),
+		| BinaryOperation::Equal => return comparison(sa == sb),
+		| BinaryOperation::NotEqual => return comparison(sa != sb),
+
+		| BinaryOperation::AddWithCarry
+		| BinaryOperation::SubstractWithCarry
+		| BinaryOperation::ShiftLeftWithCarry
+		| BinaryOperation::ShiftRightWithCarry
+		| BinaryOperation::RotateLeftWithCarry
+		| BinaryOperation::RotateRightWithCarry => return None,
+	};
+
+	Some(Constant::Value(wrap(value, &t), t))
+}
+
+pub fn fold_unary_operation(op: &UnaryOperation, value: &Constant) -> Option<Constant> {
+	let (a, t) = match value {
+		| Constant::Value(a, t) => (*a, concrete_type(t, *a)?),
+		| _ => return None,
+	};
+
+	match op {
+		| UnaryOperation::Not => Some(Constant::Value(wrap(!(a as i64), &t), t)),
+		| UnaryOperation::Invert => Some(Constant::Value(wrap(-(a as i64), &t), t)),
+		| UnaryOperation::Reference | UnaryOperation::Dereference => None,
+	}
+}
+
+fn contains_call<VariableType>(e: &Expression<VariableType>) -> bool {
+	match e {
+		| Expression::FunctionCall(_, _) => true,
+		| Expression::BinaryOp(_, lhs, rhs) => contains_call(lhs) || contains_call(rhs),
+		| Expression::UnaryOp(_, e) => contains_call(e),
+		| Expression::Variable(_) | Expression::Phi(_, _) | Expression::Constant(_) => false,
+	}
+}
+
+/*
+ * Folds the operations over constants, and propagates the constants
+ * assigned to variables along straight-line code. Every label, loop or
+ * procedure call forgets what is known about the variables, and the
+ * variables whose address is taken are never propagated.
+ */
+pub struct ConstantFolder<'a, VariableType, Environment> {
+	env: &'a Environment,
+	array_lengths: BTreeMap<VariableType, usize>,
+	escaped: BTreeSet<VariableType>,
+	known: BTreeMap<VariableType, Constant>,
+}
+
+impl<'a, VariableType, Environment> ConstantFolder<'a, VariableType, Environment>
+where
+	VariableType: Clone + Ord + TypeCheckable<Environment>,
+{
+	pub fn new(env: &'a Environment) -> Self {
+		Self {
+			env,
+			array_lengths: BTreeMap::new(),
+			escaped: BTreeSet::new(),
+			known: BTreeMap::new(),
+		}
+	}
+
+	// Used to fold LENGTH and LAST on the declared arrays
+	pub fn set_array_length(&mut self, var: VariableType, length: usize) {
+		self.array_lengths.insert(var, length);
+	}
+
+	fn array_length(&self, e: &Expression<VariableType>) -> Option<usize> {
+		match e {
+			| Expression::Constant(Constant::Array(values, _))
+			| Expression::Constant(Constant::ReadOnlyArray(values, _)) => Some(values.len()),
+			| Expression::Variable(var) => self.array_lengths.get(var).copied(),
+			| _ => None,
+		}
+	}
+
+	pub fn fold_expression(&self, e: Expression<VariableType>) -> Expression<VariableType> {
+		match e {
+			| Expression::BinaryOp(op, lhs, rhs) => {
+				let lhs = self.fold_expression(*lhs);
+				let rhs = self.fold_expression(*rhs);
+				if let (Expression::Constant(a), Expression::Constant(b)) = (&lhs, &rhs) {
+					if let Some(c) = fold_binary_operation(&op, a, b) {
+						return Expression::Constant(c);
+					}
+				}
+				Expression::BinaryOp(op, Box::new(lhs), Box::new(rhs))
+			}
+			| Expression::UnaryOp(op, e) => {
+				let e = self.fold_expression(*e);
+				if let Expression::Constant(c) = &e {
+					if let Some(c) = fold_unary_operation(&op, c) {
+						return Expression::Constant(c);
+					}
+				}
+				Expression::UnaryOp(op, Box::new(e))
+			}
+			| Expression::FunctionCall(name, args) => {
+				if args.len() == 1 && (name == "LENGTH" || name == "LAST") {
+					if let Some(length) = self.array_length(&args[0]) {
+						let value = if name == "LENGTH" { length as i32 } else { length as i32 - 1 };
+						if value >= 0 {
+							return Expression::Constant(Constant::Value(value, Type::U16));
+						}
+					}
+				}
+				Expression::FunctionCall(
+					name,
+					args.into_iter().map(|e| self.fold_expression(e)).collect(),
+				)
+			}
+			| Expression::Variable(var) => match self.known.get(&var) {
+				| Some(c) => Expression::Constant(c.clone()),
+				| None => Expression::Variable(var),
+			},
+			| e => e,
+		}
+	}
+
+	/*
+	 * The value actually stored in `var`, once truncated to its type.
+	 * Without a type, only the values fitting in a BYTE are kept.
+	 */
+	fn stored_value(&self, var: &VariableType, c: &Constant) -> Option<Constant> {
+		let value = match c {
+			| Constant::Value(value, _) => *value,
+			| _ => return None,
+		};
+
+		let mut t = var.get_type(self.env);
+		while let Some(Type::Pointer(inner)) | Some(Type::Reference(inner)) = t {
+			t = Some(*inner);
+		}
+		match t {
+			| Some(t @ (Type::U8 | Type::I8 | Type::U16 | Type::I16)) => {
+				Some(Constant::Value(wrap(value as i64, &t), t))
+			}
+			| _ if (0..=0xFF).contains(&value) => Some(c.clone()),
+			| _ => None,
+		}
+	}
+
+	fn find_escaped_variables(&mut self, e: &Expression<VariableType>) {
+		match e {
+			| Expression::UnaryOp(UnaryOperation::Reference, var) => {
+				if let Expression::Variable(var) = var.as_ref() {
+					self.escaped.insert(var.clone());
+				} else {
+					self.find_escaped_variables(var);
+				}
+			}
+			| Expression::UnaryOp(_, e) => self.find_escaped_variables(e),
+			| Expression::BinaryOp(_, lhs, rhs) => {
+				self.find_escaped_variables(lhs);
+				self.find_escaped_variables(rhs);
+			}
+			| Expression::FunctionCall(_, args) => {
+				args.iter().for_each(|e| self.find_escaped_variables(e));
+			}
+			| _ => {}
+		}
+	}
+
+	fn find_escaped_variables_in(&mut self, stmts: &[Statement<VariableType>]) {
+		for stmt in stmts {
+			match stmt {
+				| Statement::IfElse(cond, if_blk, else_blk) => {
+					self.find_escaped_variables(cond);
+					self.find_escaped_variables_in(if_blk);
+					self.find_escaped_variables_in(else_blk);
+				}
+				| Statement::Block(blk)
+				| Statement::Loop(blk)
+				| Statement::FunctionDefinition(_, _, _, _, blk) => self.find_escaped_variables_in(blk),
+				| Statement::Switch(e, cases) => {
+					self.find_escaped_variables(e);
+					for case in cases.iter().flatten() {
+						self.find_escaped_variables_in(std::slice::from_ref(case));
+					}
+				}
+				| Statement::Return(Some(e))
+				| Statement::Expression(e)
+				| Statement::Assignment(_, e)
+				| Statement::Jump(e) => self.find_escaped_variables(e),
+				| _ => {}
+			}
+		}
+	}
+
+	// Only keeps what is known on both paths
+	fn merge(&mut self, other: BTreeMap<VariableType, Constant>) {
+		self.known.retain(|var, c| other.get(var) == Some(c));
+	}
+
+	fn fold_branch(
+		&mut self,
+		stmts: Vec<Statement<VariableType>>,
+		known: &BTreeMap<VariableType, Constant>,
+	) -> (Vec<Statement<VariableType>>, BTreeMap<VariableType, Constant>) {
+		self.known = known.clone();
+		let stmts = self.fold_block(stmts);
+		(stmts, std::mem::take(&mut self.known))
+	}
+
+	fn fold_block(&mut self, stmts: Vec<Statement<VariableType>>) -> Vec<Statement<VariableType>> {
+		let mut output = Vec::with_capacity(stmts.len());
+		for stmt in stmts {
+			self.fold_statement(stmt, &mut output);
+		}
+		output
+	}
+
+	fn fold_statement(
+		&mut self,
+		stmt: Statement<VariableType>,
+		output: &mut Vec<Statement<VariableType>>,
+	) {
+		match stmt {
+			| Statement::IfElse(cond, if_blk, else_blk) => {
+				let cond = self.fold_expression(cond);
+				if contains_call(&cond) {
+					self.known.clear();
+				}
+
+				// Only the lowest bit of a condition is tested
+				if let Expression::Constant(Constant::Value(value, _)) = cond {
+					let blk = if value & 1 != 0 { if_blk } else { else_blk };
+					output.extend(self.fold_block(blk));
+					return;
+				}
+
+				let known = std::mem::take(&mut self.known);
+				let (if_blk, if_known) = self.fold_branch(if_blk, &known);
+				let (else_blk, else_known) = self.fold_branch(else_blk, &known);
+				self.known = if_known;
+				self.merge(else_known);
+				output.push(Statement::IfElse(cond, if_blk, else_blk));
+			}
+			| Statement::Block(blk) => {
+				let blk = self.fold_block(blk);
+				output.push(Statement::Block(blk));
+			}
+			| Statement::Loop(blk) => {
+				// The body can be reached from its end
+				self.known.clear();
+				let blk = self.fold_block(blk);
+				self.known.clear();
+				output.push(Statement::Loop(blk));
+			}
+			| Statement::Switch(e, cases) => {
+				let e = self.fold_expression(e);
+				if contains_call(&e) {
+					self.known.clear();
+				}
+
+				if let Expression::Constant(Constant::Value(value, _)) = e {
+					if let Some(case) = usize::try_from(value).ok().and_then(|i| cases.get(i)) {
+						if let Some(case) = case.clone() {
+							self.fold_statement(case, output);
+						}
+						return;
+					}
+				}
+
+				let known = std::mem::take(&mut self.known);
+				let mut merged: Option<BTreeMap<VariableType, Constant>> = None;
+				let mut folded_cases = Vec::with_capacity(cases.len());
+				for case in cases {
+					let (case, case_known) = match case {
+						| None => (None, known.clone()),
+						| Some(case) => {
+							let (mut stmts, case_known) = self.fold_branch(vec![case], &known);
+							let case = if stmts.len() == 1 {
+								stmts.pop().unwrap()
+							} else {
+								Statement::Block(stmts)
+							};
+							(Some(case), case_known)
+						}
+					};
+					folded_cases.push(case);
+					merged = Some(match merged {
+						| None => case_known,
+						| Some(m) => {
+							self.known = m;
+							self.merge(case_known);
+							std::mem::take(&mut self.known)
+						}
+					});
+				}
+				self.known = merged.unwrap_or(known);
+				output.push(Statement::Switch(e, folded_cases));
+			}
+			| Statement::FunctionDefinition(name, t, args, interrupt, body) => {
+				// The body runs when called, not where it is defined
+				let known = std::mem::take(&mut self.known);
+				let body = self.fold_block(body);
+				self.known = known;
+				output.push(Statement::FunctionDefinition(name, t, args, interrupt, body));
+			}
+			| Statement::Assignment(var, e) => {
+				let e = self.fold_expression(e);
+				if contains_call(&e) {
+					self.known.clear();
+				}
+
+				let value = match &e {
+					| Expression::Constant(c) if !self.escaped.contains(&var) => {
+						self.stored_value(&var, c)
+					}
+					| _ => None,
+				};
+				match value {
+					| Some(c) => self.known.insert(var.clone(), c),
+					| None => self.known.remove(&var),
+				};
+				output.push(Statement::Assignment(var, e));
+			}
+			| Statement::Expression(e) => {
+				let e = self.fold_expression(e);
+				if contains_call(&e) {
+					self.known.clear();
+				}
+				output.push(Statement::Expression(e));
+			}
+			| Statement::Return(e) => {
+				let e = e.map(|e| self.fold_expression(e));
+				self.known.clear();
+				output.push(Statement::Return(e));
+			}
+			| Statement::Jump(e) => {
+				let e = self.fold_expression(e);
+				self.known.clear();
+				output.push(Statement::Jump(e));
+			}
+			| Statement::Label(name) => {
+				self.known.clear();
+				output.push(Statement::Label(name));
+			}
+			| Statement::Goto(label) => {
+				self.known.clear();
+				output.push(Statement::Goto(label));
+			}
+			| Statement::Halt => {
+				self.known.clear();
+				output.push(Statement::Halt);
+			}
+			| stmt @ (Statement::DisableInterrupt
+			| Statement::EnableInterrupt
+			| Statement::NoOperation) => output.push(stmt),
+		}
+	}
+
+	pub fn fold(&mut self, program: Vec<Statement<VariableType>>) -> Vec<Statement<VariableType>> {
+		self.find_escaped_variables_in(&program);
+		self.known.clear();
+		self.fold_block(program)
+	}
+}
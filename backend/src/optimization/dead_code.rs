@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::ast::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadCodeOptions {
+	pub enabled: bool,
+	// Procedures kept even if nothing calls them, like those called
+	// from another module. The interrupt handlers are always kept
+	pub kept_procedures: BTreeSet<String>,
+}
+
+impl Default for DeadCodeOptions {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			kept_procedures: BTreeSet::new(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeadCodeWarning<VariableType> {
+	UnreachableStatements {
+		// None for the main program
+		procedure: Option<String>,
+		count: usize,
+	},
+	UnusedProcedure(String),
+	UnreferencedData(VariableType),
+}
+
+impl<VariableType: fmt::Display> fmt::Display for DeadCodeWarning<VariableType> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| DeadCodeWarning::UnreachableStatements {
+				procedure: None,
+				count,
+			} => write!(f, "{} unreachable statement(s) removed", count),
+			| DeadCodeWarning::UnreachableStatements {
+				procedure: Some(name),
+				count,
+			} => write!(f, "{} unreachable statement(s) removed in {}", count, name),
+			| DeadCodeWarning::UnusedProcedure(name) => {
+				write!(f, "The procedure {} is never called, it has been removed", name)
+			}
+			| DeadCodeWarning::UnreferencedData(var) => {
+				write!(f, "The DATA {} is never used, it has been removed", var)
+			}
+		}
+	}
+}
+
+fn for_each_expression<VariableType>(
+	stmts: &[Statement<VariableType>],
+	f: &mut impl FnMut(&Expression<VariableType>),
+	enter_procedures: bool,
+) {
+	for stmt in stmts {
+		match stmt {
+			| Statement::IfElse(cond, if_blk, else_blk) => {
+				f(cond);
+				for_each_expression(if_blk, f, enter_procedures);
+				for_each_expression(else_blk, f, enter_procedures);
+			}
+			| Statement::Block(blk) | Statement::Loop(blk) => {
+				for_each_expression(blk, f, enter_procedures)
+			}
+			| Statement::FunctionDefinition(_, _, _, _, body) => {
+				if enter_procedures {
+					for_each_expression(body, f, enter_procedures);
+				}
+			}
+			| Statement::Switch(e, cases) => {
+				f(e);
+				for case in cases.iter().flatten() {
+					for_each_expression(std::slice::from_ref(case), f, enter_procedures);
+				}
+			}
+			| Statement::Return(Some(e))
+			| Statement::Expression(e)
+			| Statement::Assignment(_, e)
+			| Statement::Jump(e) => f(e),
+			| Statement::Return(None)
+			| Statement::Label(_)
+			| Statement::Goto(_)
+			| Statement::DisableInterrupt
+			| Statement::EnableInterrupt
+			| Statement::Halt
+			| Statement::NoOperation => {}
+		}
+	}
+}
+
+fn visit_expression<VariableType>(
+	e: &Expression<VariableType>,
+	f: &mut impl FnMut(&Expression<VariableType>),
+) {
+	f(e);
+	match e {
+		| Expression::BinaryOp(_, lhs, rhs) => {
+			visit_expression(lhs, f);
+			visit_expression(rhs, f);
+		}
+		| Expression::UnaryOp(_, e) => visit_expression(e, f),
+		| Expression::FunctionCall(_, args) => args.iter().for_each(|e| visit_expression(e, f)),
+		| Expression::Variable(_) | Expression::Phi(_, _) | Expression::Constant(_) => {}
+	}
+}
+
+fn contains_call<VariableType>(e: &Expression<VariableType>) -> bool {
+	let mut found = false;
+	visit_expression(e, &mut |e| found |= matches!(e, Expression::FunctionCall(_, _)));
+	found
+}
+
+// A label can be reached by a jump, whatever precedes it
+fn contains_label<VariableType>(stmt: &Statement<VariableType>) -> bool {
+	match stmt {
+		| Statement::Label(_) => true,
+		| Statement::IfElse(_, if_blk, else_blk) => {
+			if_blk.iter().any(contains_label) || else_blk.iter().any(contains_label)
+		}
+		| Statement::Block(blk) | Statement::Loop(blk) => blk.iter().any(contains_label),
+		| Statement::Switch(_, cases) => cases.iter().flatten().any(contains_label),
+		| _ => false,
+	}
+}
+
+// A case of a DO CASE is a single statement
+fn into_case<VariableType>(mut stmts: Vec<Statement<VariableType>>) -> Statement<VariableType> {
+	if stmts.len() == 1 {
+		stmts.pop().unwrap()
+	} else {
+		Statement::Block(stmts)
+	}
+}
+
+pub struct DeadCodeEliminator<VariableType> {
+	options: DeadCodeOptions,
+	warnings: Vec<DeadCodeWarning<VariableType>>,
+}
+
+impl<VariableType: Clone + Ord> DeadCodeEliminator<VariableType> {
+	pub fn new(options: DeadCodeOptions) -> Self {
+		Self {
+			options,
+			warnings: Vec::new(),
+		}
+	}
+
+	// What has been dropped by the last call to `eliminate`
+	pub fn warnings(&self) -> &[DeadCodeWarning<VariableType>] {
+		&self.warnings
+	}
+
+	/*
+	 * Removes the statements following a GOTO or a RETURN until the
+	 * next label. HALT only counts when the interrupts are known to be
+	 * disabled: otherwise an interrupt resumes the execution after it.
+	 * Returns whether the end of the block can be reached.
+	 */
+	fn remove_unreachable(
+		&mut self,
+		stmts: Vec<Statement<VariableType>>,
+		procedure: &Option<String>,
+		output: &mut Vec<Statement<VariableType>>,
+	) -> bool {
+		let mut reachable = true;
+		let mut interrupts_disabled = false;
+		let mut removed = 0;
+
+		for stmt in stmts {
+			let is_definition = matches!(stmt, Statement::FunctionDefinition(_, _, _, _, _));
+			if !reachable && !is_definition && !contains_label(&stmt) {
+				removed += 1;
+				continue;
+			}
+			if !is_definition {
+				reachable = true;
+			}
+
+			let falls_through = match stmt {
+				| Statement::IfElse(cond, if_blk, else_blk) => {
+					let mut new_if_blk = Vec::new();
+					let mut new_else_blk = Vec::new();
+					let if_falls = self.remove_unreachable(if_blk, procedure, &mut new_if_blk);
+					let else_falls = self.remove_unreachable(else_blk, procedure, &mut new_else_blk);
+					output.push(Statement::IfElse(cond, new_if_blk, new_else_blk));
+					if_falls || else_falls
+				}
+				| Statement::Block(blk) => {
+					let mut new_blk = Vec::new();
+					let falls = self.remove_unreachable(blk, procedure, &mut new_blk);
+					output.push(Statement::Block(new_blk));
+					falls
+				}
+				| Statement::Loop(blk) => {
+					// A loop may be left by its condition
+					let mut new_blk = Vec::new();
+					self.remove_unreachable(blk, procedure, &mut new_blk);
+					output.push(Statement::Loop(new_blk));
+					true
+				}
+				| Statement::Switch(e, cases) => {
+					let mut new_cases = Vec::with_capacity(cases.len());
+					for case in cases {
+						new_cases.push(case.map(|case| {
+							let mut new_case = Vec::new();
+							self.remove_unreachable(vec![case], procedure, &mut new_case);
+							into_case(new_case)
+						}));
+					}
+					output.push(Statement::Switch(e, new_cases));
+					true
+				}
+				| Statement::FunctionDefinition(name, t, args, interrupt, body) => {
+					let mut new_body = Vec::new();
+					self.remove_unreachable(body, &Some(name.clone()), &mut new_body);
+					output.push(Statement::FunctionDefinition(name, t, args, interrupt, new_body));
+					reachable
+				}
+				| Statement::Return(_) | Statement::Goto(_) | Statement::Jump(_) => {
+					output.push(stmt);
+					false
+				}
+				| Statement::Halt => {
+					output.push(stmt);
+					!interrupts_disabled
+				}
+				| stmt => {
+					output.push(stmt);
+					true
+				}
+			};
+
+			interrupts_disabled = match output.last() {
+				| Some(Statement::DisableInterrupt) => true,
+				| Some(Statement::NoOperation) => interrupts_disabled,
+				| Some(Statement::Assignment(_, e)) | Some(Statement::Expression(e)) => {
+					interrupts_disabled && !contains_call(e)
+				}
+				| _ => false,
+			};
+			reachable = falls_through;
+		}
+
+		if removed > 0 {
+			self.warnings.push(DeadCodeWarning::UnreachableStatements {
+				procedure: procedure.clone(),
+				count: removed,
+			});
+		}
+		reachable
+	}
+
+	fn collect_definitions<'b>(
+		stmts: &'b [Statement<VariableType>],
+		definitions: &mut BTreeMap<&'b str, Vec<&'b [Statement<VariableType>]>>,
+		interrupts: &mut Vec<String>,
+	) {
+		for stmt in stmts {
+			match stmt {
+				| Statement::FunctionDefinition(name, _, _, interrupt, body) => {
+					definitions.entry(name.as_str()).or_default().push(body);
+					if interrupt.is_some() {
+						interrupts.push(name.clone());
+					}
+					Self::collect_definitions(body, definitions, interrupts);
+				}
+				| Statement::IfElse(_, if_blk, else_blk) => {
+					Self::collect_definitions(if_blk, definitions, interrupts);
+					Self::collect_definitions(else_blk, definitions, interrupts);
+				}
+				| Statement::Block(blk) | Statement::Loop(blk) => {
+					Self::collect_definitions(blk, definitions, interrupts)
+				}
+				| Statement::Switch(_, cases) => {
+					for case in cases.iter().flatten() {
+						Self::collect_definitions(std::slice::from_ref(case), definitions, interrupts);
+					}
+				}
+				| _ => {}
+			}
+		}
+	}
+
+	fn called_procedures(stmts: &[Statement<VariableType>]) -> BTreeSet<String> {
+		let mut called = BTreeSet::new();
+		for_each_expression(
+			stmts,
+			&mut |e| {
+				visit_expression(e, &mut |e| {
+					if let Expression::FunctionCall(name, _) = e {
+						called.insert(name.clone());
+					}
+				})
+			},
+			false,
+		);
+		called
+	}
+
+	/*
+	 * The procedures reachable from the main program, from the
+	 * interrupt handlers and from the kept procedures. A procedure
+	 * only calling itself isn't used.
+	 */
+	fn used_procedures(&self, program: &[Statement<VariableType>]) -> BTreeSet<String> {
+		let mut definitions = BTreeMap::new();
+		let mut interrupts = Vec::new();
+		Self::collect_definitions(program, &mut definitions, &mut interrupts);
+
+		let mut used = BTreeSet::new();
+		let mut queue: Vec<String> = Self::called_procedures(program).into_iter().collect();
+		queue.extend(self.options.kept_procedures.iter().cloned());
+		queue.extend(interrupts);
+
+		while let Some(name) = queue.pop() {
+			if !used.insert(name.clone()) {
+				continue;
+			}
+			for body in definitions.get(name.as_str()).into_iter().flatten() {
+				queue.extend(Self::called_procedures(body));
+			}
+		}
+		used
+	}
+
+	fn remove_procedures(
+		&mut self,
+		stmts: Vec<Statement<VariableType>>,
+		used: &BTreeSet<String>,
+	) -> Vec<Statement<VariableType>> {
+		let mut output = Vec::with_capacity(stmts.len());
+		for stmt in stmts {
+			match stmt {
+				| Statement::FunctionDefinition(name, t, args, interrupt, body) => {
+					let body = self.remove_procedures(body, used);
+					if used.contains(&name) {
+						output.push(Statement::FunctionDefinition(name, t, args, interrupt, body));
+					} else {
+						self.warnings.push(DeadCodeWarning::UnusedProcedure(name));
+					}
+				}
+				| Statement::IfElse(cond, if_blk, else_blk) => output.push(Statement::IfElse(
+					cond,
+					self.remove_procedures(if_blk, used),
+					self.remove_procedures(else_blk, used),
+				)),
+				| Statement::Block(blk) => output.push(Statement::Block(self.remove_procedures(blk, used))),
+				| Statement::Loop(blk) => output.push(Statement::Loop(self.remove_procedures(blk, used))),
+				| Statement::Switch(e, cases) => {
+					let cases = cases
+						.into_iter()
+						.map(|case| case.map(|case| into_case(self.remove_procedures(vec![case], used))))
+						.collect();
+					output.push(Statement::Switch(e, cases));
+				}
+				| stmt => output.push(stmt),
+			}
+		}
+		output
+	}
+
+	fn read_variables(program: &[Statement<VariableType>]) -> BTreeSet<VariableType> {
+		let mut read = BTreeSet::new();
+		for_each_expression(
+			program,
+			&mut |e| {
+				visit_expression(e, &mut |e| match e {
+					| Expression::Variable(var) => {
+						read.insert(var.clone());
+					}
+					| Expression::Phi(lhs, rhs) => {
+						read.extend(lhs.iter().chain(rhs.iter()).cloned());
+					}
+					| _ => {}
+				})
+			},
+			true,
+		);
+		read
+	}
+
+	// A DATA is the assignment of a read-only array to a variable
+	fn remove_data(
+		&mut self,
+		stmts: Vec<Statement<VariableType>>,
+		read: &BTreeSet<VariableType>,
+	) -> Vec<Statement<VariableType>> {
+		let mut output = Vec::with_capacity(stmts.len());
+		for stmt in stmts {
+			match stmt {
+				| Statement::Assignment(var, Expression::Constant(Constant::ReadOnlyArray(_, _)))
+					if !read.contains(&var) =>
+				{
+					self.warnings.push(DeadCodeWarning::UnreferencedData(var));
+				}
+				| Statement::FunctionDefinition(name, t, args, interrupt, body) => {
+					let body = self.remove_data(body, read);
+					output.push(Statement::FunctionDefinition(name, t, args, interrupt, body));
+				}
+				| Statement::IfElse(cond, if_blk, else_blk) => output.push(Statement::IfElse(
+					cond,
+					self.remove_data(if_blk, read),
+					self.remove_data(else_blk, read),
+				)),
+				| Statement::Block(blk) => output.push(Statement::Block(self.remove_data(blk, read))),
+				| Statement::Loop(blk) => output.push(Statement::Loop(self.remove_data(blk, read))),
+				| Statement::Switch(e, cases) => {
+					let cases = cases
+						.into_iter()
+						.map(|case| case.map(|case| into_case(self.remove_data(vec![case], read))))
+						.collect();
+					output.push(Statement::Switch(e, cases));
+				}
+				| stmt => output.push(stmt),
+			}
+		}
+		output
+	}
+
+	pub fn eliminate(&mut self, program: Vec<Statement<VariableType>>) -> Vec<Statement<VariableType>> {
+		self.warnings.clear();
+		if !self.options.enabled {
+			return program;
+		}
+
+		let mut reachable_code = Vec::with_capacity(program.len());
+		self.remove_unreachable(program, &None, &mut reachable_code);
+
+		let used = self.used_procedures(&reachable_code);
+		let program = self.remove_procedures(reachable_code, &used);
+
+		let read = Self::read_variables(&program);
+		self.remove_data(program, &read)
+	}
+}
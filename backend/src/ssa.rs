@@ -0,0 +1,515 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::ast::*;
+use crate::cfg::*;
+use crate::typing::TypeCheckable;
+
+/*
+ * A variable of the SSA form. The version 0 is the value the variable
+ * holds when entering the procedure.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Versioned<VariableType> {
+	pub variable: VariableType,
+	pub version: usize,
+}
+
+impl<VariableType> Versioned<VariableType> {
+	pub fn new(variable: VariableType, version: usize) -> Self {
+		Self { variable, version }
+	}
+}
+
+impl<VariableType: fmt::Display> fmt::Display for Versioned<VariableType> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}_{}", self.variable, self.version)
+	}
+}
+
+impl<Environment, VariableType: TypeCheckable<Environment>> TypeCheckable<Environment>
+	for Versioned<VariableType>
+{
+	fn get_type(&self, env: &Environment) -> Option<Type> {
+		self.variable.get_type(env)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SsaError<VariableType> {
+	MultipleDefinitions(Versioned<VariableType>),
+	UndefinedVariable(Versioned<VariableType>, BlockId),
+	// The definition doesn't dominate a use in the block
+	UndominatedUse(Versioned<VariableType>, BlockId),
+	// A PHI must be at the start of a block with two predecessors
+	MisplacedPhi(BlockId),
+}
+
+fn map_variables<V, W>(e: Expression<V>, f: &mut impl FnMut(V) -> W) -> Expression<W> {
+	match e {
+		| Expression::BinaryOp(op, lhs, rhs) => {
+			let lhs = map_variables(*lhs, f);
+			let rhs = map_variables(*rhs, f);
+			Expression::BinaryOp(op, Box::new(lhs), Box::new(rhs))
+		}
+		| Expression::UnaryOp(op, e) => Expression::UnaryOp(op, Box::new(map_variables(*e, f))),
+		| Expression::FunctionCall(name, args) => {
+			Expression::FunctionCall(name, args.into_iter().map(|e| map_variables(e, f)).collect())
+		}
+		| Expression::Variable(v) => Expression::Variable(f(v)),
+		| Expression::Phi(lhs, rhs) => {
+			let lhs = lhs.map(&mut *f);
+			Expression::Phi(lhs, rhs.map(f))
+		}
+		| Expression::Constant(c) => Expression::Constant(c),
+	}
+}
+
+fn map_terminator<V, W>(t: Terminator<V>, f: &mut impl FnMut(V) -> W) -> Terminator<W> {
+	match t {
+		| Terminator::Goto(b) => Terminator::Goto(b),
+		| Terminator::Branch(e, b1, b2) => Terminator::Branch(map_variables(e, f), b1, b2),
+		| Terminator::Switch(e, cases, default) => {
+			Terminator::Switch(map_variables(e, f), cases, default)
+		}
+		| Terminator::Return(e) => Terminator::Return(e.map(|e| map_variables(e, f))),
+		| Terminator::NonLocalGoto(label) => Terminator::NonLocalGoto(label),
+		| Terminator::IndirectJump(e) => Terminator::IndirectJump(map_variables(e, f)),
+	}
+}
+
+fn for_each_variable<'e, V>(e: &'e Expression<V>, f: &mut impl FnMut(&'e V)) {
+	match e {
+		| Expression::BinaryOp(_, lhs, rhs) => {
+			for_each_variable(lhs, f);
+			for_each_variable(rhs, f);
+		}
+		| Expression::UnaryOp(_, e) => for_each_variable(e, f),
+		| Expression::FunctionCall(_, args) => args.iter().for_each(|e| for_each_variable(e, f)),
+		| Expression::Variable(v) => f(v),
+		| Expression::Phi(lhs, rhs) => lhs.iter().chain(rhs.iter()).for_each(f),
+		| Expression::Constant(_) => {}
+	}
+}
+
+fn terminator_expression<V>(t: &Terminator<V>) -> Option<&Expression<V>> {
+	match t {
+		| Terminator::Branch(e, _, _)
+		| Terminator::Switch(e, _, _)
+		| Terminator::Return(Some(e))
+		| Terminator::IndirectJump(e) => Some(e),
+		| Terminator::Goto(_) | Terminator::NonLocalGoto(_) | Terminator::Return(None) => None,
+	}
+}
+
+// The variables whose address is taken can be modified through memory
+fn find_escaped<V: Clone + Ord>(e: &Expression<V>, output: &mut BTreeSet<V>) {
+	match e {
+		| Expression::UnaryOp(UnaryOperation::Reference, inner) => match inner.as_ref() {
+			| Expression::Variable(v) => {
+				output.insert(v.clone());
+			}
+			| inner => find_escaped(inner, output),
+		},
+		| Expression::UnaryOp(_, e) => find_escaped(e, output),
+		| Expression::BinaryOp(_, lhs, rhs) => {
+			find_escaped(lhs, output);
+			find_escaped(rhs, output);
+		}
+		| Expression::FunctionCall(_, args) => args.iter().for_each(|e| find_escaped(e, output)),
+		| _ => {}
+	}
+}
+
+/*
+ * PHI only has two operands, so the joins of more than two blocks are
+ * split into a chain of joins of two blocks
+ */
+pub fn limit_predecessors<VariableType>(
+	cfg: ControlFlowGraph<VariableType>,
+) -> ControlFlowGraph<VariableType> {
+	let mut predecessors: Vec<Vec<BlockId>> = (0..cfg.len()).map(|b| cfg.predecessors(b).to_vec()).collect();
+	let mut blocks = cfg.blocks;
+
+	for id in 0..blocks.len() {
+		while predecessors[id].len() > 2 {
+			let new_id = blocks.len();
+			let (p1, p2) = (predecessors[id][0], predecessors[id][1]);
+			for p in [p1, p2] {
+				blocks[p].terminator.map_targets(|b| if b == id { new_id } else { b });
+			}
+			blocks.push(BasicBlock {
+				label: None,
+				statements: Vec::new(),
+				terminator: Terminator::Goto(id),
+			});
+			predecessors.push(vec![p1, p2]);
+			predecessors[id].drain(0..2);
+			predecessors[id].push(new_id);
+		}
+	}
+
+	ControlFlowGraph::from_blocks(blocks)
+}
+
+struct Phi<VariableType> {
+	variable: VariableType,
+	dest: Option<Versioned<VariableType>>,
+	operands: [Option<Versioned<VariableType>>; 2],
+}
+
+struct Renamer<VariableType> {
+	memory: BTreeSet<VariableType>,
+	next_version: BTreeMap<VariableType, usize>,
+	stacks: BTreeMap<VariableType, Vec<usize>>,
+}
+
+impl<VariableType: Clone + Ord> Renamer<VariableType> {
+	fn current(&self, v: VariableType) -> Versioned<VariableType> {
+		let version = self.stacks.get(&v).and_then(|s| s.last()).copied().unwrap_or(0);
+		Versioned::new(v, version)
+	}
+
+	fn define(&mut self, v: VariableType, pushed: &mut Vec<VariableType>) -> Versioned<VariableType> {
+		if self.memory.contains(&v) {
+			return Versioned::new(v, 0);
+		}
+		let next = self.next_version.entry(v.clone()).or_insert(1);
+		let version = *next;
+		*next += 1;
+		self.stacks.entry(v.clone()).or_default().push(version);
+		pushed.push(v.clone());
+		Versioned::new(v, version)
+	}
+}
+
+type PendingBlock<VariableType> = (Option<String>, Vec<Statement<VariableType>>, Terminator<VariableType>);
+
+/*
+ * Converts a graph into SSA form: every assignment defines a new
+ * version of its variable, and a PHI merges the versions reaching a
+ * join. The variables in `memory`, and those whose address is taken,
+ * keep the version 0 everywhere.
+ */
+pub fn construct<VariableType: Clone + Ord>(
+	cfg: ControlFlowGraph<VariableType>,
+	memory: &BTreeSet<VariableType>,
+) -> ControlFlowGraph<Versioned<VariableType>> {
+	let cfg = limit_predecessors(cfg);
+	let tree = cfg.dominator_tree();
+	let frontiers = tree.dominance_frontiers(&cfg);
+
+	let mut memory = memory.clone();
+	let mut definitions: BTreeMap<VariableType, BTreeSet<BlockId>> = BTreeMap::new();
+	for (id, block) in cfg.blocks.iter().enumerate() {
+		for stmt in block.statements.iter() {
+			match stmt {
+				| Statement::Assignment(v, e) => {
+					definitions.entry(v.clone()).or_default().insert(id);
+					find_escaped(e, &mut memory);
+				}
+				| Statement::Expression(e) => find_escaped(e, &mut memory),
+				| _ => {}
+			}
+		}
+		if let Some(e) = terminator_expression(&block.terminator) {
+			find_escaped(e, &mut memory);
+		}
+	}
+
+	// Places the PHI on the iterated dominance frontier of the definitions
+	let mut phis: Vec<Vec<Phi<VariableType>>> = (0..cfg.len()).map(|_| Vec::new()).collect();
+	for (v, blocks) in definitions.iter() {
+		if memory.contains(v) {
+			continue;
+		}
+		let mut has_phi = BTreeSet::new();
+		let mut work: Vec<BlockId> = blocks.iter().copied().collect();
+		while let Some(b) = work.pop() {
+			for f in frontiers[b].iter() {
+				if has_phi.insert(*f) {
+					phis[*f].push(Phi {
+						variable: v.clone(),
+						dest: None,
+						operands: [None, None],
+					});
+					if !blocks.contains(f) {
+						work.push(*f);
+					}
+				}
+			}
+		}
+	}
+
+	let predecessors: Vec<Vec<BlockId>> = (0..cfg.len()).map(|b| cfg.predecessors(b).to_vec()).collect();
+	let mut pending: Vec<Option<PendingBlock<VariableType>>> = cfg
+		.blocks
+		.into_iter()
+		.map(|b| Some((b.label, b.statements, b.terminator)))
+		.collect();
+	let mut renamed: Vec<Option<BasicBlock<Versioned<VariableType>>>> = (0..pending.len()).map(|_| None).collect();
+
+	let mut renamer = Renamer {
+		memory,
+		next_version: BTreeMap::new(),
+		stacks: BTreeMap::new(),
+	};
+
+	// Walks the dominator tree, without recursion
+	enum Step {
+		Enter(BlockId),
+		Leave(BlockId),
+	}
+	let mut steps = vec![Step::Enter(ENTRY)];
+	let mut pushed_by_block: Vec<Vec<VariableType>> = (0..pending.len()).map(|_| Vec::new()).collect();
+
+	while let Some(step) = steps.pop() {
+		let id = match step {
+			| Step::Leave(id) => {
+				for v in pushed_by_block[id].drain(..) {
+					renamer.stacks.get_mut(&v).unwrap().pop();
+				}
+				continue;
+			}
+			| Step::Enter(id) => id,
+		};
+
+		let (label, stmts, terminator) = pending[id].take().unwrap();
+		let mut pushed = Vec::new();
+
+		for phi in phis[id].iter_mut() {
+			phi.dest = Some(renamer.define(phi.variable.clone(), &mut pushed));
+		}
+
+		let mut statements = Vec::with_capacity(stmts.len());
+		for stmt in stmts {
+			let stmt = match stmt {
+				| Statement::Assignment(v, e) => {
+					let e = map_variables(e, &mut |v| renamer.current(v));
+					Statement::Assignment(renamer.define(v, &mut pushed), e)
+				}
+				| Statement::Expression(e) => {
+					Statement::Expression(map_variables(e, &mut |v| renamer.current(v)))
+				}
+				| Statement::DisableInterrupt => Statement::DisableInterrupt,
+				| Statement::EnableInterrupt => Statement::EnableInterrupt,
+				| Statement::Halt => Statement::Halt,
+				| _ => unreachable!("Only simple statements are placed in a basic block"),
+			};
+			statements.push(stmt);
+		}
+		let terminator = map_terminator(terminator, &mut |v| renamer.current(v));
+
+		for succ in terminator.successors() {
+			let idx = predecessors[succ].iter().position(|p| *p == id).unwrap();
+			for phi in phis[succ].iter_mut() {
+				phi.operands[idx] = Some(renamer.current(phi.variable.clone()));
+			}
+		}
+
+		renamed[id] = Some(BasicBlock {
+			label,
+			statements,
+			terminator,
+		});
+		pushed_by_block[id] = pushed;
+
+		steps.push(Step::Leave(id));
+		for child in tree.children(id).into_iter().rev() {
+			steps.push(Step::Enter(child));
+		}
+	}
+
+	let blocks = renamed
+		.into_iter()
+		.zip(phis)
+		.map(|(block, phis)| {
+			let mut block = block.expect("Every block is dominated by the entry");
+			let mut statements: Vec<Statement<Versioned<VariableType>>> = phis
+				.into_iter()
+				.map(|phi| {
+					let [lhs, rhs] = phi.operands;
+					Statement::Assignment(phi.dest.unwrap(), Expression::Phi(lhs, rhs))
+				})
+				.collect();
+			statements.append(&mut block.statements);
+			block.statements = statements;
+			block
+		})
+		.collect();
+
+	ControlFlowGraph::from_blocks(blocks)
+}
+
+/*
+ * Orders the copies of a PHI so that no source is overwritten before
+ * being read, a temporary breaking the cycles
+ */
+fn sequentialize<VariableType: Clone + Ord>(
+	mut copies: Vec<(Versioned<VariableType>, Versioned<VariableType>)>,
+	next_version: &mut BTreeMap<VariableType, usize>,
+) -> Vec<Statement<Versioned<VariableType>>> {
+	let mut output = Vec::with_capacity(copies.len());
+	copies.retain(|(dest, src)| dest != src);
+
+	while !copies.is_empty() {
+		let ready = copies
+			.iter()
+			.position(|(dest, _)| !copies.iter().any(|(_, src)| src == dest));
+
+		match ready {
+			| Some(i) => {
+				let (dest, src) = copies.remove(i);
+				output.push(Statement::Assignment(dest, Expression::Variable(src)));
+			}
+			| None => {
+				// Every destination is still needed: save one of them
+				let saved = copies[0].0.clone();
+				let version = next_version.entry(saved.variable.clone()).or_insert(0);
+				*version += 1;
+				let temp = Versioned::new(saved.variable.clone(), *version);
+				output.push(Statement::Assignment(temp.clone(), Expression::Variable(saved.clone())));
+				for (_, src) in copies.iter_mut() {
+					if *src == saved {
+						*src = temp.clone();
+					}
+				}
+			}
+		}
+	}
+	output
+}
+
+/*
+ * Replaces every PHI by copies at the end of the predecessors. A new
+ * block is inserted on the edges leaving a block with a conditional
+ * terminator.
+ */
+pub fn destruct<VariableType: Clone + Ord>(
+	cfg: ControlFlowGraph<Versioned<VariableType>>,
+) -> ControlFlowGraph<Versioned<VariableType>> {
+	let predecessors: Vec<Vec<BlockId>> = (0..cfg.len()).map(|b| cfg.predecessors(b).to_vec()).collect();
+	let mut blocks = cfg.blocks;
+
+	let mut next_version: BTreeMap<VariableType, usize> = BTreeMap::new();
+	for block in blocks.iter() {
+		for stmt in block.statements.iter() {
+			if let Statement::Assignment(v, _) = stmt {
+				let version = next_version.entry(v.variable.clone()).or_insert(0);
+				*version = (*version).max(v.version);
+			}
+		}
+	}
+
+	for id in 0..blocks.len() {
+		let phi_count = blocks[id]
+			.statements
+			.iter()
+			.take_while(|stmt| matches!(stmt, Statement::Assignment(_, Expression::Phi(_, _))))
+			.count();
+		if phi_count == 0 {
+			continue;
+		}
+
+		let phis: Vec<_> = blocks[id].statements.drain(0..phi_count).collect();
+		for (idx, pred) in predecessors[id].iter().enumerate() {
+			let copies = phis
+				.iter()
+				.filter_map(|stmt| match stmt {
+					| Statement::Assignment(dest, Expression::Phi(lhs, rhs)) => {
+						let src = if idx == 0 { lhs } else { rhs };
+						src.clone().map(|src| (dest.clone(), src))
+					}
+					| _ => None,
+				})
+				.collect();
+			let copies = sequentialize(copies, &mut next_version);
+
+			if matches!(blocks[*pred].terminator, Terminator::Goto(_)) {
+				blocks[*pred].statements.extend(copies);
+			} else {
+				let new_id = blocks.len();
+				blocks[*pred].terminator.map_targets(|b| if b == id { new_id } else { b });
+				blocks.push(BasicBlock {
+					label: None,
+					statements: copies,
+					terminator: Terminator::Goto(id),
+				});
+			}
+		}
+	}
+
+	ControlFlowGraph::from_blocks(blocks)
+}
+
+/*
+ * Checks that every version is defined once, and that every use is
+ * dominated by its definition. The operands of a PHI are used at the
+ * end of the matching predecessor.
+ */
+pub fn verify<VariableType: Clone + Ord>(
+	cfg: &ControlFlowGraph<Versioned<VariableType>>,
+) -> Result<(), SsaError<VariableType>> {
+	let tree = cfg.dominator_tree();
+
+	let mut definitions: BTreeMap<Versioned<VariableType>, (BlockId, usize)> = BTreeMap::new();
+	for (id, block) in cfg.blocks.iter().enumerate() {
+		let mut in_phis = true;
+		for (idx, stmt) in block.statements.iter().enumerate() {
+			let is_phi = matches!(stmt, Statement::Assignment(_, Expression::Phi(_, _)));
+			if is_phi && (!in_phis || cfg.predecessors(id).len() != 2) {
+				return Err(SsaError::MisplacedPhi(id));
+			}
+			in_phis &= is_phi;
+
+			if let Statement::Assignment(v, _) = stmt {
+				if definitions.insert(v.clone(), (id, idx)).is_some() {
+					return Err(SsaError::MultipleDefinitions(v.clone()));
+				}
+			}
+		}
+	}
+
+	let check = |v: &Versioned<VariableType>, block: BlockId, idx: usize| match definitions.get(v) {
+		| None if v.version == 0 => Ok(()),
+		| None => Err(SsaError::UndefinedVariable(v.clone(), block)),
+		| Some((def_block, def_idx)) => {
+			let dominated = if *def_block == block {
+				*def_idx < idx
+			} else {
+				tree.dominates(*def_block, block)
+			};
+			if dominated {
+				Ok(())
+			} else {
+				Err(SsaError::UndominatedUse(v.clone(), block))
+			}
+		}
+	};
+
+	// Every use, with the block and the position where it happens
+	let mut uses = Vec::new();
+	for (id, block) in cfg.blocks.iter().enumerate() {
+		for (idx, stmt) in block.statements.iter().enumerate() {
+			match stmt {
+				| Statement::Assignment(_, Expression::Phi(lhs, rhs)) => {
+					for (operand, pred) in [lhs, rhs].into_iter().zip(cfg.predecessors(id)) {
+						if let Some(v) = operand {
+							uses.push((v, *pred, cfg.blocks[*pred].statements.len()));
+						}
+					}
+				}
+				| Statement::Assignment(_, e) | Statement::Expression(e) => {
+					for_each_variable(e, &mut |v| uses.push((v, id, idx)));
+				}
+				| _ => {}
+			}
+		}
+		if let Some(e) = terminator_expression(&block.terminator) {
+			let end = block.statements.len();
+			for_each_variable(e, &mut |v| uses.push((v, id, end)));
+		}
+	}
+
+	uses.into_iter().try_for_each(|(v, block, idx)| check(v, block, idx))
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeSet;
+
+use backend::ast::*;
+use backend::cfg::*;
+
+fn num(x: i32) -> Expression<usize> {
+	Expression::Constant(Constant::Value(x, Type::Number))
+}
+
+fn assign(var: usize, x: i32) -> Statement<usize> {
+	Statement::Assignment(var, num(x))
+}
+
+fn goto(name: &str) -> Statement<usize> {
+	Statement::Goto(name.to_string())
+}
+
+fn label(name: &str) -> Statement<usize> {
+	Statement::Label(name.to_string())
+}
+
+#[test]
+fn test_straight_line() {
+	let cfg = ControlFlowGraph::build(vec![assign(0, 1), Statement::NoOperation, assign(1, 2)]).unwrap();
+	assert_eq!(cfg.len(), 1);
+	assert_eq!(cfg.blocks[0].statements, vec![assign(0, 1), assign(1, 2)]);
+	assert_eq!(cfg.blocks[0].terminator, Terminator::Return(None));
+	assert_eq!(cfg.to_string(), "bb0:\n\t0 := 1\n\t1 := 2\n\treturn\n");
+}
+
+#[test]
+fn test_if_else() {
+	let cfg = ControlFlowGraph::build(vec![
+		Statement::IfElse(Expression::Variable(0), vec![assign(1, 1)], vec![assign(1, 2)]),
+		Statement::Return(Some(Expression::Variable(1))),
+	]).unwrap();
+	assert_eq!(
+		cfg.to_string(),
+		concat!(
+			"bb0:\n",
+			"\tif 0 then bb1 else bb2\n",
+			"bb1: ; preds bb0\n",
+			"\t1 := 1\n",
+			"\tgoto bb3\n",
+			"bb2: ; preds bb0\n",
+			"\t1 := 2\n",
+			"\tgoto bb3\n",
+			"bb3: ; preds bb1 bb2\n",
+			"\treturn 1\n",
+		)
+	);
+
+	let tree = cfg.dominator_tree();
+	assert_eq!(tree.immediate_dominator(0), None);
+	assert_eq!(tree.immediate_dominator(3), Some(0));
+	assert!(tree.dominates(0, 2));
+	assert!(!tree.dominates(1, 3));
+	assert_eq!(tree.children(0), vec![1, 2, 3]);
+
+	let frontiers = tree.dominance_frontiers(&cfg);
+	assert_eq!(frontiers[1], BTreeSet::from([3]));
+	assert_eq!(frontiers[2], BTreeSet::from([3]));
+	assert!(frontiers[0].is_empty());
+}
+
+#[test]
+fn test_labels_and_gotos() {
+	// A loop made of GOTO, with an exit through an IF
+	let cfg = ControlFlowGraph::build(vec![
+		assign(0, 0),
+		label("AGAIN"),
+		Statement::IfElse(Expression::Variable(0), vec![goto("DONE")], vec![]),
+		assign(0, 1),
+		goto("AGAIN"),
+		assign(0, 2),
+		label("DONE"),
+		Statement::Halt,
+	]).unwrap();
+	assert_eq!(
+		cfg.to_string(),
+		concat!(
+			"bb0:\n",
+			"\t0 := 0\n",
+			"\tgoto bb1\n",
+			"bb1 (AGAIN): ; preds bb0 bb5\n",
+			"\tif 0 then bb2 else bb3\n",
+			"bb2: ; preds bb1\n",
+			"\tgoto bb4\n",
+			"bb3: ; preds bb1\n",
+			"\tgoto bb5\n",
+			"bb4 (DONE): ; preds bb2\n",
+			"\tHALT\n",
+			"\treturn\n",
+			"bb5: ; preds bb3\n",
+			"\t0 := 1\n",
+			"\tgoto bb1\n",
+		)
+	);
+	assert_eq!(cfg.block_of_label("DONE"), Some(4));
+	assert_eq!(cfg.predecessors(1), &[0, 5]);
+	assert_eq!(cfg.successors(1), vec![2, 3]);
+
+	let tree = cfg.dominator_tree();
+	assert_eq!(tree.immediate_dominator(5), Some(3));
+	assert_eq!(tree.immediate_dominator(4), Some(2));
+	assert!(tree.dominates(1, 5));
+	assert_eq!(tree.dominance_frontiers(&cfg)[5], BTreeSet::from([1]));
+	assert_eq!(tree.dominance_frontiers(&cfg)[1], BTreeSet::from([1]));
+}
+
+#[test]
+fn test_loop_and_switch() {
+	let cfg = ControlFlowGraph::build(vec![Statement::Loop(vec![
+		Statement::Switch(
+			Expression::Variable(0),
+			vec![Some(assign(1, 0)), None, Some(Statement::Return(None))],
+		),
+		assign(1, 3),
+	])]).unwrap();
+	assert_eq!(
+		cfg.to_string(),
+		concat!(
+			"bb0:\n",
+			"\tgoto bb1\n",
+			"bb1: ; preds bb0 bb3\n",
+			"\tcase 0 of bb2 bb3 bb4 else bb3\n",
+			"bb2: ; preds bb1\n",
+			"\t1 := 0\n",
+			"\tgoto bb3\n",
+			"bb3: ; preds bb1 bb2\n",
+			"\t1 := 3\n",
+			"\tgoto bb1\n",
+			"bb4: ; preds bb1\n",
+			"\treturn\n",
+		)
+	);
+	assert_eq!(cfg.reverse_postorder()[0..2], [0, 1]);
+	assert_eq!(cfg.dominator_tree().immediate_dominator(3), Some(1));
+}
+
+#[test]
+fn test_indirect_jump_and_unreachable_code() {
+	let cfg = ControlFlowGraph::build(vec![
+		Statement::Jump(Expression::Variable(3)),
+		assign(0, 1),
+		Statement::Return(None),
+	]).unwrap();
+	assert_eq!(cfg.len(), 1);
+	assert_eq!(cfg.blocks[0].terminator, Terminator::IndirectJump(Expression::Variable(3)));
+	assert_eq!(cfg.to_string(), "bb0:\n\tgoto [3]\n");
+}
+
+#[test]
+fn test_non_local_goto() {
+	// The label is in an enclosing procedure
+	let cfg = ControlFlowGraph::build(vec![goto("OUTER"), assign(0, 1)]).unwrap();
+	assert_eq!(cfg.len(), 1);
+	assert_eq!(cfg.blocks[0].terminator, Terminator::NonLocalGoto("OUTER".to_string()));
+	assert_eq!(cfg.to_string(), "bb0:\n\tgoto OUTER\n");
+}
+
+#[test]
+fn test_duplicate_label() {
+	// Even in a nested block, and in a procedure of the program
+	let body = vec![
+		label("AGAIN"),
+		Statement::IfElse(Expression::Variable(0), vec![label("AGAIN")], vec![]),
+		goto("AGAIN"),
+	];
+	assert_eq!(
+		ControlFlowGraph::build(body.clone()),
+		Err(CfgError::DuplicateLabel("AGAIN".to_string()))
+	);
+	let program = vec![Statement::FunctionDefinition(
+		"F".to_string(),
+		Type::Void,
+		vec![],
+		None,
+		body,
+	)];
+	assert_eq!(
+		build_program(program),
+		Err(CfgError::DuplicateLabel("AGAIN".to_string()))
+	);
+
+	// The same label in two procedures is two labels
+	let procedure = |name: &str| {
+		Statement::FunctionDefinition(name.to_string(), Type::Void, vec![], None, vec![label("L")])
+	};
+	assert!(build_program(vec![procedure("F"), procedure("G")]).is_ok());
+}
+
+#[test]
+fn test_program() {
+	let program = vec![
+		Statement::FunctionDefinition(
+			"F".to_string(),
+			Type::Void,
+			vec![],
+			None,
+			vec![
+				Statement::FunctionDefinition(
+					"G".to_string(),
+					Type::Void,
+					vec![],
+					None,
+					vec![assign(2, 2)]
+				),
+				assign(1, 1),
+			],
+		),
+		Statement::Expression(Expression::FunctionCall("F".to_string(), vec![])),
+	];
+	let graphs = build_program(program).unwrap();
+	let names: Vec<Option<&str>> = graphs.iter().map(|(name, _)| name.as_deref()).collect();
+	assert_eq!(names, vec![None, Some("F"), Some("G")]);
+	assert_eq!(graphs[1].1.to_string(), "bb0:\n\t1 := 1\n\treturn\n");
+	assert_eq!(graphs[0].1.to_string(), "bb0:\n\tF()\n\treturn\n");
+}
@@ -0,0 +1,206 @@
+use backend::codegen::interrupt::{generate_procedure, InterruptOptions, RegisterSaving};
+use backend::codegen::runtime::{Routine, RuntimeLibrary};
+use backend::codegen::*;
+use z80::assembler::{Assembler, Target};
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+use z80::instruction::Condition;
+use z80::output;
+use z80::resolver::{Block, Linkage, Reference, Relocation, Segment};
+
+#[test]
+fn test_link_resolves_labels() {
+	let items = vec![
+		Item::LoadAddress(HL, "DATA".to_string()),
+		Item::Label("LOOP".to_string()),
+		Item::Instruction(DEC(ByteRegister(B))),
+		Item::JumpRelative(Some(Condition::NZ), "LOOP".to_string()),
+		Item::DecrementJumpNonZero("LOOP".to_string()),
+		Item::Call(None, "DATA".to_string()),
+		Item::Jump(Some(Condition::PE), "LOOP".to_string()),
+		Item::Label("DATA".to_string()),
+	];
+
+	let code = link(&items, 0x100).unwrap();
+	assert_eq!(code.labels["LOOP"], 0x103);
+	assert_eq!(code.labels["DATA"], 0x10E);
+	assert_eq!(code.size, 0x0E);
+	assert_eq!(
+		code.instructions,
+		vec![
+			LD(WordRegister(HL), Constant(0x10E)),
+			DEC(ByteRegister(B)),
+			JR(Some(Condition::NZ), Constant(-3)),
+			DJNZ(-5),
+			CALL(None, Constant(0x10E)),
+			JP(Some(Condition::PE), Constant(0x103)),
+		]
+	);
+}
+
+#[test]
+fn test_link_errors() {
+	assert_eq!(
+		link(&[Item::Jump(None, "NOWHERE".to_string())], 0),
+		Err(LinkError::UndefinedLabel("NOWHERE".to_string()))
+	);
+	assert_eq!(
+		link(&[Item::Label("TWICE".to_string()), Item::Label("TWICE".to_string())], 0),
+		Err(LinkError::DuplicateLabel("TWICE".to_string()))
+	);
+	assert_eq!(
+		link(&[Item::Instruction(LD(ByteRegister(A), Constant(1000)))], 0),
+		Err(LinkError::InvalidInstruction(LD(ByteRegister(A), Constant(1000))))
+	);
+
+	let mut items = vec![Item::JumpRelative(None, "FAR".to_string())];
+	items.extend((0..128).map(|_| Item::Instruction(NOP)));
+	items.push(Item::Label("FAR".to_string()));
+	assert_eq!(link(&items, 0), Err(LinkError::JumpOutOfRange("FAR".to_string(), 128)));
+}
+
+#[test]
+fn test_link_for_8080() {
+	let items = vec![
+		Item::Label("LOOP".to_string()),
+		Item::JumpRelative(Some(Condition::NZ), "LOOP".to_string()),
+		Item::DecrementJumpNonZero("LOOP".to_string()),
+		Item::JumpRelative(None, "END".to_string()),
+		Item::Label("END".to_string()),
+	];
+	let code = link_for_target(&items, 0x100, Target::I8080).unwrap();
+	assert_eq!(code.labels["END"], 0x10A);
+	assert_eq!(
+		code.instructions,
+		vec![
+			JP(Some(Condition::NZ), Constant(0x100)),
+			DEC(ByteRegister(B)),
+			JP(Some(Condition::NZ), Constant(0x100)),
+			JP(None, Constant(0x10A)),
+		]
+	);
+	assert_eq!(link_for_target(&items, 0x100, Target::Z80), link(&items, 0x100));
+
+	for inst in [EXX, LD(WordRegister(IX), Constant(0)), SLA(ByteRegister(A)), LDIR] {
+		assert_eq!(
+			link_for_target(&[Item::Instruction(inst.clone())], 0, Target::I8080),
+			Err(LinkError::InvalidInstruction(inst))
+		);
+	}
+	let items = [Item::LoadAddress(IY, "HERE".to_string()), Item::Label("HERE".to_string())];
+	assert_eq!(
+		link_for_target(&items, 0, Target::I8080),
+		Err(LinkError::InvalidInstruction(LD(WordRegister(IY), Constant(4))))
+	);
+
+	// The interrupt procedures can only save the registers on the stack
+	let options = InterruptOptions {
+		saving: RegisterSaving::ShadowRegisters,
+		..InterruptOptions::default()
+	};
+	assert!(link_for_target(&generate_procedure("P", vec![], &options), 0, Target::I8080).is_err());
+}
+
+#[test]
+fn test_link_for_z180() {
+	let items = vec![
+		Item::Label("LOOP".to_string()),
+		Item::Instruction(MLT(WordRegister(DE))),
+		Item::DecrementJumpNonZero("LOOP".to_string()),
+	];
+	let code = link_for_target(&items, 0x100, Target::Z180).unwrap();
+	assert_eq!(code.instructions, vec![MLT(WordRegister(DE)), DJNZ(-4)]);
+	assert_eq!(code.size, 4);
+
+	for target in [Target::Z80, Target::I8080] {
+		assert_eq!(
+			link_for_target(&items, 0x100, target),
+			Err(LinkError::InvalidInstruction(MLT(WordRegister(DE))))
+		);
+	}
+}
+
+#[test]
+fn test_link_relocatable() {
+	let items = vec![
+		Item::Label("MAIN".to_string()),
+		Item::LoadAddress(IX, "TABLE".to_string()),
+		Item::Call(None, "PUTCHAR".to_string()),
+		Item::JumpRelative(None, "MAIN".to_string()),
+		Item::Jump(Some(Condition::Z), "EXIT".to_string()),
+		Item::Label("TABLE".to_string()),
+	];
+	let module = link_relocatable(&items, &["MAIN".to_string()], Target::Z80).unwrap();
+	assert_eq!(
+		module.blocks,
+		vec![Block {
+			segment: Segment::Code,
+			address: 0,
+			bytes: vec![
+				0xDD, 0x21, 0x0C, 0x00, 0xCD, 0x00, 0x00, 0x18, 0xF7, 0xCA, 0x00, 0x00,
+			],
+		}]
+	);
+	let relocation = |address, reference| Relocation {
+		segment: Segment::Code,
+		address,
+		reference,
+	};
+	assert_eq!(
+		module.linkage,
+		Linkage {
+			relocations: vec![
+				relocation(0x02, Reference::Segment(Segment::Code)),
+				relocation(0x05, Reference::External("PUTCHAR".to_string())),
+				relocation(0x0A, Reference::External("EXIT".to_string())),
+			],
+			code_size: 0x0C,
+			publics: [("MAIN".to_string(), (Segment::Code, 0))].into(),
+			..Linkage::default()
+		}
+	);
+	assert_eq!(
+		module.rel("MAIN"),
+		output::rel("MAIN", &module.blocks, None, &module.linkage)
+	);
+
+	// An external is too far for a relative jump, and a PUBLIC must be defined
+	assert_eq!(
+		link_relocatable(&[Item::JumpRelative(None, "EXIT".to_string())], &[], Target::Z80),
+		Err(LinkError::UndefinedLabel("EXIT".to_string()))
+	);
+	assert_eq!(
+		link_relocatable(&[], &["MAIN".to_string()], Target::Z80),
+		Err(LinkError::UndefinedLabel("MAIN".to_string()))
+	);
+}
+
+#[test]
+fn test_8080_code_has_no_z80_instructions() {
+	let mut runtime = RuntimeLibrary::for_target(Target::I8080);
+	for routine in Routine::ALL {
+		runtime.reference(routine);
+	}
+	let mut items = generate_procedure(
+		"HANDLER",
+		vec![Item::Instruction(RET(Some(Condition::Z))), Item::Instruction(RET(None))],
+		&InterruptOptions::default(),
+	);
+	items.extend(runtime.items());
+
+	let code = link_for_target(&items, 0x100, Target::I8080).unwrap();
+	for inst in code.instructions {
+		let bytes: Vec<u8> = Assembler::new(std::iter::once(inst.clone()), false, false).collect();
+		let is_z80_only = matches!(
+			bytes[0],
+			0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xCB | 0xD9 | 0xDD | 0xED | 0xFD
+		);
+		assert!(
+			!is_z80_only,
+			"{} is only on the Z80",
+			inst
+		);
+	}
+}
@@ -0,0 +1,293 @@
+use backend::ast::*;
+use backend::optimization::constant_folding::*;
+use backend::typing::TypeCheckable;
+
+struct Environment {
+	types: Vec<Type>,
+}
+
+impl TypeCheckable<Environment> for usize {
+	fn get_type(&self, env: &Environment) -> Option<Type> {
+		env.types.get(*self).cloned()
+	}
+}
+
+static ENV: Environment = Environment { types: Vec::new() };
+
+fn num(x: i32) -> Expression<usize> {
+	Expression::Constant(Constant::Value(x, Type::Number))
+}
+
+fn value(x: i32, t: Type) -> Expression<usize> {
+	Expression::Constant(Constant::Value(x, t))
+}
+
+fn var(idx: usize) -> Expression<usize> {
+	Expression::Variable(idx)
+}
+
+fn op(op: BinaryOperation, lhs: Expression<usize>, rhs: Expression<usize>) -> Expression<usize> {
+	Expression::BinaryOp(op, Box::new(lhs), Box::new(rhs))
+}
+
+fn fold(e: Expression<usize>) -> Expression<usize> {
+	ConstantFolder::new(&ENV).fold_expression(e)
+}
+
+fn fold_program(program: Vec<Statement<usize>>) -> Vec<Statement<usize>> {
+	ConstantFolder::new(&ENV).fold(program)
+}
+
+#[test]
+fn test_byte_arithmetic_wraps() {
+	assert_eq!(fold(op(BinaryOperation::Add, num(200), num(100))), value(44, Type::U8));
+	assert_eq!(fold(op(BinaryOperation::Substract, num(5), num(10))), value(251, Type::U8));
+	assert_eq!(
+		fold(op(BinaryOperation::Add, value(127, Type::I8), value(1, Type::I8))),
+		value(-128, Type::I8)
+	);
+}
+
+#[test]
+fn test_address_arithmetic_wraps() {
+	assert_eq!(fold(op(BinaryOperation::Add, num(300), num(1))), value(301, Type::U16));
+	assert_eq!(fold(op(BinaryOperation::Add, num(0xFFFF), num(2))), value(1, Type::U16));
+	assert_eq!(
+		fold(op(BinaryOperation::Substract, value(0, Type::U16), num(1))),
+		value(0xFFFF, Type::U16)
+	);
+	assert_eq!(
+		fold(op(BinaryOperation::Multiply, num(0x100), num(0x100))),
+		value(0, Type::U16)
+	);
+	assert_eq!(
+		fold(op(BinaryOperation::Division, value(-7, Type::I16), num(2))),
+		value(-3, Type::I16)
+	);
+}
+
+#[test]
+fn test_nested_expressions() {
+	// (2 + 3) * 4 - X
+	let e = op(
+		BinaryOperation::Substract,
+		op(BinaryOperation::Multiply, op(BinaryOperation::Add, num(2), num(3)), num(4)),
+		var(0),
+	);
+	assert_eq!(fold(e), op(BinaryOperation::Substract, value(20, Type::U16), var(0)));
+}
+
+#[test]
+fn test_byte_products_are_addresses() {
+	assert_eq!(fold(op(BinaryOperation::Multiply, num(20), num(20))), value(400, Type::U16));
+	assert_eq!(fold(op(BinaryOperation::Multiply, num(16), num(16))), value(256, Type::U16));
+	assert_eq!(
+		fold(op(BinaryOperation::Division, value(200, Type::U8), num(3))),
+		value(66, Type::U16)
+	);
+	assert_eq!(fold(op(BinaryOperation::Modulo, num(200), num(7))), value(4, Type::U16));
+	assert_eq!(
+		fold(op(BinaryOperation::Multiply, value(-100, Type::I8), num(3))),
+		value(-300, Type::I16)
+	);
+	// The other operations stay within a BYTE
+	assert_eq!(fold(op(BinaryOperation::Add, num(200), num(200))), value(144, Type::U8));
+}
+
+#[test]
+fn test_operations_left_to_runtime() {
+	let e = op(BinaryOperation::Division, num(5), num(0));
+	assert_eq!(fold(e.clone()), e);
+	let e = op(BinaryOperation::Modulo, num(5), num(0));
+	assert_eq!(fold(e.clone()), e);
+	let e = op(BinaryOperation::AddWithCarry, num(5), num(1));
+	assert_eq!(fold(e.clone()), e);
+	let e = Expression::UnaryOp(UnaryOperation::Dereference, Box::new(num(5)));
+	assert_eq!(fold(e.clone()), e);
+}
+
+#[test]
+fn test_comparisons_and_logic() {
+	assert_eq!(fold(op(BinaryOperation::Less, num(3), num(5))), value(TRUE, Type::U8));
+	assert_eq!(fold(op(BinaryOperation::Equal, num(3), num(5))), value(FALSE, Type::U8));
+	assert_eq!(
+		fold(op(BinaryOperation::Less, value(-1, Type::I8), value(1, Type::I8))),
+		value(TRUE, Type::U8)
+	);
+	assert_eq!(
+		fold(op(BinaryOperation::Less, value(0xFF, Type::U8), value(1, Type::U8))),
+		value(FALSE, Type::U8)
+	);
+	assert_eq!(fold(op(BinaryOperation::Xor, num(0xF0), num(0xFF))), value(0x0F, Type::U8));
+	assert_eq!(
+		fold(Expression::UnaryOp(UnaryOperation::Not, Box::new(num(0x0F)))),
+		value(0xF0, Type::U8)
+	);
+	assert_eq!(
+		fold(Expression::UnaryOp(UnaryOperation::Not, Box::new(num(0x100)))),
+		value(0xFEFF, Type::U16)
+	);
+}
+
+#[test]
+fn test_shifts_and_rotations() {
+	assert_eq!(fold(op(BinaryOperation::ShiftLeft, num(0x81), num(1))), value(0x02, Type::U8));
+	assert_eq!(
+		fold(op(BinaryOperation::ShiftLeft, value(0x81, Type::U16), num(1))),
+		value(0x102, Type::U16)
+	);
+	assert_eq!(fold(op(BinaryOperation::ShiftRight, num(0x81), num(9))), value(0, Type::U8));
+	assert_eq!(fold(op(BinaryOperation::RotateLeft, num(0x81), num(1))), value(0x03, Type::U8));
+	assert_eq!(fold(op(BinaryOperation::RotateRight, num(0x81), num(1))), value(0xC0, Type::U8));
+	assert_eq!(
+		fold(op(BinaryOperation::RotateRight, value(0x0001, Type::U16), num(4))),
+		value(0x1000, Type::U16)
+	);
+}
+
+#[test]
+fn test_constant_conditions() {
+	let program = vec![
+		Statement::IfElse(
+			op(BinaryOperation::And, num(TRUE), num(1)),
+			vec![Statement::Halt],
+			vec![Statement::EnableInterrupt],
+		),
+		Statement::IfElse(num(0), vec![Statement::Halt], vec![]),
+		Statement::IfElse(var(0), vec![Statement::Halt], vec![]),
+	];
+	assert_eq!(
+		fold_program(program),
+		vec![Statement::Halt, Statement::IfElse(var(0), vec![Statement::Halt], vec![])]
+	);
+}
+
+#[test]
+fn test_constant_switch() {
+	let program = vec![Statement::Switch(
+		num(1),
+		vec![Some(Statement::Halt), Some(Statement::EnableInterrupt), None],
+	)];
+	assert_eq!(fold_program(program), vec![Statement::EnableInterrupt]);
+
+	let program = vec![Statement::Switch(num(5), vec![Some(Statement::Halt)])];
+	assert_eq!(fold_program(program.clone()), program);
+}
+
+#[test]
+fn test_propagation() {
+	let program = vec![
+		Statement::Assignment(0, num(10)),
+		Statement::Assignment(1, op(BinaryOperation::Add, var(0), num(1))),
+		Statement::Expression(Expression::FunctionCall("OUTPUT".to_string(), vec![var(1)])),
+		Statement::Return(Some(var(0))),
+	];
+	assert_eq!(
+		fold_program(program),
+		vec![
+			Statement::Assignment(0, num(10)),
+			Statement::Assignment(1, value(11, Type::U8)),
+			Statement::Expression(Expression::FunctionCall(
+				"OUTPUT".to_string(),
+				vec![value(11, Type::U8)]
+			)),
+			// The call may have modified the variable
+			Statement::Return(Some(var(0))),
+		]
+	);
+}
+
+#[test]
+fn test_propagation_stops_at_labels_and_loops() {
+	let program = vec![
+		Statement::Assignment(0, num(1)),
+		Statement::Label("AGAIN".to_string()),
+		Statement::Return(Some(var(0))),
+	];
+	assert_eq!(fold_program(program.clone()), program);
+
+	let program = vec![
+		Statement::Assignment(0, num(1)),
+		Statement::Loop(vec![
+			Statement::Expression(var(0)),
+			Statement::Assignment(0, num(2)),
+		]),
+	];
+	assert_eq!(fold_program(program.clone()), program);
+}
+
+#[test]
+fn test_propagation_through_branches() {
+	let program = vec![
+		Statement::Assignment(0, num(1)),
+		Statement::Assignment(1, num(1)),
+		Statement::IfElse(
+			var(2),
+			vec![Statement::Assignment(1, num(2))],
+			vec![Statement::Expression(var(1))],
+		),
+		Statement::Return(Some(op(BinaryOperation::Add, var(0), var(1)))),
+	];
+	assert_eq!(
+		fold_program(program),
+		vec![
+			Statement::Assignment(0, num(1)),
+			Statement::Assignment(1, num(1)),
+			Statement::IfElse(
+				var(2),
+				vec![Statement::Assignment(1, num(2))],
+				vec![Statement::Expression(num(1))],
+			),
+			Statement::Return(Some(op(BinaryOperation::Add, num(1), var(1)))),
+		]
+	);
+}
+
+#[test]
+fn test_escaped_variables_are_not_propagated() {
+	let program = vec![
+		Statement::Assignment(0, num(1)),
+		Statement::Expression(Expression::UnaryOp(UnaryOperation::Reference, Box::new(var(0)))),
+		Statement::Return(Some(var(0))),
+	];
+	assert_eq!(fold_program(program.clone()), program);
+}
+
+#[test]
+fn test_propagated_values_are_truncated() {
+	let env = Environment {
+		types: vec![Type::Pointer(Box::new(Type::U8)), Type::U16],
+	};
+	let program = vec![
+		Statement::Assignment(0, num(300)),
+		Statement::Assignment(1, num(300)),
+		Statement::Assignment(2, num(300)),
+		Statement::Return(Some(op(
+			BinaryOperation::Add,
+			op(BinaryOperation::Add, var(0), var(1)),
+			var(2),
+		))),
+	];
+	assert_eq!(
+		ConstantFolder::new(&env).fold(program)[3],
+		Statement::Return(Some(op(BinaryOperation::Add, value(344, Type::U16), var(2))))
+	);
+}
+
+#[test]
+fn test_length_and_last() {
+	let array = Expression::Constant(Constant::ReadOnlyArray(vec![1, 2, 3], Type::U8));
+	let length = Expression::FunctionCall("LENGTH".to_string(), vec![array.clone()]);
+	let last = Expression::FunctionCall("LAST".to_string(), vec![array]);
+	assert_eq!(fold(length), value(3, Type::U16));
+	assert_eq!(fold(last), value(2, Type::U16));
+
+	let mut folder = ConstantFolder::new(&ENV);
+	folder.set_array_length(4, 128);
+	assert_eq!(
+		folder.fold_expression(Expression::FunctionCall("LAST".to_string(), vec![var(4)])),
+		value(127, Type::U16)
+	);
+	let unknown = Expression::FunctionCall("LENGTH".to_string(), vec![var(5)]);
+	assert_eq!(folder.fold_expression(unknown.clone()), unknown);
+}
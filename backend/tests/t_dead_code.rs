@@ -0,0 +1,289 @@
+use std::collections::BTreeSet;
+
+use backend::ast::*;
+use backend::optimization::dead_code::*;
+
+fn call(name: &str) -> Statement<usize> {
+	Statement::Expression(Expression::FunctionCall(name.to_string(), vec![]))
+}
+
+fn procedure(name: &str, body: Vec<Statement<usize>>) -> Statement<usize> {
+	Statement::FunctionDefinition(name.to_string(), Type::Void, vec![], None, body)
+}
+
+fn label(name: &str) -> Statement<usize> {
+	Statement::Label(name.to_string())
+}
+
+// The target of a jump doesn't matter here
+fn goto() -> Statement<usize> {
+	Statement::Jump(Expression::Constant(Constant::Value(0, Type::U16)))
+}
+
+fn data(var: usize) -> Statement<usize> {
+	Statement::Assignment(var, Expression::Constant(Constant::ReadOnlyArray(vec![1, 2], Type::U8)))
+}
+
+fn eliminate(program: Vec<Statement<usize>>) -> (Vec<Statement<usize>>, Vec<DeadCodeWarning<usize>>) {
+	let mut eliminator = DeadCodeEliminator::new(DeadCodeOptions::default());
+	let program = eliminator.eliminate(program);
+	(program, eliminator.warnings().to_vec())
+}
+
+#[test]
+fn test_code_after_return() {
+	let (program, warnings) = eliminate(vec![
+		Statement::Return(None),
+		Statement::NoOperation,
+		Statement::EnableInterrupt,
+		label("NEXT"),
+		Statement::Halt,
+	]);
+	assert_eq!(program, vec![Statement::Return(None), label("NEXT"), Statement::Halt]);
+	assert_eq!(
+		warnings,
+		vec![DeadCodeWarning::UnreachableStatements {
+			procedure: None,
+			count: 2
+		}]
+	);
+}
+
+#[test]
+fn test_code_after_goto() {
+	let (program, _) = eliminate(vec![
+		goto(),
+		Statement::NoOperation,
+		Statement::Block(vec![Statement::NoOperation, label("LOOP")]),
+		Statement::NoOperation,
+	]);
+	// The block holds a label, so it is kept
+	assert_eq!(
+		program,
+		vec![
+			goto(),
+			Statement::Block(vec![Statement::NoOperation, label("LOOP")]),
+			Statement::NoOperation,
+		]
+	);
+}
+
+#[test]
+fn test_code_after_halt() {
+	// An interrupt may resume the execution after HALT
+	let program = vec![Statement::Halt, Statement::NoOperation];
+	assert_eq!(eliminate(program.clone()).0, program);
+
+	let (program, _) = eliminate(vec![
+		Statement::DisableInterrupt,
+		Statement::Halt,
+		Statement::NoOperation,
+	]);
+	assert_eq!(program, vec![Statement::DisableInterrupt, Statement::Halt]);
+
+	let program = vec![
+		Statement::DisableInterrupt,
+		call("ENABLE"),
+		Statement::Halt,
+		Statement::NoOperation,
+		procedure("ENABLE", vec![Statement::EnableInterrupt]),
+	];
+	assert_eq!(eliminate(program.clone()).0, program);
+}
+
+#[test]
+fn test_code_after_if_else() {
+	let (program, warnings) = eliminate(vec![procedure(
+		"P",
+		vec![
+			Statement::IfElse(
+				Expression::Variable(0),
+				vec![Statement::Return(None)],
+				vec![goto()],
+			),
+			Statement::NoOperation,
+			label("OUT"),
+		],
+	)]);
+	assert_eq!(
+		warnings,
+		vec![
+			DeadCodeWarning::UnreachableStatements {
+				procedure: Some("P".to_string()),
+				count: 1
+			},
+			DeadCodeWarning::UnusedProcedure("P".to_string())
+		]
+	);
+	assert_eq!(program, vec![]);
+
+	let program = vec![
+		Statement::IfElse(Expression::Variable(0), vec![Statement::Return(None)], vec![]),
+		Statement::NoOperation,
+	];
+	assert_eq!(eliminate(program.clone()).0, program);
+}
+
+#[test]
+fn test_unused_procedures() {
+	let (program, warnings) = eliminate(vec![
+		procedure("USED", vec![call("HELPER")]),
+		procedure("HELPER", vec![]),
+		procedure("RECURSIVE", vec![call("RECURSIVE")]),
+		procedure("UNUSED", vec![call("HELPER"), procedure("NESTED", vec![])]),
+		call("USED"),
+	]);
+	assert_eq!(
+		program,
+		vec![
+			procedure("USED", vec![call("HELPER")]),
+			procedure("HELPER", vec![]),
+			call("USED"),
+		]
+	);
+	assert_eq!(
+		warnings,
+		vec![
+			DeadCodeWarning::UnusedProcedure("RECURSIVE".to_string()),
+			DeadCodeWarning::UnusedProcedure("NESTED".to_string()),
+			DeadCodeWarning::UnusedProcedure("UNUSED".to_string()),
+		]
+	);
+}
+
+#[test]
+fn test_calls_from_unreachable_code() {
+	let (program, _) = eliminate(vec![
+		procedure("P", vec![]),
+		Statement::Return(None),
+		call("P"),
+	]);
+	assert_eq!(program, vec![Statement::Return(None)]);
+}
+
+#[test]
+fn test_goto_is_not_a_call() {
+	let (program, warnings) = eliminate(vec![
+		procedure("P", vec![]),
+		Statement::Goto("P".to_string()),
+		Statement::Halt,
+		label("P"),
+	]);
+	assert_eq!(program, vec![Statement::Goto("P".to_string()), label("P")]);
+	assert_eq!(
+		warnings,
+		vec![
+			DeadCodeWarning::UnreachableStatements {
+				procedure: None,
+				count: 1
+			},
+			DeadCodeWarning::UnusedProcedure("P".to_string()),
+		]
+	);
+}
+
+#[test]
+fn test_kept_procedures() {
+	let mut eliminator = DeadCodeEliminator::new(DeadCodeOptions {
+		enabled: true,
+		kept_procedures: BTreeSet::from(["TIMER".to_string()]),
+	});
+	let program = vec![procedure("TIMER", vec![call("TICK")]), procedure("TICK", vec![])];
+	assert_eq!(eliminator.eliminate(program.clone()), program);
+	assert!(eliminator.warnings().is_empty());
+}
+
+#[test]
+fn test_do_case() {
+	// DO CASE X; DO; P: PROCEDURE; CALL HELPER; END P; DATA; END; ...
+	let switch = |cases: Vec<Option<Statement<usize>>>| {
+		Statement::Switch(Expression::Constant(Constant::Value(0, Type::U8)), cases)
+	};
+	let (program, warnings) = eliminate(vec![
+		switch(vec![
+			Some(Statement::Block(vec![
+				procedure("P", vec![call("HELPER")]),
+				procedure("UNUSED", vec![]),
+				data(0),
+			])),
+			None,
+			Some(procedure("ALONE", vec![])),
+			Some(data(1)),
+		]),
+		procedure("HELPER", vec![]),
+		call("P"),
+	]);
+	assert_eq!(
+		program,
+		vec![
+			switch(vec![
+				Some(Statement::Block(vec![procedure("P", vec![call("HELPER")])])),
+				None,
+				Some(Statement::Block(vec![])),
+				Some(Statement::Block(vec![])),
+			]),
+			procedure("HELPER", vec![]),
+			call("P"),
+		]
+	);
+	assert_eq!(
+		warnings,
+		vec![
+			DeadCodeWarning::UnusedProcedure("UNUSED".to_string()),
+			DeadCodeWarning::UnusedProcedure("ALONE".to_string()),
+			DeadCodeWarning::UnreferencedData(0),
+			DeadCodeWarning::UnreferencedData(1),
+		]
+	);
+}
+
+#[test]
+fn test_interrupt_procedures() {
+	// Nothing calls an interrupt handler, the hardware does
+	let timer = Statement::FunctionDefinition(
+		"TIMER".to_string(),
+		Type::Void,
+		vec![],
+		Some(2),
+		vec![call("TICK")],
+	);
+	let (program, warnings) = eliminate(vec![
+		timer.clone(),
+		procedure("TICK", vec![]),
+		procedure("UNUSED", vec![]),
+	]);
+	assert_eq!(program, vec![timer, procedure("TICK", vec![])]);
+	assert_eq!(warnings, vec![DeadCodeWarning::UnusedProcedure("UNUSED".to_string())]);
+}
+
+#[test]
+fn test_unreferenced_data() {
+	let (program, warnings) = eliminate(vec![
+		data(0),
+		data(1),
+		procedure("UNUSED", vec![Statement::Expression(Expression::Variable(1))]),
+		data(2),
+		Statement::Return(Some(Expression::Variable(2))),
+	]);
+	assert_eq!(program, vec![data(2), Statement::Return(Some(Expression::Variable(2)))]);
+	assert_eq!(
+		warnings,
+		vec![
+			DeadCodeWarning::UnusedProcedure("UNUSED".to_string()),
+			DeadCodeWarning::UnreferencedData(0),
+			DeadCodeWarning::UnreferencedData(1),
+		]
+	);
+	assert_eq!(warnings[2].to_string(), "The DATA 1 is never used, it has been removed");
+}
+
+#[test]
+fn test_disabled() {
+	let mut eliminator = DeadCodeEliminator::new(DeadCodeOptions {
+		enabled: false,
+		..Default::default()
+	});
+	let program = vec![Statement::Return(None), procedure("P", vec![]), data(0)];
+	assert_eq!(eliminator.eliminate(program.clone()), program);
+	assert!(eliminator.warnings().is_empty());
+}
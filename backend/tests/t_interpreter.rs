@@ -0,0 +1,536 @@
+use std::cell::RefCell;
+
+use backend::ast::*;
+use backend::interpreter::*;
+use backend::typing::TypeCheckable;
+
+struct Environment {
+	types: Vec<Type>,
+}
+
+impl TypeCheckable<Environment> for usize {
+	fn get_type(&self, env: &Environment) -> Option<Type> {
+		env.types.get(*self).cloned()
+	}
+}
+
+// As declared by the frontend
+fn byte() -> Type {
+	Type::Pointer(Box::new(Type::U8))
+}
+
+fn address() -> Type {
+	Type::Pointer(Box::new(Type::U16))
+}
+
+fn num(x: i32) -> Expression<usize> {
+	Expression::Constant(Constant::Value(x, Type::Number))
+}
+
+fn var(idx: usize) -> Expression<usize> {
+	Expression::Variable(idx)
+}
+
+fn op(op: BinaryOperation, lhs: Expression<usize>, rhs: Expression<usize>) -> Expression<usize> {
+	Expression::BinaryOp(op, Box::new(lhs), Box::new(rhs))
+}
+
+fn reference(e: Expression<usize>) -> Expression<usize> {
+	Expression::UnaryOp(UnaryOperation::Reference, Box::new(e))
+}
+
+fn call(name: &str, args: Vec<Expression<usize>>) -> Expression<usize> {
+	Expression::FunctionCall(name.to_string(), args)
+}
+
+fn call_statement(name: &str, args: Vec<Expression<usize>>) -> Statement<usize> {
+	Statement::Expression(call(name, args))
+}
+
+fn assign(idx: usize, e: Expression<usize>) -> Statement<usize> {
+	Statement::Assignment(idx, e)
+}
+
+fn goto(name: &str) -> Statement<usize> {
+	Statement::Goto(name.to_string())
+}
+
+fn label(name: &str) -> Statement<usize> {
+	Statement::Label(name.to_string())
+}
+
+fn procedure(
+	name: &str,
+	t: Type,
+	parameters: &[(usize, Type)],
+	body: Vec<Statement<usize>>,
+) -> Statement<usize> {
+	let parameters = parameters
+		.iter()
+		.map(|(v, t)| Variable::new(*v, t.clone()))
+		.collect();
+	Statement::FunctionDefinition(name.to_string(), t, parameters, None, body)
+}
+
+fn text(s: &str) -> Vec<i32> {
+	s.bytes().map(|b| b as i32).collect()
+}
+
+#[test]
+fn test_wrap_around() {
+	let env = Environment {
+		types: vec![byte(), address(), byte(), address()],
+	};
+	let mut interpreter = Interpreter::new(&env);
+	let program = vec![
+		assign(0, op(BinaryOperation::Add, num(250), num(10))),
+		// Two BYTE constants give a BYTE
+		assign(1, op(BinaryOperation::Substract, num(0), num(1))),
+		assign(3, op(BinaryOperation::Substract, var(3), num(1))),
+		// Stored in a BYTE, only the low part is kept
+		assign(2, op(BinaryOperation::Add, var(1), num(3))),
+	];
+	assert_eq!(interpreter.run(&program), Ok(Exit::Finished));
+	assert_eq!(interpreter.read_variable(&0), Some(4));
+	assert_eq!(interpreter.read_variable(&1), Some(0xFF));
+	assert_eq!(interpreter.read_variable(&2), Some(2));
+	assert_eq!(interpreter.read_variable(&3), Some(0xFFFF));
+}
+
+#[test]
+fn test_byte_products() {
+	let env = Environment {
+		types: vec![byte(), address(), byte(), address()],
+	};
+	let mut interpreter = Interpreter::new(&env);
+	let program = vec![
+		assign(0, num(20)),
+		// The product of two BYTEs is an ADDRESS
+		assign(1, op(BinaryOperation::Multiply, var(0), var(0))),
+		assign(2, op(BinaryOperation::Multiply, var(0), var(0))),
+		assign(3, op(BinaryOperation::Multiply, num(0xFF), num(0xFF))),
+	];
+	assert_eq!(interpreter.run(&program), Ok(Exit::Finished));
+	assert_eq!(interpreter.read_variable(&1), Some(400));
+	assert_eq!(interpreter.read_variable(&2), Some(400 & 0xFF));
+	assert_eq!(interpreter.read_variable(&3), Some(0xFE01));
+}
+
+#[test]
+fn test_carry() {
+	let env = Environment {
+		types: vec![byte(), byte(), byte()],
+	};
+	let mut interpreter = Interpreter::new(&env);
+	// A 16 bits addition made of two BYTE
+	let program = vec![
+		assign(0, op(BinaryOperation::Add, num(0xF0), num(0x20))),
+		assign(1, op(BinaryOperation::AddWithCarry, num(0x01), num(0x02))),
+		assign(2, call("CARRY", vec![])),
+	];
+	assert_eq!(interpreter.run(&program), Ok(Exit::Finished));
+	assert_eq!(interpreter.read_variable(&0), Some(0x10));
+	assert_eq!(interpreter.read_variable(&1), Some(0x04));
+	assert_eq!(interpreter.read_variable(&2), Some(0x00));
+
+	assert_eq!(interpreter.call("ZERO", &[]), Ok(Some(0x00)));
+	assert_eq!(interpreter.call("CARRY", &[]), Ok(Some(0x00)));
+}
+
+#[test]
+fn test_memory() {
+	let env = Environment {
+		types: vec![address(), byte(), byte()],
+	};
+	let mut interpreter = Interpreter::new(&env);
+	interpreter.place(0, 0xFFFF);
+	let program = vec![
+		assign(0, num(0x1234)),
+		assign(
+			1,
+			Expression::UnaryOp(UnaryOperation::Dereference, Box::new(num(0))),
+		),
+		assign(2, call("HIGH", vec![var(0)])),
+	];
+	assert_eq!(interpreter.run(&program), Ok(Exit::Finished));
+	// The word is split at the end of the memory
+	assert_eq!(interpreter.memory().read_byte(0xFFFF), 0x34);
+	assert_eq!(interpreter.memory().read_byte(0x0000), 0x12);
+	assert_eq!(interpreter.memory().read_word(0xFFFF), 0x1234);
+	assert_eq!(interpreter.read_variable(&1), Some(0x12));
+	assert_eq!(interpreter.read_variable(&2), Some(0x12));
+	assert_eq!(interpreter.address_of(&1), Some(DEFAULT_ORIGIN));
+}
+
+#[test]
+fn test_arrays() {
+	let env = Environment {
+		types: vec![byte(), address(), byte(), byte()],
+	};
+	let mut interpreter = Interpreter::new(&env);
+	interpreter.set_array_length(3, 4);
+	let program = vec![
+		assign(
+			0,
+			Expression::Constant(Constant::ReadOnlyArray(text("ABC"), Type::Number)),
+		),
+		assign(
+			1,
+			op(
+				BinaryOperation::Add,
+				call("LENGTH", vec![var(0)]),
+				call("LAST", vec![var(3)]),
+			),
+		),
+		call_statement("MOVE", vec![num(3), reference(var(0)), reference(var(3))]),
+		assign(
+			2,
+			Expression::UnaryOp(
+				UnaryOperation::Dereference,
+				Box::new(op(BinaryOperation::Add, reference(var(3)), num(2))),
+			),
+		),
+	];
+	assert_eq!(interpreter.run(&program), Ok(Exit::Finished));
+	assert_eq!(interpreter.read_variable(&1), Some(6));
+	assert_eq!(interpreter.read_variable(&2), Some('C' as i32));
+	assert_eq!(interpreter.address_of(&3), Some(DEFAULT_ORIGIN + 3 + 2));
+}
+
+#[test]
+fn test_procedures() {
+	let env = Environment {
+		types: vec![address(), byte(), address()],
+	};
+	let mut interpreter = Interpreter::new(&env);
+	// A BYTE procedure truncates what it returns
+	let program = vec![
+		procedure(
+			"TWICE",
+			byte(),
+			&[(0, address())],
+			vec![Statement::Return(Some(op(
+				BinaryOperation::Multiply,
+				var(0),
+				num(2),
+			)))],
+		),
+		procedure("NOTHING", Type::Void, &[], vec![]),
+		assign(1, call("TWICE", vec![num(0x90)])),
+		assign(2, call("TWICE", vec![num(0x90)])),
+		call_statement("NOTHING", vec![]),
+	];
+	assert_eq!(interpreter.run(&program), Ok(Exit::Finished));
+	assert_eq!(interpreter.read_variable(&1), Some(0x20));
+	assert_eq!(interpreter.read_variable(&2), Some(0x20));
+	assert_eq!(interpreter.call("TWICE", &[3]), Ok(Some(6)));
+	assert_eq!(interpreter.call("NOTHING", &[]), Ok(None));
+	assert_eq!(
+		interpreter.call("TWICE", &[]),
+		Err(InterpreterError::WrongArgumentCount("TWICE".to_string()))
+	);
+	assert_eq!(
+		interpreter.run(&[assign(1, call("NOTHING", vec![]))]),
+		Err(InterpreterError::NoReturnValue("NOTHING".to_string()))
+	);
+}
+
+#[test]
+fn test_ports() {
+	let env = Environment {
+		types: vec![byte()],
+	};
+	let written = RefCell::new(Vec::new());
+	let mut interpreter = Interpreter::new(&env);
+	interpreter.set_input_handler(|port| port + 1);
+	interpreter.set_output_handler(|port, value| written.borrow_mut().push((port, value)));
+
+	// Copies the port 4 to the port 8, three times
+	let program = vec![Statement::Loop(vec![
+		assign(0, op(BinaryOperation::Add, var(0), num(1))),
+		call_statement("OUTPUT", vec![num(8), call("INPUT", vec![num(4)])]),
+		Statement::IfElse(
+			op(BinaryOperation::Equal, var(0), num(3)),
+			vec![Statement::Halt],
+			vec![],
+		),
+	])];
+	assert_eq!(interpreter.run(&program), Ok(Exit::Halted));
+	assert_eq!(*written.borrow(), vec![(8, 5), (8, 5), (8, 5)]);
+}
+
+#[test]
+fn test_jumps() {
+	let env = Environment {
+		types: vec![byte()],
+	};
+	let characters = RefCell::new(String::new());
+	let mut interpreter = Interpreter::new(&env);
+	interpreter.place(0, 0x0080);
+	interpreter.define_routine(0x3809, |memory| {
+		characters
+			.borrow_mut()
+			.push(memory.read_byte(0x0080) as char)
+	});
+	// The routine returns in place of the procedure
+	let program = vec![
+		procedure(
+			"PRINT$CHAR",
+			Type::Void,
+			&[(0, byte())],
+			vec![Statement::Jump(num(0x3809)), Statement::Halt],
+		),
+		call_statement("PRINT$CHAR", vec![num('O' as i32)]),
+		call_statement("PRINT$CHAR", vec![num('K' as i32)]),
+		goto("END"),
+		call_statement("PRINT$CHAR", vec![num('!' as i32)]),
+		label("END"),
+	];
+	assert_eq!(interpreter.run(&program), Ok(Exit::Finished));
+	assert_eq!(*characters.borrow(), "OK");
+
+	assert_eq!(
+		interpreter.run(&[Statement::Jump(num(0))]),
+		Err(InterpreterError::UnhandledJump(0))
+	);
+	assert_eq!(
+		interpreter.run(&[goto("NOWHERE")]),
+		Err(InterpreterError::UndefinedLabel("NOWHERE".to_string()))
+	);
+}
+
+#[test]
+fn test_errors() {
+	let env = Environment {
+		types: vec![byte()],
+	};
+	let mut interpreter = Interpreter::new(&env);
+	assert_eq!(
+		interpreter.run(&[assign(0, op(BinaryOperation::Division, num(1), var(0)))]),
+		Err(InterpreterError::DivisionByZero)
+	);
+	assert_eq!(
+		interpreter.run(&[assign(1, num(0))]),
+		Err(InterpreterError::UntypedVariable(1))
+	);
+	assert_eq!(
+		interpreter.run(&[call_statement("PRINT", vec![])]),
+		Err(InterpreterError::UndefinedProcedure("PRINT".to_string()))
+	);
+
+	interpreter.set_step_limit(100);
+	assert_eq!(
+		interpreter.run(&[Statement::Loop(vec![])]),
+		Err(InterpreterError::StepLimitReached)
+	);
+}
+
+/*
+ * test.plm, as the frontend would give it. PRINT$STRING is provided by
+ * the host, and the stores in TEMP are done with MOVE.
+ */
+#[test]
+fn test_square_roots() {
+	use BinaryOperation::*;
+
+	const X: usize = 0;
+	const Y: usize = 1;
+	const Z: usize = 2;
+	const CHAR: usize = 3;
+	const NUMBER: usize = 4;
+	const BASE: usize = 5;
+	const CHARS: usize = 6;
+	const ZERO_SUPPRESS: usize = 7;
+	const I: usize = 8;
+	const J: usize = 9;
+	const TEMP: usize = 10;
+	const MAIN_I: usize = 11;
+	const HEADING: usize = 12;
+
+	let mut types = vec![address(), address(), address(), byte(), address()];
+	types.extend(vec![byte(); 6]);
+	types.extend(vec![address(), byte()]);
+	let env = Environment { types };
+
+	let square_root = procedure(
+		"SQUARE$ROOT",
+		byte(),
+		&[(X, address())],
+		vec![
+			assign(Y, var(X)),
+			assign(Z, op(ShiftRight, op(Add, var(X), num(1)), num(1))),
+			label("WHILE"),
+			Statement::IfElse(
+				op(NotEqual, var(Y), var(Z)),
+				vec![
+					assign(Y, var(Z)),
+					assign(
+						Z,
+						op(
+							ShiftRight,
+							op(Add, op(Add, op(Division, var(X), var(Y)), var(Y)), num(1)),
+							num(1),
+						),
+					),
+					goto("WHILE"),
+				],
+				vec![],
+			),
+			Statement::Return(Some(var(Y))),
+		],
+	);
+
+	let print_char = procedure(
+		"PRINT$CHAR",
+		Type::Void,
+		&[(CHAR, byte())],
+		vec![Statement::Jump(num(0x3809))],
+	);
+
+	let print_number = procedure(
+		"PRINT$NUMBER",
+		Type::Void,
+		&[
+			(NUMBER, address()),
+			(BASE, byte()),
+			(CHARS, byte()),
+			(ZERO_SUPPRESS, byte()),
+		],
+		vec![
+			Statement::IfElse(
+				op(Greater, var(CHARS), call("LAST", vec![var(TEMP)])),
+				vec![assign(CHARS, call("LAST", vec![var(TEMP)]))],
+				vec![],
+			),
+			assign(I, num(1)),
+			label("DO"),
+			Statement::IfElse(op(Greater, var(I), var(CHARS)), vec![goto("END")], vec![]),
+			assign(
+				J,
+				op(Add, op(Modulo, var(NUMBER), var(BASE)), num('0' as i32)),
+			),
+			Statement::IfElse(
+				op(Greater, var(J), num('9' as i32)),
+				vec![assign(J, op(Add, var(J), num(7)))],
+				vec![],
+			),
+			Statement::IfElse(
+				op(
+					And,
+					op(And, var(ZERO_SUPPRESS), op(NotEqual, var(I), num(1))),
+					op(Equal, var(NUMBER), num(0)),
+				),
+				vec![assign(J, num(' ' as i32))],
+				vec![],
+			),
+			call_statement(
+				"MOVE",
+				vec![
+					num(1),
+					reference(var(J)),
+					op(
+						Substract,
+						op(Add, reference(var(TEMP)), call("LENGTH", vec![var(TEMP)])),
+						var(I),
+					),
+				],
+			),
+			assign(NUMBER, op(Division, var(NUMBER), var(BASE))),
+			assign(I, op(Add, var(I), num(1))),
+			goto("DO"),
+			label("END"),
+			call_statement(
+				"PRINT$STRING",
+				vec![
+					op(
+						Substract,
+						op(Add, reference(var(TEMP)), call("LENGTH", vec![var(TEMP)])),
+						var(CHARS),
+					),
+					var(CHARS),
+				],
+			),
+		],
+	);
+
+	let mut heading = text("\r\n\n\n                        TABLE OF SQUARE ROOTS\r\n\n");
+	heading.extend(text(
+		" VALUE  ROOT VALUE  ROOT VALUE  ROOT VALUE  ROOT VALUE  ROOT\r\n\n",
+	));
+
+	let program = vec![
+		square_root,
+		print_char,
+		print_number,
+		assign(
+			HEADING,
+			Expression::Constant(Constant::ReadOnlyArray(heading, Type::Number)),
+		),
+		assign(MAIN_I, num(1)),
+		label("DO"),
+		Statement::IfElse(
+			op(Greater, var(MAIN_I), num(1000)),
+			vec![goto("END")],
+			vec![],
+		),
+		Statement::IfElse(
+			op(Equal, op(Modulo, var(MAIN_I), num(5)), num(1)),
+			vec![Statement::IfElse(
+				op(Equal, op(Modulo, var(MAIN_I), num(250)), num(1)),
+				vec![call_statement(
+					"PRINT$STRING",
+					vec![reference(var(HEADING)), call("LENGTH", vec![var(HEADING)])],
+				)],
+				vec![call_statement(
+					"PRINT$STRING",
+					vec![
+						reference(Expression::Constant(Constant::Array(
+							vec![0x0D, 0x0A],
+							Type::Number,
+						))),
+						num(2),
+					],
+				)],
+			)],
+			vec![],
+		),
+		call_statement("PRINT$NUMBER", vec![var(MAIN_I), num(10), num(6), num(1)]),
+		call_statement(
+			"PRINT$NUMBER",
+			vec![
+				call("SQUARE$ROOT", vec![var(MAIN_I)]),
+				num(10),
+				num(6),
+				num(1),
+			],
+		),
+		assign(MAIN_I, op(Add, var(MAIN_I), num(1))),
+		goto("DO"),
+		label("END"),
+	];
+
+	let output = RefCell::new(String::new());
+	let mut interpreter = Interpreter::new(&env);
+	interpreter.set_array_length(TEMP, 16);
+	interpreter.define_external("PRINT$STRING", |args, memory| {
+		for i in 0..args[1] as u16 {
+			output
+				.borrow_mut()
+				.push(memory.read_byte(args[0] as u16 + i) as char);
+		}
+		None
+	});
+	assert_eq!(interpreter.run(&program), Ok(Exit::Finished));
+
+	let output = output.borrow();
+	assert!(output
+		.starts_with("\r\n\n\n                        TABLE OF SQUARE ROOTS\r\n\n VALUE  ROOT"));
+	// The roots are rounded to the nearest
+	assert!(output.contains(
+		"ROOT\r\n\n     1     1     2     2     3     2     4     2     5     2\r\n     6     3"
+	));
+	assert!(output.contains("\r\n   121    11   122    11"));
+	assert!(output.ends_with("   996    32   997    32   998    32   999    32  1000    32"));
+	assert_eq!(output.matches("TABLE OF SQUARE ROOTS").count(), 4);
+	assert_eq!(output.matches("\r\n").count(), 4 * 3 + 200 - 4);
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+
+use backend::codegen::interrupt::*;
+use backend::codegen::{link, link_module, link_program, Item, LinkError, ProgramError, Procedure};
+use z80::assembler::{Assembler, Target};
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::{Condition, Instruction};
+use z80::resolver::{Block, Reference, Relocation, Segment};
+
+fn assemble(code: Vec<Instruction<u8, u16, i32, i8>>) -> Vec<u8> {
+	let mut assembler = Assembler::new(code.into_iter(), false, false);
+	let output = assembler.by_ref().collect();
+	assert!(!assembler.has_error_occured());
+	output
+}
+
+#[test]
+fn test_stack_prologue_and_epilogue() {
+	let options = InterruptOptions::default();
+	assert_eq!(assemble(prologue(&options)), vec![0xF5, 0xC5, 0xD5, 0xE5]);
+	assert_eq!(assemble(epilogue(&options)), vec![0xE1, 0xD1, 0xC1, 0xF1, 0xFB, 0xC9]);
+}
+
+#[test]
+fn test_shadow_registers_prologue_and_epilogue() {
+	let options = InterruptOptions {
+		saving: RegisterSaving::ShadowRegisters,
+		save_index_registers: false,
+		use_reti: true,
+	};
+	assert_eq!(assemble(prologue(&options)), vec![0x08, 0xD9]);
+	assert_eq!(assemble(epilogue(&options)), vec![0xD9, 0x08, 0xFB, 0xED, 0x4D]);
+}
+
+#[test]
+fn test_index_registers_are_saved() {
+	let options = InterruptOptions {
+		saving: RegisterSaving::ShadowRegisters,
+		save_index_registers: true,
+		use_reti: false,
+	};
+	assert_eq!(assemble(prologue(&options)), vec![0x08, 0xD9, 0xDD, 0xE5, 0xFD, 0xE5]);
+	assert_eq!(
+		assemble(epilogue(&options)),
+		vec![0xFD, 0xE1, 0xDD, 0xE1, 0xD9, 0x08, 0xFB, 0xC9]
+	);
+}
+
+#[test]
+fn test_returns_go_through_the_epilogue() {
+	let body = vec![
+		Item::Instruction(LD(ByteRegister(A), Constant(1))),
+		Item::Instruction(RET(Some(Condition::Z))),
+		Item::Instruction(OUT(Port(0x10), ByteRegister(A))),
+		Item::Instruction(RET(None)),
+	];
+	let items = generate_procedure("TICK", body, &InterruptOptions::default());
+	let code = link(&items, 0x100).unwrap();
+
+	assert_eq!(code.labels["TICK"], 0x100);
+	let exit = code.labels["TICK$EXIT"];
+	assert_eq!(
+		assemble(code.instructions),
+		vec![
+			0xF5, 0xC5, 0xD5, 0xE5, // Prologue
+			0x3E, 0x01, // LD A,1
+			0xCA, exit as u8, (exit >> 8) as u8, // JP Z,exit
+			0xD3, 0x10, // OUT (10H),A
+			0xC3, exit as u8, (exit >> 8) as u8, // JP exit
+			0xE1, 0xD1, 0xC1, 0xF1, 0xFB, 0xC9 // Epilogue
+		]
+	);
+}
+
+#[test]
+fn test_vector_placement() {
+	let mut table = VectorTable::new();
+	assert!(table.is_empty());
+	table.assign(1, "SERIAL").unwrap();
+	table.assign(7, "TIMER").unwrap();
+	assert_eq!(table.handler(7), Some(&"TIMER".to_string()));
+
+	let labels = BTreeMap::from([("SERIAL".to_string(), 0x1234), ("TIMER".to_string(), 0x0200)]);
+	assert_eq!(
+		table.link(&labels),
+		Ok(vec![
+			(0x08, JP(None, Constant(0x1234))),
+			(0x38, JP(None, Constant(0x0200)))
+		])
+	);
+}
+
+#[test]
+fn test_conflicting_vectors() {
+	let mut table = VectorTable::new();
+	table.assign(2, "FIRST").unwrap();
+	assert_eq!(
+		table.assign(2, "SECOND"),
+		Err(InterruptError::ConflictingVector {
+			vector: 2,
+			first: "FIRST".to_string(),
+			second: "SECOND".to_string()
+		})
+	);
+	assert_eq!(table.assign(8, "THIRD"), Err(InterruptError::VectorOutOfRange(8)));
+	assert_eq!(table.assign(-1, "THIRD"), Err(InterruptError::VectorOutOfRange(-1)));
+}
+
+#[test]
+fn test_undefined_handler() {
+	let mut table = VectorTable::new();
+	table.assign(0, "MISSING").unwrap();
+	assert_eq!(
+		table.link(&BTreeMap::new()),
+		Err(InterruptError::UndefinedHandler("MISSING".to_string()))
+	);
+}
+
+#[test]
+fn test_code_overlapping_vectors() {
+	let mut table = VectorTable::new();
+	table.assign(2, "HANDLER").unwrap();
+	assert_eq!(table.check_overlap(0x100, 0x200), Ok(()));
+	assert_eq!(table.check_overlap(0x00, 0x10), Ok(()));
+	assert_eq!(table.check_overlap(0x13, 0x10), Ok(()));
+	assert_eq!(
+		table.check_overlap(0x10, 0x10),
+		Err(InterruptError::OverlappingVector {
+			vector: 2,
+			address: 0x10
+		})
+	);
+	assert_eq!(
+		table.check_overlap(0x0C, 0x10),
+		Err(InterruptError::OverlappingVector {
+			vector: 2,
+			address: 0x10
+		})
+	);
+}
+
+#[test]
+fn test_link_program() {
+	let procedures = vec![
+		Procedure {
+			name: "MAIN".to_string(),
+			interrupt: None,
+			body: vec![Item::Instruction(NOP), Item::Instruction(RET(None))],
+		},
+		Procedure {
+			name: "TIMER".to_string(),
+			interrupt: Some(7),
+			body: vec![Item::Call(None, "MAIN".to_string()), Item::Instruction(RET(None))],
+		},
+	];
+	let program = link_program(&procedures, 0x100, &InterruptOptions::default()).unwrap();
+	assert_eq!(program.vectors, vec![(0x38, JP(None, Constant(0x102)))]);
+	assert_eq!(program.code.labels["TIMER$EXIT"], 0x10C);
+	assert_eq!(
+		assemble(program.code.instructions),
+		vec![
+			0x00, 0xC9, // MAIN
+			0xF5, 0xC5, 0xD5, 0xE5, 0xCD, 0x00, 0x01, 0xC3, 0x0C, 0x01, // TIMER
+			0xE1, 0xD1, 0xC1, 0xF1, 0xFB, 0xC9
+		]
+	);
+
+	// As a module, the code starts at 0 and the vector is absolute
+	let module = link_module(&procedures, &InterruptOptions::default(), Target::Z80).unwrap();
+	assert_eq!(
+		module.blocks[1],
+		Block {
+			segment: Segment::Absolute,
+			address: 0x38,
+			bytes: vec![0xC3, 0x02, 0x00],
+		}
+	);
+	assert_eq!(
+		module.linkage.relocations.last(),
+		Some(&Relocation {
+			segment: Segment::Absolute,
+			address: 0x39,
+			reference: Reference::Segment(Segment::Code),
+		})
+	);
+	assert_eq!(
+		module.linkage.publics.keys().collect::<Vec<_>>(),
+		vec!["MAIN", "TIMER"]
+	);
+
+	// Lowered and checked for the target, like with link_for_target
+	let mut looping = procedures.clone();
+	looping[0].body.insert(0, Item::Label("LOOP".to_string()));
+	looping[0].body.insert(1, Item::DecrementJumpNonZero("LOOP".to_string()));
+	let module = link_module(&looping, &InterruptOptions::default(), Target::I8080).unwrap();
+	assert_eq!(module.blocks[0].bytes[..4], [0x05, 0xC2, 0x00, 0x00]);
+	assert_eq!(
+		module.linkage.relocations[0],
+		Relocation {
+			segment: Segment::Code,
+			address: 0x02,
+			reference: Reference::Segment(Segment::Code),
+		}
+	);
+	let shadow = InterruptOptions {
+		saving: RegisterSaving::ShadowRegisters,
+		..InterruptOptions::default()
+	};
+	assert!(matches!(
+		link_module(&procedures, &shadow, Target::I8080),
+		Err(ProgramError::Link(LinkError::InvalidInstruction(_)))
+	));
+
+	// The vectors are checked like those assigned by hand
+	let mut overlapping = procedures.clone();
+	overlapping[1].interrupt = Some(0);
+	assert_eq!(
+		link_program(&overlapping, 0, &InterruptOptions::default()),
+		Err(ProgramError::Interrupt(InterruptError::OverlappingVector {
+			vector: 0,
+			address: 0
+		}))
+	);
+	let mut conflicting = procedures;
+	conflicting[0].interrupt = Some(7);
+	assert_eq!(
+		link_program(&conflicting, 0x100, &InterruptOptions::default()),
+		Err(ProgramError::Interrupt(InterruptError::ConflictingVector {
+			vector: 7,
+			first: "MAIN".to_string(),
+			second: "TIMER".to_string()
+		}))
+	);
+}
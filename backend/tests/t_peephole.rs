@@ -0,0 +1,231 @@
+use backend::codegen::peephole::*;
+use backend::codegen::Item;
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+use z80::instruction::{Condition, Instruction};
+
+fn ops(code: Vec<Instruction<u8, u16, i32, i8>>) -> Vec<Item> {
+	code.into_iter().map(Item::Instruction).collect()
+}
+
+fn label(name: &str) -> Item {
+	Item::Label(name.to_string())
+}
+
+#[test]
+fn test_no_optimization() {
+	let items = ops(vec![
+		PUSH(WordRegister(HL)),
+		POP(WordRegister(HL)),
+		LD(ByteRegister(A), Constant(0)),
+		RET(None),
+	]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::None), items);
+}
+
+#[test]
+fn test_push_pop_same_register() {
+	let items = ops(vec![
+		LD(ByteRegister(A), ByteRegister(B)),
+		PUSH(WordRegister(BC)),
+		POP(WordRegister(BC)),
+		RET(None),
+	]);
+	assert_eq!(
+		optimize(items, OptimizationLevel::Basic),
+		ops(vec![LD(ByteRegister(A), ByteRegister(B)), RET(None)])
+	);
+}
+
+#[test]
+fn test_push_pop_separated_by_a_label() {
+	let items = vec![
+		Item::Instruction(PUSH(WordRegister(HL))),
+		label("L"),
+		Item::Instruction(POP(WordRegister(HL))),
+	];
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+}
+
+#[test]
+fn test_push_pop_different_registers() {
+	let items = ops(vec![PUSH(WordRegister(HL)), POP(WordRegister(DE))]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Basic), items);
+	assert_eq!(
+		optimize(items, OptimizationLevel::Full),
+		ops(vec![LD(ByteRegister(D), ByteRegister(H)), LD(ByteRegister(E), ByteRegister(L))])
+	);
+
+	let items = ops(vec![PUSH(WordRegister(IX)), POP(WordRegister(HL))]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+}
+
+#[test]
+fn test_redundant_store() {
+	let items = ops(vec![
+		LD(ByteRegister(A), Address(0x1234)),
+		LD(Address(0x1234), ByteRegister(A)),
+	]);
+	assert_eq!(
+		optimize(items, OptimizationLevel::Basic),
+		ops(vec![LD(ByteRegister(A), Address(0x1234))])
+	);
+
+	let items = ops(vec![
+		LD(Address(0x4000), WordRegister(HL)),
+		LD(WordRegister(HL), Address(0x4000)),
+	]);
+	assert_eq!(
+		optimize(items, OptimizationLevel::Basic),
+		ops(vec![LD(Address(0x4000), WordRegister(HL))])
+	);
+}
+
+#[test]
+fn test_store_to_a_different_address() {
+	let items = ops(vec![
+		LD(ByteRegister(A), Address(0x1234)),
+		LD(Address(0x1235), ByteRegister(A)),
+	]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+
+	// The first load changes the address
+	let items = ops(vec![
+		LD(ByteRegister(L), AddressRegister(HL)),
+		LD(AddressRegister(HL), ByteRegister(L)),
+	]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+
+	// R changes between the two reads
+	let items = ops(vec![LD(ByteRegister(A), R), LD(R, ByteRegister(A))]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+}
+
+#[test]
+fn test_self_load_and_double_exchange() {
+	let items = ops(vec![
+		LD(ByteRegister(C), ByteRegister(C)),
+		EX(WordRegister(DE), WordRegister(HL)),
+		EX(WordRegister(DE), WordRegister(HL)),
+		INC(ByteRegister(C)),
+	]);
+	assert_eq!(optimize(items, OptimizationLevel::Basic), ops(vec![INC(ByteRegister(C))]));
+}
+
+#[test]
+fn test_jump_to_next_instruction() {
+	let items = vec![
+		Item::Jump(None, "NEXT".to_string()),
+		label("OTHER"),
+		label("NEXT"),
+		Item::JumpRelative(Some(Condition::Z), "END".to_string()),
+		label("END"),
+		Item::Instruction(RET(None)),
+	];
+	assert_eq!(
+		optimize(items, OptimizationLevel::Basic),
+		vec![label("OTHER"), label("NEXT"), label("END"), Item::Instruction(RET(None))]
+	);
+
+	let items = vec![
+		Item::Jump(None, "FAR".to_string()),
+		Item::Instruction(NOP),
+		label("FAR"),
+	];
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+}
+
+#[test]
+fn test_zero_a_with_dead_flags() {
+	let items = ops(vec![LD(ByteRegister(A), Constant(0)), OR(ByteRegister(B)), RET(None)]);
+	assert_eq!(
+		optimize(items, OptimizationLevel::Full),
+		ops(vec![XOR(ByteRegister(A)), OR(ByteRegister(B)), RET(None)])
+	);
+}
+
+#[test]
+fn test_zero_a_with_live_flags() {
+	// The carry of the CP is used after the load
+	let items = ops(vec![
+		CP(ByteRegister(B)),
+		LD(ByteRegister(A), Constant(0)),
+		RET(Some(Condition::C)),
+	]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+
+	// INC doesn't write the carry, which is read by ADC
+	let items = ops(vec![
+		LD(ByteRegister(A), Constant(0)),
+		INC(ByteRegister(B)),
+		ADC(ByteRegister(A), ByteRegister(C)),
+	]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+
+	// The flags may be read by the caller
+	let items = ops(vec![LD(ByteRegister(A), Constant(0)), RET(None)]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+
+	// Or by the code at the target of the jump
+	let items = vec![
+		Item::Instruction(LD(ByteRegister(A), Constant(0))),
+		Item::Jump(None, "SOMEWHERE".to_string()),
+	];
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+
+	// Only done in Full
+	let items = ops(vec![LD(ByteRegister(A), Constant(0)), OR(ByteRegister(B))]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Basic), items);
+}
+
+#[test]
+fn test_increment_with_dead_carry() {
+	let items = ops(vec![
+		ADD(ByteRegister(A), Constant(1)),
+		SUB(Constant(1)),
+		INC(ByteRegister(B)),
+		CP(ByteRegister(B)),
+		RET(Some(Condition::C)),
+	]);
+	assert_eq!(
+		optimize(items, OptimizationLevel::Full),
+		ops(vec![
+			INC(ByteRegister(A)),
+			DEC(ByteRegister(A)),
+			INC(ByteRegister(B)),
+			CP(ByteRegister(B)),
+			RET(Some(Condition::C)),
+		])
+	);
+
+	let items = ops(vec![ADD(ByteRegister(A), Constant(1)), RET(Some(Condition::NC))]);
+	assert_eq!(optimize(items.clone(), OptimizationLevel::Full), items);
+}
+
+#[test]
+fn test_patterns_are_applied_until_fixed_point() {
+	// Removing the inner pair makes the outer one adjacent
+	let items = ops(vec![
+		PUSH(WordRegister(AF)),
+		PUSH(WordRegister(BC)),
+		POP(WordRegister(BC)),
+		POP(WordRegister(AF)),
+		HALT,
+	]);
+	assert_eq!(optimize(items, OptimizationLevel::Basic), ops(vec![HALT]));
+}
+
+#[test]
+fn test_flag_liveness() {
+	assert!(are_flags_dead(&ops(vec![XOR(ByteRegister(A))]), ALL_FLAGS));
+	assert!(are_flags_dead(&ops(vec![INC(ByteRegister(A)), SCF]), ALL_FLAGS));
+	assert!(!are_flags_dead(&ops(vec![INC(ByteRegister(A)), RLA]), ALL_FLAGS));
+	assert!(!are_flags_dead(&ops(vec![PUSH(WordRegister(AF))]), FLAG_Z));
+	assert!(are_flags_dead(&ops(vec![RLA, CP(Constant(3))]), FLAG_Z));
+	assert!(!are_flags_dead(&ops(vec![NOP]), FLAG_C));
+	assert_eq!(flag_effects(&ADD(WordRegister(HL), WordRegister(DE))), (0, FLAG_H | FLAG_N | FLAG_C));
+	assert_eq!(flag_effects(&INC(WordRegister(HL))), (0, 0));
+	assert_eq!(flag_effects(&JP(Some(Condition::PE), Constant(0))), (FLAG_PV, 0));
+}
@@ -0,0 +1,236 @@
+use backend::ast::{BinaryOperation, Type};
+use backend::codegen::runtime::*;
+use emulator::bus::Memory;
+use emulator::cpu::{Cpu, FLAG_C, FLAG_Z};
+use z80::assembler::{Assembler, Target};
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+
+// Where the routines return to, out of the runtime
+const RETURN_ADDRESS: u16 = 0xFFFF;
+
+// The 8080 code runs on the emulated Z80 as well
+const TARGETS: [Target; 2] = [Target::Z80, Target::I8080];
+
+/*
+ * Runs a routine of the runtime, linked at 0, with the registers set by
+ * `setup`.
+ */
+fn call(routine: Routine, target: Target, setup: impl FnOnce(&mut Cpu)) -> Cpu {
+	let (runtime, entry) = link(routine, target);
+	let mut assembler =
+		Assembler::new(runtime.instructions.into_iter(), false, false).with_target(target);
+	let code: Vec<u8> = assembler.by_ref().collect();
+	assert!(!assembler.has_error_occured());
+
+	let mut memory = Memory::new();
+	memory.load(0, &code);
+	memory.load(0xFFF0, &RETURN_ADDRESS.to_le_bytes());
+	let mut cpu = Cpu::new(false);
+	cpu.registers.sp = 0xFFF0;
+	cpu.registers.pc = entry;
+	setup(&mut cpu);
+	cpu.run_until(&mut memory, RETURN_ADDRESS, 100_000)
+		.expect("The routine doesn't terminate");
+	cpu
+}
+
+const BYTE_SAMPLES: [u8; 12] = [0, 1, 2, 3, 7, 10, 16, 99, 127, 128, 200, 255];
+const WORD_SAMPLES: [u16; 16] = [
+	0, 1, 2, 3, 10, 255, 256, 1000, 0x1234, 0x7FFF, 0x8000, 0x8001, 0xABCD, 0xFF00, 0xFFFE, 0xFFFF,
+];
+
+fn link(routine: Routine, target: Target) -> (LinkedRuntime, u16) {
+	let mut library = RuntimeLibrary::for_target(target);
+	library.reference(routine);
+	let runtime = library.link(0);
+	let entry = runtime.addresses[&routine];
+	(runtime, entry)
+}
+
+fn run8(routine: Routine, target: Target, a: u8, e: u8) -> u16 {
+	call(routine, target, |cpu| (cpu.registers.a, cpu.registers.e) = (a, e)).registers.hl()
+}
+
+fn run16(routine: Routine, target: Target, hl: u16, de: u16) -> Cpu {
+	call(routine, target, |cpu| {
+		cpu.registers.set_hl(hl);
+		cpu.registers.set_de(de);
+	})
+}
+
+#[test]
+fn test_multiply8() {
+	for target in TARGETS {
+		for a in BYTE_SAMPLES {
+			for e in BYTE_SAMPLES {
+				let product = a as u16 * e as u16;
+				assert_eq!(run8(Routine::Multiply8, target, a, e), product, "{} * {}", a, e);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_divide8() {
+	for target in TARGETS {
+		for a in BYTE_SAMPLES {
+			for e in BYTE_SAMPLES.iter().filter(|e| **e != 0) {
+				assert_eq!(run8(Routine::Divide8, target, a, *e), (a / e) as u16, "{} / {}", a, e);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_modulo8() {
+	for target in TARGETS {
+		for a in BYTE_SAMPLES {
+			for e in BYTE_SAMPLES.iter().filter(|e| **e != 0) {
+				assert_eq!(run8(Routine::Modulo8, target, a, *e), (a % e) as u16, "{} MOD {}", a, e);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_multiply16() {
+	for target in TARGETS {
+		for hl in WORD_SAMPLES {
+			for de in WORD_SAMPLES {
+				let m = run16(Routine::Multiply16, target, hl, de);
+				assert_eq!(m.registers.hl(), hl.wrapping_mul(de), "{} * {}", hl, de);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_divide16() {
+	for target in TARGETS {
+		for hl in WORD_SAMPLES {
+			for de in WORD_SAMPLES.iter().filter(|de| **de != 0) {
+				let m = run16(Routine::Divide16, target, hl, *de);
+				assert_eq!(m.registers.hl(), hl / de, "{} / {}", hl, de);
+				assert_eq!(m.registers.de(), hl % de, "{} MOD {}", hl, de);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_modulo16() {
+	for target in TARGETS {
+		for hl in WORD_SAMPLES {
+			for de in WORD_SAMPLES.iter().filter(|de| **de != 0) {
+				let m = run16(Routine::Modulo16, target, hl, *de);
+				assert_eq!(m.registers.hl(), hl % de, "{} MOD {}", hl, de);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_division_by_zero() {
+	for target in TARGETS {
+		assert_eq!(run8(Routine::Divide8, target, 42, 0), 0xFF);
+		assert_eq!(run8(Routine::Modulo8, target, 42, 0), 42);
+
+		let m = run16(Routine::Divide16, target, 1234, 0);
+		assert_eq!(m.registers.hl(), 0xFFFF);
+		assert_eq!(m.registers.de(), 1234);
+	}
+}
+
+#[test]
+fn test_compare16() {
+	for target in TARGETS {
+		for hl in WORD_SAMPLES {
+			for de in WORD_SAMPLES {
+				let m = run16(Routine::Compare16, target, hl, de);
+				assert_eq!(m.registers.f & FLAG_Z != 0, hl == de, "{} = {}", hl, de);
+				assert_eq!(m.registers.f & FLAG_C != 0, hl < de, "{} < {}", hl, de);
+				assert_eq!(m.registers.hl(), hl);
+				assert_eq!(m.registers.de(), de);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_only_referenced_routines_are_emitted() {
+	let library = RuntimeLibrary::new();
+	assert!(library.is_empty());
+	assert_eq!(library.link(0x100).instructions, vec![]);
+
+	let mut library = RuntimeLibrary::new();
+	assert_eq!(
+		library.reference_operation(&BinaryOperation::Multiply, &Type::U16),
+		Some(Routine::Multiply16)
+	);
+	assert_eq!(library.reference_operation(&BinaryOperation::Add, &Type::U16), None);
+	assert_eq!(library.reference_operation(&BinaryOperation::Less, &Type::U8), None);
+	assert_eq!(library.routines().collect::<Vec<_>>(), vec![&Routine::Multiply16]);
+
+	let runtime = library.link(0x100);
+	assert_eq!(runtime.addresses.len(), 1);
+	assert_eq!(runtime.addresses[&Routine::Multiply16], 0x100);
+	assert_eq!(runtime.size, Routine::Multiply16.size());
+}
+
+#[test]
+fn test_dependencies_are_linked() {
+	let mut library = RuntimeLibrary::new();
+	library.reference_operation(&BinaryOperation::Modulo, &Type::U8);
+	assert!(library.is_referenced(Routine::Modulo8));
+	assert!(library.is_referenced(Routine::DivideModulo8));
+	assert!(!library.is_referenced(Routine::Divide8));
+
+	let runtime = library.link(0x200);
+	let bytes: Vec<u8> = Assembler::new(runtime.instructions.into_iter(), false, false).collect();
+	assert_eq!(bytes.len(), runtime.size as usize);
+	assert_eq!(
+		runtime.size,
+		Routine::Modulo8.size() + Routine::DivideModulo8.size()
+	);
+}
+
+fn dependencies_size(routine: Routine, target: Target) -> u16 {
+	routine.dependencies().iter().map(|x| x.size_for_target(target)).sum()
+}
+
+#[test]
+fn test_every_routine_assembles() {
+	for target in TARGETS.into_iter().chain([Target::Z180]) {
+		for routine in Routine::ALL {
+			let (runtime, _) = link(routine, target);
+			let mut assembler =
+				Assembler::new(runtime.instructions.into_iter(), false, false).with_target(target);
+			let bytes: Vec<u8> = assembler.by_ref().collect();
+			assert!(!assembler.has_error_occured(), "{} doesn't assemble", routine.name());
+			assert_eq!(bytes.len(), runtime.size as usize);
+			let size = routine.size_for_target(target) + dependencies_size(routine, target);
+			assert_eq!(runtime.size, size);
+		}
+	}
+}
+
+// The emulator has no MLT, the other routines are those of the Z80
+#[test]
+fn test_z180_multiplies_with_mlt() {
+	let (runtime, entry) = link(Routine::Multiply8, Target::Z180);
+	assert_eq!(
+		runtime.instructions[entry as usize..],
+		[
+			LD(ByteRegister(H), ByteRegister(A)),
+			LD(ByteRegister(L), ByteRegister(E)),
+			MLT(WordRegister(HL)),
+			RET(None),
+		]
+	);
+	for routine in Routine::ALL.into_iter().filter(|x| *x != Routine::Multiply8) {
+		assert_eq!(link(routine, Target::Z180), link(routine, Target::Z80));
+	}
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeSet;
+
+use backend::ast::*;
+use backend::cfg::*;
+use backend::ssa::*;
+
+fn num(x: i32) -> Expression<usize> {
+	Expression::Constant(Constant::Value(x, Type::Number))
+}
+
+fn var(idx: usize) -> Expression<usize> {
+	Expression::Variable(idx)
+}
+
+fn assign(idx: usize, e: Expression<usize>) -> Statement<usize> {
+	Statement::Assignment(idx, e)
+}
+
+fn add(lhs: Expression<usize>, rhs: Expression<usize>) -> Expression<usize> {
+	Expression::BinaryOp(BinaryOperation::Add, Box::new(lhs), Box::new(rhs))
+}
+
+fn goto(name: &str) -> Statement<usize> {
+	Statement::Goto(name.to_string())
+}
+
+fn label(name: &str) -> Statement<usize> {
+	Statement::Label(name.to_string())
+}
+
+fn ssa(program: Vec<Statement<usize>>) -> ControlFlowGraph<Versioned<usize>> {
+	construct(ControlFlowGraph::build(program).unwrap(), &BTreeSet::new())
+}
+
+fn v(variable: usize, version: usize) -> Versioned<usize> {
+	Versioned::new(variable, version)
+}
+
+#[test]
+fn test_if_else_join() {
+	let cfg = ssa(vec![
+		Statement::IfElse(var(0), vec![assign(1, num(1))], vec![assign(1, num(2))]),
+		Statement::Return(Some(var(1))),
+	]);
+	assert_eq!(
+		cfg.to_string(),
+		concat!(
+			"bb0:\n",
+			"\tif 0_0 then bb1 else bb2\n",
+			"bb1: ; preds bb0\n",
+			"\t1_1 := 1\n",
+			"\tgoto bb3\n",
+			"bb2: ; preds bb0\n",
+			"\t1_2 := 2\n",
+			"\tgoto bb3\n",
+			"bb3: ; preds bb1 bb2\n",
+			"\t1_3 := PHI(1_1, 1_2)\n",
+			"\treturn 1_3\n",
+		)
+	);
+	assert_eq!(verify(&cfg), Ok(()));
+}
+
+#[test]
+fn test_loop() {
+	let cfg = ssa(vec![
+		assign(0, num(0)),
+		label("AGAIN"),
+		Statement::IfElse(var(1), vec![goto("DONE")], vec![]),
+		assign(0, add(var(0), num(1))),
+		goto("AGAIN"),
+		label("DONE"),
+		Statement::Return(Some(var(0))),
+	]);
+	assert_eq!(
+		cfg.to_string(),
+		concat!(
+			"bb0:\n",
+			"\t0_1 := 0\n",
+			"\tgoto bb1\n",
+			"bb1 (AGAIN): ; preds bb0 bb5\n",
+			"\t0_2 := PHI(0_1, 0_3)\n",
+			"\tif 1_0 then bb2 else bb3\n",
+			"bb2: ; preds bb1\n",
+			"\tgoto bb4\n",
+			"bb3: ; preds bb1\n",
+			"\tgoto bb5\n",
+			"bb4 (DONE): ; preds bb2\n",
+			"\treturn 0_2\n",
+			"bb5: ; preds bb3\n",
+			"\t0_3 := (0_2 + 1)\n",
+			"\tgoto bb1\n",
+		)
+	);
+	assert_eq!(verify(&cfg), Ok(()));
+}
+
+#[test]
+fn test_join_of_three_blocks() {
+	let cfg = ssa(vec![
+		Statement::Switch(
+			var(0),
+			vec![Some(assign(1, num(1))), Some(assign(1, num(2))), Some(assign(1, num(3)))],
+		),
+		Statement::Return(Some(var(1))),
+	]);
+	assert!(cfg.blocks.iter().enumerate().all(|(id, _)| cfg.predecessors(id).len() <= 2));
+	assert_eq!(verify(&cfg), Ok(()));
+
+	let phis: Vec<&Statement<Versioned<usize>>> = cfg
+		.blocks
+		.iter()
+		.flat_map(|b| b.statements.iter())
+		.filter(|stmt| matches!(stmt, Statement::Assignment(_, Expression::Phi(_, _))))
+		.collect();
+	// The default case keeps the value from the entry
+	assert_eq!(phis.len(), 3);
+	assert_eq!(
+		cfg.blocks[4].statements[0],
+		Statement::Assignment(v(1, 4), Expression::Phi(Some(v(1, 5)), Some(v(1, 6))))
+	);
+}
+
+#[test]
+fn test_limit_predecessors() {
+	let cfg = ControlFlowGraph::build(vec![Statement::Switch(
+		var(0),
+		vec![Some(assign(1, num(1))), Some(assign(1, num(2))), Some(assign(1, num(3)))],
+	)]).unwrap();
+	assert_eq!(cfg.predecessors(4), &[0, 1, 2, 3]);
+
+	let cfg = limit_predecessors(cfg);
+	assert_eq!(cfg.len(), 7);
+	assert_eq!(cfg.predecessors(4), &[5, 6]);
+	assert_eq!(cfg.predecessors(5), &[0, 1]);
+	assert_eq!(cfg.predecessors(6), &[2, 3]);
+	assert_eq!(cfg.blocks[0].terminator, Terminator::Switch(var(0), vec![1, 2, 3], 5));
+}
+
+#[test]
+fn test_memory_variables_are_not_renamed() {
+	let program = vec![
+		assign(0, num(1)),
+		Statement::Expression(Expression::FunctionCall(
+			"F".to_string(),
+			vec![Expression::UnaryOp(UnaryOperation::Reference, Box::new(var(0)))],
+		)),
+		assign(1, num(1)),
+		assign(2, add(var(0), var(1))),
+	];
+	let cfg = construct(ControlFlowGraph::build(program).unwrap(), &BTreeSet::from([1]));
+	assert_eq!(
+		cfg.to_string(),
+		"bb0:\n\t0_0 := 1\n\tF(.0_0)\n\t1_0 := 1\n\t2_1 := (0_0 + 1_0)\n\treturn\n"
+	);
+}
+
+#[test]
+fn test_destruct() {
+	let cfg = destruct(ssa(vec![
+		Statement::IfElse(var(0), vec![assign(1, num(1))], vec![]),
+		Statement::Return(Some(var(1))),
+	]));
+	assert_eq!(
+		cfg.to_string(),
+		concat!(
+			"bb0:\n",
+			"\tif 0_0 then bb1 else bb2\n",
+			"bb1: ; preds bb0\n",
+			"\t1_1 := 1\n",
+			"\t1_2 := 1_1\n",
+			"\tgoto bb3\n",
+			"bb2: ; preds bb0\n",
+			"\t1_2 := 1_0\n",
+			"\tgoto bb3\n",
+			"bb3: ; preds bb1 bb2\n",
+			"\treturn 1_2\n",
+		)
+	);
+}
+
+#[test]
+fn test_destruct_splits_critical_edges_and_breaks_cycles() {
+	// The two variables are swapped at each iteration
+	let cfg = ControlFlowGraph::from_blocks(vec![
+		BasicBlock {
+			label: None,
+			statements: vec![
+				Statement::Assignment(v(0, 1), Expression::Constant(Constant::Value(1, Type::U8))),
+				Statement::Assignment(v(1, 1), Expression::Constant(Constant::Value(2, Type::U8))),
+			],
+			terminator: Terminator::Goto(1),
+		},
+		BasicBlock {
+			label: None,
+			statements: vec![
+				Statement::Assignment(v(0, 2), Expression::Phi(Some(v(0, 1)), Some(v(1, 2)))),
+				Statement::Assignment(v(1, 2), Expression::Phi(Some(v(1, 1)), Some(v(0, 2)))),
+			],
+			terminator: Terminator::Branch(Expression::Variable(v(2, 0)), 1, 2),
+		},
+		BasicBlock {
+			label: None,
+			statements: vec![],
+			terminator: Terminator::Return(Some(Expression::Variable(v(0, 2)))),
+		},
+	]);
+	assert_eq!(verify(&cfg), Ok(()));
+
+	assert_eq!(
+		destruct(cfg).to_string(),
+		concat!(
+			"bb0:\n",
+			"\t0_1 := 1\n",
+			"\t1_1 := 2\n",
+			"\t0_2 := 0_1\n",
+			"\t1_2 := 1_1\n",
+			"\tgoto bb1\n",
+			"bb1: ; preds bb0 bb3\n",
+			"\tif 2_0 then bb3 else bb2\n",
+			"bb2: ; preds bb1\n",
+			"\treturn 0_2\n",
+			"bb3: ; preds bb1\n",
+			"\t0_3 := 0_2\n",
+			"\t0_2 := 1_2\n",
+			"\t1_2 := 0_3\n",
+			"\tgoto bb1\n",
+		)
+	);
+}
+
+#[test]
+fn test_verifier_errors() {
+	let block = |statements, terminator| BasicBlock {
+		label: None,
+		statements,
+		terminator,
+	};
+	let one = || Expression::Constant(Constant::Value(1, Type::U8));
+
+	let cfg = ControlFlowGraph::from_blocks(vec![block(
+		vec![Statement::Assignment(v(0, 1), one()), Statement::Assignment(v(0, 1), one())],
+		Terminator::Return(None),
+	)]);
+	assert_eq!(verify(&cfg), Err(SsaError::MultipleDefinitions(v(0, 1))));
+
+	let cfg = ControlFlowGraph::from_blocks(vec![block(
+		vec![Statement::Expression(Expression::Variable(v(0, 1)))],
+		Terminator::Return(None),
+	)]);
+	assert_eq!(verify(&cfg), Err(SsaError::UndefinedVariable(v(0, 1), 0)));
+
+	let cfg = ControlFlowGraph::from_blocks(vec![block(
+		vec![
+			Statement::Expression(Expression::Variable(v(0, 1))),
+			Statement::Assignment(v(0, 1), one()),
+		],
+		Terminator::Return(None),
+	)]);
+	assert_eq!(verify(&cfg), Err(SsaError::UndominatedUse(v(0, 1), 0)));
+
+	// Defined in a branch, used after the join
+	let cfg = ControlFlowGraph::from_blocks(vec![
+		block(vec![], Terminator::Branch(Expression::Variable(v(1, 0)), 1, 2)),
+		block(vec![Statement::Assignment(v(0, 1), one())], Terminator::Goto(2)),
+		block(vec![], Terminator::Return(Some(Expression::Variable(v(0, 1))))),
+	]);
+	assert_eq!(verify(&cfg), Err(SsaError::UndominatedUse(v(0, 1), 2)));
+
+	let cfg = ControlFlowGraph::from_blocks(vec![block(
+		vec![Statement::Assignment(v(0, 1), Expression::Phi(None, None))],
+		Terminator::Return(None),
+	)]);
+	assert_eq!(verify(&cfg), Err(SsaError::MisplacedPhi(0)));
+}
@@ -1,5 +1,7 @@
 use crate::instruction::*;
 use std::collections::VecDeque;
+use std::fmt;
+use std::iter;
 
 
 fn get_r_value(r: ByteRegister) -> u8 {
@@ -122,11 +124,118 @@ fn get_both_part_word_pair(pair: WordRegister) -> Option<(Operand<u8, u16, i32,
 }
 
 
+// The processor the code is written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+	// Without the prefixed instructions, the relative jumps and the
+	// alternate registers of the Z80
+	I8080,
+	#[default]
+	Z80,
+	// With MLT, TST, IN0, OUT0, TSTIO, SLP and the block outputs to the
+	// internal ports, but trapping the undocumented instructions
+	Z180,
+}
+
+impl fmt::Display for Target {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| Target::I8080 => write!(f, "8080"),
+			| Target::Z80 => write!(f, "Z80"),
+			| Target::Z180 => write!(f, "Z180"),
+		}
+	}
+}
+
+// The 8080 has neither IX and IY, nor the alternate and special registers
+fn is_8080_operand(op: &Operand<u8, u16, i32, i8>) -> bool {
+	use crate::instruction::WordRegister::*;
+
+	match op {
+		| Operand::WordRegister(r) | Operand::AddressRegister(r) => {
+			matches!(r, AF | BC | DE | HL | SP)
+		}
+		| Operand::AddressRegisterWithOffset(_, _)
+		| Operand::UndocumentedRegister(_)
+		| Operand::I
+		| Operand::R
+		| Operand::F => false,
+		| _ => true,
+	}
+}
+
+/*
+ * The Z80 adds the relative jumps, the exchanges with the alternate
+ * registers, and what is encoded after a CB or ED prefix
+ */
+fn is_8080_instruction(inst: &Instruction<u8, u16, i32, i8>) -> bool {
+	use crate::instruction::WordRegister::*;
+	use Instruction::*;
+	use Operand::*;
+
+	match inst {
+		| JR(_, _) | DJNZ(_) | EXX => false,
+		| LDI | LDIR | LDD | LDDR | CPI | CPIR | CPD | CPDR => false,
+		| INI | INIR | IND | INDR | OUTI | OTIR | OUTD | OTDR => false,
+		| NEG | IM(_) | RLD | RRD | RETI | RETN => false,
+		| RLC(_) | RL(_) | RRC(_) | RR(_) | SLA(_) | SLL(_) | SRA(_) | SRL(_) => false,
+		| BIT(_, _) | SET(_, _) | RES(_, _) => false,
+		| ADC(WordRegister(_), _) | SBC(WordRegister(_), _) => false,
+		| IN(_, PortRegister(_)) | OUT(PortRegister(_), _) => false,
+		// Only HL is loaded from and stored at an address
+		| LD(WordRegister(r), Address(_)) | LD(Address(_), WordRegister(r)) => *r == HL,
+		| LD(a, b) | EX(a, b) | ADD(a, b) | ADC(a, b) | SBC(a, b) | IN(a, b) | OUT(a, b) => {
+			is_8080_operand(a) && is_8080_operand(b)
+		}
+		| PUSH(a) | POP(a) | SUB(a) | AND(a) | OR(a) | XOR(a) | CP(a) | INC(a) | DEC(a)
+		| JP(_, a) | CALL(_, a) => is_8080_operand(a),
+		| _ => true,
+	}
+}
+
+// What the Z180 adds to the Z80
+fn is_z180_instruction(inst: &Instruction<u8, u16, i32, i8>) -> bool {
+	use Instruction::*;
+
+	matches!(
+		inst,
+		MLT(_) | TST(_) | IN0(_, _) | OUT0(_, _) | TSTIO(_) | OTIM | OTIMR | OTDM | OTDMR | SLP
+	)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	// No encoding exists for these operands
+	InvalidOperands(Instruction<u8, u16, i32, i8>),
+	// An immediate value, a bit number, a RST or an IM which doesn't fit
+	ValueOutOfRange(Instruction<u8, u16, i32, i8>),
+	// Only encoded with enable_undocumented_instructions
+	UndocumentedInstruction(Instruction<u8, u16, i32, i8>),
+	// Encoded for the Z80 or the Z180, but not for the target
+	UnavailableInstruction(Instruction<u8, u16, i32, i8>, Target),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| Error::InvalidOperands(inst) => write!(f, "invalid operands for {}", inst),
+			| Error::ValueOutOfRange(inst) => write!(f, "value out of range in {}", inst),
+			| Error::UndocumentedInstruction(inst) => {
+				write!(f, "{} is undocumented, and undocumented instructions aren't enabled", inst)
+			}
+			| Error::UnavailableInstruction(inst, target) => {
+				write!(f, "{} isn't an instruction of the {}", inst, target)
+			}
+		}
+	}
+}
+
 pub struct Assembler<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> {
 	input: InputType,
 	enable_macro_instructions: bool,
 	enable_undocumented_instructions: bool,
-	has_error_occured: bool,
+	target: Target,
+	error: Option<Error>,
 	queue: VecDeque<u8>,
 }
 
@@ -139,13 +248,59 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			input: input,
 			enable_macro_instructions: enable_macro_instructions,
 			enable_undocumented_instructions: enable_undocumented_instructions,
-			has_error_occured: false,
+			target: Target::Z80,
+			error: None,
 			queue: VecDeque::with_capacity(4),
 		}
 	}
 
+	// Only encodes the instructions of the target
+	pub fn with_target(mut self, target: Target) -> Self {
+		self.target = target;
+		self
+	}
+
 	pub fn has_error_occured(&self) -> bool {
-		return self.has_error_occured;
+		self.error.is_some()
+	}
+
+	// Why the assembly stopped, if it did
+	pub fn error(&self) -> Option<&Error> {
+		self.error.as_ref()
+	}
+
+	/*
+	 * Tells why an instruction can't be encoded, by trying it again for
+	 * the Z80, with the undocumented instructions, then with all its values
+	 * set to 0
+	 */
+	fn diagnose(&self, inst: Instruction<u8, u16, i32, i8>) -> Error {
+		let is_valid = |inst: &Instruction<u8, u16, i32, i8>, enable_undocumented_instructions, target| {
+			Assembler::new(
+				iter::empty(),
+				self.enable_macro_instructions,
+				enable_undocumented_instructions
+			).with_target(target).convert_instruction(inst.clone())
+		};
+		let zero = inst.try_map::<_, _, _, _, ()>(&|_| Ok(0u8), &|_| Ok(0u16), &|_| Ok(0i32), &|_| Ok(0i8));
+		let exists_on = |target| {
+			is_valid(&inst, true, target) || zero.as_ref().is_ok_and(|x| is_valid(x, true, target))
+		};
+
+		// A value out of range is reported as such, whatever the target
+		let exists_elsewhere = [Target::Z80, Target::Z180]
+			.into_iter()
+			.any(|target| target != self.target && exists_on(target));
+		if exists_elsewhere && !exists_on(self.target) {
+			return Error::UnavailableInstruction(inst, self.target);
+		}
+		if !self.enable_undocumented_instructions && is_valid(&inst, true, self.target) {
+			return Error::UndocumentedInstruction(inst);
+		}
+		match zero {
+			| Ok(zero) if is_valid(&zero, self.enable_undocumented_instructions, self.target) => Error::ValueOutOfRange(inst),
+			| _ => Error::InvalidOperands(inst),
+		}
 	}
 
 	fn convert_instruction(&mut self, inst: Instruction<u8, u16, i32, i8>) -> bool {
@@ -518,14 +673,21 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			| LD(UndocumentedRegister(IYL), UndocumentedRegister(IYH)) => b![0xFD, 0x6C],
 			| LD(UndocumentedRegister(IYL), UndocumentedRegister(IYL)) => b![0xFD, 0x6D],
 
-			| _ => {
-				println!("Error, Invalid instruction: {:?}", inst);
-				return false;
-			}
+			| _ => false,
 		}
 	}
 
 	fn convert_real_instruction(&mut self, inst: Instruction<u8, u16, i32, i8>) -> bool {
+		if self.target == Target::I8080 && !is_8080_instruction(&inst) {
+			return false;
+		}
+		if self.target != Target::Z180 && is_z180_instruction(&inst) {
+			return false;
+		}
+		self.encode_real_instruction(inst)
+	}
+
+	fn encode_real_instruction(&mut self, inst: Instruction<u8, u16, i32, i8>) -> bool {
 		use crate::instruction::ByteRegister::*;
 		use crate::instruction::WordRegister::*;
 		use Instruction::*;
@@ -591,7 +753,7 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			| LD(WordRegister(IY), Constant(nn)) if nn >= 0 && nn < 0x10000 => {
 				b![0xFD, 0x21, nn & 0xFF, (nn >> 8) & 0xFF]
 			}
-			| LD(WordRegister(dd), Constant(nn)) if nn >= 0 && nn < 0x10000 => b![
+			| LD(WordRegister(dd), Constant(nn)) if nn >= 0 && nn < 0x10000 && matches!(dd, BC | DE | HL | SP) => b![
 				0x01 | get_dd_value(dd) << 4,
 				nn & 0xFF,
 				(nn >> 8) & 0xFF
@@ -600,7 +762,7 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			| LD(WordRegister(IX), Address(nn)) => b![0xDD, 0x2A, nn & 0xFF, (nn >> 8) & 0xFF],
 			| LD(WordRegister(IY), Address(nn)) => b![0xFD, 0x2A, nn & 0xFF, (nn >> 8) & 0xFF],
 			| LD(WordRegister(HL), Address(nn)) => b![0x2A, nn & 0xFF, (nn >> 8) & 0xFF],
-			| LD(WordRegister(dd), Address(nn)) => b![
+			| LD(WordRegister(dd), Address(nn)) if matches!(dd, BC | DE | SP) => b![
 				0xED,
 				0x4B | get_dd_value(dd) << 4,
 				nn & 0xFF,
@@ -610,7 +772,7 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			| LD(Address(nn), WordRegister(IX)) => b![0xDD, 0x22, nn & 0xFF, (nn >> 8) & 0xFF],
 			| LD(Address(nn), WordRegister(IY)) => b![0xFD, 0x22, nn & 0xFF, (nn >> 8) & 0xFF],
 			| LD(Address(nn), WordRegister(HL)) => b![0x22, nn & 0xFF, (nn >> 8) & 0xFF],
-			| LD(Address(nn), WordRegister(dd)) => b![
+			| LD(Address(nn), WordRegister(dd)) if matches!(dd, BC | DE | SP) => b![
 				0xED,
 				0x43 | get_dd_value(dd) << 4,
 				nn & 0xFF,
@@ -623,11 +785,11 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 
 			| PUSH(WordRegister(IX)) => b![0xDD, 0xE5],
 			| PUSH(WordRegister(IY)) => b![0xFD, 0xE5],
-			| PUSH(WordRegister(qq)) => b![0xC5 | get_qq_value(qq) << 4],
+			| PUSH(WordRegister(qq)) if matches!(qq, BC | DE | HL | AF) => b![0xC5 | get_qq_value(qq) << 4],
 
 			| POP(WordRegister(IX)) => b![0xDD, 0xE1],
 			| POP(WordRegister(IY)) => b![0xFD, 0xE1],
-			| POP(WordRegister(qq)) => b![0xC1 | get_qq_value(qq) << 4],
+			| POP(WordRegister(qq)) if matches!(qq, BC | DE | HL | AF) => b![0xC1 | get_qq_value(qq) << 4],
 
 			| EX(WordRegister(DE), WordRegister(HL)) => b![0xEB],
 			| EX(WordRegister(AF), WordRegister(AF_)) => b![0x08],
@@ -664,19 +826,19 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			| SBC(ByteRegister(A), AddressRegisterWithOffset(IX, d)) => b![0xDD, 0x9E, d],
 			| SBC(ByteRegister(A), AddressRegisterWithOffset(IY, d)) => b![0xFD, 0x9E, d],
 
-			| ADD(WordRegister(HL), WordRegister(ss)) => b![0x09 | get_ss_value(ss) << 4],
-			| ADD(WordRegister(IX), WordRegister(pp)) => {
+			| ADD(WordRegister(HL), WordRegister(ss)) if matches!(ss, BC | DE | HL | SP) => b![0x09 | get_ss_value(ss) << 4],
+			| ADD(WordRegister(IX), WordRegister(pp)) if matches!(pp, BC | DE | IX | SP) => {
 				b![0xDD, 0x09 | get_pp_value(pp) << 4]
 			}
-			| ADD(WordRegister(IY), WordRegister(rr)) => {
+			| ADD(WordRegister(IY), WordRegister(rr)) if matches!(rr, BC | DE | IY | SP) => {
 				b![0xFD, 0x09 | get_rr_value(rr) << 4]
 			}
 
-			| ADC(WordRegister(HL), WordRegister(ss)) => {
+			| ADC(WordRegister(HL), WordRegister(ss)) if matches!(ss, BC | DE | HL | SP) => {
 				b![0xED, 0x4A | get_ss_value(ss) << 4]
 			}
 
-			| SBC(WordRegister(HL), WordRegister(ss)) => {
+			| SBC(WordRegister(HL), WordRegister(ss)) if matches!(ss, BC | DE | HL | SP) => {
 				b![0xED, 0x42 | get_ss_value(ss) << 4]
 			}
 
@@ -716,7 +878,7 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			| INC(AddressRegisterWithOffset(IY, d)) => b![0xFD, 0x34, d],
 			| INC(WordRegister(IX)) => b![0xDD, 0x23],
 			| INC(WordRegister(IY)) => b![0xFD, 0x23],
-			| INC(WordRegister(ss)) => b![0x03 | get_ss_value(ss) << 4],
+			| INC(WordRegister(ss)) if matches!(ss, BC | DE | HL | SP) => b![0x03 | get_ss_value(ss) << 4],
 
 			| DEC(ByteRegister(r)) => b![0x05 | get_r_value(r) << 3],
 			| DEC(AddressRegister(HL)) => b![0x35],
@@ -724,7 +886,7 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			| DEC(AddressRegisterWithOffset(IY, d)) => b![0xFD, 0x35, d],
 			| DEC(WordRegister(IX)) => b![0xDD, 0x2B],
 			| DEC(WordRegister(IY)) => b![0xFD, 0x2B],
-			| DEC(WordRegister(ss)) => b![0x0B | get_ss_value(ss) << 4],
+			| DEC(WordRegister(ss)) if matches!(ss, BC | DE | HL | SP) => b![0x0B | get_ss_value(ss) << 4],
 
 			| DAA => b![0x27],
 			| CPL => b![0x2F],
@@ -809,11 +971,11 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 				(nn >> 8) & 0xFF
 			],
 
-			| JR(None, Constant(d)) if d >= -126 && d <= 129 => b![0x18, d & 0xFF],
-			| JR(Some(Condition::C), Constant(d)) if d >= -126 && d <= 129 => b![0x38, d & 0xFF],
-			| JR(Some(Condition::NC), Constant(d)) if d >= -126 && d <= 129 => b![0x30, d & 0xFF],
-			| JR(Some(Condition::Z), Constant(d)) if d >= -126 && d <= 129 => b![0x28, d & 0xFF],
-			| JR(Some(Condition::NZ), Constant(d)) if d >= -126 && d <= 129 => b![0x20, d & 0xFF],
+			| JR(None, Constant(d)) if d >= -128 && d <= 127 => b![0x18, d & 0xFF],
+			| JR(Some(Condition::C), Constant(d)) if d >= -128 && d <= 127 => b![0x38, d & 0xFF],
+			| JR(Some(Condition::NC), Constant(d)) if d >= -128 && d <= 127 => b![0x30, d & 0xFF],
+			| JR(Some(Condition::Z), Constant(d)) if d >= -128 && d <= 127 => b![0x28, d & 0xFF],
+			| JR(Some(Condition::NZ), Constant(d)) if d >= -128 && d <= 127 => b![0x20, d & 0xFF],
 
 			| DJNZ(offset) => b![0x10, offset],
 
@@ -848,13 +1010,26 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Assembler<InputT
 			| OUTD => b![0xED, 0xAB],
 			| OTDR => b![0xED, 0xBB],
 
-			| _ if self.enable_undocumented_instructions => {
-				self.convert_undocumented_instruction(inst)
+			| MLT(WordRegister(ss)) if matches!(ss, BC | DE | HL | SP) => {
+				b![0xED, 0x4C | get_ss_value(ss) << 4]
 			}
-			| _ => {
-				println!("Error, Invalid instruction: {:?}", inst);
-				return false;
+			| TST(ByteRegister(r)) => b![0xED, 0x04 | get_r_value(r) << 3],
+			| TST(AddressRegister(HL)) => b![0xED, 0x34],
+			| TST(Constant(n)) if (-128..256).contains(&n) => b![0xED, 0x64, n & 0xFF],
+			| IN0(ByteRegister(r), Port(n)) => b![0xED, get_r_value(r) << 3, n],
+			| IN0(F, Port(n)) => b![0xED, 0x30, n],
+			| OUT0(Port(n), ByteRegister(r)) => b![0xED, 0x01 | get_r_value(r) << 3, n],
+			| TSTIO(n) => b![0xED, 0x74, n],
+			| OTIM => b![0xED, 0x83],
+			| OTIMR => b![0xED, 0x93],
+			| OTDM => b![0xED, 0x8B],
+			| OTDMR => b![0xED, 0x9B],
+			| SLP => b![0xED, 0x76],
+
+			| _ if self.enable_undocumented_instructions && self.target != Target::Z180 => {
+				self.convert_undocumented_instruction(inst)
 			}
+			| _ => false,
 		}
 	}
 
@@ -878,8 +1053,9 @@ impl<InputType: Iterator<Item = Instruction<u8, u16, i32, i8>>> Iterator for Ass
 					return None;
 				}
 				| Some(inst) => {
-					if !self.convert_instruction(inst) {
-						self.has_error_occured = true;
+					if !self.convert_instruction(inst.clone()) {
+						self.queue.clear();
+						self.error = Some(self.diagnose(inst));
 						return None;
 					}
 				}
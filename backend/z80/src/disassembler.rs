@@ -0,0 +1,537 @@
+use std::fmt;
+
+use crate::instruction::*;
+
+type Inst = Instruction<u8, u16, i32, i8>;
+type Op = Operand<u8, u16, i32, i8>;
+
+const BYTE_REGISTERS: [ByteRegister; 8] = [
+	ByteRegister::B,
+	ByteRegister::C,
+	ByteRegister::D,
+	ByteRegister::E,
+	ByteRegister::H,
+	ByteRegister::L,
+	ByteRegister::A, // Never used: 6 is (HL)
+	ByteRegister::A,
+];
+
+const CONDITIONS: [Condition; 8] = [
+	Condition::NZ,
+	Condition::Z,
+	Condition::NC,
+	Condition::C,
+	Condition::PO,
+	Condition::PE,
+	Condition::P,
+	Condition::M,
+];
+
+/*
+ * Decodes one instruction, and returns it with its size. What the
+ * Assembler can't encode, and the undocumented instructions when they
+ * are disabled, come out as Binary. A DD or FD which doesn't change the
+ * instruction after it is a Binary of its own, as the CPU ignores it.
+ */
+pub fn decode(bytes: &[u8], enable_undocumented_instructions: bool) -> Option<(Inst, usize)> {
+	if bytes.is_empty() {
+		return None;
+	}
+
+	let mut decoder = Decoder::new(bytes);
+	let inst = match decoder.decode() {
+		| Some(_) if decoder.undocumented && !enable_undocumented_instructions => decoder.binary(),
+		| Some(inst) => inst,
+		// Cut in the middle of an instruction
+		| None => Instruction::Binary(bytes.to_vec()),
+	};
+	let size = match &inst {
+		| Instruction::Binary(data) => data.len(),
+		| _ => decoder.position,
+	};
+	Some((inst, size))
+}
+
+pub struct Disassembler<'a> {
+	bytes: &'a [u8],
+	position: usize,
+	enable_undocumented_instructions: bool,
+}
+
+impl<'a> Disassembler<'a> {
+	pub fn new(bytes: &'a [u8], enable_undocumented_instructions: bool) -> Self {
+		Self {
+			bytes,
+			position: 0,
+			enable_undocumented_instructions,
+		}
+	}
+
+	// The offset of the next instruction
+	pub fn position(&self) -> usize {
+		self.position
+	}
+}
+
+impl Iterator for Disassembler<'_> {
+	type Item = Inst;
+
+	fn next(&mut self) -> Option<Inst> {
+		let (inst, size) = decode(
+			&self.bytes[self.position..],
+			self.enable_undocumented_instructions,
+		)?;
+		self.position += size;
+		Some(inst)
+	}
+}
+
+/*
+ * The text disassembly: one line per instruction, with its address and
+ * its bytes. The relative jumps also get their target.
+ */
+pub fn disassemble(bytes: &[u8], origin: u16, enable_undocumented_instructions: bool) -> String {
+	let mut text = String::new();
+	let mut disassembler = Disassembler::new(bytes, enable_undocumented_instructions);
+	loop {
+		let start = disassembler.position();
+		let Some(inst) = disassembler.next() else {
+			break;
+		};
+		let end = disassembler.position();
+		let address = origin.wrapping_add(start as u16);
+		let encoding: Vec<String> = bytes[start..end]
+			.iter()
+			.map(|b| format!("{:02X}", b))
+			.collect();
+		let line = format!("{:04X}  {:<12}{}", address, encoding.join(" "), inst);
+		let displacement = match inst {
+			| Instruction::JR(_, Operand::Constant(d)) => Some(d),
+			| Instruction::DJNZ(d) => Some(d as i32),
+			| _ => None,
+		};
+		match displacement {
+			| Some(d) => {
+				let target = address.wrapping_add(2).wrapping_add(d as u16);
+				text += &format!("{:<40}; {:04X}H\n", line, target);
+			}
+			| None => text += &format!("{}\n", line),
+		}
+	}
+	text
+}
+
+struct Decoder<'a> {
+	bytes: &'a [u8],
+	position: usize,
+	// HL, or IX and IY after DD and FD
+	index: WordRegister,
+	undocumented: bool,
+}
+
+impl<'a> Decoder<'a> {
+	fn new(bytes: &'a [u8]) -> Self {
+		Self {
+			bytes,
+			position: 0,
+			index: WordRegister::HL,
+			undocumented: false,
+		}
+	}
+
+	fn byte(&mut self) -> Option<u8> {
+		let byte = *self.bytes.get(self.position)?;
+		self.position += 1;
+		Some(byte)
+	}
+
+	fn word(&mut self) -> Option<u16> {
+		let low = self.byte()?;
+		let high = self.byte()?;
+		Some(u16::from_le_bytes([low, high]))
+	}
+
+	// What has been read so far, as is
+	fn binary(&self) -> Inst {
+		Instruction::Binary(self.bytes[..self.position].to_vec())
+	}
+
+	// (HL), or (IX+d) reading d
+	fn memory(&mut self) -> Option<Op> {
+		if self.index == WordRegister::HL {
+			return Some(Operand::AddressRegister(WordRegister::HL));
+		}
+		let d = self.byte()? as i8;
+		Some(Operand::AddressRegisterWithOffset(self.index.clone(), d))
+	}
+
+	// B, C, D, E, H, L, (HL), A with H and L replaced by the index halves
+	fn register(&mut self, r: u8) -> Option<Op> {
+		let half = match (&self.index, r) {
+			| (_, 6) => return self.memory(),
+			| (WordRegister::IX, 4) => UndocumentedRegister::IXH,
+			| (WordRegister::IX, 5) => UndocumentedRegister::IXL,
+			| (WordRegister::IY, 4) => UndocumentedRegister::IYH,
+			| (WordRegister::IY, 5) => UndocumentedRegister::IYL,
+			| _ => return Some(Self::real_register(r)),
+		};
+		self.undocumented = true;
+		Some(Operand::UndocumentedRegister(half))
+	}
+
+	// B, C, D, E, H, L, A even after DD and FD
+	fn real_register(r: u8) -> Op {
+		Operand::ByteRegister(BYTE_REGISTERS[r as usize].clone())
+	}
+
+	// BC, DE, HL, SP
+	fn pair(&self, p: u8) -> Op {
+		Operand::WordRegister(match p {
+			| 0 => WordRegister::BC,
+			| 1 => WordRegister::DE,
+			| 2 => self.index.clone(),
+			| _ => WordRegister::SP,
+		})
+	}
+
+	// BC, DE, HL, AF
+	fn pair2(&self, p: u8) -> Op {
+		match p {
+			| 3 => Operand::WordRegister(WordRegister::AF),
+			| _ => self.pair(p),
+		}
+	}
+
+	fn decode(&mut self) -> Option<Inst> {
+		let prefix = self.byte()?;
+		match prefix {
+			| 0xCB => {
+				let op = self.byte()?;
+				let operand = self.register(op & 7)?;
+				Some(self.bit_operation(op, operand))
+			}
+			| 0xED => self.decode_ed(),
+			| 0xDD | 0xFD => {
+				self.index = if prefix == 0xDD {
+					WordRegister::IX
+				} else {
+					WordRegister::IY
+				};
+				let op = self.byte()?;
+				match op {
+					| 0xCB => {
+						let d = self.byte()? as i8;
+						let op = self.byte()?;
+						if op & 7 != 6 {
+							// The copy of the result into a register can't be written
+							return Some(self.binary());
+						}
+						let operand = Operand::AddressRegisterWithOffset(self.index.clone(), d);
+						Some(self.bit_operation(op, operand))
+					}
+					| 0xDD | 0xED | 0xFD => Some(Instruction::Binary(vec![prefix])),
+					| _ => {
+						let inst = self.decode_main(op)?;
+						if Decoder::new(&self.bytes[1..]).decode().as_ref() == Some(&inst) {
+							// Nothing used HL
+							self.undocumented = false;
+							return Some(Instruction::Binary(vec![prefix]));
+						}
+						Some(inst)
+					}
+				}
+			}
+			| _ => self.decode_main(prefix),
+		}
+	}
+
+	// RLC ... SRL, BIT, RES and SET, after CB
+	fn bit_operation(&mut self, op: u8, operand: Op) -> Inst {
+		let (x, y) = (op >> 6, (op >> 3) & 7);
+		match x {
+			| 0 => match y {
+				| 0 => Instruction::RLC(operand),
+				| 1 => Instruction::RRC(operand),
+				| 2 => Instruction::RL(operand),
+				| 3 => Instruction::RR(operand),
+				| 4 => Instruction::SLA(operand),
+				| 5 => Instruction::SRA(operand),
+				| 6 => {
+					self.undocumented = true;
+					Instruction::SLL(operand)
+				}
+				| _ => Instruction::SRL(operand),
+			},
+			| 1 => Instruction::BIT(y, operand),
+			| 2 => Instruction::RES(y, operand),
+			| _ => Instruction::SET(y, operand),
+		}
+	}
+
+	fn alu(y: u8, operand: Op) -> Inst {
+		let a = || Operand::ByteRegister(ByteRegister::A);
+		match y {
+			| 0 => Instruction::ADD(a(), operand),
+			| 1 => Instruction::ADC(a(), operand),
+			| 2 => Instruction::SUB(operand),
+			| 3 => Instruction::SBC(a(), operand),
+			| 4 => Instruction::AND(operand),
+			| 5 => Instruction::XOR(operand),
+			| 6 => Instruction::OR(operand),
+			| _ => Instruction::CP(operand),
+		}
+	}
+
+	// Everything without a prefix, or after DD and FD
+	fn decode_main(&mut self, op: u8) -> Option<Inst> {
+		use crate::instruction::ByteRegister::A;
+		use crate::instruction::WordRegister::*;
+		use Instruction::*;
+		use Operand::*;
+
+		let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+		let (p, q) = (y >> 1, y & 1);
+		let index = WordRegister(self.index.clone());
+
+		let inst = match x {
+			| 0 => match z {
+				| 0 => match y {
+					| 0 => NOP,
+					| 1 => EX(WordRegister(AF), WordRegister(AF_)),
+					| 2 => DJNZ(self.byte()? as i8),
+					| 3 => JR(None, Constant(self.byte()? as i8 as i32)),
+					| _ => JR(
+						Some(CONDITIONS[(y - 4) as usize].clone()),
+						Constant(self.byte()? as i8 as i32),
+					),
+				},
+				| 1 if q == 0 => LD(self.pair(p), Constant(self.word()? as i32)),
+				| 1 => ADD(index, self.pair(p)),
+				| 2 => match (p, q) {
+					| (0, 0) => LD(AddressRegister(BC), ByteRegister(A)),
+					| (0, _) => LD(ByteRegister(A), AddressRegister(BC)),
+					| (1, 0) => LD(AddressRegister(DE), ByteRegister(A)),
+					| (1, _) => LD(ByteRegister(A), AddressRegister(DE)),
+					| (2, 0) => LD(Address(self.word()?), index),
+					| (2, _) => LD(index, Address(self.word()?)),
+					| (_, 0) => LD(Address(self.word()?), ByteRegister(A)),
+					| _ => LD(ByteRegister(A), Address(self.word()?)),
+				},
+				| 3 if q == 0 => INC(self.pair(p)),
+				| 3 => DEC(self.pair(p)),
+				| 4 => INC(self.register(y)?),
+				| 5 => DEC(self.register(y)?),
+				| 6 => {
+					let operand = self.register(y)?;
+					LD(operand, Constant(self.byte()? as i32))
+				}
+				| _ => [RLCA, RRCA, RLA, RRA, DAA, CPL, SCF, CCF][y as usize].clone(),
+			},
+			| 1 if y == 6 && z == 6 => HALT,
+			// Next to (IX+d), H and L are the real ones
+			| 1 if y == 6 => LD(self.memory()?, Self::real_register(z)),
+			| 1 if z == 6 => LD(Self::real_register(y), self.memory()?),
+			| 1 => LD(self.register(y)?, self.register(z)?),
+			| 2 => Self::alu(y, self.register(z)?),
+			| _ => match z {
+				| 0 => RET(Some(CONDITIONS[y as usize].clone())),
+				| 1 if q == 0 => POP(self.pair2(p)),
+				| 1 => match p {
+					| 0 => RET(None),
+					| 1 => EXX,
+					| 2 => JP(None, index),
+					| _ => LD(WordRegister(SP), index),
+				},
+				| 2 => JP(
+					Some(CONDITIONS[y as usize].clone()),
+					Constant(self.word()? as i32),
+				),
+				| 3 => match y {
+					| 0 => JP(None, Constant(self.word()? as i32)),
+					| 2 => OUT(Port(self.byte()?), ByteRegister(A)),
+					| 3 => IN(ByteRegister(A), Port(self.byte()?)),
+					| 4 => EX(AddressRegister(SP), index),
+					| 5 => EX(WordRegister(DE), WordRegister(HL)),
+					| 6 => DI,
+					| 7 => EI,
+					| _ => unreachable!("CB is a prefix"),
+				},
+				| 4 => CALL(
+					Some(CONDITIONS[y as usize].clone()),
+					Constant(self.word()? as i32),
+				),
+				| 5 if q == 0 => PUSH(self.pair2(p)),
+				| 5 if p == 0 => CALL(None, Constant(self.word()? as i32)),
+				| 5 => unreachable!("DD, ED and FD are prefixes"),
+				| 6 => Self::alu(y, Constant(self.byte()? as i32)),
+				| _ => RST(y * 8),
+			},
+		};
+		Some(inst)
+	}
+
+	fn decode_ed(&mut self) -> Option<Inst> {
+		use crate::instruction::ByteRegister::{A, C};
+		use crate::instruction::WordRegister::*;
+		use Instruction::*;
+		use Operand::*;
+
+		let op = self.byte()?;
+		let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+		let (p, q) = (y >> 1, y & 1);
+
+		let inst = match (x, z) {
+			| (1, 0) if y == 6 => {
+				self.undocumented = true;
+				IN(F, PortRegister(C))
+			}
+			| (1, 0) => IN(Self::real_register(y), PortRegister(C)),
+			| (1, 1) if y == 6 => {
+				self.undocumented = true;
+				OUT(PortRegister(C), Constant(0))
+			}
+			| (1, 1) => OUT(PortRegister(C), Self::real_register(y)),
+			| (1, 2) if q == 0 => SBC(WordRegister(HL), self.pair(p)),
+			| (1, 2) => ADC(WordRegister(HL), self.pair(p)),
+			// ED 63 and ED 6B are long forms of LD (nn),HL and LD HL,(nn)
+			| (1, 3) if p == 2 => {
+				self.word()?;
+				self.binary()
+			}
+			| (1, 3) if q == 0 => LD(Address(self.word()?), self.pair(p)),
+			| (1, 3) => LD(self.pair(p), Address(self.word()?)),
+			/*
+			 * The mirrors of NEG, RETN and IM are decoded as what the CPU
+			 * does with them, even if they don't assemble back the same
+			 */
+			| (1, 4) => {
+				self.undocumented |= y != 0;
+				NEG
+			}
+			| (1, 5) if y == 1 => RETI,
+			| (1, 5) => {
+				self.undocumented |= y != 0;
+				RETN
+			}
+			| (1, 6) => {
+				self.undocumented |= y & 4 != 0 || y == 1;
+				IM([0, 0, 1, 2][(y & 3) as usize])
+			}
+			| (1, 7) => match y {
+				| 0 => LD(I, ByteRegister(A)),
+				| 1 => LD(R, ByteRegister(A)),
+				| 2 => LD(ByteRegister(A), I),
+				| 3 => LD(ByteRegister(A), R),
+				| 4 => RRD,
+				| 5 => RLD,
+				| _ => self.binary(),
+			},
+			| (2, 0..=3) if y >= 4 => [
+				[LDI, CPI, INI, OUTI],
+				[LDD, CPD, IND, OUTD],
+				[LDIR, CPIR, INIR, OTIR],
+				[LDDR, CPDR, INDR, OTDR],
+			][(y - 4) as usize][z as usize]
+				.clone(),
+			| _ => self.binary(),
+		};
+		Some(inst)
+	}
+}
+
+// Numbers in hexadecimal, with a leading 0 when they start with a letter
+fn hex(value: u32, digits: usize) -> String {
+	let text = format!("{:0digits$X}H", value, digits = digits);
+	if text.starts_with(|c: char| c.is_ascii_alphabetic()) {
+		format!("0{}", text)
+	} else {
+		text
+	}
+}
+
+impl fmt::Display for Operand<u8, u16, i32, i8> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| Operand::Constant(n) if (0..0x100).contains(n) => write!(f, "{}", hex(*n as u32, 2)),
+			| Operand::Constant(n) => write!(f, "{}", hex(*n as u16 as u32, 4)),
+			| Operand::Address(nn) => write!(f, "({})", hex(*nn as u32, 4)),
+			| Operand::Port(n) => write!(f, "({})", hex(*n as u32, 2)),
+			| Operand::ByteRegister(r) => write!(f, "{:?}", r),
+			| Operand::WordRegister(WordRegister::AF_) => write!(f, "AF'"),
+			| Operand::WordRegister(r) => write!(f, "{:?}", r),
+			| Operand::PortRegister(r) => write!(f, "({:?})", r),
+			| Operand::AddressRegister(r) => write!(f, "({:?})", r),
+			| Operand::AddressRegisterWithOffset(r, d) => write!(f, "({:?}{:+})", r, d),
+			| Operand::UndocumentedRegister(r) => write!(f, "{:?}", r),
+			| Operand::I => write!(f, "I"),
+			| Operand::R => write!(f, "R"),
+			| Operand::F => write!(f, "F"),
+		}
+	}
+}
+
+impl fmt::Display for Instruction<u8, u16, i32, i8> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use Instruction::*;
+
+		let condition = |cc: &Option<Condition>| match cc {
+			| Some(cc) => format!("{:?},", cc),
+			| None => String::new(),
+		};
+		// Relative to the start of the instruction, like the Zilog $
+		let relative = |d: i32| match d + 2 {
+			| 0 => "$".to_string(),
+			| d => format!("${:+}", d),
+		};
+
+		match self {
+			| LD(a, b) => write!(f, "LD {},{}", a, b),
+			| EX(a, b) => write!(f, "EX {},{}", a, b),
+			| ADD(a, b) => write!(f, "ADD {},{}", a, b),
+			| ADC(a, b) => write!(f, "ADC {},{}", a, b),
+			| SBC(a, b) => write!(f, "SBC {},{}", a, b),
+			| IN(a, b) => write!(f, "IN {},{}", a, b),
+			| OUT(a, b) => write!(f, "OUT {},{}", a, b),
+			| IN0(a, b) => write!(f, "IN0 {},{}", a, b),
+			| OUT0(a, b) => write!(f, "OUT0 {},{}", a, b),
+			| MLT(a) => write!(f, "MLT {}", a),
+			| TST(a) => write!(f, "TST {}", a),
+			| TSTIO(n) => write!(f, "TSTIO {}", hex(*n as u32, 2)),
+			| PUSH(a) => write!(f, "PUSH {}", a),
+			| POP(a) => write!(f, "POP {}", a),
+			| SUB(a) => write!(f, "SUB {}", a),
+			| AND(a) => write!(f, "AND {}", a),
+			| OR(a) => write!(f, "OR {}", a),
+			| XOR(a) => write!(f, "XOR {}", a),
+			| CP(a) => write!(f, "CP {}", a),
+			| INC(a) => write!(f, "INC {}", a),
+			| DEC(a) => write!(f, "DEC {}", a),
+			| RLC(a) => write!(f, "RLC {}", a),
+			| RL(a) => write!(f, "RL {}", a),
+			| RRC(a) => write!(f, "RRC {}", a),
+			| RR(a) => write!(f, "RR {}", a),
+			| SLA(a) => write!(f, "SLA {}", a),
+			| SLL(a) => write!(f, "SLL {}", a),
+			| SRA(a) => write!(f, "SRA {}", a),
+			| SRL(a) => write!(f, "SRL {}", a),
+			| BIT(b, a) => write!(f, "BIT {},{}", b, a),
+			| SET(b, a) => write!(f, "SET {},{}", b, a),
+			| RES(b, a) => write!(f, "RES {},{}", b, a),
+			| IM(n) => write!(f, "IM {}", n),
+			| RST(n) => write!(f, "RST {}", hex(*n as u32, 2)),
+			| JP(None, Operand::WordRegister(r)) => write!(f, "JP ({:?})", r),
+			| JP(cc, a) => write!(f, "JP {}{}", condition(cc), a),
+			| CALL(cc, a) => write!(f, "CALL {}{}", condition(cc), a),
+			| JR(cc, Operand::Constant(d)) => write!(f, "JR {}{}", condition(cc), relative(*d)),
+			| JR(cc, a) => write!(f, "JR {}{}", condition(cc), a),
+			| DJNZ(d) => write!(f, "DJNZ {}", relative(*d as i32)),
+			| RET(Some(cc)) => write!(f, "RET {:?}", cc),
+			| RET(None) => write!(f, "RET"),
+			| Binary(data) => {
+				let data: Vec<String> = data.iter().map(|b| hex(*b as u32, 2)).collect();
+				write!(f, "DB {}", data.join(","))
+			}
+			| inst => write!(f, "{:?}", inst),
+		}
+	}
+}
@@ -1,3 +1,7 @@
+use std::iter;
+
+use crate::assembler::{Assembler, Target};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ByteRegister {
 	A,
@@ -61,6 +65,13 @@ pub enum Condition {
 	M,
 }
 
+impl Condition {
+	// Whether JR can test it, PO, PE, P and M being only for JP
+	pub fn is_relative(&self) -> bool {
+		matches!(self, Condition::Z | Condition::NZ | Condition::C | Condition::NC)
+	}
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction<ToU8, Address, Constant, Offset> {
 	LD(Operand<ToU8, Address, Constant, Offset>, Operand<ToU8, Address, Constant, Offset>),
@@ -142,6 +153,184 @@ pub enum Instruction<ToU8, Address, Constant, Offset> {
 	OUTD,
 	OTDR,
 
+	// Those of the Z180
+	MLT(Operand<ToU8, Address, Constant, Offset>),
+	TST(Operand<ToU8, Address, Constant, Offset>),
+	IN0(Operand<ToU8, Address, Constant, Offset>, Operand<ToU8, Address, Constant, Offset>),
+	OUT0(Operand<ToU8, Address, Constant, Offset>, Operand<ToU8, Address, Constant, Offset>),
+	TSTIO(ToU8),
+	OTIM,
+	OTIMR,
+	OTDM,
+	OTDMR,
+	SLP,
+
 	/* This is Assembler specific */
 	Binary(Vec<ToU8>),
 }
+
+impl<ToU8, Address, Constant, Offset> Operand<ToU8, Address, Constant, Offset> {
+	/*
+	 * Converts the value of the operand, if it has one, with the function
+	 * matching its kind
+	 */
+	pub fn try_map<T, A, C, O, E>(
+		&self,
+		byte: &impl Fn(&ToU8) -> Result<T, E>,
+		address: &impl Fn(&Address) -> Result<A, E>,
+		constant: &impl Fn(&Constant) -> Result<C, E>,
+		offset: &impl Fn(&Offset) -> Result<O, E>,
+	) -> Result<Operand<T, A, C, O>, E> {
+		use Operand::*;
+
+		Ok(match self {
+			| Constant(n) => Constant(constant(n)?),
+			| Address(nn) => Address(address(nn)?),
+			| Port(n) => Port(byte(n)?),
+			| ByteRegister(r) => ByteRegister(r.clone()),
+			| WordRegister(r) => WordRegister(r.clone()),
+			| PortRegister(r) => PortRegister(r.clone()),
+			| AddressRegister(r) => AddressRegister(r.clone()),
+			| AddressRegisterWithOffset(r, d) => AddressRegisterWithOffset(r.clone(), offset(d)?),
+			| UndocumentedRegister(r) => UndocumentedRegister(r.clone()),
+			| I => I,
+			| R => R,
+			| F => F,
+		})
+	}
+}
+
+impl<ToU8, Address, Constant, Offset> Instruction<ToU8, Address, Constant, Offset> {
+	/*
+	 * Converts every value of the instruction, stopping at the first
+	 * error
+	 */
+	pub fn try_map<T, A, C, O, E>(
+		&self,
+		byte: &impl Fn(&ToU8) -> Result<T, E>,
+		address: &impl Fn(&Address) -> Result<A, E>,
+		constant: &impl Fn(&Constant) -> Result<C, E>,
+		offset: &impl Fn(&Offset) -> Result<O, E>,
+	) -> Result<Instruction<T, A, C, O>, E> {
+		use Instruction::*;
+
+		let operand = |op: &Operand<ToU8, Address, Constant, Offset>| op.try_map(byte, address, constant, offset);
+		Ok(match self {
+			| LD(a, b) => LD(operand(a)?, operand(b)?),
+			| PUSH(a) => PUSH(operand(a)?),
+			| POP(a) => POP(operand(a)?),
+			| EX(a, b) => EX(operand(a)?, operand(b)?),
+			| EXX => EXX,
+			| LDI => LDI,
+			| LDIR => LDIR,
+			| LDD => LDD,
+			| LDDR => LDDR,
+			| CPI => CPI,
+			| CPIR => CPIR,
+			| CPD => CPD,
+			| CPDR => CPDR,
+
+			| ADD(a, b) => ADD(operand(a)?, operand(b)?),
+			| ADC(a, b) => ADC(operand(a)?, operand(b)?),
+			| SUB(a) => SUB(operand(a)?),
+			| SBC(a, b) => SBC(operand(a)?, operand(b)?),
+			| AND(a) => AND(operand(a)?),
+			| OR(a) => OR(operand(a)?),
+			| XOR(a) => XOR(operand(a)?),
+			| CP(a) => CP(operand(a)?),
+
+			| INC(a) => INC(operand(a)?),
+			| DEC(a) => DEC(operand(a)?),
+
+			| DAA => DAA,
+			| CPL => CPL,
+			| NEG => NEG,
+			| CCF => CCF,
+			| SCF => SCF,
+			| NOP => NOP,
+			| HALT => HALT,
+			| DI => DI,
+			| EI => EI,
+
+			| IM(n) => IM(byte(n)?),
+
+			| RLCA => RLCA,
+			| RLA => RLA,
+			| RRCA => RRCA,
+			| RRA => RRA,
+			| RLC(a) => RLC(operand(a)?),
+			| RL(a) => RL(operand(a)?),
+			| RRC(a) => RRC(operand(a)?),
+			| RR(a) => RR(operand(a)?),
+			| SLA(a) => SLA(operand(a)?),
+			| SLL(a) => SLL(operand(a)?),
+			| SRA(a) => SRA(operand(a)?),
+			| SRL(a) => SRL(operand(a)?),
+			| RLD => RLD,
+			| RRD => RRD,
+
+			| BIT(b, a) => BIT(byte(b)?, operand(a)?),
+			| SET(b, a) => SET(byte(b)?, operand(a)?),
+			| RES(b, a) => RES(byte(b)?, operand(a)?),
+
+			| JP(cc, a) => JP(cc.clone(), operand(a)?),
+			| JR(cc, a) => JR(cc.clone(), operand(a)?),
+
+			| DJNZ(d) => DJNZ(offset(d)?),
+
+			| CALL(cc, a) => CALL(cc.clone(), operand(a)?),
+			| RET(cc) => RET(cc.clone()),
+			| RETI => RETI,
+			| RETN => RETN,
+			| RST(n) => RST(byte(n)?),
+
+			| IN(a, b) => IN(operand(a)?, operand(b)?),
+			| INI => INI,
+			| INIR => INIR,
+			| IND => IND,
+			| INDR => INDR,
+			| OUT(a, b) => OUT(operand(a)?, operand(b)?),
+			| OUTI => OUTI,
+			| OTIR => OTIR,
+			| OUTD => OUTD,
+			| OTDR => OTDR,
+
+			| MLT(a) => MLT(operand(a)?),
+			| TST(a) => TST(operand(a)?),
+			| IN0(a, b) => IN0(operand(a)?, operand(b)?),
+			| OUT0(a, b) => OUT0(operand(a)?, operand(b)?),
+			| TSTIO(n) => TSTIO(byte(n)?),
+			| OTIM => OTIM,
+			| OTIMR => OTIMR,
+			| OTDM => OTDM,
+			| OTDMR => OTDMR,
+			| SLP => SLP,
+
+			| Binary(data) => Binary(data.iter().map(byte).collect::<Result<_, _>>()?),
+		})
+	}
+}
+
+impl Instruction<u8, u16, i32, i8> {
+	/*
+	 * The bytes the Assembler gives for the instruction, with the macro and
+	 * the undocumented instructions, or those of the Z180, or None if it
+	 * can't be encoded
+	 */
+	pub fn encode(&self) -> Option<Vec<u8>> {
+		[Target::Z80, Target::Z180].into_iter().find_map(|target| {
+			let mut assembler =
+				Assembler::new(iter::once(self.clone()), true, true).with_target(target);
+			let bytes = assembler.by_ref().collect();
+			match assembler.has_error_occured() {
+				| true => None,
+				| false => Some(bytes),
+			}
+		})
+	}
+
+	// How many bytes it takes
+	pub fn size(&self) -> Option<usize> {
+		self.encode().map(|bytes| bytes.len())
+	}
+}
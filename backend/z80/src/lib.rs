@@ -1,3 +1,8 @@
 pub mod assembler;
+pub mod disassembler;
 pub mod instruction;
+pub mod listing;
+pub mod output;
 pub mod parser;
+pub mod resolver;
+pub mod timing;
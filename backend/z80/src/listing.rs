@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::resolver::{Program, Record};
+
+// What doesn't fit goes on the next rows, without the source
+const BYTES_PER_ROW: usize = 4;
+
+fn row(output: &mut String, number: &str, address: &str, bytes: &str, cycles: &str, text: &str) {
+	let row = format!(
+		"{:>5} {:4}  {:<w$} {:>5}  {}",
+		number,
+		address,
+		bytes,
+		cycles,
+		text,
+		w = BYTES_PER_ROW * 3 - 1
+	);
+	writeln!(output, "{}", row.trim_end()).unwrap();
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes
+		.iter()
+		.map(|x| format!("{:02X}", x))
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+/*
+ * Every line of the source, with its number, its address, its bytes and
+ * how many T-states it takes when it is an instruction. What EQU and SET
+ * give is written instead of the bytes. The lines after the END, or
+ * without a statement, are only copied.
+ */
+pub fn listing(source: &str, program: &Program) -> String {
+	let records: BTreeMap<usize, &Record> = program.records.iter().map(|x| (x.line, x)).collect();
+
+	let mut output = String::new();
+	for (index, text) in source.lines().enumerate() {
+		let number = (index + 1).to_string();
+		let Some(record) = records.get(&(index + 1)) else {
+			row(&mut output, &number, "", "", "", text);
+			continue;
+		};
+
+		let address = format!("{:04X}", record.address);
+		let cycles = record.cycles.map(|x| x.to_string()).unwrap_or_default();
+		let mut chunks = record.bytes.chunks(BYTES_PER_ROW);
+		let bytes = match record.value {
+			| Some(value) => format!("= {:04X}", value as u16),
+			| None => chunks.next().map(hex).unwrap_or_default(),
+		};
+		row(&mut output, &number, &address, &bytes, &cycles, text);
+
+		for (i, chunk) in chunks.enumerate() {
+			let address = record
+				.address
+				.wrapping_add(((i + 1) * BYTES_PER_ROW) as u16);
+			row(
+				&mut output,
+				"",
+				&format!("{:04X}", address),
+				&hex(chunk),
+				"",
+				"",
+			);
+		}
+	}
+	output
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolFormat {
+	// NAME EQU 0100H, which can be included in another source
+	Equ,
+	// DEF NAME 0100, the commands loading them in NoICE
+	NoIce,
+	// 0100 NAME, the .SYM files of the CP/M debuggers
+	Sym,
+}
+
+// The symbols sorted by name, with their values on 16 bits
+pub fn symbol_map(symbols: &BTreeMap<String, i32>, format: SymbolFormat) -> String {
+	let mut output = String::new();
+	for (name, value) in symbols {
+		let value = *value as u16;
+		match format {
+			| SymbolFormat::Equ => {
+				// A number can't start with a letter
+				let zero = if value >> 12 >= 0xA { "0" } else { "" };
+				writeln!(output, "{}\tEQU {}{:04X}H", name, zero, value).unwrap()
+			}
+			| SymbolFormat::NoIce => writeln!(output, "DEF {} {:04X}", name, value).unwrap(),
+			| SymbolFormat::Sym => writeln!(output, "{:04X} {}", value, name).unwrap(),
+		}
+	}
+	output
+}
@@ -1,31 +1,86 @@
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::BufReader;
+use std::fs;
+use std::path::Path;
 use std::process::exit;
 
-fn show_help_and_die() {
+use z80::assembler::Target;
+use z80::listing::{self, SymbolFormat};
+use z80::output;
+use z80::parser::{parse_program_as, Syntax};
+use z80::resolver::{Dialect, Program, Resolver};
+
+fn show_help_and_die() -> ! {
 	println!(concat!(
 		"./z80 [ARGUMENTS] [INPUT FILES]\n",
 		"-h: Show this message\n",
-		"-o [FILE]: Set the output file",
+		"-o [FILE]: Set the output file, when there is one input file\n",
+		"-f bin|hex|com|rel: Set the output format, taken from the extension of the output file by default. A com file starts at 100H, as after ORG 100H, and a rel file is a relocatable object file for L80, accepting CSEG, DSEG and EXTRN\n",
+		"-l [FILE]: Write a listing of the source, when there is one input file\n",
+		"-s [FILE]: Write the symbols, as DEF NAME ADDR for a .noi file, ADDR NAME for a .sym file, and NAME EQU ADDR otherwise\n",
+		"-m: Enable the macro instructions\n",
+		"-u: Enable the undocumented instructions\n",
+		"-r: Use JR when the target is near enough and JP otherwise, for the jumps without a condition or with Z, NZ, C or NC, and DEC B with JP NZ for a DJNZ too far\n",
+		"-t 8080|z80|z180: Set the processor, only accepting its instructions, Z80 by default\n",
+		"-8080: Read the sources in the Intel 8080 mnemonics, like after .8080\n",
+		"-dri: Read the sources like MAC and RMAC, in the 8080 mnemonics and with 16 bits values\n",
 		"-D NAME VALUE: Define a global variable",
 	));
 	exit(0);
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+	Binary,
+	IntelHex,
+	// Starts at 100H, where CP/M loads it
+	Com,
+	// For the linker, like the output of M80
+	Rel,
+}
+
+impl Format {
+	fn from_name(name: &str) -> Option<Format> {
+		match name.to_ascii_lowercase().as_str() {
+			| "bin" => Some(Format::Binary),
+			| "hex" => Some(Format::IntelHex),
+			| "com" => Some(Format::Com),
+			| "rel" => Some(Format::Rel),
+			| _ => None,
+		}
+	}
+
+	fn extension(&self) -> &'static str {
+		match self {
+			| Format::Binary => "bin",
+			| Format::IntelHex => "hex",
+			| Format::Com => "com",
+			| Format::Rel => "rel",
+		}
+	}
+}
+
 #[derive(Default)]
 struct ParsedArguments {
 	input_files_path: Vec<String>,
 	output_path: Option<String>,
+	listing_path: Option<String>,
+	symbols_path: Option<String>,
+	format: Option<Format>,
+	enable_macro_instructions: bool,
+	enable_undocumented_instructions: bool,
+	relaxation: bool,
+	intel_syntax: bool,
+	dialect: Dialect,
+	target: Target,
 	definitions: HashMap<String, i32>,
 }
 
 fn parse_arguments() -> ParsedArguments {
-    let mut output = ParsedArguments::default();
-    let mut args = env::args();
-    
-    args.next(); // Skip the first one, it's the executable's path
+	let mut output = ParsedArguments::default();
+	let mut args = env::args();
+
+	args.next(); // Skip the first one, it's the executable's path
 	while let Some(arg) = args.next() {
 		match arg.as_str() {
 			| "-h" => {
@@ -36,12 +91,52 @@ fn parse_arguments() -> ParsedArguments {
 					panic!("No path has been provided with '-o'");
 				}
 				| Some(path) => {
-					if let Some(_) = output.output_path {
+					if output.output_path.is_some() {
 						panic!("The output path has been specified multiple times");
 					}
 					output.output_path = Some(path);
 				}
 			},
+			| "-l" => match args.next() {
+				| None => {
+					panic!("No path has been provided with '-l'");
+				}
+				| Some(path) => output.listing_path = Some(path),
+			},
+			| "-s" => match args.next() {
+				| None => {
+					panic!("No path has been provided with '-s'");
+				}
+				| Some(path) => output.symbols_path = Some(path),
+			},
+			| "-f" => match args.next().as_deref().map(Format::from_name) {
+				| None => {
+					panic!("No format has been provided with '-f'");
+				}
+				| Some(None) => {
+					panic!("The format should be bin, hex or com");
+				}
+				| Some(format) => output.format = format,
+			},
+			| "-m" => output.enable_macro_instructions = true,
+			| "-u" => output.enable_undocumented_instructions = true,
+			| "-r" => output.relaxation = true,
+			| "-8080" => output.intel_syntax = true,
+			| "-dri" => {
+				output.intel_syntax = true;
+				output.dialect = Dialect::DigitalResearch;
+			}
+			| "-t" => match args.next().map(|x| x.to_ascii_lowercase()).as_deref() {
+				| None => {
+					panic!("No processor has been provided with '-t'");
+				}
+				| Some("8080") => output.target = Target::I8080,
+				| Some("z80") => output.target = Target::Z80,
+				| Some("z180") => output.target = Target::Z180,
+				| Some(_) => {
+					panic!("The processor should be 8080, z80 or z180");
+				}
+			},
 			| "-D" => {
 				let first_arg = args.next();
 				let second_arg = args.next();
@@ -49,7 +144,7 @@ fn parse_arguments() -> ParsedArguments {
 					| (Some(_), None) | (None, _) => {
 						panic!("Not enough arguments for -D");
 					}
-					| (Some(name), Some(val)) => match i32::from_str_radix(val.as_str(), 10) {
+					| (Some(name), Some(val)) => match val.parse::<i32>() {
 						| Err(_) => {
 							panic!("Invalid value for {}: {}", name, val);
 						}
@@ -66,29 +161,130 @@ fn parse_arguments() -> ParsedArguments {
 		}
 	}
 
-    output
+	output
+}
+
+/*
+ * Assembles a file, with the errors written like path:line: message.
+ * Returns the source with the program, for the listing.
+ */
+fn assemble(
+	path: &str,
+	format: Format,
+	args: &ParsedArguments,
+) -> Result<(String, Program), String> {
+	let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+	let syntax = if args.intel_syntax {
+		Syntax::Intel
+	} else {
+		Syntax::Zilog
+	};
+	let lines = parse_program_as(&source, syntax).map_err(|e| {
+		format!(
+			"{}:{}:{}: syntax error at '{}'",
+			path, e.line, e.column, e.text
+		)
+	})?;
+
+	let origin = if format == Format::Com { 0x0100 } else { 0 };
+	let mut resolver = Resolver::new(
+		origin,
+		args.enable_macro_instructions,
+		args.enable_undocumented_instructions,
+	)
+	.with_target(args.target)
+	.with_dialect(args.dialect)
+	.with_relaxation(args.relaxation)
+	.with_relocation(format == Format::Rel);
+	for (name, value) in &args.definitions {
+		resolver.define(name, *value);
+	}
+	let program = resolver
+		.resolve(&lines)
+		.map_err(|e| format!("{}:{}: {}", path, e.line, e.error))?;
+	Ok((source, program))
+}
+
+fn encode(path: &str, format: Format, program: &Program) -> Result<Vec<u8>, String> {
+	match format {
+		| Format::Binary => Ok(output::binary(&program.blocks)),
+		| Format::IntelHex => Ok(output::intel_hex(&program.blocks, program.entry).into_bytes()),
+		| Format::Com => output::com(&program.blocks)
+			.ok_or_else(|| format!("{}: a .COM file can't write below 100H", path)),
+		// Named after the file, like with M80
+		| Format::Rel => {
+			let name = Path::new(path)
+				.file_stem()
+				.map_or(String::new(), |x| x.to_string_lossy().to_ascii_uppercase());
+			Ok(output::rel(
+				&name,
+				&program.blocks,
+				program.entry,
+				&program.linkage,
+			))
+		}
+	}
 }
 
 fn main() {
-    let args = parse_arguments();
-    
-	if args.input_files_path.len() == 0 {
+	let args = parse_arguments();
+
+	if args.input_files_path.is_empty() {
 		show_help_and_die();
 	}
+	if args.input_files_path.len() > 1 {
+		if args.output_path.is_some() {
+			panic!("'-o' can only be used with one input file");
+		}
+		if args.listing_path.is_some() || args.symbols_path.is_some() {
+			panic!("'-l' and '-s' can only be used with one input file");
+		}
+	}
 
-	for path in args.input_files_path {
-		match File::options()
-			.read(true)
-			.write(false)
-			.open(&path)
-		{
-			| Err(e) => {
-				panic!("Unable to open {}: {}", path, e);
-			}
-			| Ok(file) => {
-                let _reader = BufReader::new(file);
+	let format = args
+		.format
+		.or_else(|| {
+			let output_path = Path::new(args.output_path.as_ref()?);
+			Format::from_name(output_path.extension()?.to_str()?)
+		})
+		.unwrap_or(Format::Binary);
+
+	let mut has_error_occured = false;
+	for path in &args.input_files_path {
+		let output_path = match &args.output_path {
+			| Some(output_path) => output_path.clone(),
+			| None => Path::new(path)
+				.with_extension(format.extension())
+				.to_string_lossy()
+				.into_owned(),
+		};
 
+		let write = |path: &str, contents: &[u8]| {
+			fs::write(path, contents).map_err(|e| format!("{}: {}", path, e))
+		};
+		let result = assemble(path, format, &args).and_then(|(source, program)| {
+			write(&output_path, &encode(path, format, &program)?)?;
+			if let Some(listing_path) = &args.listing_path {
+				write(listing_path, listing::listing(&source, &program).as_bytes())?;
+			}
+			if let Some(symbols_path) = &args.symbols_path {
+				let format = match Path::new(symbols_path).extension().and_then(|x| x.to_str()) {
+					| Some(x) if x.eq_ignore_ascii_case("noi") => SymbolFormat::NoIce,
+					| Some(x) if x.eq_ignore_ascii_case("sym") => SymbolFormat::Sym,
+					| _ => SymbolFormat::Equ,
+				};
+				let map = listing::symbol_map(&program.symbols, format);
+				write(symbols_path, map.as_bytes())?;
 			}
+			Ok(())
+		});
+		if let Err(message) = result {
+			eprintln!("{}", message);
+			has_error_occured = true;
 		}
 	}
+
+	if has_error_occured {
+		exit(1);
+	}
 }
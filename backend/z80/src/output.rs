@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::resolver::{Block, Linkage, Reference, Segment};
+
+// The memory image from start, with what isn't written left at 0
+fn image(blocks: &[Block], start: usize) -> Vec<u8> {
+	let end = blocks
+		.iter()
+		.map(|block| block.address as usize + block.bytes.len())
+		.max()
+		.unwrap_or(start);
+
+	let mut image = vec![0; end.saturating_sub(start)];
+	for block in blocks {
+		let offset = block.address as usize - start;
+		image[offset..offset + block.bytes.len()].copy_from_slice(&block.bytes);
+	}
+	image
+}
+
+/*
+ * The memory image from the lowest address written to the highest one,
+ * with the gaps between the blocks filled with 0
+ */
+pub fn binary(blocks: &[Block]) -> Vec<u8> {
+	match blocks.iter().map(|block| block.address as usize).min() {
+		| None => Vec::new(),
+		| Some(start) => image(blocks, start),
+	}
+}
+
+// A CP/M program, which is loaded at 100H. None if it writes below it.
+pub fn com(blocks: &[Block]) -> Option<Vec<u8>> {
+	if blocks.iter().any(|block| block.address < 0x0100) {
+		return None;
+	}
+	Some(image(blocks, 0x0100))
+}
+
+fn record(output: &mut String, address: u16, kind: u8, data: &[u8]) {
+	let header = [data.len() as u8, (address >> 8) as u8, address as u8, kind];
+	let checksum = header
+		.iter()
+		.chain(data)
+		.fold(0u8, |acc, x| acc.wrapping_add(*x))
+		.wrapping_neg();
+	output.push(':');
+	for byte in header.iter().chain(data).chain([checksum].iter()) {
+		write!(output, "{:02X}", byte).unwrap();
+	}
+	output.push('\n');
+}
+
+/*
+ * Intel HEX, with records of at most 16 bytes which never span two blocks.
+ * The entry point is the address of the end of file record, like with the
+ * Intel tools.
+ */
+pub fn intel_hex(blocks: &[Block], entry: Option<u16>) -> String {
+	let mut output = String::new();
+	for block in blocks {
+		for (i, data) in block.bytes.chunks(16).enumerate() {
+			record(
+				&mut output,
+				block.address.wrapping_add(i as u16 * 16),
+				0x00,
+				data,
+			);
+		}
+	}
+	record(&mut output, entry.unwrap_or(0), 0x01, &[]);
+	output
+}
+
+// The bits of a .REL file, from the highest one of each byte
+#[derive(Default)]
+struct Bits {
+	bytes: Vec<u8>,
+	// Of the last byte, 8 once it is full
+	used: u8,
+}
+
+impl Bits {
+	fn push(&mut self, value: u16, count: u8) {
+		for i in (0..count).rev() {
+			if self.bytes.is_empty() || self.used == 8 {
+				self.bytes.push(0);
+				self.used = 0;
+			}
+			*self.bytes.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (7 - self.used);
+			self.used += 1;
+		}
+	}
+
+	// The next item starts a byte
+	fn align(&mut self) {
+		self.used = 8;
+	}
+
+	fn byte(&mut self, byte: u8) {
+		self.push(0, 1);
+		self.push(byte as u16, 8);
+	}
+
+	// Relative to a segment, an absolute word being two bytes
+	fn word(&mut self, segment: Segment, word: u16) {
+		match segment {
+			| Segment::Absolute => {
+				self.byte(word as u8);
+				self.byte((word >> 8) as u8);
+			}
+			| _ => {
+				self.push(1, 1);
+				self.push(segment as u16, 2);
+				self.push(word & 0xFF, 8);
+				self.push(word >> 8, 8);
+			}
+		}
+	}
+
+	// The special items, with their address and their name
+	fn link(&mut self, control: u16, address: Option<(Segment, u16)>, name: Option<&str>) {
+		self.push(0b100, 3);
+		self.push(control, 4);
+		if let Some((segment, address)) = address {
+			self.push(segment as u16, 2);
+			self.push(address & 0xFF, 8);
+			self.push(address >> 8, 8);
+		}
+		if let Some(name) = name {
+			let name = &name.as_bytes()[..name.len().min(7)];
+			self.push(name.len() as u16, 3);
+			for c in name {
+				self.push(*c as u16, 8);
+			}
+		}
+	}
+}
+
+const ENTRY_SYMBOL: u16 = 0;
+const PROGRAM_NAME: u16 = 2;
+const CHAIN_EXTERNAL: u16 = 6;
+const ENTRY_POINT: u16 = 7;
+const DATA_SIZE: u16 = 10;
+const LOCATION: u16 = 11;
+const PROGRAM_SIZE: u16 = 13;
+const END_PROGRAM: u16 = 14;
+const END_FILE: u16 = 15;
+
+/*
+ * The relocatable object file of M80, which L80 links. The words to
+ * relocate are written with their segment, and those referring to an
+ * external hold where the previous one is, the first one 0, so that the
+ * linker follows the chain from the last one. Names keep the 7 characters
+ * the format has room for.
+ */
+pub fn rel(name: &str, blocks: &[Block], entry: Option<u16>, linkage: &Linkage) -> Vec<u8> {
+	let mut bits = Bits::default();
+	bits.link(PROGRAM_NAME, None, Some(name));
+	for public in linkage.publics.keys() {
+		bits.link(ENTRY_SYMBOL, None, Some(public));
+	}
+	if linkage.data_size > 0 {
+		bits.link(
+			DATA_SIZE,
+			Some((Segment::Absolute, linkage.data_size)),
+			None,
+		);
+	}
+	bits.link(PROGRAM_SIZE, Some((Segment::Code, linkage.code_size)), None);
+
+	let relocations: BTreeMap<_, _> = linkage
+		.relocations
+		.iter()
+		.map(|x| ((x.segment, x.address), &x.reference))
+		.collect();
+	let mut chains: BTreeMap<&str, (Segment, u16)> = BTreeMap::new();
+	let mut location = None;
+	for block in blocks {
+		if location != Some((block.segment, block.address)) {
+			bits.link(LOCATION, Some((block.segment, block.address)), None);
+		}
+		let mut i = 0;
+		while i < block.bytes.len() {
+			let address = block.address.wrapping_add(i as u16);
+			let word = || u16::from_le_bytes([block.bytes[i], block.bytes[i + 1]]);
+			match relocations.get(&(block.segment, address)) {
+				| None => {
+					bits.byte(block.bytes[i]);
+					i += 1;
+					continue;
+				}
+				| Some(Reference::Segment(segment)) => bits.word(*segment, word()),
+				| Some(Reference::External(name)) => {
+					match chains.insert(name, (block.segment, address)) {
+						| Some((segment, previous)) => bits.word(segment, previous),
+						| None => bits.word(Segment::Absolute, 0),
+					}
+				}
+			}
+			i += 2;
+		}
+		location = Some((
+			block.segment,
+			block.address.wrapping_add(block.bytes.len() as u16),
+		));
+	}
+
+	for (public, address) in &linkage.publics {
+		bits.link(ENTRY_POINT, Some(*address), Some(public));
+	}
+	for (external, last) in chains {
+		bits.link(CHAIN_EXTERNAL, Some(last), Some(external));
+	}
+	let entry = entry.map_or((Segment::Absolute, 0), |x| (linkage.entry, x));
+	bits.link(END_PROGRAM, Some(entry), None);
+	bits.align();
+	bits.link(END_FILE, None, None);
+	bits.bytes
+}
@@ -1,73 +1,297 @@
-use nom::{IResult, Parser, branch::alt, bytes::complete::{tag_no_case, take_until}, character::{complete::{char, multispace0}, digit1}, combinator::{map, opt}, multi::fold_many0, sequence::{delimited, terminated}};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use nom::{IResult, Parser, branch::alt, bytes::complete::{tag, tag_no_case, take_till, take_until, take_while, take_while1}, character::complete::{char, multispace0, none_of, one_of, satisfy, space0, space1}, combinator::{eof, map, not, opt, peek, recognize, verify}, multi::{fold_many0, fold_many1, separated_list0, separated_list1}, sequence::{delimited, preceded, terminated}};
 
 use crate::instruction::{ByteRegister, Condition, Instruction, Operand, UndocumentedRegister, WordRegister};
 
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// A name which isn't the start of a longer one
+fn keyword<'a>(name: &'static str) -> impl Parser<&'a str, Output = &'a str, Error = nom::error::Error<&'a str>> {
+    terminated(tag_no_case(name), not(satisfy(is_identifier_char)))
+}
+
+// The $ after the first letter only separate words, like in the numbers
+fn parse_identifier(text: &str) -> IResult<&str, String> {
+    map(
+        recognize((
+            satisfy(|c| c.is_ascii_alphabetic() || c == '_'),
+            take_while(|c| is_identifier_char(c) || c == '$'),
+        )),
+        |x: &str| x.chars().filter(|c| *c != '$').collect::<String>().to_ascii_uppercase()
+    ).parse(text)
+}
+
 fn parse_condition(text: &str) -> IResult<&str, Condition> {
     alt((
-        map(tag_no_case("Z"),   |_| Condition::Z),
-        map(tag_no_case("NZ"),  |_| Condition::NZ),
-        map(tag_no_case("C"),   |_| Condition::C),
-        map(tag_no_case("NC"),  |_| Condition::NC),
-        map(tag_no_case("PO"),  |_| Condition::PO),
-        map(tag_no_case("PE"),  |_| Condition::PE),
-        map(tag_no_case("P"),   |_| Condition::P),
-        map(tag_no_case("M"),   |_| Condition::M),
+        map(keyword("Z"),   |_| Condition::Z),
+        map(keyword("NZ"),  |_| Condition::NZ),
+        map(keyword("C"),   |_| Condition::C),
+        map(keyword("NC"),  |_| Condition::NC),
+        map(keyword("PO"),  |_| Condition::PO),
+        map(keyword("PE"),  |_| Condition::PE),
+        map(keyword("P"),   |_| Condition::P),
+        map(keyword("M"),   |_| Condition::M),
     )).parse(text)
 }
 
 fn parse_byte_register(text: &str) -> IResult<&str, ByteRegister> {
     alt((
-        map(tag_no_case("A"), |_| ByteRegister::A),
-        map(tag_no_case("B"), |_| ByteRegister::B),
-        map(tag_no_case("C"), |_| ByteRegister::C),
-        map(tag_no_case("D"), |_| ByteRegister::D),
-        map(tag_no_case("E"), |_| ByteRegister::E),
-        map(tag_no_case("H"), |_| ByteRegister::H),
-        map(tag_no_case("L"), |_| ByteRegister::L),
+        map(keyword("A"), |_| ByteRegister::A),
+        map(keyword("B"), |_| ByteRegister::B),
+        map(keyword("C"), |_| ByteRegister::C),
+        map(keyword("D"), |_| ByteRegister::D),
+        map(keyword("E"), |_| ByteRegister::E),
+        map(keyword("H"), |_| ByteRegister::H),
+        map(keyword("L"), |_| ByteRegister::L),
     )).parse(text)
 }
 
 fn parse_word_register(text: &str) -> IResult<&str, WordRegister> {
     alt((
-        map(tag_no_case("AF"), |_| WordRegister::AF),
-        map(tag_no_case("BC"), |_| WordRegister::BC),
-        map(tag_no_case("DE"), |_| WordRegister::DE),
-        map(tag_no_case("HL"), |_| WordRegister::HL),
         map(tag_no_case("AF'"), |_| WordRegister::AF_),
         map(tag_no_case("BC'"), |_| WordRegister::BC_),
         map(tag_no_case("DE'"), |_| WordRegister::DE_),
         map(tag_no_case("HL'"), |_| WordRegister::HL_),
-        map(tag_no_case("IX"), |_| WordRegister::IX),
-        map(tag_no_case("IY"), |_| WordRegister::IY),
-        map(tag_no_case("SP"), |_| WordRegister::SP),
+        map(keyword("AF"), |_| WordRegister::AF),
+        map(keyword("BC"), |_| WordRegister::BC),
+        map(keyword("DE"), |_| WordRegister::DE),
+        map(keyword("HL"), |_| WordRegister::HL),
+        map(keyword("IX"), |_| WordRegister::IX),
+        map(keyword("IY"), |_| WordRegister::IY),
+        map(keyword("SP"), |_| WordRegister::SP),
     )).parse(text)
 }
 
 fn parse_undocumented_register(text: &str) -> IResult<&str, UndocumentedRegister> {
     alt((
-        map(tag_no_case("IXH"), |_| UndocumentedRegister::IXH),
-        map(tag_no_case("IXL"), |_| UndocumentedRegister::IXL),
-        map(tag_no_case("IYH"), |_| UndocumentedRegister::IYH),
-        map(tag_no_case("IYL"), |_| UndocumentedRegister::IYL),
+        map(keyword("IXH"), |_| UndocumentedRegister::IXH),
+        map(keyword("IXL"), |_| UndocumentedRegister::IXL),
+        map(keyword("IYH"), |_| UndocumentedRegister::IYH),
+        map(keyword("IYL"), |_| UndocumentedRegister::IYL),
     )).parse(text)
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Minus,
+    Not,
+    High,
+    Low,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Expr {
     Constant(i32),
+    Identifier(String),
+    // $, the address of the current instruction
+    Location,
+    Unary(UnaryOperator, Box<Expr>),
+    Binary(BinaryOperator, Box<Expr>, Box<Expr>),
+}
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvaluationError {
+    UndefinedSymbol(String),
+    DivisionByZero,
 }
 
-fn parse_expr(text: &str) -> IResult<&str, Expr> {
-    map(
-        (
-            multispace0,
-            alt((
-                map(digit1(), |x: &str| Expr::Constant(x.parse().unwrap())),
-            )),
-            multispace0,
+impl Expr {
+    /*
+     * Computes the value of the expression, in 32 bits so that the
+     * caller can tell whether it fits where it's used
+     */
+    pub fn evaluate(&self, symbols: &BTreeMap<String, i32>, location: u16) -> Result<i32, EvaluationError> {
+        match self {
+            | Expr::Constant(n) => Ok(*n),
+            | Expr::Identifier(name) => match symbols.get(name) {
+                | Some(n) => Ok(*n),
+                | None => Err(EvaluationError::UndefinedSymbol(name.clone())),
+            },
+            | Expr::Location => Ok(location as i32),
+            | Expr::Unary(op, x) => {
+                let x = x.evaluate(symbols, location)?;
+                Ok(match op {
+                    | UnaryOperator::Minus => x.wrapping_neg(),
+                    | UnaryOperator::Not => !x,
+                    | UnaryOperator::High => (x >> 8) & 0xFF,
+                    | UnaryOperator::Low => x & 0xFF,
+                })
+            }
+            | Expr::Binary(op, x, y) => {
+                let x = x.evaluate(symbols, location)?;
+                let y = y.evaluate(symbols, location)?;
+                let shift = |x: i32| if (0..32).contains(&y) { x >> y } else { 0 };
+                Ok(match op {
+                    | BinaryOperator::Add => x.wrapping_add(y),
+                    | BinaryOperator::Sub => x.wrapping_sub(y),
+                    | BinaryOperator::Mul => x.wrapping_mul(y),
+                    | BinaryOperator::Div | BinaryOperator::Mod if y == 0 => {
+                        return Err(EvaluationError::DivisionByZero)
+                    }
+                    | BinaryOperator::Div => x.wrapping_div(y),
+                    | BinaryOperator::Mod => x.wrapping_rem(y),
+                    | BinaryOperator::And => x & y,
+                    | BinaryOperator::Or => x | y,
+                    | BinaryOperator::Xor => x ^ y,
+                    | BinaryOperator::Shl if (0..32).contains(&y) => x << y,
+                    | BinaryOperator::Shl => 0,
+                    // A negative value is shifted as the 16 bits word it stands for
+                    | BinaryOperator::Shr if x < 0 => shift(x & 0xFFFF),
+                    | BinaryOperator::Shr => shift(x),
+                })
+            }
+        }
+    }
+}
+
+const OPERATOR_NAMES: [&str; 9] = ["MOD", "AND", "OR", "XOR", "NOT", "SHL", "SHR", "HIGH", "LOW"];
+
+fn parse_symbol(text: &str) -> IResult<&str, String> {
+    verify(parse_identifier, |x: &str| !OPERATOR_NAMES.contains(&x)).parse(text)
+}
+
+/*
+ * Numbers are written like in PL/M: they start with a digit, may end with
+ * the radix (B, O or Q, D, H) and the $ in them are ignored. $FF is also
+ * accepted for hexadecimal.
+ */
+fn parse_number(text: &str) -> IResult<&str, i32> {
+    let (rest, digits) = alt((
+        recognize((satisfy(|c| c.is_ascii_digit()), take_while(|c: char| is_identifier_char(c) || c == '$'))),
+        preceded(char('$'), terminated(take_while1(|c: char| c.is_ascii_hexdigit()), not(satisfy(is_identifier_char)))),
+    )).parse(text)?;
+    let digits: String = digits.chars().filter(|c| *c != '$').collect();
+
+    let (digits, radix) = match digits.chars().last().map(|c| c.to_ascii_uppercase()) {
+        | _ if text.starts_with('$') => (&digits[..], 16),
+        | Some('B') => (&digits[..digits.len() - 1], 2),
+        | Some('O') | Some('Q') => (&digits[..digits.len() - 1], 8),
+        | Some('D') => (&digits[..digits.len() - 1], 10),
+        | Some('H') => (&digits[..digits.len() - 1], 16),
+        | _ => (&digits[..], 10),
+    };
+    match i32::from_str_radix(digits, radix) {
+        | Ok(n) => Ok((rest, n)),
+        | Err(_) => Err(nom::Err::Error(nom::error::Error::new(text, nom::error::ErrorKind::Digit))),
+    }
+}
+
+// 'A' is 41H and 'AB' is 4142H, with '' standing for a quote
+fn parse_character_constant(text: &str) -> IResult<&str, i32> {
+    let (rest, characters) = delimited(
+        char('\''),
+        fold_many1(
+            alt((map(tag("\'\'"), |_| '\''), none_of("'\n"))),
+            || 0i32,
+            |acc, c| acc.wrapping_shl(8) | (c as i32 & 0xFF),
         ),
-        |x| x.1
+        char('\''),
+    ).parse(text)?;
+    Ok((rest, characters))
+}
+
+fn parse_primary(text: &str) -> IResult<&str, Expr> {
+    delimited(
+        space0,
+        alt((
+            map(parse_number, Expr::Constant),
+            map(parse_character_constant, Expr::Constant),
+            map(char('$'), |_| Expr::Location),
+            map(parse_symbol, Expr::Identifier),
+            delimited(char('('), parse_expr, char(')')),
+        )),
+        space0
+    ).parse(text)
+}
+
+fn parse_unary(text: &str) -> IResult<&str, Expr> {
+    let unary = |op: UnaryOperator| move |x| Expr::Unary(op.clone(), Box::new(x));
+    alt((
+        map(preceded((space0, char('-')), parse_unary), unary(UnaryOperator::Minus)),
+        preceded((space0, char('+')), parse_unary),
+        map(preceded((space0, keyword("HIGH")), parse_unary), unary(UnaryOperator::High)),
+        map(preceded((space0, keyword("LOW")), parse_unary), unary(UnaryOperator::Low)),
+        parse_primary,
+    )).parse(text)
+}
+
+// A sequence of operands with operators of the same precedence, from left to right
+fn parse_binary<'a>(
+    operand: fn(&'a str) -> IResult<&'a str, Expr>,
+    operator: fn(&'a str) -> IResult<&'a str, BinaryOperator>,
+    text: &'a str
+) -> IResult<&'a str, Expr> {
+    let (rest, first) = operand(text)?;
+    fold_many0(
+        (operator, operand),
+        move || first.clone(),
+        |x, (op, y)| Expr::Binary(op, Box::new(x), Box::new(y))
+    ).parse(rest)
+}
+
+fn parse_multiplicative(text: &str) -> IResult<&str, Expr> {
+    parse_binary(parse_unary, |text| alt((
+        map(char('*'), |_| BinaryOperator::Mul),
+        map(char('/'), |_| BinaryOperator::Div),
+        map(keyword("MOD"), |_| BinaryOperator::Mod),
+        map(keyword("SHL"), |_| BinaryOperator::Shl),
+        map(keyword("SHR"), |_| BinaryOperator::Shr),
+    )).parse(text), text)
+}
+
+fn parse_additive(text: &str) -> IResult<&str, Expr> {
+    parse_binary(parse_multiplicative, |text| alt((
+        map(char('+'), |_| BinaryOperator::Add),
+        map(char('-'), |_| BinaryOperator::Sub),
+    )).parse(text), text)
+}
+
+fn parse_not(text: &str) -> IResult<&str, Expr> {
+    alt((
+        map(preceded((space0, keyword("NOT")), parse_not), |x| Expr::Unary(UnaryOperator::Not, Box::new(x))),
+        parse_additive,
+    )).parse(text)
+}
+
+fn parse_and(text: &str) -> IResult<&str, Expr> {
+    parse_binary(parse_not, |text| map(keyword("AND"), |_| BinaryOperator::And).parse(text), text)
+}
+
+/*
+ * The precedence is the one of the Intel assemblers: * / MOD SHL SHR,
+ * then + -, then NOT, then AND, then OR XOR. HIGH, LOW and the unary
+ * minus apply to what directly follows them.
+ */
+pub fn parse_expr(text: &str) -> IResult<&str, Expr> {
+    parse_binary(parse_and, |text| alt((
+        map(keyword("OR"), |_| BinaryOperator::Or),
+        map(keyword("XOR"), |_| BinaryOperator::Xor),
+    )).parse(text), text)
+}
+
+// What may follow an operand
+fn parse_operand_end(text: &str) -> IResult<&str, ()> {
+    map(
+        peek((space0, alt((tag(","), tag(";"), tag("\n"), tag("\r"), eof)))),
+        |_| ()
     ).parse(text)
 }
 
@@ -76,9 +300,14 @@ fn parse_operand_main(is_expected_to_be_port: bool)
 {
     return move |text| {
         alt((
+            // (1+2)*3 is a constant, and (1+2) an address
             delimited(
                 (char('('), multispace0),
                 alt((
+                    map(
+                        (parse_word_register, space0, peek(one_of("+-")), parse_expr),
+                        |x| Operand::AddressRegisterWithOffset(x.0, x.3)
+                    ),
                     map(
                         parse_byte_register,
                        |x| Operand::PortRegister(x)
@@ -87,10 +316,6 @@ fn parse_operand_main(is_expected_to_be_port: bool)
                         parse_word_register,
                         |x| Operand::AddressRegister(x)
                     ),
-                    map(
-                        (parse_word_register, (multispace0, char('+')), parse_expr),
-                        |x| Operand::AddressRegisterWithOffset(x.0, x.2)
-                    ),
                     map(
                         parse_expr,
                         |x| {
@@ -102,7 +327,7 @@ fn parse_operand_main(is_expected_to_be_port: bool)
                         }
                     )
                 )),
-                (multispace0, char(')'))
+                (multispace0, char(')'), parse_operand_end)
             ),
             map(
                 parse_undocumented_register,
@@ -110,9 +335,9 @@ fn parse_operand_main(is_expected_to_be_port: bool)
             ),
             map(parse_byte_register, |x| Operand::ByteRegister(x)),
             map(parse_word_register, |x| Operand::WordRegister(x)),
-            map(tag_no_case("I"), |_| Operand::I),
-            map(tag_no_case("R"), |_| Operand::R),
-            map(tag_no_case("F"), |_| Operand::F),
+            map(keyword("I"), |_| Operand::I),
+            map(keyword("R"), |_| Operand::R),
+            map(keyword("F"), |_| Operand::F),
             map(parse_expr, |x| Operand::Constant(x))
         )).parse(text)
     }
@@ -124,14 +349,93 @@ fn parse_operand(is_expected_to_be_port: bool)
 {
     move |text|
         delimited(
-            multispace0,
+            space0,
             parse_operand_main(is_expected_to_be_port),
-            multispace0
+            space0
         ).parse(text)
 }
 
+/*
+ * The instructions without operands, read as a whole word so that CPL
+ * isn't CP L and RETI isn't RET followed by garbage
+ */
+fn parse_implied_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr, Expr>> {
+    let (rest, name) = parse_identifier(text)?;
+    let inst = match name.as_str() {
+        | "EXX" => Instruction::EXX,
+        | "LDI" => Instruction::LDI,
+        | "LDIR" => Instruction::LDIR,
+        | "LDD" => Instruction::LDD,
+        | "LDDR" => Instruction::LDDR,
+        | "CPI" => Instruction::CPI,
+        | "CPIR" => Instruction::CPIR,
+        | "CPD" => Instruction::CPD,
+        | "CPDR" => Instruction::CPDR,
+        | "DAA" => Instruction::DAA,
+        | "CPL" => Instruction::CPL,
+        | "NEG" => Instruction::NEG,
+        | "CCF" => Instruction::CCF,
+        | "SCF" => Instruction::SCF,
+        | "NOP" => Instruction::NOP,
+        | "HALT" => Instruction::HALT,
+        | "DI" => Instruction::DI,
+        | "EI" => Instruction::EI,
+        | "RLCA" => Instruction::RLCA,
+        | "RLA" => Instruction::RLA,
+        | "RRCA" => Instruction::RRCA,
+        | "RRA" => Instruction::RRA,
+        | "RLD" => Instruction::RLD,
+        | "RRD" => Instruction::RRD,
+        | "RETI" => Instruction::RETI,
+        | "RETN" => Instruction::RETN,
+        | "INI" => Instruction::INI,
+        | "INIR" => Instruction::INIR,
+        | "IND" => Instruction::IND,
+        | "INDR" => Instruction::INDR,
+        | "OUTI" => Instruction::OUTI,
+        | "OTIR" => Instruction::OTIR,
+        | "OUTD" => Instruction::OUTD,
+        | "OTDR" => Instruction::OTDR,
+        | "OTIM" => Instruction::OTIM,
+        | "OTIMR" => Instruction::OTIMR,
+        | "OTDM" => Instruction::OTDM,
+        | "OTDMR" => Instruction::OTDMR,
+        | "SLP" => Instruction::SLP,
+        | _ => return Err(nom::Err::Error(nom::error::Error::new(text, nom::error::ErrorKind::Tag))),
+    };
+    Ok((rest, inst))
+}
+
+// Those of the Z180 with operands, before IN and OUT take IN0 and OUT0
+fn parse_z180_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr, Expr>> {
+    alt((
+        map(
+            (tag_no_case("MLT"), parse_operand(false)),
+            | x | Instruction::MLT(x.1)
+        ),
+        map(
+            (tag_no_case("TSTIO"), parse_expr),
+            | x | Instruction::TSTIO(x.1)
+        ),
+        map(
+            (tag_no_case("TST"), parse_operand(false)),
+            | x | Instruction::TST(x.1)
+        ),
+        map(
+            (tag_no_case("IN0"), parse_operand(false), char(','), parse_operand(true)),
+            | x | Instruction::IN0(x.1, x.3)
+        ),
+        map(
+            (tag_no_case("OUT0"), parse_operand(true), char(','), parse_operand(false)),
+            | x | Instruction::OUT0(x.1, x.3)
+        ),
+    )).parse(text)
+}
+
 fn parse_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr, Expr>> {
     alt((
+        parse_implied_instruction,
+        parse_z180_instruction,
         alt((
             map(
                 (tag_no_case("LD"), parse_operand(false), char(','), parse_operand(false)),
@@ -149,42 +453,6 @@ fn parse_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr,
                 (tag_no_case("EX"), parse_operand(false), char(','), parse_operand(false)),
                 | x | Instruction::EX(x.1, x.3)
             ),
-            map(
-                tag_no_case("EXX"),
-                | _ | Instruction::EXX
-            ),
-            map(
-                tag_no_case("LDI"),
-                | _ | Instruction::LDI
-            ),
-            map(
-                tag_no_case("LDIR"),
-                | _ | Instruction::LDIR
-            ),
-            map(
-                tag_no_case("LDD"),
-                | _ | Instruction::LDD
-            ),
-            map(
-                tag_no_case("LDDR"),
-                | _ | Instruction::LDDR
-            ),
-            map(
-                tag_no_case("CPI"),
-                | _ | Instruction::CPI
-            ),
-            map(
-                tag_no_case("CPIR"),
-                | _ | Instruction::CPIR
-            ),
-            map(
-                tag_no_case("CPD"),
-                | _ | Instruction::CPD
-            ),
-            map(
-                tag_no_case("CPDR"),
-                | _ | Instruction::CPDR
-            ),
             map(
                 (tag_no_case("ADD"), parse_operand(false), char(','), parse_operand(false)),
                 | x | Instruction::ADD(x.1, x.3)
@@ -227,62 +495,10 @@ fn parse_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr,
                 (tag_no_case("DEC"), parse_operand(false)),
                 | x | Instruction::DEC(x.1)
             ),
-            map(
-                tag_no_case("DAA"),
-                | _ | Instruction::DAA
-            ),
-            map(
-                tag_no_case("CPL"),
-                | _ | Instruction::CPL
-            ),
-            map(
-                tag_no_case("NEG"),
-                | _ | Instruction::NEG
-            ),
-            map(
-                tag_no_case("CCF"),
-                | _ | Instruction::CCF
-            ),
-            map(
-                tag_no_case("SCF"),
-                | _ | Instruction::SCF
-            ),
-            map(
-                tag_no_case("NOP"),
-                | _ | Instruction::NOP
-            ),
-            map(
-                tag_no_case("HALT"),
-                | _ | Instruction::HALT
-            ),
-            map(
-                tag_no_case("DI"),
-                | _ | Instruction::DI
-            ),
-            map(
-                tag_no_case("EI"),
-                | _ | Instruction::EI
-            ),
             map(
                 (tag_no_case("IM"), parse_expr),
                 | x | Instruction::IM(x.1)
             ),
-            map(
-                tag_no_case("RLCA"),
-                | _ | Instruction::RLCA
-            ),
-            map(
-                tag_no_case("RLA"),
-                | _ | Instruction::RLA
-            ),
-            map(
-                tag_no_case("RRCA"),
-                | _ | Instruction::RRCA
-            ),
-            map(
-                tag_no_case("RRA"),
-                | _ | Instruction::RRA
-            ),
             map(
                 (tag_no_case("RLC"), parse_operand(false)),
                 | x | Instruction::RLC(x.1)
@@ -303,8 +519,6 @@ fn parse_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr,
                 (tag_no_case("SLA"), parse_operand(false)),
                 | x | Instruction::SLA(x.1)
             ),
-        )),
-        alt((
             map(
                 (tag_no_case("SLL"), parse_operand(false)),
                 | x | Instruction::SLL(x.1)
@@ -317,14 +531,8 @@ fn parse_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr,
                 (tag_no_case("SRL"), parse_operand(false)),
                 | x | Instruction::SRL(x.1)
             ),
-            map(
-                tag_no_case("RLD"),
-                | _ | Instruction::RLD
-            ),
-            map(
-                tag_no_case("RRD"),
-                | _ | Instruction::RRD
-            ),
+        )),
+        alt((
             map(
                 (tag_no_case("BIT"), parse_expr, char(','), parse_operand(false)),
                 | x | Instruction::BIT(x.1, x.3)
@@ -338,35 +546,25 @@ fn parse_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr,
                 | x | Instruction::RES(x.1, x.3)
             ),
             map(
-                (tag_no_case("JP"), opt(terminated(parse_condition, char(','))), parse_operand(false)),
+                (tag_no_case("JP"), opt(terminated(preceded(space0, parse_condition), char(','))), parse_operand(false)),
                 | x | Instruction::JP(x.1, x.2)
             ),
             map(
-                (tag_no_case("JR"), opt(terminated(parse_condition, char(','))), parse_operand(false)),
+                (tag_no_case("JR"), opt(terminated(preceded(space0, parse_condition), char(','))), parse_operand(false)),
                 | x | Instruction::JR(x.1, x.2)
             ),
-            /*
             map(
-                tag_no_case("DJNZ"), //(i8),
-                | x | Instruction::DJNZ
+                (tag_no_case("DJNZ"), parse_expr),
+                | x | Instruction::DJNZ(x.1)
             ),
-            */
             map(
-                (tag_no_case("CALL"), opt(terminated(parse_condition, char(','))), parse_operand(false)),
+                (tag_no_case("CALL"), opt(terminated(preceded(space0, parse_condition), char(','))), parse_operand(false)),
                 | x | Instruction::CALL(x.1, x.2)
             ),
             map(
-                (tag_no_case("RET"), opt(parse_condition)),
+                (keyword("RET"), opt(preceded(space0, parse_condition))),
                 | x | Instruction::RET(x.1)
             ),
-            map(
-                tag_no_case("RETI"),
-                | _ | Instruction::RETI
-            ),
-            map(
-                tag_no_case("RETN"),
-                | _ | Instruction::RETN
-            ),
             map(
                 (tag_no_case("RST"), parse_expr),
                 | x | Instruction::RST(x.1)
@@ -375,48 +573,145 @@ fn parse_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr,
                 (tag_no_case("IN"), parse_operand(false), char(','), parse_operand(true)),
                 | x | Instruction::IN(x.1, x.3)
             ),
-            map(
-                tag_no_case("INI"),
-                | _ | Instruction::INI
-            ),
-            map(
-                tag_no_case("INIR"),
-                | _ | Instruction::INIR
-            ),
-            map(
-                tag_no_case("IND"),
-                | _ | Instruction::IND
-            ),
-        )),
-        alt((
-            map(
-                tag_no_case("INDR"),
-                | _ | Instruction::INDR
-            ),
             map(
                 (tag_no_case("OUT"), parse_operand(true), char(','), parse_operand(false)),
                 | x | Instruction::OUT(x.1, x.3)
             ),
-            map(
-                tag_no_case("OUTI"),
-                | _ | Instruction::OUTI
-            ),
-            map(
-                tag_no_case("OTIR"),
-                | _ | Instruction::OTIR
-            ),
-            map(
-                tag_no_case("OUTD"),
-                | _ | Instruction::OUTD
-            ),
-            map(
-                tag_no_case("OTDR"),
-                | _ | Instruction::OTDR
-            )
         ))
     )).parse(text)
 }
 
+// The registers of the 8080, with M for the byte at HL
+fn parse_intel_register(text: &str) -> IResult<&str, Operand<Expr, Expr, Expr, Expr>> {
+    delimited(
+        space0,
+        alt((
+            map(keyword("M"), |_| Operand::AddressRegister(WordRegister::HL)),
+            map(parse_byte_register, Operand::ByteRegister),
+        )),
+        space0
+    ).parse(text)
+}
+
+// B, D and H stand for BC, DE and HL, and PSW for AF
+fn parse_intel_pair(text: &str) -> IResult<&str, WordRegister> {
+    delimited(
+        space0,
+        alt((
+            map(keyword("B"), |_| WordRegister::BC),
+            map(keyword("D"), |_| WordRegister::DE),
+            map(keyword("H"), |_| WordRegister::HL),
+            map(keyword("SP"), |_| WordRegister::SP),
+            map(keyword("PSW"), |_| WordRegister::AF),
+        )),
+        space0
+    ).parse(text)
+}
+
+// What follows the J, C or R of a conditional jump, call or return
+fn intel_condition(suffix: &str) -> Option<Condition> {
+    match suffix {
+        | "NZ" => Some(Condition::NZ),
+        | "Z" => Some(Condition::Z),
+        | "NC" => Some(Condition::NC),
+        | "C" => Some(Condition::C),
+        | "PO" => Some(Condition::PO),
+        | "PE" => Some(Condition::PE),
+        | "P" => Some(Condition::P),
+        | "M" => Some(Condition::M),
+        | _ => None,
+    }
+}
+
+/*
+ * The Intel 8080 mnemonics, like MOV A,M or LXI H,1234H, read into the
+ * Z80 instructions doing the same thing
+ */
+fn parse_intel_instruction(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr, Expr>> {
+    let (rest, name) = parse_identifier(text)?;
+    let a = || Operand::ByteRegister(ByteRegister::A);
+    let hl = || Operand::WordRegister(WordRegister::HL);
+    let register = parse_intel_register;
+    let pair = |text| map(parse_intel_pair, Operand::WordRegister).parse(text);
+    // LDAX and STAX only use BC and DE
+    let pointer = |text| map(
+        verify(parse_intel_pair, |x| *x == WordRegister::BC || *x == WordRegister::DE),
+        Operand::AddressRegister
+    ).parse(text);
+    let value = |text| map(parse_expr, Operand::Constant).parse(text);
+    let address = |text| map(parse_expr, Operand::Address).parse(text);
+
+    match name.as_str() {
+        | "MOV" => map((register, char(','), register), |x| Instruction::LD(x.0, x.2)).parse(rest),
+        | "MVI" => map((register, char(','), value), |x| Instruction::LD(x.0, x.2)).parse(rest),
+        | "LXI" => map((pair, char(','), value), |x| Instruction::LD(x.0, x.2)).parse(rest),
+        | "LDA" => map(address, |x| Instruction::LD(a(), x)).parse(rest),
+        | "STA" => map(address, |x| Instruction::LD(x, a())).parse(rest),
+        | "LHLD" => map(address, |x| Instruction::LD(hl(), x)).parse(rest),
+        | "SHLD" => map(address, |x| Instruction::LD(x, hl())).parse(rest),
+        | "LDAX" => map(pointer, |x| Instruction::LD(a(), x)).parse(rest),
+        | "STAX" => map(pointer, |x| Instruction::LD(x, a())).parse(rest),
+        | "XCHG" => Ok((rest, Instruction::EX(Operand::WordRegister(WordRegister::DE), hl()))),
+        | "XTHL" => Ok((rest, Instruction::EX(Operand::AddressRegister(WordRegister::SP), hl()))),
+        | "SPHL" => Ok((rest, Instruction::LD(Operand::WordRegister(WordRegister::SP), hl()))),
+        | "PCHL" => Ok((rest, Instruction::JP(None, hl()))),
+        | "PUSH" => map(pair, Instruction::PUSH).parse(rest),
+        | "POP" => map(pair, Instruction::POP).parse(rest),
+
+        | "ADD" => map(register, |x| Instruction::ADD(a(), x)).parse(rest),
+        | "ADI" => map(value, |x| Instruction::ADD(a(), x)).parse(rest),
+        | "ADC" => map(register, |x| Instruction::ADC(a(), x)).parse(rest),
+        | "ACI" => map(value, |x| Instruction::ADC(a(), x)).parse(rest),
+        | "SUB" => map(register, Instruction::SUB).parse(rest),
+        | "SUI" => map(value, Instruction::SUB).parse(rest),
+        | "SBB" => map(register, |x| Instruction::SBC(a(), x)).parse(rest),
+        | "SBI" => map(value, |x| Instruction::SBC(a(), x)).parse(rest),
+        | "ANA" => map(register, Instruction::AND).parse(rest),
+        | "ANI" => map(value, Instruction::AND).parse(rest),
+        | "XRA" => map(register, Instruction::XOR).parse(rest),
+        | "XRI" => map(value, Instruction::XOR).parse(rest),
+        | "ORA" => map(register, Instruction::OR).parse(rest),
+        | "ORI" => map(value, Instruction::OR).parse(rest),
+        | "CMP" => map(register, Instruction::CP).parse(rest),
+        | "CPI" => map(value, Instruction::CP).parse(rest),
+        | "INR" => map(register, Instruction::INC).parse(rest),
+        | "DCR" => map(register, Instruction::DEC).parse(rest),
+        | "INX" => map(pair, Instruction::INC).parse(rest),
+        | "DCX" => map(pair, Instruction::DEC).parse(rest),
+        | "DAD" => map(pair, |x| Instruction::ADD(hl(), x)).parse(rest),
+
+        | "DAA" => Ok((rest, Instruction::DAA)),
+        | "CMA" => Ok((rest, Instruction::CPL)),
+        | "STC" => Ok((rest, Instruction::SCF)),
+        | "CMC" => Ok((rest, Instruction::CCF)),
+        | "RLC" => Ok((rest, Instruction::RLCA)),
+        | "RRC" => Ok((rest, Instruction::RRCA)),
+        | "RAL" => Ok((rest, Instruction::RLA)),
+        | "RAR" => Ok((rest, Instruction::RRA)),
+        | "NOP" => Ok((rest, Instruction::NOP)),
+        | "HLT" => Ok((rest, Instruction::HALT)),
+        | "DI" => Ok((rest, Instruction::DI)),
+        | "EI" => Ok((rest, Instruction::EI)),
+
+        | "JMP" => map(value, |x| Instruction::JP(None, x)).parse(rest),
+        | "CALL" => map(value, |x| Instruction::CALL(None, x)).parse(rest),
+        | "RET" => Ok((rest, Instruction::RET(None))),
+        // The number of the restart, and not its address
+        | "RST" => map(parse_expr, |x| Instruction::RST(
+            Expr::Binary(BinaryOperator::Mul, Box::new(x), Box::new(Expr::Constant(8)))
+        )).parse(rest),
+        | "IN" => map(parse_expr, |x| Instruction::IN(a(), Operand::Port(x))).parse(rest),
+        | "OUT" => map(parse_expr, |x| Instruction::OUT(Operand::Port(x), a())).parse(rest),
+
+        | _ => match (&name[..1], intel_condition(&name[1..])) {
+            | ("J", Some(cc)) => map(value, |x| Instruction::JP(Some(cc.clone()), x)).parse(rest),
+            | ("C", Some(cc)) => map(value, |x| Instruction::CALL(Some(cc.clone()), x)).parse(rest),
+            | ("R", Some(cc)) => Ok((rest, Instruction::RET(Some(cc)))),
+            | _ => Err(nom::Err::Error(nom::error::Error::new(text, nom::error::ErrorKind::Tag))),
+        },
+    }
+}
+
 fn parse_line(text: &str) -> IResult<&str, Instruction<Expr, Expr, Expr, Expr>> {
     delimited(
         multispace0,
@@ -435,3 +730,574 @@ pub fn parse(text: &str) -> IResult<&str, Vec<Instruction<Expr, Expr, Expr, Expr
         }
     ).parse(text)
 }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Data {
+    Value(Expr),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Directive {
+    ORG(Expr),
+    DB(Vec<Data>),
+    DW(Vec<Expr>),
+    // The size, and the value to fill it with if any
+    DS(Expr, Option<Expr>),
+    EQU(String, Expr),
+    // Like EQU, but may be defined again
+    SET(String, Expr),
+    // With the entry point
+    END(Option<Expr>),
+    // The part up to ELSE or ENDIF is only assembled when it isn't 0
+    IF(Expr),
+    ELSE,
+    ENDIF,
+    // For the listings of the Digital Research assemblers, and ignored
+    TITLE(String),
+    PAGE(Option<Expr>),
+    // A library of macros to read
+    MACLIB(String),
+    // The absolute, code and data segments of a relocatable program
+    ASEG,
+    CSEG,
+    DSEG,
+    // The symbols given to, and taken from, the other modules
+    PUBLIC(Vec<String>),
+    EXTRN(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    Label(String),
+    Instruction(Instruction<Expr, Expr, Expr, Expr>),
+    Directive(Directive),
+}
+
+// Between quotes or double quotes, which are doubled to be written in it
+fn parse_string(text: &str) -> IResult<&str, String> {
+    let quoted = |quote: char| delimited(
+        char(quote),
+        fold_many0(
+            alt((map((char(quote), char(quote)), move |_| quote), satisfy(move |c| c != quote && c != '\n'))),
+            String::new,
+            |mut acc, c| {
+                acc.push(c);
+                acc
+            }
+        ),
+        char(quote)
+    );
+    alt((quoted('\''), quoted('"'))).parse(text)
+}
+
+// A string is only one if it isn't the start of an expression, like 'A'+1
+fn parse_data(text: &str) -> IResult<&str, Data> {
+    alt((
+        map(delimited(space0, parse_string, (space0, parse_operand_end)), Data::String),
+        map(parse_expr, Data::Value),
+    )).parse(text)
+}
+
+fn parse_directive(text: &str) -> IResult<&str, Directive> {
+    alt((
+        map(preceded(keyword("ORG"), parse_expr), Directive::ORG),
+        map(
+            preceded(alt((keyword("DB"), keyword("DEFB"), keyword("DEFM"))), separated_list1(char(','), parse_data)),
+            Directive::DB
+        ),
+        map(
+            preceded(alt((keyword("DW"), keyword("DEFW"))), separated_list1(char(','), parse_expr)),
+            Directive::DW
+        ),
+        map(
+            (alt((keyword("DS"), keyword("DEFS"))), parse_expr, opt(preceded(char(','), parse_expr))),
+            |x| Directive::DS(x.1, x.2)
+        ),
+        map(preceded(keyword("END"), opt(parse_expr)), Directive::END),
+        map(preceded(keyword("IF"), parse_expr), Directive::IF),
+        map(keyword("ELSE"), |_| Directive::ELSE),
+        map(keyword("ENDIF"), |_| Directive::ENDIF),
+        map(preceded((keyword("TITLE"), space0), parse_string), Directive::TITLE),
+        map(preceded(keyword("PAGE"), opt(parse_expr)), Directive::PAGE),
+        map(preceded((keyword("MACLIB"), space1), parse_identifier), Directive::MACLIB),
+        map(keyword("ASEG"), |_| Directive::ASEG),
+        map(keyword("CSEG"), |_| Directive::CSEG),
+        map(keyword("DSEG"), |_| Directive::DSEG),
+        map(preceded(keyword("PUBLIC"), parse_symbols), Directive::PUBLIC),
+        map(preceded(alt((keyword("EXTRN"), keyword("EXTERNAL"))), parse_symbols), Directive::EXTRN),
+    )).parse(text)
+}
+
+// NAME, NAME...
+fn parse_symbols(text: &str) -> IResult<&str, Vec<String>> {
+    separated_list1(char(','), delimited(space0, parse_symbol, space0)).parse(text)
+}
+
+// NAME[:] EQU VALUE and NAME[:] SET VALUE
+fn parse_definition(text: &str) -> IResult<&str, Directive> {
+    map(
+        (
+            parse_symbol,
+            opt((space0, char(':'))),
+            space1,
+            alt((map(keyword("EQU"), |_| true), map(alt((keyword("SET"), keyword("DEFL"))), |_| false))),
+            parse_expr,
+        ),
+        |x| if x.3 { Directive::EQU(x.0, x.4) } else { Directive::SET(x.0, x.4) }
+    ).parse(text)
+}
+
+fn parse_comment(text: &str) -> IResult<&str, ()> {
+    map(
+        (space0, opt((char(';'), take_till(|c| c == '\n')))),
+        |_| ()
+    ).parse(text)
+}
+
+// CP/M sources end their lines with CR LF
+fn parse_end_of_line(text: &str) -> IResult<&str, ()> {
+    map(
+        (parse_comment, alt((map((opt(char('\r')), char('\n')), |_| ""), eof))),
+        |_| ()
+    ).parse(text)
+}
+
+// The mnemonics in which the instructions are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    Zilog,
+    Intel,
+}
+
+// .8080 and .Z80 change the syntax of the lines which follow
+fn parse_syntax(text: &str) -> IResult<&str, Syntax> {
+    delimited(
+        space0,
+        alt((
+            map(keyword(".8080"), |_| Syntax::Intel),
+            map(keyword(".Z80"), |_| Syntax::Zilog),
+        )),
+        parse_end_of_line
+    ).parse(text)
+}
+
+/*
+ * [LABEL:] [INSTRUCTION | DIRECTIVE] [; COMMENT], up to the end of the line.
+ * Like in MAC, the colon may be left out of a label in the first column
+ * when a statement follows it, if the line can't be read otherwise.
+ */
+fn parse_statement_line(text: &str, syntax: Syntax) -> IResult<&str, Vec<Statement>> {
+    let instruction = |text| match syntax {
+        | Syntax::Zilog => parse_instruction(text),
+        | Syntax::Intel => parse_intel_instruction(text),
+    };
+    let statement = move |text| alt((
+        map(parse_directive, Statement::Directive),
+        map(instruction, Statement::Instruction),
+    )).parse(text);
+    let result = alt((
+        map(
+            delimited(space0, parse_definition, parse_end_of_line),
+            |x| vec![Statement::Directive(x)]
+        ),
+        map(
+            (
+                space0,
+                opt(terminated(parse_identifier, (space0, char(':')))),
+                space0,
+                opt(statement),
+                parse_end_of_line,
+            ),
+            |x| x.1.map(Statement::Label).into_iter().chain(x.3).collect()
+        ),
+    )).parse(text);
+    match result {
+        // The error stays the one of the usual statements
+        | Err(nom::Err::Error(e)) => map(
+            (parse_symbol, space1, statement, parse_end_of_line),
+            |x| vec![Statement::Label(x.0), x.2]
+        ).parse(text).map_err(|_| nom::Err::Error(e)),
+        | result => result,
+    }
+}
+
+// The statements of a line of the source, numbered from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub number: usize,
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    // What couldn't be parsed, up to the end of the line
+    pub text: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: syntax error at '{}'", self.line, self.column, self.text)
+    }
+}
+
+// What comes before the lines up to ENDM
+#[derive(Debug, Clone, PartialEq)]
+enum Block {
+    // NAME MACRO PARAMETER,...
+    Macro(String, Vec<String>),
+    // REPT COUNT
+    Repeat(Expr),
+    // IRP PARAMETER,<ARGUMENT,...>
+    Iterate(String, Vec<String>),
+}
+
+fn parse_block_start(text: &str) -> IResult<&str, Block> {
+    let parameters = separated_list0(char(','), delimited(space0, parse_symbol, space0));
+    let arguments = delimited(char('<'), take_till(|c| c == '>' || c == '\n'), char('>'));
+    terminated(
+        alt((
+            map(
+                (space0, parse_symbol, opt((space0, char(':'))), space1, keyword("MACRO"), space0, parameters),
+                |x| Block::Macro(x.1, x.6)
+            ),
+            map(preceded((space0, keyword("REPT")), parse_expr), Block::Repeat),
+            map(
+                (space0, keyword("IRP"), space1, parse_symbol, space0, char(','), space0, arguments),
+                |x: (_, _, _, String, _, _, _, &str)| {
+                    Block::Iterate(x.3, x.7.split(',').map(|x| x.trim().to_string()).collect())
+                }
+            ),
+        )),
+        parse_end_of_line
+    ).parse(text)
+}
+
+fn is_block_end(text: &str) -> bool {
+    (space0, keyword("ENDM"), parse_end_of_line).parse(text).is_ok()
+}
+
+// LOCAL NAME,... at the start of a macro
+fn parse_locals(text: &str) -> IResult<&str, Vec<String>> {
+    delimited((space0, keyword("LOCAL")), parse_symbols, parse_end_of_line).parse(text)
+}
+
+// The index of the ENDM of the block starting at `start`
+fn find_block_end(lines: &[&str], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, line) in lines.iter().enumerate().skip(start + 1) {
+        if parse_block_start(line).is_ok() {
+            depth += 1;
+        } else if is_block_end(line) {
+            if depth == 0 {
+                return Some(index);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+/*
+ * The arguments of a macro, split at the commas, up to the comment. An
+ * argument between < and > may have commas.
+ */
+fn split_arguments(text: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut argument = String::new();
+    let mut quote = None;
+    let mut depth = 0;
+    for c in text.chars() {
+        match (quote, c) {
+            | (Some(q), c) if c == q => quote = None,
+            | (Some(_), _) => {}
+            | (None, '\'' | '"') => quote = Some(c),
+            | (None, ';') if depth == 0 => break,
+            | (None, '<') => depth += 1,
+            | (None, '>') => depth -= 1,
+            | (None, ',') if depth == 0 => {
+                arguments.push(argument);
+                argument = String::new();
+                continue;
+            }
+            | (None, _) => {}
+        }
+        argument.push(c);
+    }
+    if !arguments.is_empty() || !argument.trim().is_empty() {
+        arguments.push(argument);
+    }
+    arguments
+        .into_iter()
+        .map(|x| {
+            let x = x.trim();
+            match x.strip_prefix('<').and_then(|x| x.strip_suffix('>')) {
+                | Some(inner) => inner.to_string(),
+                | None => x.to_string(),
+            }
+        })
+        .collect()
+}
+
+/*
+ * Replaces the names by their values in a line of a macro, outside of the
+ * strings. & only separates a name from what is around it, like in J&CC.
+ */
+fn substitute(text: &str, names: &[(String, String)]) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+    let mut quote = None;
+    // The character before the one being read
+    let mut previous = ' ';
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        match quote {
+            | Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+                output.push(c);
+            }
+            // The ' of AF' isn't a quote
+            | None if (c == '\'' && !previous.is_ascii_alphanumeric()) || c == '"' => {
+                quote = Some(c);
+                output.push(c);
+            }
+            | None if c == ';' => {
+                output.push_str(&text[start..]);
+                break;
+            }
+            | None if c == '&' => {}
+            | None if is_identifier_char(c) || c == '$' => {
+                while let Some((i, c)) = chars.peek() {
+                    if !is_identifier_char(*c) && *c != '$' {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                let word = &text[start..end];
+                let name = word.chars().filter(|c| *c != '$').collect::<String>().to_ascii_uppercase();
+                match names.iter().find(|x| x.0 == name) {
+                    | Some((_, value)) if c.is_ascii_alphabetic() || c == '_' => output.push_str(value),
+                    | _ => output.push_str(word),
+                }
+            }
+            | None => output.push(c),
+        }
+        previous = text[..end].chars().next_back().unwrap_or(' ');
+    }
+    output
+}
+
+// A macro calling itself forever stops there
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// Where a line couldn't be read, as its index in the lines being read
+struct LineError {
+    index: usize,
+    column: usize,
+    text: String,
+}
+
+/*
+ * Reads the lines, expanding the macros as they are met. What they give is
+ * part of the line using them, so that the listing and the errors show it.
+ * The IF are left to the resolver: they don't hide a MACRO from the reader.
+ */
+struct Reader {
+    syntax: Syntax,
+    // The parameters and the lines of each macro
+    macros: BTreeMap<String, (Vec<String>, Vec<String>)>,
+    // The values known while reading, for the counts of REPT
+    symbols: BTreeMap<String, i32>,
+    expansions: usize,
+    depth: usize,
+}
+
+impl Reader {
+    // [LABEL[:]] NAME [ARGUMENT,...], NAME being a macro
+    fn invocation<'a>(&self, text: &'a str) -> Option<(Option<String>, String, &'a str)> {
+        let is_macro = |x: &(Option<String>, String, &str)| self.macros.contains_key(&x.1);
+        let rest = |text: &'a str| -> IResult<&'a str, &'a str> { Ok(("", text)) };
+        alt((
+            verify(
+                map(
+                    (space0, opt(terminated(parse_identifier, (space0, char(':')))), space0, parse_identifier, rest),
+                    |x| (x.1, x.3, x.4)
+                ),
+                is_macro
+            ),
+            // Without the colon in the first column
+            verify(map((parse_symbol, space1, parse_identifier, rest), |x| (Some(x.0), x.2, x.3)), is_macro),
+        )).parse(text).ok().map(|x| x.1)
+    }
+
+    fn define(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            if let Statement::Directive(Directive::EQU(name, value) | Directive::SET(name, value)) = statement {
+                match value.evaluate(&self.symbols, 0) {
+                    | Ok(n) => self.symbols.insert(name.clone(), n),
+                    | Err(_) => self.symbols.remove(name),
+                };
+            }
+        }
+    }
+
+    // The statements of the lines of a macro, with the names replaced
+    fn expand(&mut self, lines: &[String], names: &[(String, String)], index: usize) -> Result<Vec<Statement>, LineError> {
+        if self.depth == MAX_EXPANSION_DEPTH {
+            return Err(LineError { index, column: 1, text: String::new() });
+        }
+        self.expansions += 1;
+        let mut names = names.to_vec();
+        let mut body = lines;
+        while let Some(Ok((_, locals))) = body.first().map(|x| parse_locals(x)) {
+            for local in locals {
+                let unique = format!("_{}_{:04}", local, self.expansions);
+                names.push((local, unique));
+            }
+            body = &body[1..];
+        }
+        let body: Vec<String> = body.iter().map(|x| substitute(x, &names)).collect();
+
+        self.depth += 1;
+        let result = self.read(&body.iter().map(|x| x.as_str()).collect::<Vec<_>>());
+        self.depth -= 1;
+        match result {
+            | Ok(statements) => Ok(statements.into_iter().flatten().collect()),
+            // Located on the line using the macro
+            | Err(e) => Err(LineError { index, ..e }),
+        }
+    }
+
+    // The statements of each line, up to END
+    fn read(&mut self, lines: &[&str]) -> Result<Vec<Vec<Statement>>, LineError> {
+        let mut output = Vec::with_capacity(lines.len());
+        let mut index = 0;
+        while index < lines.len() {
+            let line = lines[index];
+            if let Ok((_, syntax)) = parse_syntax(line) {
+                self.syntax = syntax;
+                output.push(Vec::new());
+                index += 1;
+                continue;
+            }
+
+            if let Ok((_, block)) = parse_block_start(line) {
+                let end = find_block_end(lines, index).ok_or_else(|| LineError {
+                    index,
+                    column: 1,
+                    text: line.trim().to_string(),
+                })?;
+                let body: Vec<String> = lines[index + 1..end].iter().map(|x| x.to_string()).collect();
+                let mut statements = Vec::new();
+                match block {
+                    | Block::Macro(name, parameters) => {
+                        self.macros.insert(name, (parameters, body));
+                    }
+                    | Block::Repeat(count) => {
+                        let count = count.evaluate(&self.symbols, 0).map_err(|_| LineError {
+                            index,
+                            column: 1,
+                            text: line.trim().to_string(),
+                        })?;
+                        for _ in 0..count {
+                            statements.extend(self.expand(&body, &[], index)?);
+                        }
+                    }
+                    | Block::Iterate(parameter, arguments) => {
+                        for argument in arguments {
+                            statements.extend(self.expand(&body, &[(parameter.clone(), argument)], index)?);
+                        }
+                    }
+                }
+                output.push(statements);
+                // The lines of the block and its ENDM
+                output.extend((index..end).map(|_| Vec::new()));
+                index = end + 1;
+                continue;
+            }
+
+            let statements = match self.invocation(line) {
+                | Some((label, name, arguments)) => {
+                    let (parameters, body) = self.macros[&name].clone();
+                    let arguments = split_arguments(arguments);
+                    if arguments.len() > parameters.len() {
+                        let column = line.len() - line.trim_start().len() + 1;
+                        return Err(LineError { index, column, text: line.trim().to_string() });
+                    }
+                    // The missing arguments are empty
+                    let names: Vec<(String, String)> = parameters
+                        .into_iter()
+                        .zip(arguments.into_iter().chain(std::iter::repeat(String::new())))
+                        .collect();
+                    let mut statements: Vec<Statement> = label.map(Statement::Label).into_iter().collect();
+                    statements.extend(self.expand(&body, &names, index)?);
+                    statements
+                }
+                | None => match parse_statement_line(line, self.syntax) {
+                    | Ok((_, statements)) => statements,
+                    | Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                        // Where the parsing stopped, if it is on this line
+                        let position = line.len() - e.input.len().min(line.len());
+                        return Err(LineError {
+                            index,
+                            column: line[..position].chars().count() + 1,
+                            text: line[position..].trim_end().to_string(),
+                        });
+                    }
+                    | Err(nom::Err::Incomplete(_)) => unreachable!(),
+                },
+            };
+            self.define(&statements);
+            let end = statements.iter().any(|x| matches!(x, Statement::Directive(Directive::END(_))));
+            output.push(statements);
+            if end {
+                break;
+            }
+            index += 1;
+        }
+        Ok(output)
+    }
+}
+
+/*
+ * Parses a whole source, with its labels and its comments, up to END if
+ * there is one. The macros, REPT and IRP are expanded. The error is located
+ * where the parsing of the first line which isn't understood stopped.
+ */
+pub fn parse_program(text: &str) -> Result<Vec<Line>, ParseError> {
+    parse_program_as(text, Syntax::Zilog)
+}
+
+// Like parse_program, with the syntax the source starts in
+pub fn parse_program_as(text: &str, syntax: Syntax) -> Result<Vec<Line>, ParseError> {
+    // CP/M sources end their lines with CR LF
+    let lines: Vec<&str> = text
+        .split_inclusive('\n')
+        .map(|x| x.strip_suffix('\n').unwrap_or(x))
+        .map(|x| x.strip_suffix('\r').unwrap_or(x))
+        .collect();
+    let mut reader = Reader {
+        syntax,
+        macros: BTreeMap::new(),
+        symbols: BTreeMap::new(),
+        expansions: 0,
+        depth: 0,
+    };
+    match reader.read(&lines) {
+        | Ok(statements) => Ok(statements
+            .into_iter()
+            .enumerate()
+            .map(|(index, statements)| Line { number: index + 1, statements })
+            .collect()),
+        | Err(e) => Err(ParseError {
+            line: e.index + 1,
+            column: e.column,
+            text: e.text,
+        }),
+    }
+}
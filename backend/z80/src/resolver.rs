@@ -0,0 +1,1173 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::iter;
+use std::ops::RangeInclusive;
+
+use crate::assembler::{self, Assembler, Target};
+use crate::instruction::{ByteRegister, Condition, Instruction, Operand};
+use crate::parser::{Data, Directive, EvaluationError, Expr, Line, Statement};
+use crate::timing::Cycles;
+
+type Inst = Instruction<u8, u16, i32, i8>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	UndefinedSymbol(String),
+	DivisionByZero,
+	DuplicateLabel(String),
+	// The displacement of a JR or a DJNZ
+	JumpOutOfRange(i32),
+	// The d of (IX+d) and (IY+d)
+	OffsetOutOfRange(i32),
+	ValueOutOfRange(i32),
+	// What the Assembler can't encode
+	Instruction(assembler::Error),
+	// A label moved between the two passes
+	PhaseError(String),
+	// An IF without its ENDIF, or an ELSE or an ENDIF without their IF
+	Unmatched(String),
+	// What only a relocatable program has, like CSEG and EXTRN
+	Relocation(String),
+	// A value moving with a segment or an external other than as a word
+	Unrelocatable(Reference),
+	Unsupported(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| Error::UndefinedSymbol(name) => write!(f, "undefined symbol {}", name),
+			| Error::DivisionByZero => write!(f, "division by zero"),
+			| Error::DuplicateLabel(name) => write!(f, "{} is defined more than once", name),
+			| Error::JumpOutOfRange(d) => write!(f, "relative jump out of range ({})", d),
+			| Error::OffsetOutOfRange(d) => write!(f, "index offset out of range ({})", d),
+			| Error::ValueOutOfRange(n) => write!(f, "value out of range ({})", n),
+			| Error::Instruction(error) => write!(f, "{}", error),
+			| Error::PhaseError(name) => {
+				write!(f, "the address of {} changed between the passes", name)
+			}
+			| Error::Unmatched(directive) if directive == "IF" => write!(f, "IF without ENDIF"),
+			| Error::Unmatched(directive) => write!(f, "{} without IF", directive),
+			| Error::Relocation(directive) => {
+				write!(f, "{} needs a relocatable output", directive)
+			}
+			| Error::Unrelocatable(reference) => {
+				write!(
+					f,
+					"a value relative to {} can't be relocated here",
+					reference
+				)
+			}
+			| Error::Unsupported(directive) => write!(f, "{} isn't supported", directive),
+		}
+	}
+}
+
+// An error, with the line of the source where it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+	pub line: usize,
+	pub error: Error,
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.error)
+	}
+}
+
+impl From<EvaluationError> for Error {
+	fn from(error: EvaluationError) -> Self {
+		match error {
+			| EvaluationError::UndefinedSymbol(name) => Error::UndefinedSymbol(name),
+			| EvaluationError::DivisionByZero => Error::DivisionByZero,
+		}
+	}
+}
+
+// The parts of a relocatable program, numbered like in a .REL file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Segment {
+	// Where it is written, like all of an absolute program
+	#[default]
+	Absolute = 0,
+	Code = 1,
+	Data = 2,
+}
+
+impl fmt::Display for Segment {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| Segment::Absolute => write!(f, "ASEG"),
+			| Segment::Code => write!(f, "CSEG"),
+			| Segment::Data => write!(f, "DSEG"),
+		}
+	}
+}
+
+// What a relocatable value moves with
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reference {
+	Segment(Segment),
+	// A symbol of EXTRN, which is 0 until the linker knows it
+	External(String),
+}
+
+impl fmt::Display for Reference {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			| Reference::Segment(segment) => write!(f, "{}", segment),
+			| Reference::External(name) => write!(f, "{}", name),
+		}
+	}
+}
+
+// A word the linker changes, at an address of a segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+	pub segment: Segment,
+	pub address: u16,
+	pub reference: Reference,
+}
+
+// What a relocatable program tells the linker, beyond its bytes
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Linkage {
+	pub relocations: Vec<Relocation>,
+	// As far as each segment went, with what DS reserves
+	pub code_size: u16,
+	pub data_size: u16,
+	// The symbols of PUBLIC, with their segment
+	pub publics: BTreeMap<String, (Segment, u16)>,
+	// The segment of the operand of END
+	pub entry: Segment,
+}
+
+// Bytes written one after the other, from an address of a segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+	pub segment: Segment,
+	pub address: u16,
+	pub bytes: Vec<u8>,
+}
+
+// What a line of the source became, for the listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+	pub line: usize,
+	pub address: u16,
+	pub bytes: Vec<u8>,
+	// Only for the instructions
+	pub cycles: Option<Cycles>,
+	// What EQU or SET gave
+	pub value: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+	pub instructions: Vec<Inst>,
+	// The labels, and the symbols defined beforehand
+	pub symbols: BTreeMap<String, i32>,
+	// A new one starts after each ORG or DS leaving a gap
+	pub blocks: Vec<Block>,
+	// The operand of END
+	pub entry: Option<u16>,
+	// One for each line with a statement, up to the END
+	pub records: Vec<Record>,
+	// Empty for an absolute program, but for the symbols of PUBLIC
+	pub linkage: Linkage,
+}
+
+// How the sources are understood, beyond their syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dialect {
+	#[default]
+	Standard,
+	/*
+	 * Like MAC and RMAC: the values are words, so that NOT 0FFFFH is 0 and
+	 * 0FFFFH is a byte, and an EQU may be given the same value again
+	 */
+	DigitalResearch,
+}
+
+#[derive(Default)]
+struct Pass {
+	symbols: BTreeMap<String, i32>,
+	position: u16,
+	instructions: Vec<Inst>,
+	blocks: Vec<Block>,
+	entry: Option<u16>,
+	records: Vec<Record>,
+	// The line of each IF being in, and whether its current part is assembled
+	conditions: Vec<(usize, bool)>,
+	// Where each segment of a relocatable program is at, but the current one
+	segment: Segment,
+	counters: BTreeMap<Segment, u16>,
+	// What the symbols which aren't absolute move with
+	references: BTreeMap<String, Reference>,
+	linkage: Linkage,
+}
+
+impl Pass {
+	fn is_assembling(&self) -> bool {
+		self.conditions.iter().all(|x| x.1)
+	}
+
+	// Writes the bytes at the position, and moves it after them
+	fn emit(&mut self, bytes: Vec<u8>) {
+		if bytes.is_empty() {
+			return;
+		}
+		if let Some(record) = self.records.last_mut() {
+			record.bytes.extend(&bytes);
+		}
+		let size = bytes.len() as u16;
+		match self.blocks.last_mut() {
+			| Some(block)
+				if block.segment == self.segment
+					&& block.address.wrapping_add(block.bytes.len() as u16) == self.position =>
+			{
+				block.bytes.extend(bytes)
+			}
+			| _ => self.blocks.push(Block {
+				segment: self.segment,
+				address: self.position,
+				bytes,
+			}),
+		}
+		self.position = self.position.wrapping_add(size);
+	}
+
+	// Where the line starts is where ORG goes
+	fn origin(&mut self, address: u16) {
+		if let Some(record) = self.records.last_mut() {
+			record.address = address;
+		}
+		self.position = address;
+	}
+
+	// Leaves the segment where it is at, to go on where the other one was
+	fn switch(&mut self, segment: Segment) {
+		self.counters.insert(self.segment, self.position);
+		self.position = self.counters.get(&segment).copied().unwrap_or(0);
+		self.segment = segment;
+		self.origin(self.position);
+	}
+
+	// The words at offsets of the bytes about to be written
+	fn relocate(&mut self, relocations: Vec<(u16, Reference)>) {
+		for (offset, reference) in relocations {
+			self.linkage.relocations.push(Relocation {
+				segment: self.segment,
+				address: self.position.wrapping_add(offset),
+				reference,
+			});
+		}
+	}
+
+	// A segment is as long as how far it went
+	fn extend(&mut self) {
+		let size = match self.segment {
+			| Segment::Absolute => return,
+			| Segment::Code => &mut self.linkage.code_size,
+			| Segment::Data => &mut self.linkage.data_size,
+		};
+		*size = (*size).max(self.position);
+	}
+}
+
+// The symbols an expression uses
+fn collect_names(expr: &Expr, names: &mut BTreeSet<String>) {
+	match expr {
+		| Expr::Identifier(name) => {
+			names.insert(name.clone());
+		}
+		| Expr::Unary(_, x) => collect_names(x, names),
+		| Expr::Binary(_, x, y) => {
+			collect_names(x, names);
+			collect_names(y, names);
+		}
+		| Expr::Constant(_) | Expr::Location => {}
+	}
+}
+
+// The symbols a statement uses
+fn names(statement: &Statement) -> BTreeSet<String> {
+	let names = RefCell::new(BTreeSet::new());
+	let collect = |expr: &Expr| {
+		collect_names(expr, &mut names.borrow_mut());
+		Ok::<_, ()>(())
+	};
+	match statement {
+		| Statement::Instruction(inst) => {
+			let _ = inst.try_map(&collect, &collect, &collect, &collect);
+		}
+		| Statement::Directive(Directive::DB(items)) => {
+			for item in items {
+				if let Data::Value(expr) = item {
+					let _ = collect(expr);
+				}
+			}
+		}
+		| Statement::Directive(Directive::DW(items)) => {
+			let _ = items.iter().try_for_each(collect);
+		}
+		| Statement::Directive(Directive::DS(size, fill)) => {
+			let _ = iter::once(size).chain(fill).try_for_each(collect);
+		}
+		| Statement::Directive(
+			Directive::EQU(_, expr) | Directive::SET(_, expr) | Directive::END(Some(expr)),
+		) => {
+			let _ = collect(expr);
+		}
+		| _ => {}
+	}
+	names.into_inner()
+}
+
+// How far a relocatable value is moved to find where it is
+const SHIFT: u16 = 0x0101;
+
+fn is_segment(directive: &Directive) -> bool {
+	matches!(
+		directive,
+		Directive::ASEG | Directive::CSEG | Directive::DSEG
+	)
+}
+
+fn segment(directive: &Directive) -> Segment {
+	match directive {
+		| Directive::CSEG => Segment::Code,
+		| Directive::DSEG => Segment::Data,
+		| _ => Segment::Absolute,
+	}
+}
+
+/*
+ * Turns parsed statements into instructions the Assembler can encode.
+ * The first pass gives an address to every label, with the forward
+ * references left at 0 as they don't change the size of an instruction.
+ * The second one replaces every symbol by its value, and checks that
+ * each value fits where it is used.
+ * ORG and DS change the size of the program, so their operand must be
+ * known in the first pass already.
+ * When it chooses between JR and JP, the first pass is done again until
+ * every jump reaches its target.
+ */
+pub struct Resolver {
+	origin: u16,
+	symbols: BTreeMap<String, i32>,
+	enable_macro_instructions: bool,
+	enable_undocumented_instructions: bool,
+	target: Target,
+	dialect: Dialect,
+	relaxation: bool,
+	relocatable: bool,
+}
+
+impl Resolver {
+	pub fn new(
+		origin: u16,
+		enable_macro_instructions: bool,
+		enable_undocumented_instructions: bool,
+	) -> Self {
+		Self {
+			origin,
+			symbols: BTreeMap::new(),
+			enable_macro_instructions,
+			enable_undocumented_instructions,
+			target: Target::Z80,
+			dialect: Dialect::Standard,
+			relaxation: false,
+			relocatable: false,
+		}
+	}
+
+	pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+		self.dialect = dialect;
+		self
+	}
+
+	// Only accepts the instructions of the target
+	pub fn with_target(mut self, target: Target) -> Self {
+		self.target = target;
+		self
+	}
+
+	/*
+	 * Chooses between JR and JP for the jumps without a condition or with
+	 * Z, NZ, C or NC, as their target is near or not, and replaces a DJNZ
+	 * too far by DEC B and JP NZ. The others always use JP.
+	 */
+	pub fn with_relaxation(mut self, relaxation: bool) -> Self {
+		self.relaxation = relaxation;
+		self
+	}
+
+	/*
+	 * Assembles for a linker, like M80: CSEG, DSEG and ASEG each have their
+	 * own addresses, the program starting in CSEG at the origin, and the
+	 * symbols of EXTRN are 0 until it is linked
+	 */
+	pub fn with_relocation(mut self, relocatable: bool) -> Self {
+		self.relocatable = relocatable;
+		self
+	}
+
+	fn first_segment(&self) -> Segment {
+		match self.relocatable {
+			| true => Segment::Code,
+			| false => Segment::Absolute,
+		}
+	}
+
+	// Like -D on the command line
+	pub fn define(&mut self, name: &str, value: i32) {
+		self.symbols.insert(name.to_ascii_uppercase(), value);
+	}
+
+	pub fn resolve(&self, lines: &[Line]) -> Result<Program, Diagnostic> {
+		/*
+		 * The jumps start short, and those whose target is too far are made
+		 * long before laying the program out again. As they never become
+		 * short again, this stops once they all reach their target.
+		 */
+		let mut long = BTreeSet::new();
+		let (mut pass, variables) = loop {
+			let (pass, variables, jumps) = self.layout(lines, &long)?;
+			let far: Vec<_> = jumps
+				.into_iter()
+				.filter(|(_, segment, position, target)| {
+					!self.is_near(target, &pass, *segment, *position)
+				})
+				.map(|(index, _, _, _)| index)
+				.collect();
+			if far.is_empty() {
+				break (pass, variables);
+			}
+			long.extend(far);
+		};
+
+		let located = |line| move |error| Diagnostic { line, error };
+
+		// The values of SET are taken again in order
+		for (name, _) in variables.iter().filter(|x| *x.1) {
+			pass.symbols.remove(name);
+		}
+		pass.position = self.origin;
+		pass.segment = self.first_segment();
+		pass.counters.clear();
+		let mut index = 0;
+		'lines: for line in lines.iter().filter(|x| !x.statements.is_empty()) {
+			pass.records.push(Record {
+				line: line.number,
+				address: pass.position,
+				bytes: Vec::new(),
+				cycles: None,
+				value: None,
+			});
+			// Only the lines which are assembled are recorded, with the IF,
+			// ELSE and ENDIF starting or ending what is
+			let mut is_assembled = false;
+			for statement in &line.statements {
+				index += 1;
+				let was_assembling = pass.is_assembling();
+				if self
+					.condition(statement, line.number, &mut pass)
+					.map_err(located(line.number))?
+				{
+					is_assembled |= was_assembling || pass.is_assembling();
+					continue;
+				}
+				if !pass.is_assembling() {
+					continue;
+				}
+				is_assembled = true;
+				for statement in self.relaxed(statement, long.contains(&index)) {
+					let is_end = self
+						.second_pass(&statement, &mut pass)
+						.map_err(located(line.number))?;
+					pass.extend();
+					if is_end {
+						break 'lines;
+					}
+				}
+			}
+			if !is_assembled {
+				pass.records.pop();
+			}
+		}
+
+		Ok(Program {
+			instructions: pass.instructions,
+			symbols: pass.symbols,
+			blocks: pass.blocks,
+			entry: pass.entry,
+			records: pass.records,
+			linkage: pass.linkage,
+		})
+	}
+
+	/*
+	 * Gives an address to the labels with the long jumps as they are, and
+	 * tells where are the short ones which could be too far, each with the
+	 * number of its statement
+	 */
+	#[allow(clippy::type_complexity)]
+	fn layout<'a>(
+		&self,
+		lines: &'a [Line],
+		long: &BTreeSet<usize>,
+	) -> Result<
+		(
+			Pass,
+			BTreeMap<String, bool>,
+			Vec<(usize, Segment, u16, &'a Expr)>,
+		),
+		Diagnostic,
+	> {
+		let statements = lines.iter().flat_map(|line| {
+			line.statements
+				.iter()
+				.map(|statement| (line.number, statement))
+		});
+		let located = |line| move |error| Diagnostic { line, error };
+
+		let mut pass = Pass {
+			symbols: self.symbols.clone(),
+			position: self.origin,
+			segment: self.first_segment(),
+			..Pass::default()
+		};
+		// Whether each name is one of SET, which may be defined again
+		let mut variables: BTreeMap<String, bool> = pass
+			.symbols
+			.keys()
+			.map(|name| (name.clone(), false))
+			.collect();
+		let mut jumps = Vec::new();
+		'statements: for ((line, statement), index) in statements.zip(1..) {
+			if self
+				.condition(statement, line, &mut pass)
+				.map_err(located(line))?
+				|| !pass.is_assembling()
+			{
+				continue;
+			}
+			let is_long = long.contains(&index);
+			let is_short = !is_long && self.has_short_form(statement);
+			if let Some(target) = self.jump_target(statement).filter(|_| is_short) {
+				jumps.push((index, pass.segment, pass.position, target));
+			}
+			for statement in self.relaxed(statement, is_long) {
+				if self
+					.first_pass(&statement, &mut pass, &mut variables)
+					.map_err(located(line))?
+				{
+					break 'statements;
+				}
+			}
+		}
+		Self::check_conditions(&pass)?;
+		Ok((pass, variables, jumps))
+	}
+
+	// The target of a jump which may be JR or JP, when they are chosen
+	fn jump_target<'a>(&self, statement: &'a Statement) -> Option<&'a Expr> {
+		if !self.relaxation {
+			return None;
+		}
+		match statement {
+			| Statement::Instruction(
+				Instruction::JR(cc, Operand::Constant(target))
+				| Instruction::JP(cc, Operand::Constant(target)),
+			) if cc.as_ref().is_none_or(Condition::is_relative) => Some(target),
+			// Which is made long
+			| Statement::Instruction(Instruction::JR(Some(_), Operand::Constant(target))) => {
+				Some(target)
+			}
+			| Statement::Instruction(Instruction::DJNZ(target)) => Some(target),
+			| _ => None,
+		}
+	}
+
+	// Whether the jump may be a JR or a DJNZ, the 8080 having only JP
+	fn has_short_form(&self, statement: &Statement) -> bool {
+		match statement {
+			| Statement::Instruction(Instruction::JR(cc, _) | Instruction::JP(cc, _)) => {
+				self.target != Target::I8080 && cc.as_ref().is_none_or(Condition::is_relative)
+			}
+			| _ => self.target != Target::I8080,
+		}
+	}
+
+	/*
+	 * Whether a JR or a DJNZ at the position reaches the target. In CSEG
+	 * and DSEG, it must be a label of the same segment, as the distance to
+	 * an absolute address, another segment or an external isn't known.
+	 */
+	fn is_near(&self, target: &Expr, pass: &Pass, segment: Segment, position: u16) -> bool {
+		let mut names = BTreeSet::new();
+		collect_names(target, &mut names);
+		let references: BTreeSet<_> = names
+			.iter()
+			.filter_map(|name| pass.references.get(name))
+			.collect();
+		let is_relative = match segment {
+			| Segment::Absolute => references.is_empty(),
+			| _ => references == BTreeSet::from([&Reference::Segment(segment)]),
+		};
+		if !is_relative {
+			return false;
+		}
+		// What isn't known yet is an error of the second pass
+		match self.evaluate(target, &pass.symbols, position) {
+			| Ok(n) => (-128..=127).contains(&(n - (position as i32 + 2))),
+			| Err(_) => true,
+		}
+	}
+
+	/*
+	 * The jump in the form chosen for it, a DJNZ being long as DEC B and
+	 * JP NZ
+	 */
+	fn relaxed(&self, statement: &Statement, is_long: bool) -> Vec<Statement> {
+		let Some(target) = self.jump_target(statement) else {
+			return vec![statement.clone()];
+		};
+		let target = Operand::Constant(target.clone());
+		let is_long = is_long || !self.has_short_form(statement);
+		let instruction = match statement {
+			| Statement::Instruction(Instruction::DJNZ(_)) if is_long => {
+				return vec![
+					Statement::Instruction(Instruction::DEC(Operand::ByteRegister(
+						ByteRegister::B,
+					))),
+					Statement::Instruction(Instruction::JP(Some(Condition::NZ), target)),
+				];
+			}
+			| Statement::Instruction(Instruction::DJNZ(target)) => {
+				Instruction::DJNZ(target.clone())
+			}
+			| Statement::Instruction(Instruction::JR(cc, _) | Instruction::JP(cc, _)) if is_long => {
+				Instruction::JP(cc.clone(), target)
+			}
+			| Statement::Instruction(Instruction::JR(cc, _) | Instruction::JP(cc, _)) => {
+				Instruction::JR(cc.clone(), target)
+			}
+			| _ => unreachable!(),
+		};
+		vec![Statement::Instruction(instruction)]
+	}
+
+	/*
+	 * Follows IF, ELSE and ENDIF, and tells whether the statement is one of
+	 * them. The condition must be known when it is met, and isn't computed
+	 * in a part which isn't assembled.
+	 */
+	fn condition(
+		&self,
+		statement: &Statement,
+		line: usize,
+		pass: &mut Pass,
+	) -> Result<bool, Error> {
+		match statement {
+			| Statement::Directive(Directive::IF(condition)) => {
+				let is_true = pass.is_assembling()
+					&& self.evaluate(condition, &pass.symbols, pass.position)? != 0;
+				pass.conditions.push((line, is_true));
+			}
+			| Statement::Directive(Directive::ELSE) => match pass.conditions.last_mut() {
+				| Some(condition) => condition.1 = !condition.1,
+				| None => return Err(Error::Unmatched("ELSE".to_string())),
+			},
+			| Statement::Directive(Directive::ENDIF) => {
+				if pass.conditions.pop().is_none() {
+					return Err(Error::Unmatched("ENDIF".to_string()));
+				}
+			}
+			| _ => return Ok(false),
+		}
+		Ok(true)
+	}
+
+	// Every IF must have been closed at the end, which is where the pass stopped
+	fn check_conditions(pass: &Pass) -> Result<(), Diagnostic> {
+		match pass.conditions.first() {
+			| Some((line, _)) => Err(Diagnostic {
+				line: *line,
+				error: Error::Unmatched("IF".to_string()),
+			}),
+			| None => Ok(()),
+		}
+	}
+
+	// Gives their value to the symbols, and tells whether it is the END
+	fn first_pass(
+		&self,
+		statement: &Statement,
+		pass: &mut Pass,
+		variables: &mut BTreeMap<String, bool>,
+	) -> Result<bool, Error> {
+		let mut define =
+			|name: &String, variable: bool| match variables.insert(name.clone(), variable) {
+				| Some(false) => Err(Error::DuplicateLabel(name.clone())),
+				| Some(true) if !variable => Err(Error::DuplicateLabel(name.clone())),
+				| _ => Ok(()),
+			};
+
+		let position = pass.position;
+		match statement {
+			| Statement::Label(name) => {
+				define(name, false)?;
+				pass.symbols.insert(name.clone(), position as i32);
+				if pass.segment != Segment::Absolute {
+					pass.references
+						.insert(name.clone(), Reference::Segment(pass.segment));
+				}
+			}
+			| Statement::Directive(Directive::EQU(name, value)) => {
+				let n = self.evaluate(value, &pass.symbols, position).ok();
+				let is_same_again = self.dialect == Dialect::DigitalResearch
+					&& n.is_some() && pass.symbols.get(name) == n.as_ref();
+				if !is_same_again {
+					define(name, false)?;
+				}
+				// Left undefined when it depends on what comes later
+				if let Some(n) = n {
+					self.define_value(statement, name, n, pass)?;
+				}
+			}
+			| Statement::Directive(Directive::SET(name, value)) => {
+				define(name, true)?;
+				match self.evaluate(value, &pass.symbols, position) {
+					| Ok(n) => self.define_value(statement, name, n, pass)?,
+					| Err(_) => {
+						pass.symbols.remove(name);
+					}
+				};
+			}
+			| Statement::Directive(Directive::EXTRN(names)) if self.relocatable => {
+				for name in names {
+					define(name, false)?;
+					pass.symbols.insert(name.clone(), 0);
+					pass.references
+						.insert(name.clone(), Reference::External(name.clone()));
+				}
+			}
+			| Statement::Directive(Directive::ORG(address)) => {
+				pass.position = self.count(address, &pass.symbols, position)?;
+			}
+			| Statement::Directive(Directive::DS(size, _)) => {
+				pass.position = position.wrapping_add(self.count(size, &pass.symbols, position)?);
+			}
+			| Statement::Directive(Directive::END(_)) => return Ok(true),
+			| Statement::Directive(directive) if self.relocatable && is_segment(directive) => {
+				pass.switch(segment(directive));
+			}
+			| Statement::Directive(directive) => {
+				self.check_relocation(directive)?;
+				let data = self.data(directive, &pass.symbols, position, false)?;
+				pass.position = position.wrapping_add(data.len() as u16);
+			}
+			| Statement::Instruction(inst) => {
+				let resolved = self.convert(inst, &pass.symbols, position, false)?;
+				pass.position = position.wrapping_add(self.assemble(resolved)?.1.len() as u16);
+			}
+		}
+		Ok(false)
+	}
+
+	// Produces the instructions and their bytes, and tells whether it is the END
+	fn second_pass(&self, statement: &Statement, pass: &mut Pass) -> Result<bool, Error> {
+		let position = pass.position;
+		match statement {
+			| Statement::Label(name) => {
+				if pass.symbols[name] != position as i32 {
+					return Err(Error::PhaseError(name.clone()));
+				}
+			}
+			| Statement::Directive(Directive::EQU(name, value)) => {
+				let n = self.evaluate(value, &pass.symbols, position)?;
+				if pass
+					.symbols
+					.get(name)
+					.is_some_and(|previous| *previous != n)
+				{
+					return Err(Error::PhaseError(name.clone()));
+				}
+				self.define_value(statement, name, n, pass)?;
+				pass.records.last_mut().unwrap().value = Some(n);
+			}
+			| Statement::Directive(Directive::SET(name, value)) => {
+				let n = self.evaluate(value, &pass.symbols, position)?;
+				self.define_value(statement, name, n, pass)?;
+				pass.records.last_mut().unwrap().value = Some(n);
+			}
+			| Statement::Directive(directive) if self.relocatable && is_segment(directive) => {
+				pass.switch(segment(directive));
+			}
+			| Statement::Directive(Directive::ORG(address)) => {
+				let address = self.count(address, &pass.symbols, position)?;
+				pass.origin(address);
+			}
+			| Statement::Directive(Directive::DS(size, None)) => {
+				pass.position = position.wrapping_add(self.count(size, &pass.symbols, position)?);
+			}
+			| Statement::Directive(Directive::END(address)) => {
+				if let Some(address) = address {
+					let entry = self.count(address, &pass.symbols, position)?;
+					let word = |symbols: &BTreeMap<String, i32>, location| {
+						Ok(self
+							.count(address, symbols, location)?
+							.to_le_bytes()
+							.to_vec())
+					};
+					let bytes = entry.to_le_bytes();
+					pass.linkage.entry =
+						match self.relocations(statement, pass, &bytes, word)?.pop() {
+							| None => Segment::Absolute,
+							| Some((_, Reference::Segment(segment))) => segment,
+							| Some((_, reference)) => return Err(Error::Unrelocatable(reference)),
+						};
+					pass.entry = Some(entry);
+				}
+				return Ok(true);
+			}
+			// The symbols are all known already, but those of EXTRN
+			| Statement::Directive(Directive::PUBLIC(names)) => {
+				for name in names {
+					let Some(n) = pass.symbols.get(name) else {
+						return Err(Error::UndefinedSymbol(name.clone()));
+					};
+					let segment = match pass.references.get(name) {
+						| None => Segment::Absolute,
+						| Some(Reference::Segment(segment)) => *segment,
+						| Some(reference) => return Err(Error::Unrelocatable(reference.clone())),
+					};
+					pass.linkage
+						.publics
+						.insert(name.clone(), (segment, *n as u16));
+				}
+			}
+			| Statement::Directive(directive) => {
+				let data = self.data(directive, &pass.symbols, position, true)?;
+				let produce = |symbols: &BTreeMap<String, i32>, location| {
+					self.data(directive, symbols, location, true)
+				};
+				let relocations = self.relocations(statement, pass, &data, produce)?;
+				pass.relocate(relocations);
+				if !data.is_empty() {
+					pass.instructions.push(Instruction::Binary(data.clone()));
+				}
+				pass.emit(data);
+			}
+			| Statement::Instruction(inst) => {
+				let resolved = self.convert(inst, &pass.symbols, position, true)?;
+				let (resolved, bytes) = self.assemble(resolved)?;
+				let produce = |symbols: &BTreeMap<String, i32>, location| {
+					Ok(self
+						.assemble(self.convert(inst, symbols, location, true)?)?
+						.1)
+				};
+				let relocations = self.relocations(statement, pass, &bytes, produce)?;
+				pass.relocate(relocations);
+				// The time of all the instructions of the line, like the two of a
+				// long DJNZ
+				let record = pass.records.last_mut().unwrap();
+				record.cycles = match (record.cycles, resolved.cycles()) {
+					| (Some(before), Some(cycles)) => Some(before + cycles),
+					| (_, cycles) => cycles,
+				};
+				pass.emit(bytes);
+				pass.instructions.push(resolved);
+			}
+		}
+		Ok(false)
+	}
+
+	// The value of EQU or SET, with what it moves with
+	fn define_value(
+		&self,
+		statement: &Statement,
+		name: &str,
+		n: i32,
+		pass: &mut Pass,
+	) -> Result<(), Error> {
+		let Statement::Directive(Directive::EQU(_, value) | Directive::SET(_, value)) = statement
+		else {
+			unreachable!()
+		};
+		let word = |symbols: &BTreeMap<String, i32>, location| {
+			Ok((self.evaluate(value, symbols, location)? as u16)
+				.to_le_bytes()
+				.to_vec())
+		};
+		let bytes = (n as u16).to_le_bytes();
+		match self.relocations(statement, pass, &bytes, word)?.pop() {
+			| Some((_, reference)) => pass.references.insert(name.to_string(), reference),
+			| None => pass.references.remove(name),
+		};
+		pass.symbols.insert(name.to_string(), n);
+		Ok(())
+	}
+
+	/*
+	 * Finds the words of the bytes of a statement which are to be relocated,
+	 * by producing them again with each segment or external it uses moved:
+	 * a word relative to it moves by as much, and what else moves can't be
+	 * relocated, like a byte or a word times 2. A word relative to an
+	 * external must be nothing but it.
+	 */
+	fn relocations(
+		&self,
+		statement: &Statement,
+		pass: &Pass,
+		bytes: &[u8],
+		produce: impl Fn(&BTreeMap<String, i32>, u16) -> Result<Vec<u8>, Error>,
+	) -> Result<Vec<(u16, Reference)>, Error> {
+		if !self.relocatable {
+			return Ok(Vec::new());
+		}
+		let names = names(statement);
+		let mut references: BTreeSet<_> = names
+			.iter()
+			.filter_map(|name| pass.references.get(name).cloned())
+			.collect();
+		if pass.segment != Segment::Absolute {
+			references.insert(Reference::Segment(pass.segment));
+		}
+
+		let mut relocations: Vec<(u16, Reference)> = Vec::new();
+		for reference in references {
+			let symbols = names
+				.iter()
+				.filter_map(|name| {
+					let n = *pass.symbols.get(name)?;
+					match pass.references.get(name) == Some(&reference) {
+						| true => Some((name.clone(), n + SHIFT as i32)),
+						| false => Some((name.clone(), n)),
+					}
+				})
+				.collect();
+			let location = match reference == Reference::Segment(pass.segment) {
+				| true => pass.position.wrapping_add(SHIFT),
+				| false => pass.position,
+			};
+			let unrelocatable = || Error::Unrelocatable(reference.clone());
+			let moved = produce(&symbols, location).map_err(|_| unrelocatable())?;
+			if moved.len() != bytes.len() {
+				return Err(unrelocatable());
+			}
+			let word = |bytes: &[u8], i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+			let mut i = 0;
+			while i < bytes.len() {
+				if moved[i] == bytes[i] {
+					i += 1;
+					continue;
+				}
+				if i + 1 == bytes.len()
+					|| word(&moved, i) != word(bytes, i).wrapping_add(SHIFT)
+					|| matches!(reference, Reference::External(_)) && word(bytes, i) != 0
+					|| relocations.iter().any(|x| x.0.abs_diff(i as u16) < 2)
+				{
+					return Err(unrelocatable());
+				}
+				relocations.push((i as u16, reference.clone()));
+				i += 2;
+			}
+		}
+		relocations.sort_by_key(|x| x.0);
+		Ok(relocations)
+	}
+
+	// What can't be assembled into an absolute program
+	fn check_relocation(&self, directive: &Directive) -> Result<(), Error> {
+		match directive {
+			| Directive::CSEG => Err(Error::Relocation("CSEG".to_string())),
+			| Directive::DSEG => Err(Error::Relocation("DSEG".to_string())),
+			| Directive::EXTRN(_) => Err(Error::Relocation("EXTRN".to_string())),
+			| Directive::MACLIB(_) => Err(Error::Unsupported("MACLIB".to_string())),
+			| _ => Ok(()),
+		}
+	}
+
+	// The value of an expression, as a word for Digital Research
+	fn evaluate(
+		&self,
+		expr: &Expr,
+		symbols: &BTreeMap<String, i32>,
+		location: u16,
+	) -> Result<i32, Error> {
+		let n = expr.evaluate(symbols, location)?;
+		match self.dialect {
+			| Dialect::Standard => Ok(n),
+			| Dialect::DigitalResearch => Ok(n as u16 as i32),
+		}
+	}
+
+	// A value where a byte is expected, which may be a word from 0FF00H for Digital Research
+	fn byte(&self, n: i32) -> i32 {
+		match self.dialect {
+			| Dialect::DigitalResearch if n >= 0xFF00 => n & 0xFF,
+			| _ => n,
+		}
+	}
+
+	// The d of (IX+d), which is a negative word for Digital Research
+	fn offset(&self, n: i32) -> i32 {
+		match self.dialect {
+			| Dialect::Standard => n,
+			| Dialect::DigitalResearch => n as i16 as i32,
+		}
+	}
+
+	// The operand of ORG, DS and END, which can't be a forward reference
+	fn count(
+		&self,
+		expr: &Expr,
+		symbols: &BTreeMap<String, i32>,
+		location: u16,
+	) -> Result<u16, Error> {
+		match self.evaluate(expr, symbols, location)? {
+			| n if (0..=0xFFFF).contains(&n) => Ok(n as u16),
+			| n => Err(Error::ValueOutOfRange(n)),
+		}
+	}
+
+	// The bytes of DB, DW, and DS with a value
+	fn data(
+		&self,
+		directive: &Directive,
+		symbols: &BTreeMap<String, i32>,
+		location: u16,
+		strict: bool,
+	) -> Result<Vec<u8>, Error> {
+		let value = |expr: &Expr| match self.evaluate(expr, symbols, location) {
+			| Err(_) if !strict => Ok(0),
+			| result => result,
+		};
+		let checked = |n: i32, range: RangeInclusive<i32>| match n {
+			| n if strict && !range.contains(&n) => Err(Error::ValueOutOfRange(n)),
+			| n => Ok(n),
+		};
+
+		match directive {
+			| Directive::DB(items) => {
+				let mut bytes = Vec::new();
+				for item in items {
+					match item {
+						| Data::Value(expr) => {
+							bytes.push(checked(self.byte(value(expr)?), -0x80..=0xFF)? as u8)
+						}
+						| Data::String(string) => bytes.extend(string.bytes()),
+					}
+				}
+				Ok(bytes)
+			}
+			| Directive::DW(items) => {
+				let mut bytes = Vec::new();
+				for expr in items {
+					bytes.extend((checked(value(expr)?, -0x8000..=0xFFFF)? as u16).to_le_bytes());
+				}
+				Ok(bytes)
+			}
+			| Directive::DS(size, Some(fill)) => {
+				Ok(vec![
+					checked(self.byte(value(fill)?), -0x80..=0xFF)? as u8;
+					self.count(size, symbols, location)? as usize
+				])
+			}
+			| _ => Ok(Vec::new()),
+		}
+	}
+
+	/*
+	 * The constants are bytes or words depending on the instruction, so
+	 * those which don't fit are tried again as bytes for Digital Research.
+	 * Gives the instruction which was encoded, with its bytes.
+	 */
+	fn assemble(&self, resolved: Inst) -> Result<(Inst, Vec<u8>), Error> {
+		match self.encode(resolved.clone()) {
+			| Err(error @ Error::Instruction(assembler::Error::ValueOutOfRange(_)))
+				if self.dialect == Dialect::DigitalResearch =>
+			{
+				let bytes = resolved
+					.try_map::<_, _, _, _, ()>(
+						&|n| Ok(*n),
+						&|nn| Ok(*nn),
+						&|x| Ok(self.byte(*x)),
+						&|d| Ok(*d),
+					)
+					.unwrap();
+				let encoded = self.encode(bytes.clone()).map_err(|_| error)?;
+				Ok((bytes, encoded))
+			}
+			| result => Ok((resolved, result?)),
+		}
+	}
+
+	fn encode(&self, resolved: Inst) -> Result<Vec<u8>, Error> {
+		let mut assembler = Assembler::new(
+			iter::once(resolved),
+			self.enable_macro_instructions,
+			self.enable_undocumented_instructions,
+		)
+		.with_target(self.target);
+		let bytes = assembler.by_ref().collect();
+		match assembler.error() {
+			| Some(error) => Err(Error::Instruction(error.clone())),
+			| None => Ok(bytes),
+		}
+	}
+
+	/*
+	 * When it isn't strict, what can't be computed yet is 0 and the values
+	 * aren't checked
+	 */
+	fn convert(
+		&self,
+		inst: &Instruction<Expr, Expr, Expr, Expr>,
+		symbols: &BTreeMap<String, i32>,
+		location: u16,
+		strict: bool,
+	) -> Result<Inst, Error> {
+		let value = |expr: &Expr| match self.evaluate(expr, symbols, location) {
+			| Err(_) if !strict => Ok(0),
+			| result => Ok(result?),
+		};
+		let ranged = |n: i32, range: RangeInclusive<i32>, error: fn(i32) -> Error| {
+			if strict && !range.contains(&n) {
+				return Err(error(n));
+			}
+			Ok(n)
+		};
+		// Relative to the end of the instruction, JR and DJNZ being 2 bytes long
+		let displacement = |expr: &Expr| match value(expr)? - (location as i32 + 2) {
+			| _ if !strict => Ok(0),
+			| d if (-128..=127).contains(&d) => Ok(d),
+			| d => Err(Error::JumpOutOfRange(d)),
+		};
+
+		match inst {
+			| Instruction::JR(cc, Operand::Constant(target)) => Ok(Instruction::JR(
+				cc.clone(),
+				Operand::Constant(displacement(target)?),
+			)),
+			| Instruction::DJNZ(target) => Ok(Instruction::DJNZ(displacement(target)? as i8)),
+			| _ => inst.try_map(
+				&|n| Ok(ranged(self.byte(value(n)?), -0x80..=0xFF, Error::ValueOutOfRange)? as u8),
+				&|nn| Ok(ranged(value(nn)?, -0x8000..=0xFFFF, Error::ValueOutOfRange)? as u16),
+				&value,
+				&|d| {
+					Ok(ranged(
+						self.offset(value(d)?),
+						-0x80..=0x7F,
+						Error::OffsetOutOfRange,
+					)? as i8)
+				},
+			),
+		}
+	}
+}
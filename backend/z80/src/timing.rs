@@ -0,0 +1,203 @@
+use std::fmt;
+use std::ops::Add;
+
+use crate::disassembler::decode;
+use crate::instruction::{Instruction, Operand};
+
+/*
+ * The T-states of the unprefixed instructions, which the emulator counts
+ * too. The conditional ones are given when the condition is false, and
+ * the prefixes are 0.
+ */
+#[rustfmt::skip]
+pub const CYCLES: [u8; 256] = [
+	 4, 10,  7,  6,  4,  4,  7,  4,  4, 11,  7,  6,  4,  4,  7,  4,
+	 8, 10,  7,  6,  4,  4,  7,  4, 12, 11,  7,  6,  4,  4,  7,  4,
+	 7, 10, 16,  6,  4,  4,  7,  4,  7, 11, 16,  6,  4,  4,  7,  4,
+	 7, 10, 13,  6, 11, 11, 10,  4,  7, 11, 13,  6,  4,  4,  7,  4,
+	 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+	 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+	 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+	 7,  7,  7,  7,  7,  7,  4,  7,  4,  4,  4,  4,  4,  4,  7,  4,
+	 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+	 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+	 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+	 4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+	 5, 10, 10, 10, 10, 11,  7, 11,  5, 10, 10,  0, 10, 17,  7, 11,
+	 5, 10, 10, 11, 10, 11,  7, 11,  5,  4, 10, 11, 10,  0,  7, 11,
+	 5, 10, 10, 19, 10, 11,  7, 11,  5,  4, 10,  4, 10,  0,  7, 11,
+	 5, 10, 10,  4, 10, 11,  7, 11,  5,  6, 10,  4, 10,  0,  7, 11,
+];
+
+// The T-states of the instruction after ED, a repeat not being taken
+pub fn ed_cycles(op: u8) -> u32 {
+	let (y, z) = ((op >> 3) & 7, op & 7);
+	match op {
+		| 0x40..=0x7F => match z {
+			| 0 | 1 => 12,
+			| 2 => 15,
+			| 3 => 20,
+			| 4 | 6 => 8,
+			| 5 => 14,
+			| _ if y < 4 => 9,
+			| _ if y < 6 => 18,
+			| _ => 8,
+		},
+		| 0xA0..=0xBF if z < 4 && y >= 4 => 16,
+		| _ => 8,
+	}
+}
+
+// Whether the instruction uses (HL), which becomes (IX+d) with a prefix
+fn uses_memory(op: u8) -> bool {
+	let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+	match x {
+		| 0 => op == 0x34 || op == 0x35,
+		| 1 => (y == 6) != (z == 6),
+		| 2 => z == 6,
+		| _ => false,
+	}
+}
+
+/*
+ * The T-states of an instruction. They are the same when it doesn't
+ * branch or repeat, and taken is the time of the jump or of one more
+ * iteration otherwise.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cycles {
+	pub taken: u32,
+	pub not_taken: u32,
+}
+
+impl Cycles {
+	fn new(not_taken: u32, extra: u32) -> Self {
+		Self {
+			taken: not_taken + extra,
+			not_taken,
+		}
+	}
+}
+
+// One instruction after the other
+impl Add for Cycles {
+	type Output = Cycles;
+
+	fn add(self, other: Cycles) -> Cycles {
+		Cycles {
+			taken: self.taken + other.taken,
+			not_taken: self.not_taken + other.not_taken,
+		}
+	}
+}
+
+impl fmt::Display for Cycles {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.taken == self.not_taken {
+			write!(f, "{}", self.taken)
+		} else {
+			write!(f, "{}/{}", self.taken, self.not_taken)
+		}
+	}
+}
+
+// The time of the main instructions, with what a branch adds
+fn main_cycles(op: u8) -> Cycles {
+	let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+	let extra = match (x, z) {
+		// DJNZ and JR cc
+		| (0, 0) if y == 2 || y >= 4 => 5,
+		// RET cc
+		| (3, 0) => 6,
+		// CALL cc
+		| (3, 4) => 7,
+		| _ => 0,
+	};
+	Cycles::new(CYCLES[op as usize] as u32, extra)
+}
+
+/*
+ * The T-states of the bytes of one instruction, or None if it is cut
+ * short. A DD or FD alone or followed by another prefix is a NOP of its
+ * own.
+ */
+fn encoded_cycles(bytes: &[u8]) -> Option<Cycles> {
+	match *bytes {
+		| [0xCB, op, ..] => Some(Cycles::new(
+			match (op >> 6, op & 7) {
+				| (1, 6) => 12,
+				| (_, 6) => 15,
+				| _ => 8,
+			},
+			0,
+		)),
+		| [0xED, op, ..] => {
+			let repeat = (0xB0..=0xBF).contains(&op) && op & 7 < 4;
+			Some(Cycles::new(ed_cycles(op), if repeat { 5 } else { 0 }))
+		}
+		| [0xDD | 0xFD] | [0xDD | 0xFD, 0xDD | 0xFD | 0xED, ..] => Some(Cycles::new(4, 0)),
+		| [0xDD | 0xFD, 0xCB, _, op, ..] => {
+			Some(Cycles::new(if op >> 6 == 1 { 20 } else { 23 }, 0))
+		}
+		| [0xDD | 0xFD, 0xCB, ..] => None,
+		| [0xDD | 0xFD, 0x36, ..] => Some(Cycles::new(19, 0)),
+		| [0xDD | 0xFD, op, ..] => {
+			let cycles = main_cycles(op);
+			let indexed = if uses_memory(op) { 8 } else { 0 };
+			Some(Cycles {
+				taken: 4 + cycles.taken + indexed,
+				not_taken: 4 + cycles.not_taken + indexed,
+			})
+		}
+		| [0xCB | 0xED] | [] => None,
+		| [op, ..] => Some(main_cycles(op)),
+	}
+}
+
+// Those of the Z180, whose bytes the Z80 runs as other instructions
+fn z180_cycles(inst: &Instruction<u8, u16, i32, i8>) -> Option<Cycles> {
+	use Instruction::*;
+
+	Some(match inst {
+		| MLT(_) => Cycles::new(17, 0),
+		| TST(Operand::ByteRegister(_)) => Cycles::new(7, 0),
+		| TST(Operand::AddressRegister(_)) => Cycles::new(10, 0),
+		| TST(_) => Cycles::new(9, 0),
+		| IN0(_, _) | TSTIO(_) => Cycles::new(12, 0),
+		| OUT0(_, _) => Cycles::new(13, 0),
+		| OTIM | OTDM => Cycles::new(14, 0),
+		| OTIMR | OTDMR => Cycles::new(14, 2),
+		| SLP => Cycles::new(8, 0),
+		| _ => return None,
+	})
+}
+
+impl Instruction<u8, u16, i32, i8> {
+	/*
+	 * The T-states of the instruction, from its bytes. Those of the
+	 * instructions making a macro instruction add up, and a Binary takes
+	 * the time of the instruction its bytes start with.
+	 */
+	pub fn cycles(&self) -> Option<Cycles> {
+		let bytes = self.encode()?;
+		if let Some(cycles) = z180_cycles(self) {
+			return Some(cycles);
+		}
+		if let Instruction::Binary(_) = self {
+			return encoded_cycles(&bytes);
+		}
+		let mut cycles = Cycles::new(0, 0);
+		let mut position = 0;
+		while position < bytes.len() {
+			let (_, size) = decode(&bytes[position..], true)?;
+			cycles = cycles + encoded_cycles(&bytes[position..position + size])?;
+			position += size;
+		}
+		Some(cycles)
+	}
+}
+
+// The T-states of the instruction at the start of bytes, or None if it is cut short
+pub fn cycles(bytes: &[u8]) -> Option<Cycles> {
+	decode(bytes, true)?.0.cycles()
+}
@@ -0,0 +1,343 @@
+use std::iter;
+
+use z80::assembler::{self, Assembler, Target};
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::UndocumentedRegister::*;
+use z80::instruction::WordRegister::*;
+use z80::instruction::*;
+use z80::parser::*;
+use z80::resolver::*;
+
+fn assemble(
+	inst: Instruction<u8, u16, i32, i8>,
+	enable_undocumented_instructions: bool,
+) -> Result<Vec<u8>, assembler::Error> {
+	let mut assembler = Assembler::new(iter::once(inst), false, enable_undocumented_instructions);
+	let bytes = assembler.by_ref().collect();
+	match assembler.error() {
+		| Some(error) => Err(error.clone()),
+		| None => Ok(bytes),
+	}
+}
+
+#[test]
+fn test_assembler_errors() {
+	for inst in [
+		LD(AddressRegisterWithOffset(IX, 5), AddressRegister(HL)),
+		ADD(WordRegister(IX), WordRegister(HL)),
+		PUSH(WordRegister(SP)),
+		INC(WordRegister(AF)),
+	] {
+		assert_eq!(
+			assemble(inst.clone(), true),
+			Err(assembler::Error::InvalidOperands(inst))
+		);
+	}
+
+	for inst in [
+		LD(ByteRegister(A), Constant(300)),
+		CP(Constant(-129)),
+		RST(0x05),
+		IM(3),
+		BIT(8, ByteRegister(B)),
+	] {
+		assert_eq!(
+			assemble(inst.clone(), true),
+			Err(assembler::Error::ValueOutOfRange(inst))
+		);
+	}
+
+	for inst in [
+		SLL(ByteRegister(B)),
+		LD(UndocumentedRegister(IXH), Constant(1)),
+		IN(F, PortRegister(C)),
+	] {
+		assert_eq!(
+			assemble(inst.clone(), false),
+			Err(assembler::Error::UndocumentedInstruction(inst.clone()))
+		);
+		assert!(assemble(inst, true).is_ok());
+	}
+
+	// Nothing is written for the instruction which fails
+	let mut assembler = Assembler::new(
+		vec![NOP, LD(ByteRegister(A), Constant(256)), NOP].into_iter(),
+		false,
+		false,
+	);
+	assert_eq!(assembler.by_ref().collect::<Vec<_>>(), vec![0x00]);
+	assert!(assembler.has_error_occured());
+
+	assert_eq!(
+		assembler::Error::InvalidOperands(LD(
+			AddressRegisterWithOffset(IX, 5),
+			AddressRegister(HL)
+		))
+		.to_string(),
+		"invalid operands for LD (IX+5),(HL)"
+	);
+	assert_eq!(
+		assembler::Error::UndocumentedInstruction(SLL(ByteRegister(B))).to_string(),
+		"SLL B is undocumented, and undocumented instructions aren't enabled"
+	);
+}
+
+#[test]
+fn test_8080_target() {
+	let assemble = |inst: Instruction<u8, u16, i32, i8>| {
+		let mut assembler = Assembler::new(iter::once(inst), true, true).with_target(Target::I8080);
+		let bytes: Vec<u8> = assembler.by_ref().collect();
+		match assembler.error() {
+			| Some(error) => Err(error.clone()),
+			| None => Ok(bytes),
+		}
+	};
+
+	assert_eq!(
+		assemble(LD(ByteRegister(A), AddressRegister(HL))),
+		Ok(vec![0x7E])
+	);
+	assert_eq!(
+		assemble(EX(WordRegister(DE), WordRegister(HL))),
+		Ok(vec![0xEB])
+	);
+	assert_eq!(assemble(Binary(vec![0xDD, 0xCB])), Ok(vec![0xDD, 0xCB]));
+	// The macro instructions made of 8080 instructions are kept
+	assert_eq!(
+		assemble(LD(WordRegister(BC), WordRegister(DE))),
+		Ok(vec![0x42, 0x4B])
+	);
+
+	for inst in [
+		JR(None, Constant(0)),
+		DJNZ(0),
+		EXX,
+		EX(WordRegister(AF), WordRegister(AF_)),
+		LD(WordRegister(IX), Constant(0)),
+		LD(ByteRegister(A), AddressRegisterWithOffset(IY, 1)),
+		SET(0, ByteRegister(A)),
+		SBC(WordRegister(HL), WordRegister(DE)),
+		LDIR,
+		SLL(ByteRegister(B)),
+		// SLA of a pair is made of Z80 instructions
+		SLA(WordRegister(BC)),
+	] {
+		assert_eq!(
+			assemble(inst.clone()),
+			Err(assembler::Error::UnavailableInstruction(
+				inst,
+				Target::I8080
+			))
+		);
+	}
+	assert_eq!(
+		assemble(LD(ByteRegister(A), Constant(300))),
+		Err(assembler::Error::ValueOutOfRange(LD(
+			ByteRegister(A),
+			Constant(300)
+		)))
+	);
+	assert_eq!(
+		assemble(PUSH(WordRegister(SP))),
+		Err(assembler::Error::InvalidOperands(PUSH(WordRegister(SP))))
+	);
+	assert_eq!(
+		assembler::Error::UnavailableInstruction(EXX, Target::I8080).to_string(),
+		"EXX isn't an instruction of the 8080"
+	);
+
+	let lines = parse_program("NOP\nLD A,(IX+1)\n").unwrap();
+	assert_eq!(
+		Resolver::new(0, false, false)
+			.with_target(Target::I8080)
+			.resolve(&lines)
+			.unwrap_err()
+			.to_string(),
+		"line 2: LD A,(IX+1) isn't an instruction of the 8080"
+	);
+
+	// Checked by the instruction, not by its first byte
+	for (source, name) in [
+		("loop: JR loop\n", "JR"),
+		("loop: DJNZ loop\n", "DJNZ"),
+		("EX AF,AF'\n", "EX AF,AF'"),
+		("EXX\n", "EXX"),
+	] {
+		let lines = parse_program(source).unwrap();
+		let error = Resolver::new(0, false, false)
+			.with_target(Target::I8080)
+			.resolve(&lines)
+			.unwrap_err()
+			.to_string();
+		assert!(error.starts_with(&format!("line 1: {}", name)), "{}", error);
+		assert!(error.ends_with("isn't an instruction of the 8080"), "{}", error);
+	}
+	for inst in [
+		LD(WordRegister(HL), Address(0x1234)),
+		LD(Address(0x1234), WordRegister(HL)),
+		JP(None, WordRegister(HL)),
+		EX(AddressRegister(SP), WordRegister(HL)),
+		IN(ByteRegister(A), Port(1)),
+	] {
+		assert!(assemble(inst.clone()).is_ok(), "{}", inst);
+	}
+	for inst in [
+		LD(WordRegister(BC), Address(0x1234)),
+		LD(Address(0x1234), WordRegister(SP)),
+		JP(None, WordRegister(IX)),
+		IN(ByteRegister(A), PortRegister(C)),
+		ADC(WordRegister(HL), WordRegister(BC)),
+		LD(ByteRegister(A), I),
+		NEG,
+		IM(1),
+		RETI,
+	] {
+		assert_eq!(
+			assemble(inst.clone()),
+			Err(assembler::Error::UnavailableInstruction(inst, Target::I8080))
+		);
+	}
+}
+
+#[test]
+fn test_z180_target() {
+	type Inst = Instruction<u8, u16, i32, i8>;
+	let assemble = |inst: Inst, target| {
+		let mut assembler = Assembler::new(iter::once(inst), false, true).with_target(target);
+		let bytes: Vec<u8> = assembler.by_ref().collect();
+		match assembler.error() {
+			| Some(error) => Err(error.clone()),
+			| None => Ok(bytes),
+		}
+	};
+
+	let cases: Vec<(Inst, Vec<u8>)> = vec![
+		(MLT(WordRegister(BC)), vec![0xED, 0x4C]),
+		(MLT(WordRegister(DE)), vec![0xED, 0x5C]),
+		(MLT(WordRegister(HL)), vec![0xED, 0x6C]),
+		(MLT(WordRegister(SP)), vec![0xED, 0x7C]),
+		(TST(ByteRegister(B)), vec![0xED, 0x04]),
+		(TST(ByteRegister(A)), vec![0xED, 0x3C]),
+		(TST(AddressRegister(HL)), vec![0xED, 0x34]),
+		(TST(Constant(0x80)), vec![0xED, 0x64, 0x80]),
+		(IN0(ByteRegister(B), Port(0x10)), vec![0xED, 0x00, 0x10]),
+		(IN0(ByteRegister(A), Port(0x10)), vec![0xED, 0x38, 0x10]),
+		(IN0(F, Port(0x10)), vec![0xED, 0x30, 0x10]),
+		(OUT0(Port(0x10), ByteRegister(C)), vec![0xED, 0x09, 0x10]),
+		(OUT0(Port(0x10), ByteRegister(A)), vec![0xED, 0x39, 0x10]),
+		(TSTIO(0x20), vec![0xED, 0x74, 0x20]),
+		(OTIM, vec![0xED, 0x83]),
+		(OTIMR, vec![0xED, 0x93]),
+		(OTDM, vec![0xED, 0x8B]),
+		(OTDMR, vec![0xED, 0x9B]),
+		(SLP, vec![0xED, 0x76]),
+	];
+	for (inst, bytes) in cases {
+		assert_eq!(assemble(inst.clone(), Target::Z180), Ok(bytes.clone()), "{}", inst);
+		assert_eq!(inst.size(), Some(bytes.len()), "{}", inst);
+		assert_eq!(
+			assemble(inst.clone(), Target::Z80),
+			Err(assembler::Error::UnavailableInstruction(inst.clone(), Target::Z80))
+		);
+		assert_eq!(
+			assemble(inst.clone(), Target::I8080),
+			Err(assembler::Error::UnavailableInstruction(inst, Target::I8080))
+		);
+	}
+	assert_eq!(MLT(WordRegister(HL)).cycles().unwrap().to_string(), "17");
+	assert_eq!(OTIMR.cycles().unwrap().to_string(), "16/14");
+
+	// What the Z80 has is kept, but the Z180 traps the undocumented instructions
+	assert_eq!(assemble(LDIR, Target::Z180), Ok(vec![0xED, 0xB0]));
+	assert_eq!(
+		assemble(INC(UndocumentedRegister(IXH)), Target::Z180),
+		Err(assembler::Error::UnavailableInstruction(
+			INC(UndocumentedRegister(IXH)),
+			Target::Z180
+		))
+	);
+	assert_eq!(
+		assemble(MLT(WordRegister(IX)), Target::Z180),
+		Err(assembler::Error::InvalidOperands(MLT(WordRegister(IX))))
+	);
+	assert_eq!(
+		assemble(TST(Constant(300)), Target::Z180),
+		Err(assembler::Error::ValueOutOfRange(TST(Constant(300))))
+	);
+
+	let lines = parse_program(
+		"MLT HL\nTST (HL)\nTST 1\nIN0 D,(3)\nOUT0 (3),E\nTSTIO 0FFH\nOTDMR\nSLP\n",
+	)
+	.unwrap();
+	let program = Resolver::new(0, false, false)
+		.with_target(Target::Z180)
+		.resolve(&lines)
+		.unwrap();
+	assert_eq!(
+		program.blocks[0].bytes,
+		[
+			0xED, 0x6C, 0xED, 0x34, 0xED, 0x64, 0x01, 0xED, 0x10, 0x03, 0xED, 0x19, 0x03, 0xED,
+			0x74, 0xFF, 0xED, 0x9B, 0xED, 0x76
+		]
+	);
+	assert_eq!(
+		Resolver::new(0, false, false)
+			.resolve(&lines)
+			.unwrap_err()
+			.to_string(),
+		"line 1: MLT HL isn't an instruction of the Z80"
+	);
+}
+
+#[test]
+fn test_located_errors() {
+	let error = parse_program("NOP\n\nlabel: LD A,B C\n").unwrap_err();
+	assert_eq!(
+		error,
+		ParseError {
+			line: 3,
+			column: 15,
+			text: "C".to_string()
+		}
+	);
+	assert_eq!(error.to_string(), "line 3, column 15: syntax error at 'C'");
+
+	let resolve = |source: &str| {
+		Resolver::new(0, false, false)
+			.resolve(&parse_program(source).unwrap())
+			.unwrap_err()
+	};
+	let diagnostic = resolve("NOP\n; Nothing\n\tLD A,missing\n");
+	assert_eq!(
+		diagnostic,
+		Diagnostic {
+			line: 3,
+			error: Error::UndefinedSymbol("MISSING".to_string())
+		}
+	);
+	assert_eq!(diagnostic.to_string(), "line 3: undefined symbol MISSING");
+
+	assert_eq!(
+		resolve("NOP\nLD (IX+1),(HL)\n"),
+		Diagnostic {
+			line: 2,
+			error: Error::Instruction(assembler::Error::InvalidOperands(LD(
+				AddressRegisterWithOffset(IX, 1),
+				AddressRegister(HL)
+			)))
+		}
+	);
+	assert_eq!(
+		resolve("start: NOP\nSLL A\n").to_string(),
+		"line 2: SLL A is undocumented, and undocumented instructions aren't enabled"
+	);
+	assert_eq!(
+		resolve("x EQU 1\nNOP\nx: NOP\n"),
+		Diagnostic {
+			line: 3,
+			error: Error::DuplicateLabel("X".to_string())
+		}
+	);
+}
@@ -0,0 +1,202 @@
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::output::*;
+use z80::parser::*;
+use z80::resolver::*;
+
+fn resolve(source: &str) -> Result<Program, Error> {
+	let lines = parse_program(source).unwrap();
+	Resolver::new(0x0100, false, false)
+		.resolve(&lines)
+		.map_err(|x| x.error)
+}
+
+fn block(address: u16, bytes: &[u8]) -> Block {
+	Block {
+		segment: Segment::Absolute,
+		address,
+		bytes: bytes.to_vec(),
+	}
+}
+
+#[test]
+fn test_parse_directives() {
+	let lines = parse_program(
+		"\tORG 100H\n\
+		 message: DB 'It''s', \"a\" ,'A'+1,0DH\n\
+		 \tDEFW message,0\n\
+		 \tDS 10\n\
+		 \tDEFS 2,0FFH\n\
+		 SIZE EQU $-message\n\
+		 count: SET 1\n\
+		 \tSET 3,A\n\
+		 \tEND\n",
+	)
+	.unwrap();
+	let statements: Vec<_> = lines.into_iter().flat_map(|line| line.statements).collect();
+	let message = || Expr::Identifier("MESSAGE".to_string());
+	assert_eq!(
+		statements,
+		vec![
+			Statement::Directive(Directive::ORG(Expr::Constant(0x100))),
+			Statement::Label("MESSAGE".to_string()),
+			Statement::Directive(Directive::DB(vec![
+				Data::String("It's".to_string()),
+				Data::String("a".to_string()),
+				Data::Value(Expr::Binary(
+					BinaryOperator::Add,
+					Box::new(Expr::Constant(0x41)),
+					Box::new(Expr::Constant(1))
+				)),
+				Data::Value(Expr::Constant(0x0D)),
+			])),
+			Statement::Directive(Directive::DW(vec![message(), Expr::Constant(0)])),
+			Statement::Directive(Directive::DS(Expr::Constant(10), None)),
+			Statement::Directive(Directive::DS(Expr::Constant(2), Some(Expr::Constant(0xFF)))),
+			Statement::Directive(Directive::EQU(
+				"SIZE".to_string(),
+				Expr::Binary(
+					BinaryOperator::Sub,
+					Box::new(Expr::Location),
+					Box::new(message())
+				)
+			)),
+			Statement::Directive(Directive::SET("COUNT".to_string(), Expr::Constant(1))),
+			Statement::Instruction(SET(Expr::Constant(3), ByteRegister(A))),
+			Statement::Directive(Directive::END(None)),
+		]
+	);
+}
+
+#[test]
+fn test_data() {
+	let program = resolve(
+		"start: DB 'Hi',0DH,-1\n\
+		 \tDW start,1234H,-2\n\
+		 \tDS 3,'*'\n\
+		 \tDB 0\n",
+	)
+	.unwrap();
+	assert_eq!(
+		program.blocks,
+		vec![block(
+			0x0100,
+			&[0x48, 0x69, 0x0D, 0xFF, 0x00, 0x01, 0x34, 0x12, 0xFE, 0xFF, 0x2A, 0x2A, 0x2A, 0x00]
+		)]
+	);
+	assert_eq!(
+		program.instructions[0],
+		Binary(vec![0x48, 0x69, 0x0D, 0xFF])
+	);
+
+	assert_eq!(resolve("DB 256\n").err(), Some(Error::ValueOutOfRange(256)));
+	assert_eq!(
+		resolve("DW 10000H\n").err(),
+		Some(Error::ValueOutOfRange(0x10000))
+	);
+}
+
+#[test]
+fn test_gaps() {
+	let program = resolve(
+		"\tJP main\n\
+		 buffer: DS 4\n\
+		 main: LD A,(buffer)\n\
+		 \tORG 200H\n\
+		 \tNOP\n\
+		 \tORG 110H\n\
+		 \tHALT\n\
+		 \tEND main\n\
+		 \tWhat follows END is ignored\n",
+	)
+	.unwrap();
+	assert_eq!(program.entry, Some(0x0107));
+	assert_eq!(program.symbols["BUFFER"], 0x0103);
+	assert_eq!(
+		program.blocks,
+		vec![
+			block(0x0100, &[0xC3, 0x07, 0x01]),
+			block(0x0107, &[0x3A, 0x03, 0x01]),
+			block(0x0200, &[0x00]),
+			block(0x0110, &[0x76]),
+		]
+	);
+
+	let image = binary(&program.blocks);
+	assert_eq!(image.len(), 0x101);
+	assert_eq!(
+		image[..10],
+		[0xC3, 0x07, 0x01, 0x00, 0x00, 0x00, 0x00, 0x3A, 0x03, 0x01]
+	);
+	assert_eq!(image[0x10], 0x76);
+	assert_eq!(image[0x100], 0x00);
+	assert!(binary(&[]).is_empty());
+	assert_eq!(com(&program.blocks), Some(image));
+	assert_eq!(
+		com(&[block(0x0103, &[0xC9])]),
+		Some(vec![0x00, 0x00, 0x00, 0xC9])
+	);
+	assert_eq!(com(&[block(0x00FF, &[0xC9])]), None);
+
+	assert_eq!(
+		intel_hex(&program.blocks, program.entry),
+		":03010000C3070131\n\
+		 :030107003A0301B7\n\
+		 :0102000000FD\n\
+		 :010110007678\n\
+		 :00010701F7\n"
+	);
+	let long = [block(0x0000, &[0xAA; 17])];
+	assert_eq!(
+		intel_hex(&long, None),
+		":10000000AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA50\n\
+		 :01001000AA45\n\
+		 :00000001FF\n"
+	);
+}
+
+#[test]
+fn test_symbols() {
+	// EQU may use what comes later, SET takes its values in order
+	let program = resolve(
+		"SIZE EQU end-start\n\
+		 n SET 1\n\
+		 start: LD A,n\n\
+		 n SET n+1\n\
+		 \tLD A,n\n\
+		 \tLD A,SIZE\n\
+		 end:\n",
+	)
+	.unwrap();
+	assert_eq!(
+		program.instructions,
+		vec![
+			LD(ByteRegister(A), Constant(1)),
+			LD(ByteRegister(A), Constant(2)),
+			LD(ByteRegister(A), Constant(6)),
+		]
+	);
+	assert_eq!(program.symbols["SIZE"], 6);
+	assert_eq!(program.symbols["N"], 2);
+
+	assert_eq!(
+		resolve("X EQU 1\nX EQU 1\n").err(),
+		Some(Error::DuplicateLabel("X".to_string()))
+	);
+	assert_eq!(
+		resolve("X: NOP\nX SET 1\n").err(),
+		Some(Error::DuplicateLabel("X".to_string()))
+	);
+	assert_eq!(
+		resolve("X SET 1\nX EQU 1\n").err(),
+		Some(Error::DuplicateLabel("X".to_string()))
+	);
+
+	// What changes the size of the program must be known in the first pass
+	assert_eq!(
+		resolve("DS later\nlater: NOP\n").err(),
+		Some(Error::UndefinedSymbol("LATER".to_string()))
+	);
+	assert_eq!(resolve("ORG -1\n").err(), Some(Error::ValueOutOfRange(-1)));
+}
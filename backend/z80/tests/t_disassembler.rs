@@ -0,0 +1,268 @@
+use z80::assembler::Assembler;
+use z80::disassembler::*;
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::UndocumentedRegister::*;
+use z80::instruction::WordRegister::*;
+use z80::instruction::*;
+
+type Inst = Instruction<u8, u16, i32, i8>;
+
+fn assemble(inst: Inst, enable_undocumented_instructions: bool) -> Vec<u8> {
+	let mut assembler = Assembler::new(
+		std::iter::once(inst.clone()),
+		false,
+		enable_undocumented_instructions,
+	);
+	let bytes: Vec<u8> = assembler.by_ref().collect();
+	assert!(
+		!assembler.has_error_occured(),
+		"{:?} can't be assembled",
+		inst
+	);
+	bytes
+}
+
+// NEG, RETN and IM also run with other ED opcodes
+fn is_mirror(bytes: &[u8]) -> bool {
+	bytes[0] == 0xED
+		&& (0x44..0x80).contains(&bytes[1])
+		&& (4..=6).contains(&(bytes[1] & 7))
+		&& ![0x44, 0x45, 0x4D, 0x46, 0x56, 0x5E].contains(&bytes[1])
+}
+
+// Every opcode, with every prefix, followed by a few operands
+fn encodings() -> Vec<Vec<u8>> {
+	let mut encodings = vec![];
+	for op in 0..=255u8 {
+		for operand in [[0x00, 0x00], [0x7F, 0x12], [0x80, 0xFF], [0xFE, 0x80]] {
+			for prefix in [&[][..], &[0xCB], &[0xED], &[0xDD], &[0xFD]] {
+				encodings.push([prefix, &[op], &operand].concat());
+			}
+			for prefix in [0xDD, 0xFD] {
+				encodings.push(vec![prefix, 0xCB, operand[0], op]);
+			}
+		}
+	}
+	encodings
+}
+
+#[test]
+fn test_decode_encode() {
+	for enable_undocumented_instructions in [false, true] {
+		let mut decoded = 0;
+		for bytes in encodings() {
+			let (inst, size) = decode(&bytes, enable_undocumented_instructions).unwrap();
+			if let Binary(data) = &inst {
+				assert_eq!(&data[..], &bytes[..size]);
+				continue;
+			}
+			decoded += 1;
+			let encoded = assemble(inst.clone(), enable_undocumented_instructions);
+			if is_mirror(&bytes) {
+				assert_eq!(decode(&encoded, false), Some((inst, 2)));
+				continue;
+			}
+			assert_eq!(
+				encoded,
+				&bytes[..size],
+				"{:?} isn't {:02X?}",
+				inst,
+				&bytes[..size]
+			);
+			assert_eq!(
+				decode(&encoded, enable_undocumented_instructions),
+				Some((inst, size))
+			);
+		}
+		assert!(decoded > 1000);
+	}
+}
+
+#[test]
+fn test_encode_decode() {
+	let a = || ByteRegister(A);
+	let mut instructions = vec![];
+	for r in [A, B, C, D, E, H, L] {
+		instructions.push(LD(ByteRegister(r.clone()), Constant(0xA5)));
+		instructions.push(LD(
+			ByteRegister(r.clone()),
+			AddressRegisterWithOffset(IX, -128),
+		));
+		instructions.push(LD(
+			AddressRegisterWithOffset(IY, 127),
+			ByteRegister(r.clone()),
+		));
+		instructions.push(IN(ByteRegister(r.clone()), PortRegister(C)));
+		instructions.push(SRA(ByteRegister(r.clone())));
+		for b in 0..8 {
+			instructions.push(SET(b, ByteRegister(r.clone())));
+		}
+	}
+	for d in -126..=127 {
+		instructions.push(JR(Some(Condition::NC), Constant(d)));
+		instructions.push(DJNZ(d as i8));
+		instructions.push(RES(3, AddressRegisterWithOffset(IX, d as i8)));
+	}
+	for cc in [
+		Condition::Z,
+		Condition::NZ,
+		Condition::C,
+		Condition::NC,
+		Condition::PO,
+		Condition::PE,
+		Condition::P,
+		Condition::M,
+	] {
+		instructions.push(JP(Some(cc.clone()), Constant(0x1234)));
+		instructions.push(CALL(Some(cc.clone()), Constant(0xFFFF)));
+		instructions.push(RET(Some(cc)));
+	}
+	for rr in [BC, DE, HL, SP] {
+		instructions.push(LD(WordRegister(rr.clone()), Address(0x8000)));
+		instructions.push(LD(Address(0x0001), WordRegister(rr.clone())));
+		instructions.push(SBC(WordRegister(HL), WordRegister(rr.clone())));
+		instructions.push(ADC(WordRegister(HL), WordRegister(rr)));
+	}
+	instructions.extend([
+		LD(WordRegister(IY), Constant(0xBEEF)),
+		LD(AddressRegisterWithOffset(IX, -1), Constant(0xFF)),
+		ADD(WordRegister(IX), WordRegister(IX)),
+		ADD(WordRegister(IY), WordRegister(SP)),
+		EX(AddressRegister(SP), WordRegister(IY)),
+		JP(None, WordRegister(IX)),
+		JP(None, WordRegister(HL)),
+		LD(WordRegister(SP), WordRegister(IY)),
+		PUSH(WordRegister(AF)),
+		POP(WordRegister(IX)),
+		CP(AddressRegisterWithOffset(IY, 5)),
+		SUB(Constant(1)),
+		SBC(a(), Constant(0x80)),
+		OUT(Port(0xFE), a()),
+		IN(a(), Port(0x10)),
+		LD(a(), I),
+		LD(R, a()),
+		IM(2),
+		RST(0x38),
+		RETI,
+		RETN,
+		NEG,
+		LDIR,
+		OTDR,
+		CPD,
+	]);
+	for inst in instructions {
+		let bytes = assemble(inst.clone(), false);
+		assert_eq!(decode(&bytes, false), Some((inst, bytes.len())));
+	}
+}
+
+#[test]
+fn test_undocumented_instructions() {
+	let instructions = vec![
+		SLL(ByteRegister(B)),
+		SLL(AddressRegister(HL)),
+		SLL(AddressRegisterWithOffset(IY, -7)),
+		IN(F, PortRegister(C)),
+		OUT(PortRegister(C), Constant(0)),
+		LD(UndocumentedRegister(IXH), Constant(0x12)),
+		LD(UndocumentedRegister(IYL), UndocumentedRegister(IYH)),
+		LD(ByteRegister(E), UndocumentedRegister(IXL)),
+		LD(UndocumentedRegister(IYH), ByteRegister(A)),
+		ADC(ByteRegister(A), UndocumentedRegister(IXH)),
+		CP(UndocumentedRegister(IYL)),
+		DEC(UndocumentedRegister(IXL)),
+	];
+	for inst in instructions {
+		let bytes = assemble(inst.clone(), true);
+		assert_eq!(decode(&bytes, true), Some((inst, bytes.len())));
+		assert_eq!(
+			decode(&bytes, false),
+			Some((Binary(bytes.clone()), bytes.len()))
+		);
+	}
+
+	// The mirrors run as their documented twin
+	assert_eq!(decode(&[0xED, 0x7C], true), Some((NEG, 2)));
+	assert_eq!(decode(&[0xED, 0x76], true), Some((IM(1), 2)));
+	assert_eq!(
+		decode(&[0xED, 0x55], false),
+		Some((Binary(vec![0xED, 0x55]), 2))
+	);
+}
+
+#[test]
+fn test_binary() {
+	// A prefix in front of an instruction without HL is alone
+	assert_eq!(decode(&[0xDD, 0x41], false), Some((Binary(vec![0xDD]), 1)));
+	assert_eq!(
+		decode(&[0xFD, 0xFD, 0xE9], false),
+		Some((Binary(vec![0xFD]), 1))
+	);
+	assert_eq!(
+		decode(&[0xFD, 0xE9], false),
+		Some((JP(None, WordRegister(IY)), 2))
+	);
+	// LD HL,(nn) with ED can't be written
+	assert_eq!(
+		decode(&[0xED, 0x6B, 0x34, 0x12], true),
+		Some((Binary(vec![0xED, 0x6B, 0x34, 0x12]), 4))
+	);
+	// Nor RLC (IX+d) also copied into B
+	assert_eq!(
+		decode(&[0xDD, 0xCB, 0x01, 0x00], true),
+		Some((Binary(vec![0xDD, 0xCB, 0x01, 0x00]), 4))
+	);
+	// Cut in the middle
+	assert_eq!(
+		decode(&[0xC3, 0x00], false),
+		Some((Binary(vec![0xC3, 0x00]), 2))
+	);
+	assert_eq!(decode(&[], false), None);
+
+	let instructions: Vec<Inst> = Disassembler::new(&[0x00, 0xDD, 0x00, 0x3E], false).collect();
+	assert_eq!(
+		instructions,
+		vec![NOP, Binary(vec![0xDD]), NOP, Binary(vec![0x3E])]
+	);
+}
+
+#[test]
+fn test_disassemble() {
+	let mut assembler = Assembler::new(
+		vec![
+			LD(WordRegister(HL), Constant(0x1234)),
+			LD(AddressRegisterWithOffset(IX, 5), Constant(0xFF)),
+			LD(ByteRegister(A), AddressRegisterWithOffset(IY, -3)),
+			JR(Some(Condition::NZ), Constant(-9)),
+			DJNZ(3),
+			LD(Address(0xC000), ByteRegister(A)),
+			EX(WordRegister(AF), WordRegister(AF_)),
+			JP(None, WordRegister(HL)),
+			RST(0x08),
+		]
+		.into_iter(),
+		false,
+		false,
+	);
+	let mut bytes: Vec<u8> = assembler.by_ref().collect();
+	bytes.extend([0xED, 0x70, 0xCB]);
+	assert_eq!(
+		disassemble(&bytes, 0x0100, false),
+		"\
+0100  21 34 12    LD HL,1234H
+0103  DD 36 05 FF LD (IX+5),0FFH
+0107  FD 7E FD    LD A,(IY-3)
+010A  20 F7       JR NZ,$-7             ; 0103H
+010C  10 03       DJNZ $+5              ; 0111H
+010E  32 00 C0    LD (0C000H),A
+0111  08          EX AF,AF'
+0112  E9          JP (HL)
+0113  CF          RST 08H
+0114  ED 70       DB 0EDH,70H
+0116  CB          DB 0CBH
+"
+	);
+}
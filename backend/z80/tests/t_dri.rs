@@ -0,0 +1,196 @@
+use z80::assembler::Target;
+use z80::output::binary;
+use z80::parser::*;
+use z80::resolver::*;
+
+fn resolve_as(source: &str, dialect: Dialect) -> Result<Program, Diagnostic> {
+	let lines = parse_program_as(source, Syntax::Intel).unwrap();
+	Resolver::new(0, false, false)
+		.with_target(Target::I8080)
+		.with_dialect(dialect)
+		.resolve(&lines)
+}
+
+fn resolve(source: &str) -> Result<Program, Error> {
+	resolve_as(source, Dialect::DigitalResearch).map_err(|x| x.error)
+}
+
+fn assemble(source: &str) -> Vec<u8> {
+	binary(&resolve(source).unwrap().blocks)
+}
+
+#[test]
+fn test_save_asm() {
+	// The SAVE RSX of CP/M 3, as it was written for RMAC
+	let program = resolve(include_str!("../../../save.asm")).unwrap();
+	let bytes = binary(&program.blocks);
+	assert_eq!(bytes.len(), 0x04A7);
+	assert_eq!(
+		&bytes[..12],
+		&[0, 0, 0, 0, 0, 0, 0xC3, 0x1B, 0x00, 0xC3, 0x00, 0x00]
+	);
+
+	// The header and the prefix, as assembled by hand from the source
+	let (goodbye, scbadr) = (program.symbols["GOODBYE"], program.symbols["SCBADR"]);
+	#[rustfmt::skip]
+	let prefix = [
+		0xC3, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // NEXTJ to STKYBT
+		b'S', b'A', b'V', b'E', b' ', b' ', b' ', b' ', 0x00, 0x00, 0x00,
+		0x79, 0xFE, 0x3C, 0xC2, 0x31, 0x00, // MOV A,C; CPI CALRSX; JNZ GETGOING
+		0xC5, 0xD5, 0xE5, 0x21, 0x00, 0x00, 0x19, 0x7E, 0xFE, 0xA0,
+		0xE1, 0xD1, 0xC1, 0xCA, goodbye as u8, (goodbye >> 8) as u8,
+		0xFE, 0x3B, 0xCA, 0x3A, 0x00, 0x2A, 0x0A, 0x00, 0xE9, // GETGOING
+		0xC5, 0xD5, 0xCD, 0xB2, 0x01, 0x22, scbadr as u8, (scbadr >> 8) as u8, // START
+		0x2E, 0xFA, 0x7E, 0xB7, 0x3E, 0x00, // MVI L,CMMON+1 ... MVI A,FALSE
+	];
+	assert_eq!(&bytes[0x09..0x09 + prefix.len()], &prefix);
+
+	assert_eq!(program.symbols["TRUE"], 0xFFFF);
+	assert_eq!(program.symbols["FALSE"], 0);
+	assert_eq!(program.symbols["CTLC"], 3);
+	assert_eq!(program.symbols["BDOSAD"], 6);
+	assert_eq!(program.symbols["GETSETSCB"], 0x01B2);
+	assert_eq!(program.symbols["CHGJMP"], 0x03CA);
+	assert_eq!(program.symbols["STACK"], 0x04B7);
+	// MVI A,TRUE and LXI H,0FFFFH
+	assert_eq!(&bytes[0x59..0x5B], &[0x3E, 0xFF]);
+	assert_eq!(&bytes[0x299..0x29C], &[0x21, 0xFF, 0xFF]);
+}
+
+#[test]
+fn test_identifiers() {
+	// The $ in a name are left out, and $ alone is still the location
+	assert_eq!(
+		assemble("CTL$C\tEQU 3\n\tMVI A,CTLC\n\tJMP $\n\tLXI H,CTL$$C\n"),
+		vec![0x3E, 0x03, 0xC3, 0x02, 0x00, 0x21, 0x03, 0x00]
+	);
+	assert_eq!(
+		assemble("\tCALL GET$SCB\n\tRET\nget$scb:\tNOP\n"),
+		vec![0xCD, 0x04, 0x00, 0xC9, 0x00]
+	);
+
+	// The colon may be left out of a label in the first column
+	let lines = parse_program_as("FLAG\tDB 0\nNOP\nRET\tNOP\n", Syntax::Intel).unwrap();
+	let statements: Vec<_> = lines.into_iter().flat_map(|line| line.statements).collect();
+	assert_eq!(statements.len(), 5);
+	assert_eq!(statements[0], Statement::Label("FLAG".to_string()));
+	assert_eq!(statements[3], Statement::Label("RET".to_string()));
+	assert!(parse_program_as(" FLAG DB 0\n", Syntax::Intel).is_err());
+}
+
+#[test]
+fn test_parse_directives() {
+	let lines = parse_program_as(
+		"\ttitle\t'SAVE.RSX'\n\
+		 \tpage\n\
+		 \tpage 60\n\
+		 \tmaclib z80\n\
+		 \taseg\n\
+		 \tcseg\n\
+		 \tdseg\n\
+		 \tpublic START,get$scb\n\
+		 \textrn BDOS\n\
+		 \tif not TRUE\n\
+		 \telse\n\
+		 \tendif\n",
+		Syntax::Intel,
+	)
+	.unwrap();
+	let statements: Vec<_> = lines.into_iter().flat_map(|line| line.statements).collect();
+	assert_eq!(
+		statements,
+		vec![
+			Statement::Directive(Directive::TITLE("SAVE.RSX".to_string())),
+			Statement::Directive(Directive::PAGE(None)),
+			Statement::Directive(Directive::PAGE(Some(Expr::Constant(60)))),
+			Statement::Directive(Directive::MACLIB("Z80".to_string())),
+			Statement::Directive(Directive::ASEG),
+			Statement::Directive(Directive::CSEG),
+			Statement::Directive(Directive::DSEG),
+			Statement::Directive(Directive::PUBLIC(vec![
+				"START".to_string(),
+				"GETSCB".to_string()
+			])),
+			Statement::Directive(Directive::EXTRN(vec!["BDOS".to_string()])),
+			Statement::Directive(Directive::IF(Expr::Unary(
+				UnaryOperator::Not,
+				Box::new(Expr::Identifier("TRUE".to_string()))
+			))),
+			Statement::Directive(Directive::ELSE),
+			Statement::Directive(Directive::ENDIF),
+		]
+	);
+}
+
+#[test]
+fn test_values() {
+	// The values are words, and those from 0FF00H are also bytes
+	let source = "TRUE\tEQU 0FFFFH\n\
+		FALSE\tEQU NOT TRUE\n\
+		\tMVI A,TRUE\n\
+		\tMVI B,FALSE\n\
+		\tCPI -2\n\
+		\tLXI H,TRUE\n\
+		\tLXI D,-1\n\
+		\tDB TRUE,-128,0FF00H\n\
+		\tDW -1\n\
+		\tDS 2,TRUE\n";
+	assert_eq!(
+		assemble(source),
+		vec![
+			0x3E, 0xFF, 0x06, 0x00, 0xFE, 0xFE, 0x21, 0xFF, 0xFF, 0x11, 0xFF, 0xFF, 0xFF, 0x80,
+			0x00, 0xFF, 0xFF, 0xFF, 0xFF
+		]
+	);
+	assert_eq!(
+		resolve("\tMVI A,0FEFFH\n").err().map(|x| x.to_string()),
+		Some("value out of range in LD A,0FEFFH".to_string())
+	);
+	assert!(resolve("\tDB 0FEFFH\n").is_err());
+	assert!(resolve("\tORG 0FFFFH\n\tDS 1\n").is_ok());
+
+	// Which the standard dialect doesn't do
+	assert!(resolve_as("\tMVI A,NOT 0FFFFH\n", Dialect::Standard).is_err());
+	assert_eq!(
+		resolve_as("\tDB NOT 0FFFFH\n", Dialect::Standard)
+			.err()
+			.map(|x| x.error),
+		Some(Error::ValueOutOfRange(-0x10000))
+	);
+}
+
+#[test]
+fn test_equ_again() {
+	// Like BDOSAD in save.asm
+	assert!(resolve("BDOS\tEQU 5\nBDOS\tEQU 5\n").is_ok());
+	assert!(resolve("BDOS\tEQU 5\nBDOS\tEQU 0FFFFH+6\n").is_ok());
+	assert_eq!(
+		resolve("BDOS\tEQU 5\nBDOS\tEQU 6\n").err(),
+		Some(Error::DuplicateLabel("BDOS".to_string()))
+	);
+	assert_eq!(
+		resolve_as("BDOS\tEQU 5\nBDOS\tEQU 5\n", Dialect::Standard)
+			.err()
+			.map(|x| x.error),
+		Some(Error::DuplicateLabel("BDOS".to_string()))
+	);
+}
+
+#[test]
+fn test_module_directives() {
+	// Only what changes nothing in an absolute program is accepted
+	assert_eq!(
+		assemble("\ttitle 'TEST'\n\tpage\n\taseg\nSTART:\tpublic START\n\tNOP\n"),
+		vec![0x00]
+	);
+	assert_eq!(
+		resolve("\tpublic START\n").err(),
+		Some(Error::UndefinedSymbol("START".to_string()))
+	);
+
+	let error = |source| resolve(source).err().unwrap().to_string();
+	assert_eq!(error("\tcseg\n"), "CSEG needs a relocatable output");
+	assert_eq!(error("\tdseg\n"), "DSEG needs a relocatable output");
+	assert_eq!(error("\textrn BDOS\n"), "EXTRN needs a relocatable output");
+	assert_eq!(error("\tmaclib z80\n"), "MACLIB isn't supported");
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+use z80::assembler::Assembler;
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+use z80::parser::*;
+use z80::resolver::*;
+
+fn evaluate(text: &str) -> Result<i32, EvaluationError> {
+	let (rest, expr) = parse_expr(text).unwrap();
+	assert_eq!(rest, "", "{} isn't fully parsed", text);
+	let symbols = BTreeMap::from([("TEN".to_string(), 10), ("TABLE".to_string(), 0x1234)]);
+	expr.evaluate(&symbols, 0x0100)
+}
+
+fn assemble(source: &str) -> Vec<u8> {
+	let lines = parse_program(source).unwrap();
+	let program = Resolver::new(0x0100, false, false)
+		.resolve(&lines)
+		.unwrap();
+	let mut assembler = Assembler::new(program.instructions.into_iter(), false, false);
+	let bytes: Vec<u8> = assembler.by_ref().collect();
+	assert!(!assembler.has_error_occured());
+	bytes
+}
+
+#[test]
+fn test_numbers() {
+	assert_eq!(evaluate("1234"), Ok(1234));
+	assert_eq!(evaluate("12D"), Ok(12));
+	assert_eq!(evaluate("0FFH"), Ok(0xFF));
+	assert_eq!(evaluate("0ffh"), Ok(0xFF));
+	assert_eq!(evaluate("$FF"), Ok(0xFF));
+	assert_eq!(evaluate("1010B"), Ok(10));
+	assert_eq!(evaluate("17Q"), Ok(15));
+	assert_eq!(evaluate("17O"), Ok(15));
+	assert_eq!(evaluate("1111$0000B"), Ok(0xF0));
+	assert_eq!(evaluate("'A'"), Ok(0x41));
+	assert_eq!(evaluate("'AB'"), Ok(0x4142));
+	assert_eq!(evaluate("''''"), Ok(0x27));
+	assert_eq!(evaluate("$"), Ok(0x0100));
+	assert_eq!(evaluate("ten"), Ok(10));
+	assert_eq!(
+		evaluate("UNKNOWN"),
+		Err(EvaluationError::UndefinedSymbol("UNKNOWN".to_string()))
+	);
+
+	// Not numbers
+	assert!(parse_expr("12H4").is_err());
+	assert!(parse_expr("102B").is_err());
+	assert!(parse_expr("''").is_err());
+}
+
+#[test]
+fn test_operators() {
+	assert_eq!(evaluate("1+2*3"), Ok(7));
+	assert_eq!(evaluate("(1+2)*3"), Ok(9));
+	assert_eq!(evaluate("10 - 2 - 3"), Ok(5));
+	assert_eq!(evaluate("100/7"), Ok(14));
+	assert_eq!(evaluate("100 MOD 7"), Ok(2));
+	assert_eq!(evaluate("1 SHL 4 + 1"), Ok(17));
+	assert_eq!(evaluate("0F0H SHR 4"), Ok(0x0F));
+	assert_eq!(evaluate("-1 SHR 12"), Ok(0x0F));
+	assert_eq!(evaluate("-TEN"), Ok(-10));
+	assert_eq!(evaluate("+TEN"), Ok(10));
+	assert_eq!(evaluate("HIGH TABLE"), Ok(0x12));
+	assert_eq!(evaluate("LOW TABLE"), Ok(0x34));
+	assert_eq!(evaluate("LOW(TABLE+1)"), Ok(0x35));
+	assert_eq!(evaluate("NOT 0 AND 0FFH"), Ok(0xFF));
+	assert_eq!(evaluate("NOT (0 AND 0FFH)"), Ok(-1));
+	assert_eq!(evaluate("1 OR 2 AND 3"), Ok(3));
+	assert_eq!(evaluate("6 XOR 3"), Ok(5));
+	assert_eq!(evaluate("$ + 3"), Ok(0x0103));
+	assert_eq!(evaluate("1/0"), Err(EvaluationError::DivisionByZero));
+	assert_eq!(
+		evaluate("TEN MOD (TEN-10)"),
+		Err(EvaluationError::DivisionByZero)
+	);
+}
+
+#[test]
+fn test_operands() {
+	let lines =
+		parse_program("LD A,(IX-5)\nLD (IY + 2*3),B\nLD HL,(TABLE)\nLD HL,(1+2)*3\nJP (HL)\n")
+			.unwrap();
+	let instructions: Vec<_> = lines
+		.into_iter()
+		.flat_map(|line| line.statements)
+		.map(|statement| match statement {
+			| Statement::Instruction(inst) => inst,
+			| _ => panic!(),
+		})
+		.collect();
+	let binary = |op, x, y| Expr::Binary(op, Box::new(x), Box::new(y));
+	assert_eq!(
+		instructions,
+		vec![
+			LD(
+				ByteRegister(A),
+				AddressRegisterWithOffset(
+					IX,
+					Expr::Unary(UnaryOperator::Minus, Box::new(Expr::Constant(5)))
+				)
+			),
+			LD(
+				AddressRegisterWithOffset(
+					IY,
+					binary(BinaryOperator::Mul, Expr::Constant(2), Expr::Constant(3))
+				),
+				ByteRegister(B)
+			),
+			LD(
+				WordRegister(HL),
+				Address(Expr::Identifier("TABLE".to_string()))
+			),
+			LD(
+				WordRegister(HL),
+				Constant(binary(
+					BinaryOperator::Mul,
+					binary(BinaryOperator::Add, Expr::Constant(1), Expr::Constant(2)),
+					Expr::Constant(3)
+				))
+			),
+			JP(None, AddressRegister(HL)),
+		]
+	);
+}
+
+#[test]
+fn test_assemble() {
+	assert_eq!(
+		assemble(
+			"\tLD A,'*'\n\
+			 \tLD BC,(end-start) SHR 1\n\
+			 start:\tLD (IX-1),HIGH end\n\
+			 \tLD A,(IX+end-start-3)\n\
+			 \tAND LOW(NOT 80H)\n\
+			 \tJR $+4\n\
+			 \tRST 38H\n\
+			 end:\tCP 0DH ; CR\n"
+		),
+		vec![
+			0x3E, 0x2A, 0x01, 0x06, 0x00, 0xDD, 0x36, 0xFF, 0x01, 0xDD, 0x7E, 0x09, 0xE6, 0x7F,
+			0x18, 0x02, 0xFF, 0xFE, 0x0D
+		]
+	);
+
+	let lines = parse_program("LD A,1/n\nn: NOP\n").unwrap();
+	assert!(Resolver::new(0, false, false).resolve(&lines).is_ok());
+	let lines = parse_program("LD A,1/(n-2)\nn: NOP\n").unwrap();
+	assert_eq!(
+		Resolver::new(0, false, false).resolve(&lines).err(),
+		Some(Diagnostic {
+			line: 1,
+			error: Error::DivisionByZero
+		})
+	);
+}
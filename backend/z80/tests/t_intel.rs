@@ -0,0 +1,162 @@
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Condition;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::output::binary;
+use z80::parser::*;
+use z80::resolver::*;
+
+fn assemble(source: &str, syntax: Syntax) -> Vec<u8> {
+	let lines = parse_program_as(source, syntax).unwrap();
+	let program = Resolver::new(0, false, false).resolve(&lines).unwrap();
+	binary(&program.blocks)
+}
+
+#[test]
+fn test_intel_mnemonics() {
+	// The opcodes are the ones of the 8080
+	let cases: &[(&str, &[u8])] = &[
+		("MOV A,M", &[0x7E]),
+		("mov m,b", &[0x70]),
+		("MOV D,E", &[0x53]),
+		("MVI M,12H", &[0x36, 0x12]),
+		("MVI C,-1", &[0x0E, 0xFF]),
+		("LXI H,1234H", &[0x21, 0x34, 0x12]),
+		("LXI SP,0", &[0x31, 0x00, 0x00]),
+		("LDA 1234H", &[0x3A, 0x34, 0x12]),
+		("STA 1234H", &[0x32, 0x34, 0x12]),
+		("LHLD 1234H", &[0x2A, 0x34, 0x12]),
+		("SHLD 1234H", &[0x22, 0x34, 0x12]),
+		("LDAX B", &[0x0A]),
+		("LDAX D", &[0x1A]),
+		("STAX B", &[0x02]),
+		("STAX D", &[0x12]),
+		("XCHG", &[0xEB]),
+		("XTHL", &[0xE3]),
+		("SPHL", &[0xF9]),
+		("PCHL", &[0xE9]),
+		("PUSH PSW", &[0xF5]),
+		("PUSH B", &[0xC5]),
+		("POP H", &[0xE1]),
+		("ADD B", &[0x80]),
+		("ADI 1", &[0xC6, 0x01]),
+		("ADC M", &[0x8E]),
+		("ACI 1", &[0xCE, 0x01]),
+		("SUB A", &[0x97]),
+		("SUI 1", &[0xD6, 0x01]),
+		("SBB C", &[0x99]),
+		("SBI 1", &[0xDE, 0x01]),
+		("ANA D", &[0xA2]),
+		("ANI 0FH", &[0xE6, 0x0F]),
+		("XRA A", &[0xAF]),
+		("XRI 1", &[0xEE, 0x01]),
+		("ORA L", &[0xB5]),
+		("ORI 80H", &[0xF6, 0x80]),
+		("CMP H", &[0xBC]),
+		("CPI 'A'", &[0xFE, 0x41]),
+		("INR M", &[0x34]),
+		("DCR E", &[0x1D]),
+		("INX D", &[0x13]),
+		("DCX SP", &[0x3B]),
+		("DAD B", &[0x09]),
+		("DAD H", &[0x29]),
+		("DAA", &[0x27]),
+		("CMA", &[0x2F]),
+		("STC", &[0x37]),
+		("CMC", &[0x3F]),
+		("RLC", &[0x07]),
+		("RRC", &[0x0F]),
+		("RAL", &[0x17]),
+		("RAR", &[0x1F]),
+		("NOP", &[0x00]),
+		("HLT", &[0x76]),
+		("DI", &[0xF3]),
+		("EI", &[0xFB]),
+		("JMP 1234H", &[0xC3, 0x34, 0x12]),
+		("JNZ 1234H", &[0xC2, 0x34, 0x12]),
+		("JPE 1234H", &[0xEA, 0x34, 0x12]),
+		("JM 1234H", &[0xFA, 0x34, 0x12]),
+		("CALL 1234H", &[0xCD, 0x34, 0x12]),
+		("CZ 1234H", &[0xCC, 0x34, 0x12]),
+		("CP 1234H", &[0xF4, 0x34, 0x12]),
+		("CNC 1234H", &[0xD4, 0x34, 0x12]),
+		("RET", &[0xC9]),
+		("RC", &[0xD8]),
+		("RPO", &[0xE0]),
+		("RST 7", &[0xFF]),
+		("RST 1", &[0xCF]),
+		("IN 10H", &[0xDB, 0x10]),
+		("OUT 10H", &[0xD3, 0x10]),
+	];
+	for (source, bytes) in cases {
+		assert_eq!(
+			assemble(&format!("\t{}\n", source), Syntax::Intel),
+			bytes.to_vec(),
+			"{}",
+			source
+		);
+	}
+
+	// Not 8080 instructions
+	for source in ["LDAX H", "MOV A,(HL)", "JR 0", "LD A,B", "PUSH X", "JNX 0"] {
+		assert!(
+			parse_program_as(source, Syntax::Intel).is_err(),
+			"{}",
+			source
+		);
+	}
+}
+
+#[test]
+fn test_syntax_directives() {
+	let lines = parse_program(
+		"\tLD A,B\n\
+		 .8080\n\
+		 loop:\tMOV A,B\n\
+		 \tCP loop ; Call if positive\n\
+		 .Z80\n\
+		 \tCP 1\n",
+	)
+	.unwrap();
+	let statements: Vec<_> = lines.into_iter().flat_map(|x| x.statements).collect();
+	let label = || Expr::Identifier("LOOP".to_string());
+	assert_eq!(
+		statements,
+		vec![
+			Statement::Instruction(LD(ByteRegister(A), ByteRegister(B))),
+			Statement::Label("LOOP".to_string()),
+			Statement::Instruction(LD(ByteRegister(A), ByteRegister(B))),
+			Statement::Instruction(CALL(Some(Condition::P), Constant(label()))),
+			Statement::Instruction(CP(Constant(Expr::Constant(1)))),
+		]
+	);
+
+	// The same program in both syntaxes
+	assert_eq!(
+		assemble(
+			"start:\tLXI D,message\n\
+			 \tMVI C,9\n\
+			 \tCALL 5\n\
+			 \tLDAX D\n\
+			 \tJNZ start\n\
+			 message: DB 'Hi$'\n",
+			Syntax::Intel
+		),
+		assemble(
+			"start:\tLD DE,message\n\
+			 \tLD C,9\n\
+			 \tCALL 5\n\
+			 \tLD A,(DE)\n\
+			 \tJP NZ,start\n\
+			 message: DB 'Hi$'\n",
+			Syntax::Zilog
+		)
+	);
+	assert_eq!(
+		assemble(
+			"\tMOV A,M\r\n.Z80\r\n\tLD A,(HL) ; CR LF\r\n",
+			Syntax::Intel
+		),
+		vec![0x7E, 0x7E]
+	);
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+
+use z80::listing::*;
+use z80::parser::*;
+use z80::resolver::*;
+use z80::timing::*;
+
+fn cycles(taken: u32, not_taken: u32) -> Option<Cycles> {
+	Some(Cycles { taken, not_taken })
+}
+
+#[test]
+fn test_cycles() {
+	assert_eq!(z80::timing::cycles(&[0x00]), cycles(4, 4));
+	assert_eq!(z80::timing::cycles(&[0x3E, 0x01]), cycles(7, 7));
+	assert_eq!(z80::timing::cycles(&[0x10, 0xFE]), cycles(13, 8));
+	assert_eq!(z80::timing::cycles(&[0x28, 0x00]), cycles(12, 7));
+	assert_eq!(z80::timing::cycles(&[0x18, 0x00]), cycles(12, 12));
+	assert_eq!(z80::timing::cycles(&[0xC0]), cycles(11, 5));
+	assert_eq!(z80::timing::cycles(&[0xC4, 0x00, 0x00]), cycles(17, 10));
+	assert_eq!(z80::timing::cycles(&[0xC2, 0x00, 0x00]), cycles(10, 10));
+	assert_eq!(z80::timing::cycles(&[0xCB, 0x00]), cycles(8, 8));
+	assert_eq!(z80::timing::cycles(&[0xCB, 0x46]), cycles(12, 12));
+	assert_eq!(z80::timing::cycles(&[0xCB, 0xC6]), cycles(15, 15));
+	assert_eq!(z80::timing::cycles(&[0xED, 0xB0]), cycles(21, 16));
+	assert_eq!(z80::timing::cycles(&[0xED, 0xA0]), cycles(16, 16));
+	assert_eq!(
+		z80::timing::cycles(&[0xED, 0x4B, 0x00, 0x00]),
+		cycles(20, 20)
+	);
+	assert_eq!(
+		z80::timing::cycles(&[0xDD, 0x21, 0x00, 0x00]),
+		cycles(14, 14)
+	);
+	assert_eq!(z80::timing::cycles(&[0xDD, 0x7E, 0x01]), cycles(19, 19));
+	assert_eq!(z80::timing::cycles(&[0xFD, 0x34, 0x01]), cycles(23, 23));
+	assert_eq!(
+		z80::timing::cycles(&[0xFD, 0x36, 0x01, 0x02]),
+		cycles(19, 19)
+	);
+	assert_eq!(z80::timing::cycles(&[0xDD, 0xE9]), cycles(8, 8));
+	assert_eq!(
+		z80::timing::cycles(&[0xDD, 0xCB, 0x01, 0x46]),
+		cycles(20, 20)
+	);
+	assert_eq!(
+		z80::timing::cycles(&[0xDD, 0xCB, 0x01, 0x06]),
+		cycles(23, 23)
+	);
+	assert_eq!(z80::timing::cycles(&[0xDD, 0xCB, 0x01]), None);
+	assert_eq!(z80::timing::cycles(&[]), None);
+
+	assert_eq!(
+		Cycles {
+			taken: 7,
+			not_taken: 7
+		}
+		.to_string(),
+		"7"
+	);
+	assert_eq!(
+		Cycles {
+			taken: 13,
+			not_taken: 8
+		}
+		.to_string(),
+		"13/8"
+	);
+}
+
+#[test]
+fn test_listing() {
+	let source = "; Prints the message\n\
+		\tORG 100H\n\
+		LENGTH EQU end-message\n\
+		start:\tLD DE,message\n\
+		\tJR NZ,start\n\
+		message: DB 'Hello!',0\n\
+		\tDS 2\n\
+		end:\tEND start\n\
+		Not assembled\n";
+	let program = Resolver::new(0, false, false)
+		.resolve(&parse_program(source).unwrap())
+		.unwrap();
+	assert_eq!(
+		program.records[3],
+		Record {
+			line: 5,
+			address: 0x0103,
+			bytes: vec![0x20, 0xFB],
+			cycles: cycles(12, 7),
+			value: None
+		}
+	);
+	assert_eq!(
+		listing(source, &program),
+		"    1                          ; Prints the message\n\
+		 \x20   2 0100                     \tORG 100H\n\
+		 \x20   3 0100  = 0009             LENGTH EQU end-message\n\
+		 \x20   4 0100  11 05 01       10  start:\tLD DE,message\n\
+		 \x20   5 0103  20 FB        12/7  \tJR NZ,start\n\
+		 \x20   6 0105  48 65 6C 6C        message: DB 'Hello!',0\n\
+		 \x20     0109  6F 21 00\n\
+		 \x20   7 010C                     \tDS 2\n\
+		 \x20   8 010E                     end:\tEND start\n\
+		 \x20   9                          Not assembled\n"
+	);
+}
+
+#[test]
+fn test_symbol_map() {
+	let symbols = BTreeMap::from([
+		("START".to_string(), 0x0100),
+		("TOP".to_string(), 0xC000),
+		("MINUS".to_string(), -1),
+	]);
+	assert_eq!(
+		symbol_map(&symbols, SymbolFormat::Equ),
+		"MINUS\tEQU 0FFFFH\nSTART\tEQU 0100H\nTOP\tEQU 0C000H\n"
+	);
+	assert_eq!(
+		symbol_map(&symbols, SymbolFormat::NoIce),
+		"DEF MINUS FFFF\nDEF START 0100\nDEF TOP C000\n"
+	);
+	assert_eq!(
+		symbol_map(&symbols, SymbolFormat::Sym),
+		"FFFF MINUS\n0100 START\nC000 TOP\n"
+	);
+}
+
+#[test]
+fn test_instruction_size_and_cycles() {
+	use z80::instruction::ByteRegister::*;
+	use z80::instruction::Instruction::*;
+	use z80::instruction::Operand::*;
+	use z80::instruction::WordRegister::*;
+	use z80::instruction::*;
+	type Inst = Instruction<u8, u16, i32, i8>;
+
+	let cases: Vec<(Inst, usize, Option<Cycles>)> = vec![
+		(NOP, 1, cycles(4, 4)),
+		(LD(ByteRegister(A), Constant(1)), 2, cycles(7, 7)),
+		(JP(None, Constant(0x1234)), 3, cycles(10, 10)),
+		(
+			CALL(Some(Condition::NZ), Constant(0x1234)),
+			3,
+			cycles(17, 10),
+		),
+		(JR(Some(Condition::C), Constant(0)), 2, cycles(12, 7)),
+		(
+			LD(AddressRegisterWithOffset(IX, 1), Constant(2)),
+			4,
+			cycles(19, 19),
+		),
+		(LDIR, 2, cycles(21, 16)),
+		// A macro instruction takes the time of its instructions
+		(LD(WordRegister(HL), WordRegister(DE)), 2, cycles(8, 8)),
+		(Binary(vec![0x00, 0x00]), 2, cycles(4, 4)),
+	];
+	for (inst, size, time) in cases {
+		assert_eq!(inst.size(), Some(size), "{:?}", inst);
+		assert_eq!(inst.cycles(), time, "{:?}", inst);
+	}
+
+	let invalid: Inst = LD(ByteRegister(A), WordRegister(HL));
+	assert_eq!(invalid.size(), None);
+	assert_eq!(invalid.cycles(), None);
+
+	// The Assembler and the Disassembler agree on the size of everything
+	for op in 0..=255u8 {
+		for code in [
+			vec![op, 0x10, 0x20, 0x30],
+			vec![0xCB, op],
+			vec![0xED, op, 0x10, 0x20],
+			vec![0xDD, op, 0x10, 0x20],
+			vec![0xDD, 0xCB, 0x10, op],
+		] {
+			let (inst, size) = z80::disassembler::decode(&code, true).unwrap();
+			assert_eq!(inst.size(), Some(size), "{:02X?}", code);
+			assert!(inst.cycles().is_some(), "{:02X?}", code);
+		}
+	}
+}
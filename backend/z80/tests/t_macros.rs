@@ -0,0 +1,263 @@
+use z80::output::binary;
+use z80::parser::*;
+use z80::resolver::*;
+
+fn resolve(source: &str) -> Result<Program, Diagnostic> {
+	let lines = parse_program(source).map_err(|e| panic!("{}", e)).unwrap();
+	Resolver::new(0, false, false).resolve(&lines)
+}
+
+fn assemble(source: &str) -> Vec<u8> {
+	binary(&resolve(source).unwrap().blocks)
+}
+
+#[test]
+fn test_macro() {
+	let source = "ADDN\tMACRO REG,N\n\
+		\tLD A,REG\n\
+		\tADD A,N\n\
+		\tLD REG,A\n\
+		\tENDM\n\
+		\tADDN B,1\n\
+		next:\taddn c, 2 ; a comment\n\
+		\tADDN D,3\n";
+	assert_eq!(
+		assemble(source),
+		vec![
+			0x78, 0xC6, 0x01, 0x47, // B
+			0x79, 0xC6, 0x02, 0x4F, // C
+			0x7A, 0xC6, 0x03, 0x57, // D
+		]
+	);
+
+	// What a macro gives is part of the line using it
+	let lines = parse_program(source).unwrap();
+	assert_eq!(lines.len(), 8);
+	assert!(lines[..5].iter().all(|line| line.statements.is_empty()));
+	assert_eq!(lines[6].number, 7);
+	assert_eq!(lines[6].statements.len(), 4);
+	assert_eq!(lines[6].statements[0], Statement::Label("NEXT".to_string()));
+	let program = resolve(source).unwrap();
+	assert_eq!(program.symbols["NEXT"], 4);
+	assert_eq!(program.records[1].line, 7);
+	assert_eq!(program.records[1].bytes, vec![0x79, 0xC6, 0x02, 0x4F]);
+}
+
+#[test]
+fn test_arguments() {
+	// Between < and >, an argument may have commas
+	let source = "STORE\tMACRO DATA,PLACE\n\
+		PLACE:\tDB DATA\n\
+		\tENDM\n\
+		\tSTORE <1,2,3>,TABLE\n\
+		\tSTORE 'A;B',TEXT\n\
+		\tLD HL,TABLE\n\
+		\tLD DE,TEXT\n";
+	let program = resolve(source).unwrap();
+	assert_eq!(
+		binary(&program.blocks),
+		vec![1, 2, 3, b'A', b';', b'B', 0x21, 0x00, 0x00, 0x11, 0x03, 0x00]
+	);
+
+	// The missing arguments are empty
+	assert_eq!(
+		assemble("M\tMACRO X,Y\n\tDB X Y\n\tENDM\n\tM 1\n\tM 1,+1\n"),
+		vec![1, 2]
+	);
+	// A name is only replaced as a whole, and not in the strings
+	assert_eq!(
+		assemble("M\tMACRO X\n\tDB X,XX,'X'\nXX\tEQU 2\n\tENDM\n\tM 1\n"),
+		vec![1, 2, b'X']
+	);
+	// & joins a parameter to what is around it
+	assert_eq!(
+		assemble("JUMP\tMACRO CC\n\tJP CC,DEST&CC\nDESTNZ:\n\tENDM\n\tJUMP NZ\n"),
+		vec![0xC2, 0x03, 0x00]
+	);
+	// The ' of AF' doesn't start a string
+	assert_eq!(
+		assemble("SWAP\tMACRO R\n\tEX AF,AF'\n\tLD A,R\n\tENDM\n\tSWAP B\n"),
+		vec![0x08, 0x78]
+	);
+}
+
+#[test]
+fn test_local_labels() {
+	// Each expansion has its own labels
+	let source = "DELAY\tMACRO N\n\
+		\tLOCAL LOOP\n\
+		\tLD B,N\n\
+		LOOP:\tDJNZ LOOP\n\
+		\tENDM\n\
+		\tDELAY 10\n\
+		\tDELAY 20\n\
+		LOOP:\tJR LOOP\n";
+	let program = resolve(source).unwrap();
+	assert_eq!(
+		binary(&program.blocks),
+		vec![0x06, 0x0A, 0x10, 0xFE, 0x06, 0x14, 0x10, 0xFE, 0x18, 0xFE]
+	);
+	assert_eq!(program.symbols["LOOP"], 8);
+	assert_eq!(program.symbols["_LOOP_0001"], 2);
+	assert_eq!(program.symbols["_LOOP_0002"], 6);
+
+	// Without LOCAL, the label is defined twice
+	assert_eq!(
+		resolve("M\tMACRO\nL:\tNOP\n\tENDM\n\tM\n\tM\n").err(),
+		Some(Diagnostic {
+			line: 5,
+			error: Error::DuplicateLabel("L".to_string())
+		})
+	);
+}
+
+#[test]
+fn test_repeat() {
+	let source = "COUNT\tEQU 3\n\
+		\tREPT COUNT\n\
+		\tRLCA\n\
+		\tENDM\n\
+		\tREPT 0\n\
+		\tHALT\n\
+		\tENDM\n\
+		\tIRP RR,<BC, DE>\n\
+		\tPUSH RR\n\
+		\tENDM\n\
+		\tRET\n";
+	assert_eq!(assemble(source), vec![0x07, 0x07, 0x07, 0xC5, 0xD5, 0xC9]);
+
+	// The count is known when the REPT is read
+	assert_eq!(
+		parse_program("\tREPT LATER\n\tNOP\n\tENDM\nLATER\tEQU 1\n"),
+		Err(ParseError {
+			line: 1,
+			column: 1,
+			text: "REPT LATER".to_string()
+		})
+	);
+}
+
+#[test]
+fn test_nested_macros() {
+	// A macro may use another one, or be made of REPT
+	let source = "CLEAR\tMACRO R\n\
+		\tLD R,0\n\
+		\tENDM\n\
+		CLEARALL\tMACRO\n\
+		\tIRP R,<A,B>\n\
+		\tCLEAR R\n\
+		\tENDM\n\
+		\tREPT 2\n\
+		\tINC B\n\
+		\tENDM\n\
+		\tENDM\n\
+		\tCLEARALL\n";
+	assert_eq!(assemble(source), vec![0x3E, 0x00, 0x06, 0x00, 0x04, 0x04]);
+
+	// A macro using itself is stopped
+	let error = parse_program("LOOP\tMACRO\n\tLOOP\n\tENDM\n\tNOP\n\tLOOP\n").unwrap_err();
+	assert_eq!(error.line, 5);
+}
+
+#[test]
+fn test_macro_errors() {
+	let error = parse_program("\tNOP\nM\tMACRO\n\tNOP\n").unwrap_err();
+	assert_eq!(
+		error,
+		ParseError {
+			line: 2,
+			column: 1,
+			text: "M\tMACRO".to_string()
+		}
+	);
+	assert_eq!(parse_program("\tNOP\n\tENDM\n").unwrap_err().line, 2);
+	assert_eq!(
+		parse_program("M\tMACRO A\n\tENDM\n\tM 1,2\n").unwrap_err(),
+		ParseError {
+			line: 3,
+			column: 2,
+			text: "M 1,2".to_string()
+		}
+	);
+	// What can't be read in a macro is shown on the line using it
+	assert_eq!(
+		parse_program("M\tMACRO R\n\tLD R,A\n\tENDM\n\n\tM (\n").unwrap_err(),
+		ParseError {
+			line: 5,
+			column: 2,
+			text: "LD (,A".to_string()
+		}
+	);
+}
+
+#[test]
+fn test_conditional_assembly() {
+	let source = "BANKED\tEQU 0\n\
+		\tIF BANKED\n\
+		\tLD A,1\n\
+		\tIF UNDEFINED\n\
+		\tLD A,2\n\
+		\tENDIF\n\
+		\tELSE\n\
+		\tLD A,3\n\
+		\tIF NOT BANKED\n\
+		\tLD A,4\n\
+		\tELSE\n\
+		\tLD A,5\n\
+		\tENDIF\n\
+		\tENDIF\n\
+		\tRET\n";
+	let program = resolve(source).unwrap();
+	assert_eq!(binary(&program.blocks), vec![0x3E, 0x03, 0x3E, 0x04, 0xC9]);
+	// The lines which aren't assembled have no address in the listing
+	let lines: Vec<_> = program.records.iter().map(|x| x.line).collect();
+	assert_eq!(lines, vec![1, 2, 7, 8, 9, 10, 11, 13, 14, 15]);
+
+	// Labels aren't defined in what isn't assembled
+	assert_eq!(
+		resolve("\tIF 0\nSKIPPED:\tNOP\n\tENDIF\n\tJP SKIPPED\n")
+			.err()
+			.map(|x| x.error),
+		Some(Error::UndefinedSymbol("SKIPPED".to_string()))
+	);
+	// The condition can't come later
+	assert_eq!(
+		resolve("\tIF LATER\n\tENDIF\nLATER\tEQU 1\n")
+			.err()
+			.map(|x| x.error),
+		Some(Error::UndefinedSymbol("LATER".to_string()))
+	);
+
+	// In a macro, on its parameters
+	let source = "LOAD\tMACRO R,N\n\
+		\tIF N\n\
+		\tLD R,N\n\
+		\tELSE\n\
+		\tXOR R\n\
+		\tENDIF\n\
+		\tENDM\n\
+		\tLOAD A,0\n\
+		\tLOAD B,2\n";
+	assert_eq!(assemble(source), vec![0xAF, 0x06, 0x02]);
+}
+
+#[test]
+fn test_conditional_errors() {
+	let diagnostic = |source| resolve(source).err().unwrap();
+
+	let error = diagnostic("\tNOP\n\tELSE\n");
+	assert_eq!(
+		(error.line, error.to_string()),
+		(2, "line 2: ELSE without IF".to_string())
+	);
+	let error = diagnostic("\tNOP\n\tENDIF\n");
+	assert_eq!(error.error, Error::Unmatched("ENDIF".to_string()));
+	assert_eq!(error.error.to_string(), "ENDIF without IF");
+	let error = diagnostic("\tIF 1\n\tIF 0\n\tENDIF\n\tNOP\n");
+	assert_eq!(
+		(error.line, error.to_string()),
+		(1, "line 1: IF without ENDIF".to_string())
+	);
+	let error = diagnostic("\tIF 1\n\tEND\n\tENDIF\n");
+	assert_eq!(error.error, Error::Unmatched("IF".to_string()));
+}
@@ -0,0 +1,198 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// A directory of its own for each test, as they run in parallel
+fn directory(name: &str) -> PathBuf {
+	let directory = std::env::temp_dir().join(format!("z80-{}-{}", name, std::process::id()));
+	fs::create_dir_all(&directory).unwrap();
+	directory
+}
+
+fn z80(directory: &PathBuf, args: &[&str]) -> (bool, String) {
+	let output = Command::new(env!("CARGO_BIN_EXE_z80"))
+		.current_dir(directory)
+		.args(args)
+		.output()
+		.unwrap();
+	(
+		output.status.success(),
+		String::from_utf8(output.stderr).unwrap(),
+	)
+}
+
+#[test]
+fn test_formats() {
+	let directory = directory("formats");
+	fs::write(
+		directory.join("hello.asm"),
+		"start:\tLD C,9\n\tLD DE,message\n\tCALL 5\n\tRET\nmessage: DB 'Hi$'\n\tEND start\n",
+	)
+	.unwrap();
+
+	assert_eq!(
+		z80(&directory, &["-f", "com", "hello.asm"]),
+		(true, String::new())
+	);
+	assert_eq!(
+		fs::read(directory.join("hello.com")).unwrap(),
+		vec![0x0E, 0x09, 0x11, 0x09, 0x01, 0xCD, 0x05, 0x00, 0xC9, 0x48, 0x69, 0x24]
+	);
+	// The implicit ORG 100H is the same as an explicit one
+	fs::write(directory.join("org.asm"), "\tORG 100H\n\tLD DE,$\n").unwrap();
+	assert_eq!(
+		z80(&directory, &["-f", "com", "org.asm"]),
+		(true, String::new())
+	);
+	assert_eq!(
+		fs::read(directory.join("org.com")).unwrap(),
+		vec![0x11, 0x00, 0x01]
+	);
+	fs::write(directory.join("low.asm"), "\tORG 0\n\tNOP\n").unwrap();
+	assert_eq!(
+		z80(&directory, &["-f", "com", "low.asm"]),
+		(
+			false,
+			"low.asm: a .COM file can't write below 100H\n".to_string()
+		)
+	);
+
+	assert_eq!(
+		z80(&directory, &["-o", "out.hex", "hello.asm"]),
+		(true, String::new())
+	);
+	assert_eq!(
+		fs::read_to_string(directory.join("out.hex")).unwrap(),
+		":0C0000000E09110900CD0500C948692453\n:00000001FF\n"
+	);
+
+	assert_eq!(z80(&directory, &["hello.asm"]), (true, String::new()));
+	assert_eq!(fs::read(directory.join("hello.bin")).unwrap().len(), 12);
+
+	// Only a relocatable output has EXTRN, the module being named after the file
+	fs::write(directory.join("module.asm"), "\tEXTRN BDOS\n\tJP BDOS\n").unwrap();
+	assert_eq!(
+		z80(&directory, &["module.asm"]),
+		(
+			false,
+			"module.asm:1: EXTRN needs a relocatable output\n".to_string()
+		)
+	);
+	assert_eq!(
+		z80(&directory, &["-f", "rel", "module.asm"]),
+		(true, String::new())
+	);
+	// 1 00 0010 for its name, 110 for its 6 characters, and the M
+	assert_eq!(
+		fs::read(directory.join("module.rel")).unwrap()[..2],
+		[0b1000_0101, 0b1001_0011]
+	);
+
+	fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_errors() {
+	let directory = directory("errors");
+	fs::write(directory.join("symbol.asm"), "\tNOP\n\tLD A,value\n").unwrap();
+	fs::write(directory.join("syntax.asm"), "\tNOP\n\n\tLD A,,B\n").unwrap();
+	fs::write(directory.join("undocumented.asm"), "\tSLL A\n").unwrap();
+
+	assert_eq!(
+		z80(&directory, &["symbol.asm"]),
+		(false, "symbol.asm:2: undefined symbol VALUE\n".to_string())
+	);
+	assert_eq!(
+		z80(&directory, &["-D", "value", "10", "symbol.asm"]),
+		(true, String::new())
+	);
+	assert_eq!(
+		fs::read(directory.join("symbol.bin")).unwrap(),
+		vec![0x00, 0x3E, 0x0A]
+	);
+
+	assert_eq!(
+		z80(&directory, &["syntax.asm"]),
+		(
+			false,
+			"syntax.asm:3:2: syntax error at 'LD A,,B'\n".to_string()
+		)
+	);
+
+	assert_eq!(
+		z80(&directory, &["-t", "8080", "-u", "undocumented.asm"]),
+		(
+			false,
+			"undocumented.asm:1: SLL A isn't an instruction of the 8080\n".to_string()
+		)
+	);
+	assert!(!z80(&directory, &["undocumented.asm"]).0);
+	assert_eq!(
+		z80(&directory, &["-u", "undocumented.asm"]),
+		(true, String::new())
+	);
+	assert_eq!(
+		fs::read(directory.join("undocumented.bin")).unwrap(),
+		vec![0xCB, 0x37]
+	);
+
+	fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_listing_and_symbols() {
+	let directory = directory("listing");
+	fs::write(
+		directory.join("loop.asm"),
+		"\tORG 8000H\nloop:\tDJNZ loop\n\tRET\n",
+	)
+	.unwrap();
+
+	assert_eq!(
+		z80(
+			&directory,
+			&["-l", "loop.lst", "-s", "loop.noi", "loop.asm"]
+		),
+		(true, String::new())
+	);
+	assert_eq!(
+		fs::read_to_string(directory.join("loop.lst")).unwrap(),
+		"    1 8000                     \tORG 8000H\n\
+		 \x20   2 8000  10 FE        13/8  loop:\tDJNZ loop\n\
+		 \x20   3 8002  C9             10  \tRET\n"
+	);
+	assert_eq!(
+		fs::read_to_string(directory.join("loop.noi")).unwrap(),
+		"DEF LOOP 8000\n"
+	);
+
+	assert_eq!(
+		z80(&directory, &["-s", "loop.sym", "loop.asm"]),
+		(true, String::new())
+	);
+	assert_eq!(
+		fs::read_to_string(directory.join("loop.sym")).unwrap(),
+		"8000 LOOP\n"
+	);
+
+	fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_digital_research() {
+	let directory = directory("dri");
+	fs::write(
+		directory.join("rsx.asm"),
+		"\ttitle\t'RSX'\r\nTRUE\tequ\t0FFFFh\r\nCTL$C\tequ\t3\r\nstart\tmvi\ta,CTL$C\r\n\tif\tTRUE\r\n\tcpi\tTRUE\r\n\tendif\r\n\tend\r\n",
+	)
+	.unwrap();
+
+	assert_eq!(z80(&directory, &["-dri", "rsx.asm"]), (true, String::new()));
+	assert_eq!(
+		fs::read(directory.join("rsx.bin")).unwrap(),
+		vec![0x3E, 0x03, 0xFE, 0xFF]
+	);
+	assert!(!z80(&directory, &["-8080", "rsx.asm"]).0);
+
+	fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,298 @@
+use std::cell::Cell;
+
+use z80::output::*;
+use z80::parser::*;
+use z80::resolver::*;
+
+fn resolve_with(source: &str, relaxation: bool) -> Result<Program, Error> {
+	let lines = parse_program(source).unwrap();
+	Resolver::new(0, false, false)
+		.with_relocation(true)
+		.with_relaxation(relaxation)
+		.resolve(&lines)
+		.map_err(|x| x.error)
+}
+
+fn resolve(source: &str) -> Result<Program, Error> {
+	resolve_with(source, false)
+}
+
+fn relocation(segment: Segment, address: u16, reference: Reference) -> Relocation {
+	Relocation {
+		segment,
+		address,
+		reference,
+	}
+}
+
+fn external(name: &str) -> Reference {
+	Reference::External(name.to_string())
+}
+
+#[derive(Debug, PartialEq)]
+enum Item {
+	Byte(u8),
+	Word(u8, u16),
+	// The control, with the A field and the B field
+	Link(u8, Option<(u8, u16)>, Option<String>),
+}
+
+// Reads a .REL file the way L80 does
+fn items(bytes: &[u8]) -> Vec<Item> {
+	let position = Cell::new(0);
+	let take = |count: usize| {
+		let mut value = 0;
+		for _ in 0..count {
+			let bit = (bytes[position.get() / 8] >> (7 - position.get() % 8)) & 1;
+			value = value << 1 | bit as u16;
+			position.set(position.get() + 1);
+		}
+		value
+	};
+
+	let mut items = Vec::new();
+	loop {
+		if take(1) == 0 {
+			items.push(Item::Byte(take(8) as u8));
+			continue;
+		}
+		let kind = take(2) as u8;
+		if kind != 0 {
+			items.push(Item::Word(kind, take(8) | take(8) << 8));
+			continue;
+		}
+		let control = take(4) as u8;
+		let a = (5..=14)
+			.contains(&control)
+			.then(|| (take(2) as u8, take(8) | take(8) << 8));
+		let b = (control <= 7).then(|| {
+			let size = take(3);
+			(0..size).map(|_| take(8) as u8 as char).collect()
+		});
+		items.push(Item::Link(control, a, b));
+		match control {
+			| 14 => take((8 - position.get() % 8) % 8),
+			| 15 => break,
+			| _ => 0,
+		};
+	}
+	assert_eq!(position.get().div_ceil(8), bytes.len());
+	items
+}
+
+#[test]
+fn test_segments() {
+	let program = resolve(
+		"\tpublic start,count\n\
+		 \textrn bdos\n\
+		 start:\tld hl,message\n\
+		 \tld de,(count)\n\
+		 \tcall bdos\n\
+		 \tjp start\n\
+		 \tcall bdos\n\
+		 \tdseg\n\
+		 count:\tdw start,count-message\n\
+		 message: db 'HI$'\n\
+		 \tds 4\n\
+		 \taseg\n\
+		 \torg 38h\n\
+		 \tjp start+1\n\
+		 \tcseg\n\
+		 \tret\n\
+		 \tend start\n",
+	)
+	.unwrap();
+
+	assert_eq!(
+		program.blocks,
+		vec![
+			Block {
+				segment: Segment::Code,
+				address: 0,
+				bytes: vec![
+					0x21, 0x04, 0x00, 0xED, 0x5B, 0x00, 0x00, 0xCD, 0x00, 0x00, 0xC3, 0x00, 0x00,
+					0xCD, 0x00, 0x00,
+				],
+			},
+			Block {
+				segment: Segment::Data,
+				address: 0,
+				bytes: vec![0x00, 0x00, 0xFC, 0xFF, b'H', b'I', b'$'],
+			},
+			Block {
+				segment: Segment::Absolute,
+				address: 0x38,
+				bytes: vec![0xC3, 0x01, 0x00],
+			},
+			// CSEG goes on where it was
+			Block {
+				segment: Segment::Code,
+				address: 0x10,
+				bytes: vec![0xC9],
+			},
+		]
+	);
+
+	let code = Reference::Segment(Segment::Code);
+	let data = Reference::Segment(Segment::Data);
+	assert_eq!(
+		program.linkage,
+		Linkage {
+			relocations: vec![
+				relocation(Segment::Code, 0x01, data.clone()),
+				relocation(Segment::Code, 0x05, data),
+				relocation(Segment::Code, 0x08, external("BDOS")),
+				relocation(Segment::Code, 0x0B, code.clone()),
+				relocation(Segment::Code, 0x0E, external("BDOS")),
+				relocation(Segment::Data, 0x00, code.clone()),
+				relocation(Segment::Absolute, 0x39, code),
+			],
+			code_size: 0x11,
+			data_size: 0x0B,
+			publics: [
+				("COUNT".to_string(), (Segment::Data, 0)),
+				("START".to_string(), (Segment::Code, 0)),
+			]
+			.into(),
+			entry: Segment::Code,
+		}
+	);
+	assert_eq!(program.entry, Some(0));
+}
+
+#[test]
+fn test_relocatable_symbols() {
+	// What EQU gives moves like its value, a difference being absolute
+	let program = resolve(
+		"\textrn put\n\
+		 print\tequ put\n\
+		 after\tequ here+3\n\
+		 size\tequ after-here\n\
+		 here:\tcall print\n\
+		 \tjp after\n\
+		 \tld a,size\n",
+	)
+	.unwrap();
+	assert_eq!(
+		program.linkage.relocations,
+		vec![
+			relocation(Segment::Code, 0x01, external("PUT")),
+			relocation(Segment::Code, 0x04, Reference::Segment(Segment::Code)),
+		]
+	);
+	assert_eq!(
+		program.blocks[0].bytes,
+		vec![0xCD, 0x00, 0x00, 0xC3, 0x03, 0x00, 0x3E, 0x03]
+	);
+
+	// A JR can't leave its segment
+	let program = resolve_with(
+		"\textrn put\n\
+		 back:\tjp back\n\
+		 \tjp put\n\
+		 \tjp far\n\
+		 \tdjnz put\n\
+		 \tdseg\n\
+		 far:\tnop\n",
+		true,
+	)
+	.unwrap();
+	assert_eq!(
+		program.blocks[0].bytes,
+		vec![0x18, 0xFE, 0xC3, 0x00, 0x00, 0xC3, 0x00, 0x00, 0x05, 0xC2, 0x00, 0x00]
+	);
+
+	// Nor go to an absolute address, but in ASEG
+	let program = resolve_with("\tjp 0\n\tjp z,5\n\taseg\n\tjp 0\n", true).unwrap();
+	assert_eq!(
+		program.blocks[0].bytes,
+		vec![0xC3, 0x00, 0x00, 0xCA, 0x05, 0x00]
+	);
+	assert_eq!(program.blocks[1].bytes, vec![0x18, 0xFE]);
+	assert!(program.linkage.relocations.is_empty());
+}
+
+#[test]
+fn test_unrelocatable_values() {
+	let error = |source| resolve(source).err().unwrap().to_string();
+	assert_eq!(
+		error("here:\tld a,here\n"),
+		"a value relative to CSEG can't be relocated here"
+	);
+	assert_eq!(
+		error("here:\tld a,high here\n"),
+		"a value relative to CSEG can't be relocated here"
+	);
+	assert_eq!(
+		error("here:\tdw here*2\n"),
+		"a value relative to CSEG can't be relocated here"
+	);
+	assert_eq!(
+		error("\tdseg\nhere:\tdw 0\n\tcseg\n\tdw here+here\n"),
+		"a value relative to DSEG can't be relocated here"
+	);
+	assert_eq!(
+		error("\textrn put\n\tld hl,put+1\n"),
+		"a value relative to PUT can't be relocated here"
+	);
+	assert_eq!(
+		error("\textrn put\n\tpublic put\n"),
+		"a value relative to PUT can't be relocated here"
+	);
+	assert_eq!(
+		error("\tjp 0\n\tjr 0\n"),
+		"a value relative to CSEG can't be relocated here"
+	);
+	assert_eq!(
+		resolve("\textrn put\nput:\tnop\n").err(),
+		Some(Error::DuplicateLabel("PUT".to_string()))
+	);
+}
+
+#[test]
+fn test_rel_file() {
+	let program = resolve(
+		"\tpublic go\n\
+		 \textrn putchar\n\
+		 go:\tcall putchar\n\
+		 \tcall putchar\n\
+		 \tjp go\n\
+		 \tdseg\n\
+		 \tdw go\n\
+		 \tds 2\n\
+		 \tend go\n",
+	)
+	.unwrap();
+	let bytes = rel("HELLO", &program.blocks, program.entry, &program.linkage);
+	let name = |x: &str| Some(x.to_string());
+	assert_eq!(
+		items(&bytes),
+		vec![
+			Item::Link(2, None, name("HELLO")),
+			Item::Link(0, None, name("GO")),
+			Item::Link(10, Some((0, 4)), None),
+			Item::Link(13, Some((1, 9)), None),
+			Item::Link(11, Some((1, 0)), None),
+			// The chain of PUTCHAR, from the last one
+			Item::Byte(0xCD),
+			Item::Byte(0x00),
+			Item::Byte(0x00),
+			Item::Byte(0xCD),
+			Item::Word(1, 0x0001),
+			Item::Byte(0xC3),
+			Item::Word(1, 0x0000),
+			Item::Link(11, Some((2, 0)), None),
+			Item::Word(1, 0x0000),
+			Item::Link(7, Some((1, 0)), name("GO")),
+			Item::Link(6, Some((1, 4)), name("PUTCHAR")),
+			Item::Link(14, Some((1, 0)), None),
+			Item::Link(15, None, None),
+		]
+	);
+
+	// The names are cut to 7 characters
+	let program = resolve("\textrn printstr\n\tjp printstr\n").unwrap();
+	let bytes = rel("A", &program.blocks, program.entry, &program.linkage);
+	assert!(items(&bytes).contains(&Item::Link(6, Some((1, 1)), name("PRINTST"))));
+	assert_eq!(items(&bytes).last(), Some(&Item::Link(15, None, None)));
+}
@@ -0,0 +1,255 @@
+use z80::assembler::{self, Assembler};
+use z80::instruction::ByteRegister::*;
+use z80::instruction::Instruction::*;
+use z80::instruction::Operand::*;
+use z80::instruction::WordRegister::*;
+use z80::instruction::*;
+use z80::parser::{parse_program, Expr, Line, ParseError, Statement};
+use z80::resolver::*;
+
+fn resolve(source: &str, origin: u16) -> Result<Program, Error> {
+	let lines = parse_program(source).unwrap();
+	Resolver::new(origin, false, false)
+		.resolve(&lines)
+		.map_err(|x| x.error)
+}
+
+fn statements(lines: Vec<Line>) -> Vec<Statement> {
+	lines.into_iter().flat_map(|line| line.statements).collect()
+}
+
+fn assemble(source: &str, origin: u16) -> Vec<u8> {
+	let program = resolve(source, origin).unwrap();
+	let mut assembler = Assembler::new(program.instructions.into_iter(), false, false);
+	let bytes: Vec<u8> = assembler.by_ref().collect();
+	assert!(!assembler.has_error_occured());
+	bytes
+}
+
+#[test]
+fn test_parse_program() {
+	let lines = parse_program(
+		"; A comment on its own\n\
+		 start:\n\
+		 \tLD HL,buffer ; Where to write\n\
+		 loop: LD (HL),A\n\
+		 \n\
+		 \tDJNZ loop\n\
+		 \tJP NZ,$\n\
+		 \tRET",
+	)
+	.unwrap();
+	assert_eq!(lines.len(), 8);
+	assert_eq!(lines[2].number, 3);
+	assert_eq!(
+		statements(lines),
+		vec![
+			Statement::Label("START".to_string()),
+			Statement::Instruction(LD(
+				WordRegister(HL),
+				Constant(Expr::Identifier("BUFFER".to_string()))
+			)),
+			Statement::Label("LOOP".to_string()),
+			Statement::Instruction(LD(AddressRegister(HL), ByteRegister(A))),
+			Statement::Instruction(DJNZ(Expr::Identifier("LOOP".to_string()))),
+			Statement::Instruction(JP(Some(Condition::NZ), Constant(Expr::Location))),
+			Statement::Instruction(RET(None)),
+		]
+	);
+
+	// What isn't understood is located
+	assert_eq!(
+		parse_program("NOP\n  LD A,,B\nNOP\n"),
+		Err(ParseError {
+			line: 2,
+			column: 3,
+			text: "LD A,,B".to_string()
+		})
+	);
+}
+
+#[test]
+fn test_names_and_registers() {
+	// A name may start like a register, a condition or a mnemonic
+	let lines =
+		parse_program("JP CALLBACK\nCALL C,HLT\nLD BC,AF_SAVE\nCPL\nRETI\nRLD\nEX AF,AF'").unwrap();
+	assert_eq!(
+		statements(lines),
+		vec![
+			Statement::Instruction(JP(None, Constant(Expr::Identifier("CALLBACK".to_string())))),
+			Statement::Instruction(CALL(
+				Some(Condition::C),
+				Constant(Expr::Identifier("HLT".to_string()))
+			)),
+			Statement::Instruction(LD(
+				WordRegister(BC),
+				Constant(Expr::Identifier("AF_SAVE".to_string()))
+			)),
+			Statement::Instruction(CPL),
+			Statement::Instruction(RETI),
+			Statement::Instruction(RLD),
+			Statement::Instruction(EX(WordRegister(AF), WordRegister(AF_))),
+		]
+	);
+}
+
+#[test]
+fn test_forward_references() {
+	let bytes = assemble(
+		"\tLD HL,message\n\
+		 \tCALL print\n\
+		 \tJR end\n\
+		 print:\tLD A,(HL)\n\
+		 \tRET\n\
+		 message:\tNOP\n\
+		 end:\tHALT\n",
+		0x0100,
+	);
+	assert_eq!(
+		bytes,
+		vec![0x21, 0x0A, 0x01, 0xCD, 0x08, 0x01, 0x18, 0x03, 0x7E, 0xC9, 0x00, 0x76]
+	);
+}
+
+#[test]
+fn test_location_counter() {
+	assert_eq!(
+		assemble("NOP\nJP $\nJR $\nDJNZ $\nLD HL,$\n", 0x8000),
+		vec![0x00, 0xC3, 0x01, 0x80, 0x18, 0xFE, 0x10, 0xFE, 0x21, 0x08, 0x80]
+	);
+}
+
+#[test]
+fn test_symbols() {
+	let program = resolve("start: NOP\nnext: LD (IX+offset),A\n", 0x0100);
+	assert_eq!(
+		program.err(),
+		Some(Error::UndefinedSymbol("OFFSET".to_string()))
+	);
+
+	let lines = parse_program("start: NOP\nnext: LD (IX+offset),A\n").unwrap();
+	let mut resolver = Resolver::new(0x0100, false, false);
+	resolver.define("offset", 5);
+	let program = resolver.resolve(&lines).unwrap();
+	assert_eq!(
+		program.instructions,
+		vec![NOP, LD(AddressRegisterWithOffset(IX, 5), ByteRegister(A))]
+	);
+	assert_eq!(
+		program.symbols.into_iter().collect::<Vec<_>>(),
+		vec![
+			("NEXT".to_string(), 0x0101),
+			("OFFSET".to_string(), 5),
+			("START".to_string(), 0x0100)
+		]
+	);
+
+	assert_eq!(
+		resolve("a1: NOP\nA1: NOP\n", 0).err(),
+		Some(Error::DuplicateLabel("A1".to_string()))
+	);
+}
+
+#[test]
+fn test_range_checks() {
+	let far = format!("JR far\n{}far: NOP\n", "NOP\n".repeat(128));
+	assert_eq!(resolve(&far, 0).err(), Some(Error::JumpOutOfRange(128)));
+	let near = format!("JR near\n{}near: NOP\n", "NOP\n".repeat(127));
+	assert_eq!(assemble(&near, 0)[..2], [0x18, 0x7F]);
+	let back = format!("back: {}DJNZ back\n", "NOP\n".repeat(126));
+	assert_eq!(assemble(&back, 0)[126..], [0x10, 0x80]);
+	let back = format!("back: {}DJNZ back\n", "NOP\n".repeat(127));
+	assert_eq!(resolve(&back, 0).err(), Some(Error::JumpOutOfRange(-129)));
+
+	let lines = parse_program("LD A,(IX+d)\n").unwrap();
+	let mut resolver = Resolver::new(0, false, false);
+	resolver.define("D", 128);
+	assert_eq!(
+		resolver.resolve(&lines).err(),
+		Some(Diagnostic {
+			line: 1,
+			error: Error::OffsetOutOfRange(128)
+		})
+	);
+	resolver.define("D", 127);
+	assert!(resolver.resolve(&lines).is_ok());
+
+	assert_eq!(
+		resolve("OUT (300),A\n", 0).err(),
+		Some(Error::ValueOutOfRange(300))
+	);
+	assert_eq!(
+		resolve("LD A,(70000)\n", 0).err(),
+		Some(Error::ValueOutOfRange(70000))
+	);
+	assert_eq!(
+		resolve("LD A,300\n", 0).err(),
+		Some(Error::Instruction(assembler::Error::ValueOutOfRange(LD(
+			ByteRegister(A),
+			Constant(300)
+		))))
+	);
+}
+
+#[test]
+fn test_relaxation() {
+	let relax = |source: &str, target: assembler::Target| {
+		let lines = parse_program(source).unwrap();
+		Resolver::new(0, false, false)
+			.with_target(target)
+			.with_relaxation(true)
+			.resolve(&lines)
+			.unwrap()
+	};
+	let bytes = |source: &str| {
+		relax(source, assembler::Target::Z80).blocks[0]
+			.bytes
+			.clone()
+	};
+
+	// The jumps too far become long, and those near enough short
+	let far = format!("JR far\n{}far: NOP\n", "NOP\n".repeat(128));
+	assert_eq!(bytes(&far)[..3], [0xC3, 0x83, 0x00]);
+	let near = format!("JP NZ,near\n{}near: NOP\n", "NOP\n".repeat(127));
+	assert_eq!(bytes(&near)[..2], [0x20, 0x7F]);
+	// PO, PE, P and M only exist for JP
+	assert_eq!(bytes("here: JP PE,here\n"), [0xEA, 0x00, 0x00]);
+	assert_eq!(bytes("here: JR M,here\n"), [0xFA, 0x00, 0x00]);
+	let back = format!("back: {}DJNZ back\n", "NOP\n".repeat(126));
+	assert_eq!(bytes(&back)[126..], [0x10, 0x80]);
+	let back = format!("back: {}DJNZ back\n", "NOP\n".repeat(127));
+	assert_eq!(bytes(&back)[127..], [0x05, 0xC2, 0x00, 0x00]);
+
+	// The second jump becoming long puts the target of the first too far
+	let chain = format!(
+		"JR first\n{}JR second\nfirst: {}second: NOP\n",
+		"NOP\n".repeat(125),
+		"NOP\n".repeat(128)
+	);
+	let chained = bytes(&chain);
+	assert_eq!(chained[..3], [0xC3, 0x83, 0x00]);
+	assert_eq!(chained[128..131], [0xC3, 0x03, 0x01]);
+	assert_eq!(chained.len(), 0x0104);
+
+	// The line of a long DJNZ takes the time of its two instructions
+	let program = relax(&back, assembler::Target::Z80);
+	assert_eq!(
+		program.records.last().unwrap().cycles.unwrap().to_string(),
+		"14"
+	);
+	assert_eq!(
+		program.instructions[127..],
+		[DEC(ByteRegister(B)), JP(Some(Condition::NZ), Constant(0))]
+	);
+
+	// The 8080 only has the long ones
+	let program = relax("here: JR C,here\nDJNZ here\n", assembler::Target::I8080);
+	assert_eq!(
+		program.blocks[0].bytes,
+		[0xDA, 0x00, 0x00, 0x05, 0xC2, 0x00, 0x00]
+	);
+
+	// Without it, they stay as they are written
+	assert_eq!(resolve(&far, 0).err(), Some(Error::JumpOutOfRange(128)));
+	assert_eq!(assemble(&near, 0)[..3], [0xC2, 0x82, 0x00]);
+}
@@ -90,7 +90,8 @@ pub enum Statement {
 	Return(Option<Expression>),
 	Expression(Expression),
 	Label(String),
-	Procedure(Vec<String>, Type, Box<Statement>),
+	// The parameters, the return type and the n of INTERRUPT n
+	Procedure(Vec<String>, Type, Option<u8>, Box<Statement>),
 	EndOfFile,
 	NoOperation,
 }
@@ -52,6 +52,7 @@ extern crate backend;
 
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::iter::Peekable;
 
 use crate::ast;
 use crate::ast::Expression;
@@ -74,7 +75,7 @@ impl TypeCheckable<Environment> for VariableIdx {
 }
 
 pub struct BackendConverter<InputType: Iterator<Item = ast::Statement>> {
-	input: InputType,
+	input: Peekable<InputType>,
 	statement_queue: VecDeque<Statement<VariableIdx>>,
 	env: Environment,
 }
@@ -82,7 +83,7 @@ pub struct BackendConverter<InputType: Iterator<Item = ast::Statement>> {
 impl<InputType: Iterator<Item = ast::Statement>> BackendConverter<InputType> {
 	pub fn new(input: InputType) -> Self {
 		Self {
-			input: input,
+			input: input.peekable(),
 			statement_queue: VecDeque::new(),
 			env: Environment {
 				vars_type: Vec::new(),
@@ -197,6 +198,35 @@ impl<InputType: Iterator<Item = ast::Statement>> BackendConverter<InputType> {
 		}
 	}
 
+	/*
+	 * A procedure is named by the label before it. Its parameters are
+	 * declared in its body, which isn't supported yet.
+	 */
+	fn parse_procedure(
+		&self,
+		name: String,
+		stmt: ast::Statement,
+	) -> Result<Vec<Statement<VariableIdx>>, ()> {
+		match stmt {
+			| ast::Statement::Procedure(args, return_type, interrupt, body) if args.is_empty() => {
+				let return_type = match return_type {
+					| ast::Type::Void => Type::Void,
+					| ast::Type::Byte(1) => Type::U8,
+					| ast::Type::Address(1) => Type::U16,
+					| _ => return Err(()),
+				};
+				Ok(vec![Statement::FunctionDefinition(
+					name,
+					return_type,
+					Vec::new(),
+					interrupt,
+					self.parse_statement(*body)?,
+				)])
+			}
+			| _ => Err(()),
+		}
+	}
+
 	fn parse_statement(&self, stmt: ast::Statement) -> Result<Vec<Statement<VariableIdx>>, ()> {
 		//self.statement_stack.push_back(Statement::NoOperation);
 		//Err(())
@@ -206,8 +236,17 @@ impl<InputType: Iterator<Item = ast::Statement>> BackendConverter<InputType> {
 			| ast::Statement::Block(blk) => {
 				let mut output = Vec::new();
 
-				for stmt in blk.into_iter() {
-					match self.parse_statement(stmt) {
+				let mut blk = blk.into_iter().peekable();
+				while let Some(stmt) = blk.next() {
+					let converted = match stmt {
+						| ast::Statement::Label(name)
+							if matches!(blk.peek(), Some(ast::Statement::Procedure(..))) =>
+						{
+							self.parse_procedure(name, blk.next().unwrap())
+						}
+						| stmt => self.parse_statement(stmt),
+					};
+					match converted {
 						| Ok(blk) => {
 							output.extend(blk.into_iter());
 						}
@@ -242,12 +281,22 @@ impl<InputType: Iterator<Item = ast::Statement>> BackendConverter<InputType> {
 
 				match (cond_var, if_blk, else_blk) {
 					| (Some(cond_var), Ok(if_blk), Ok(else_blk)) => {
-						// TODO: Phi operator
+						// The PHI are placed when converting to SSA form
 						Ok(vec![IfElse(cond_var, if_blk, else_blk)])
 					}
 					| _ => Err(()),
 				}
 			}
+			| ast::Statement::Label(lbl) => Ok(vec![Label(lbl)]),
+			| ast::Statement::GoToIdentifier(lbl) => Ok(vec![Goto(lbl)]),
+			| ast::Statement::GoToValue(addr) => Ok(vec![Jump(backend::ast::Expression::Constant(
+				backend::ast::Constant::Value(addr, Type::U16),
+			))]),
+			| ast::Statement::Return(None) => Ok(vec![Return(None)]),
+			| ast::Statement::Return(Some(e)) => match self.convert_expression(e) {
+				| Some(e) => Ok(vec![Return(Some(e))]),
+				| None => Err(()),
+			},
 			| ast::Statement::Halt => Ok(vec![Halt]),
 			| ast::Statement::NoOperation => Ok(vec![NoOperation]),
 			| ast::Statement::EnableInterrupt => Ok(vec![EnableInterrupt]),
@@ -276,7 +325,16 @@ impl<InputType: Iterator<Item = ast::Statement>> Iterator
 				return None;
 			}
 
-			match self.parse_statement(stmt.unwrap()) {
+			let converted = match stmt.unwrap() {
+				| ast::Statement::Label(name)
+					if matches!(self.input.peek(), Some(ast::Statement::Procedure(..))) =>
+				{
+					let procedure = self.input.next().unwrap();
+					self.parse_procedure(name, procedure)
+				}
+				| stmt => self.parse_statement(stmt),
+			};
+			match converted {
 				| Ok(output) => {
 					self.statement_queue.extend(output.into_iter());
 				}
@@ -1,13 +1,18 @@
 use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::fs::File;
+use std::io;
+use std::path::PathBuf;
 use std::process::exit;
 
+use emulator::cpm::{Cpm, BDOS_BASE, TPA};
 use plm::{parser::Parser, EOSDetector, lexer::Lexer, preprocessor_parser::*};
 
-fn show_help_and_die() {
+fn show_help_and_die() -> ! {
 	println!(concat!(
 		"./plm [ARGUMENTS] [INPUT FILES]\n",
+		"./plm run [-d DIRECTORY] [PROGRAM.COM] [ARGUMENTS]: Run a CP/M program, with DIRECTORY as drive A:\n",
 		"-h: Show this message\n",
 		"-o [FILE]: Set the output file",
 		"-D NAME VALUE: Define a global variable",
@@ -74,7 +79,56 @@ fn parse_arguments() -> ParsedArguments {
 	output
 }
 
+/*
+ * Runs a .COM file in the emulator, with the console on stdin and stdout.
+ * Everything after the program goes to its command line.
+ */
+fn run_program(mut args: impl Iterator<Item = String>) -> ! {
+	let mut directory = PathBuf::from(".");
+	let mut program_path = None;
+	let mut arguments = vec![];
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			| "-d" if program_path.is_none() => match args.next() {
+				| None => {
+					panic!("No directory has been provided with '-d'");
+				}
+				| Some(path) => directory = PathBuf::from(path),
+			},
+			| _ if program_path.is_none() => program_path = Some(arg),
+			| _ => arguments.push(arg),
+		}
+	}
+
+	let Some(path) = program_path else {
+		show_help_and_die();
+	};
+	let program = match fs::read(&path) {
+		| Err(e) => {
+			panic!("Unable to open {}: {}", path, e);
+		}
+		| Ok(program) => program,
+	};
+	if program.len() > (BDOS_BASE - TPA) as usize {
+		panic!("{} doesn't fit in the TPA", path);
+	}
+
+	let stdin = io::stdin();
+	let mut cpm = Cpm::new(&directory, stdin.lock(), io::stdout());
+	cpm.set_arguments(&arguments);
+	cpm.load(&program);
+	if let Err(e) = cpm.run() {
+		eprintln!("{}: {}", path, e);
+		exit(1);
+	}
+	exit(0);
+}
+
 fn main() {
+	if env::args().nth(1).as_deref() == Some("run") {
+		run_program(env::args().skip(2));
+	}
+
 	let user_infos = parse_arguments();
 
 	if user_infos.input_files_path.len() == 0 {
@@ -97,6 +151,10 @@ fn main() {
 					ast.push(stmt);
 				}
 
+				/*
+				 * The code isn't generated from the AST yet. Once it is, -o
+				 * gets a .REL file from backend::codegen::link_module.
+				 */
 				if parser.reached_eos() {
 					dbg!(ast);
 				}
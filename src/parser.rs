@@ -862,7 +862,7 @@ impl Parser {
 							}
 						}
 						| Some(Type::Void) => {
-							panic!("Impossible")
+							parsing_error!(stmt_pos, "A variable must have a type");
 						}
 					}
 
@@ -1027,6 +1027,9 @@ impl Parser {
 
 				let return_type;
 				match self.lexer.peek() {
+					| Some((Token::Keyword("INTERRUPT"), _)) | Some((Token::SemiColon, _)) => {
+						return_type = Type::Void;
+					}
 					| Some((Token::Keyword(_), _)) => match self.parse_type_from_token(1) {
 						| Some(t) => {
 							return_type = t;
@@ -1035,9 +1038,6 @@ impl Parser {
 							return None;
 						}
 					},
-					| Some((Token::SemiColon, _)) => {
-						return_type = Type::Void;
-					}
 					| Some((_, pos)) => {
 						parsing_error!(pos, "Invalid token")
 					}
@@ -1046,6 +1046,28 @@ impl Parser {
 					}
 				}
 
+				let mut interrupt = None;
+				if let Some((Token::Keyword("INTERRUPT"), _)) = self.lexer.peek() {
+					self.lexer.next();
+					match self.lexer.next() {
+						| Some((Token::Number(n), pos)) => {
+							if !(0..8).contains(&n) {
+								parsing_error!(pos, "Invalid interrupt number");
+							}
+							if !args.is_empty() {
+								parsing_error!(stmt_pos, "An interrupt procedure can't have parameters");
+							}
+							interrupt = Some(n as u8);
+						}
+						| Some((_, pos)) => {
+							parsing_error!(pos, "Missing the interrupt number")
+						}
+						| None => {
+							parsing_error!("Missing the interrupt number")
+						}
+					}
+				}
+
 				check_token!(self.lexer.next(), Token::SemiColon);
 
 				match self.parse_statement_block(Some(label)) {
@@ -1053,7 +1075,12 @@ impl Parser {
 						return None;
 					}
 					| Some(block) => {
-						return Some(Statement::Procedure(args, return_type, Box::new(block)));
+						return Some(Statement::Procedure(
+							args,
+							return_type,
+							interrupt,
+							Box::new(block),
+						));
 					}
 				}
 			}
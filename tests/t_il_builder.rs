@@ -0,0 +1,44 @@
+use backend::ast::*;
+use plm::{il_builder::BackendConverter, lexer::Lexer, parser::Parser};
+
+fn convert(input: &str) -> Vec<Statement<usize>> {
+	let parser = Parser::new(Lexer::from_string(String::from(input)));
+	BackendConverter::new(parser).collect()
+}
+
+#[test]
+fn test_interrupt_procedure() {
+	assert_eq!(
+		convert("TIMER: PROCEDURE INTERRUPT 7; HALT; END TIMER;"),
+		vec![Statement::FunctionDefinition(
+			"TIMER".to_string(),
+			Type::Void,
+			vec![],
+			Some(7),
+			vec![Statement::Halt]
+		)]
+	);
+	assert_eq!(
+		convert("F: PROCEDURE; RETURN; END F;"),
+		vec![Statement::FunctionDefinition(
+			"F".to_string(),
+			Type::Void,
+			vec![],
+			None,
+			vec![Statement::Return(None)]
+		)]
+	);
+}
+
+#[test]
+fn test_goto() {
+	assert_eq!(
+		convert("GO TO LOOP; LOOP: HALT; GOTO 0;"),
+		vec![
+			Statement::Goto("LOOP".to_string()),
+			Statement::Label("LOOP".to_string()),
+			Statement::Halt,
+			Statement::Jump(Expression::Constant(Constant::Value(0, Type::U16))),
+		]
+	);
+}
@@ -993,7 +993,7 @@ fn test_valid_procedure0() {
 		"f: PROCEDURE; END f;",
 		Some(vec![
 			Statement::Label("F".to_string()),
-			Statement::Procedure(vec![], Type::Void, Box::new(Statement::Block(vec![])))
+			Statement::Procedure(vec![], Type::Void, None, Box::new(Statement::Block(vec![])))
 		])
 	)
 }
@@ -1007,6 +1007,7 @@ fn test_valid_procedure1() {
 			Statement::Procedure(
 				vec!["X".to_string(), "Y".to_string()],
 				Type::Void,
+				None,
 				Box::new(Statement::Block(vec![]))
 			)
 		])
@@ -1022,6 +1023,7 @@ fn test_valid_procedure2() {
 			Statement::Procedure(
 				vec!["X".to_string(), "Y".to_string()],
 				Type::Byte(1),
+				None,
 				Box::new(Statement::Block(vec![]))
 			)
 		])
@@ -1034,7 +1036,7 @@ fn test_valid_procedure3() {
 		"f: PROCEDURE BYTE; END f;",
 		Some(vec![
 			Statement::Label("F".to_string()),
-			Statement::Procedure(vec![], Type::Byte(1), Box::new(Statement::Block(vec![])))
+			Statement::Procedure(vec![], Type::Byte(1), None, Box::new(Statement::Block(vec![])))
 		])
 	)
 }
@@ -1048,15 +1050,59 @@ fn test_valid_procedure4() {
 			Statement::Procedure(
 				vec![],
 				Type::Void,
+				None,
 				Box::new(Statement::Block(vec![
 					Statement::Label("F2".to_string()),
-					Statement::Procedure(vec![], Type::Void, Box::new(Statement::Block(vec![])))
+					Statement::Procedure(vec![], Type::Void, None, Box::new(Statement::Block(vec![])))
 				]))
 			)
 		])
 	)
 }
 
+#[test]
+fn test_valid_interrupt_procedure() {
+	compare_ast!(
+		"f: PROCEDURE INTERRUPT 7; END f;",
+		Some(vec![
+			Statement::Label("F".to_string()),
+			Statement::Procedure(vec![], Type::Void, Some(7), Box::new(Statement::Block(vec![])))
+		])
+	)
+}
+
+#[test]
+fn test_valid_interrupt_procedure1() {
+	// The interrupt number is kept along with the return type
+	compare_ast!(
+		"f: PROCEDURE BYTE INTERRUPT 2; RETURN 1; END f;",
+		Some(vec![
+			Statement::Label("F".to_string()),
+			Statement::Procedure(
+				vec![],
+				Type::Byte(1),
+				Some(2),
+				Box::new(Statement::Return(Some(Expression::Constant(1))))
+			)
+		])
+	)
+}
+
+#[test]
+fn test_invalid_interrupt_procedure0() {
+	compare_ast!("f: PROCEDURE INTERRUPT 8; END f;", None)
+}
+
+#[test]
+fn test_invalid_interrupt_procedure1() {
+	compare_ast!("f: PROCEDURE(x) INTERRUPT 1; END f;", None)
+}
+
+#[test]
+fn test_invalid_interrupt_procedure2() {
+	compare_ast!("f: PROCEDURE INTERRUPT; END f;", None)
+}
+
 #[test]
 fn test_valid_return0() {
 	compare_ast!("RETURN;", Some(vec![Statement::Return(None)]))